@@ -1,33 +1,34 @@
-use std::thread;
-use std::time::Duration;
-
 use criterion::{Criterion, criterion_group, criterion_main};
 
-use kvs::benchmark_common::{self, RemoteEngine};
-use kvs::KvsEngine;
+use kvs::benchmark_common::{self, LatencyRecorder, RemoteEngine, SpaceProbe};
+use kvs::{KvsEngine, KvStore};
+use kvs::engines::kvs::{SyncPolicy, WritePath};
 use kvs::server_common::{Engine, Pool};
 use kvs::thread_pool::*;
+use kvs::workload::Workload;
 
-fn write_heavy(store: impl KvsEngine, pool: impl ThreadPool) {
-    let keys = benchmark_common::insert_keys(store.clone(), &pool, 100);
-    benchmark_common::read_exist(store.clone(), &pool, 10, keys);
+fn write_heavy(store: impl KvsEngine, pool: &impl ThreadPool, latencies: &LatencyRecorder) {
+    let keys = benchmark_common::insert_keys(store.clone(), pool, 100, latencies);
+    benchmark_common::read_exist(store.clone(), pool, 10, keys, latencies);
 }
 
-fn read_heavy(store: impl KvsEngine, pool: impl ThreadPool) {
-    let keys = benchmark_common::insert_keys(store.clone(), &pool, 10);
-    benchmark_common::read_exist(store.clone(), &pool, 100, keys);
+fn read_heavy(store: impl KvsEngine, pool: &impl ThreadPool, latencies: &LatencyRecorder) {
+    let keys = benchmark_common::insert_keys(store.clone(), pool, 10, latencies);
+    benchmark_common::read_exist(store.clone(), pool, 100, keys, latencies);
 }
 
 fn write_queued_kvstore(c: &mut Criterion) {
     let temp = tempfile::tempdir().unwrap();
     std::env::set_current_dir(temp.path()).unwrap();
     let store = RemoteEngine::spawn_new(None, Default::default(), Default::default());
-    thread::sleep(Duration::from_secs(1));
+    let pool = RayonThreadPool::global();
+    let latencies = LatencyRecorder::new();
     c.bench_function("queued_kvstore", |b| {
         b.iter(|| {
-            write_heavy(store.clone(), RayonThreadPool::new(4).unwrap());
+            write_heavy(store.clone(), &pool, &latencies);
         })
     });
+    println!("queued_kvstore: {}", latencies.report());
 }
 
 fn read_queued_kvstore(c: &mut Criterion) {
@@ -38,12 +39,14 @@ fn read_queued_kvstore(c: &mut Criterion) {
         Default::default(),
         Default::default(),
     );
-    thread::sleep(Duration::from_secs(1));
+    let pool = RayonThreadPool::global();
+    let latencies = LatencyRecorder::new();
     c.bench_function("queued_kvstore_read", |b| {
         b.iter(|| {
-            read_heavy(store.clone(), RayonThreadPool::new(4).unwrap());
+            read_heavy(store.clone(), &pool, &latencies);
         })
     });
+    println!("queued_kvstore_read: {}", latencies.report());
 }
 
 fn write_rayon_kvstore(c: &mut Criterion) {
@@ -54,12 +57,36 @@ fn write_rayon_kvstore(c: &mut Criterion) {
         Default::default(),
         Pool::Rayon,
     );
-    thread::sleep(Duration::from_secs(1));
+    let pool = RayonThreadPool::global();
+    let latencies = LatencyRecorder::new();
     c.bench_function("rayon_kvstore", |b| {
         b.iter(|| {
-            write_heavy(store.clone(), RayonThreadPool::new(4).unwrap());
+            write_heavy(store.clone(), &pool, &latencies);
+        })
+    });
+    println!("rayon_kvstore: {}", latencies.report());
+}
+
+/// the write-heavy workload on a local store syncing each write, appended by `write_path`.
+fn write_synced_kvstore(c: &mut Criterion, name: &str, write_path: WritePath) {
+    let temp = tempfile::tempdir().unwrap();
+    let store = KvStore::open(temp.path()).unwrap().with_sync(SyncPolicy::Always).with_write_path(write_path);
+    let pool = RayonThreadPool::global();
+    let latencies = LatencyRecorder::new();
+    c.bench_function(name, |b| {
+        b.iter(|| {
+            write_heavy(store.clone(), &pool, &latencies);
         })
     });
+    println!("{}: {}", name, latencies.report());
+}
+
+fn write_buffered_kvstore(c: &mut Criterion) {
+    write_synced_kvstore(c, "buffered_synced_kvstore", WritePath::Buffered);
+}
+
+fn write_vectored_kvstore(c: &mut Criterion) {
+    write_synced_kvstore(c, "vectored_synced_kvstore", WritePath::Vectored);
 }
 
 fn read_rayon_kvstore(c: &mut Criterion) {
@@ -70,12 +97,14 @@ fn read_rayon_kvstore(c: &mut Criterion) {
         Default::default(),
         Pool::Rayon,
     );
-    thread::sleep(Duration::from_secs(1));
+    let pool = RayonThreadPool::global();
+    let latencies = LatencyRecorder::new();
     c.bench_function("rayon_kvstore_read", |b| {
         b.iter(|| {
-            read_heavy(store.clone(), RayonThreadPool::new(4).unwrap());
+            read_heavy(store.clone(), &pool, &latencies);
         })
     });
+    println!("rayon_kvstore_read: {}", latencies.report());
 }
 
 fn write_queued_sled(c: &mut Criterion) {
@@ -86,12 +115,14 @@ fn write_queued_sled(c: &mut Criterion) {
         Engine::Sled,
         Default::default(),
     );
-    thread::sleep(Duration::from_secs(1));
+    let pool = RayonThreadPool::global();
+    let latencies = LatencyRecorder::new();
     c.bench_function("queued_sled", |b| {
         b.iter(|| {
-            write_heavy(store.clone(), RayonThreadPool::new(4).unwrap());
+            write_heavy(store.clone(), &pool, &latencies);
         })
     });
+    println!("queued_sled: {}", latencies.report());
 }
 
 fn read_queued_sled(c: &mut Criterion) {
@@ -102,12 +133,14 @@ fn read_queued_sled(c: &mut Criterion) {
         Engine::Sled,
         Default::default(),
     );
-    thread::sleep(Duration::from_secs(1));
+    let pool = RayonThreadPool::global();
+    let latencies = LatencyRecorder::new();
     c.bench_function("queued_sled_read", |b| {
         b.iter(|| {
-            read_heavy(store.clone(), RayonThreadPool::new(4).unwrap());
+            read_heavy(store.clone(), &pool, &latencies);
         })
     });
+    println!("queued_sled_read: {}", latencies.report());
 }
 
 fn write_rayon_sled(c: &mut Criterion) {
@@ -118,12 +151,14 @@ fn write_rayon_sled(c: &mut Criterion) {
         Engine::Sled,
         Pool::Rayon,
     );
-    thread::sleep(Duration::from_secs(1));
+    let pool = RayonThreadPool::global();
+    let latencies = LatencyRecorder::new();
     c.bench_function("rayon_sled", |b| {
         b.iter(|| {
-            write_heavy(store.clone(), RayonThreadPool::new(4).unwrap());
+            write_heavy(store.clone(), &pool, &latencies);
         })
     });
+    println!("rayon_sled: {}", latencies.report());
 }
 
 fn read_rayon_sled(c: &mut Criterion) {
@@ -134,12 +169,58 @@ fn read_rayon_sled(c: &mut Criterion) {
         Engine::Sled,
         Pool::Rayon,
     );
-    thread::sleep(Duration::from_secs(1));
+    let pool = RayonThreadPool::global();
+    let latencies = LatencyRecorder::new();
     c.bench_function("read_rayon_sled", |b| {
         b.iter(|| {
-            read_heavy(store.clone(), RayonThreadPool::new(4).unwrap());
+            read_heavy(store.clone(), &pool, &latencies);
+        })
+    });
+    println!("read_rayon_sled: {}", latencies.report());
+}
+
+fn workload_kvstore(c: &mut Criterion) {
+    let temp = tempfile::tempdir().unwrap();
+    std::env::set_current_dir(temp.path()).unwrap();
+    let store = RemoteEngine::spawn_new(
+        Some("127.0.0.1:4009".parse().unwrap()),
+        Engine::Kvs,
+        Pool::SharedQueue,
+    );
+    let pool = RayonThreadPool::global();
+    let workload = Workload::default();
+    let logical_per_run: u64 = workload.load().chain(workload.operations()).map(|op| op.logical_bytes()).sum();
+    let mut logical_bytes = 0;
+    let probe = SpaceProbe::start(temp.path(), store.stats().unwrap().engine);
+    c.bench_function("workload_a_kvstore", |b| {
+        b.iter(|| {
+            benchmark_common::run_workload(store.clone(), &pool, &workload);
+            logical_bytes += logical_per_run;
+        })
+    });
+    println!("workload_a_kvstore: {}", probe.report(logical_bytes, store.stats().unwrap().engine).unwrap());
+}
+
+fn workload_sled(c: &mut Criterion) {
+    let temp = tempfile::tempdir().unwrap();
+    std::env::set_current_dir(temp.path()).unwrap();
+    let store = RemoteEngine::spawn_new(
+        Some("127.0.0.1:4010".parse().unwrap()),
+        Engine::Sled,
+        Pool::SharedQueue,
+    );
+    let pool = RayonThreadPool::global();
+    let workload = Workload::default();
+    let logical_per_run: u64 = workload.load().chain(workload.operations()).map(|op| op.logical_bytes()).sum();
+    let mut logical_bytes = 0;
+    let probe = SpaceProbe::start(temp.path(), store.stats().unwrap().engine);
+    c.bench_function("workload_a_sled", |b| {
+        b.iter(|| {
+            benchmark_common::run_workload(store.clone(), &pool, &workload);
+            logical_bytes += logical_per_run;
         })
     });
+    println!("workload_a_sled: {}", probe.report(logical_bytes, store.stats().unwrap().engine).unwrap());
 }
 
 criterion_group! {
@@ -147,6 +228,8 @@ criterion_group! {
     config = Criterion::default()
         .sample_size(10);
     targets =  write_rayon_sled, write_queued_kvstore, write_rayon_kvstore, write_queued_sled,
-        read_rayon_sled, read_queued_kvstore, read_rayon_kvstore, read_queued_sled
+        write_buffered_kvstore, write_vectored_kvstore,
+        read_rayon_sled, read_queued_kvstore, read_rayon_kvstore, read_queued_sled,
+        workload_kvstore, workload_sled
 }
 criterion_main!(tbenches);
@@ -0,0 +1,6 @@
+//! the parsers of the requests and the responses, see `kvs::fuzz::parse_contract`.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| kvs::fuzz::parse_contract(data));
@@ -0,0 +1,6 @@
+//! the reader of the data files, see `kvs::fuzz::read_data_file`.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| kvs::fuzz::read_data_file(data));
@@ -1,17 +1,30 @@
 use std::collections::HashSet;
-use std::net::SocketAddr;
-use std::sync::{Arc, atomic::Ordering, Condvar, Mutex, RwLock};
+use std::fmt;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}, Condvar, Mutex, MutexGuard, PoisonError, RwLock};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use assert_cmd::prelude::CommandCargoExt;
-use crossbeam_utils::sync::WaitGroup;
-use failure::_core::hash::BuildHasher;
-use failure::_core::sync::atomic::AtomicBool;
 use rand::prelude::IteratorRandom;
 use rand::thread_rng;
+use walkdir::WalkDir;
 
-use crate::{KvError, KvsEngine};
-use crate::server_common::{Engine, Pool};
-use crate::thread_pool::ThreadPool;
+use crate::{EngineMetricsSnapshot, KvError, KvsEngine, server};
+use crate::client::KvsClient;
+use crate::config::server::ServerConfig;
+use crate::engines::engine::{BatchWrite, SetCondition};
+use crate::engines::lease::Lease;
+use crate::engines::pattern::ListOptions;
+use crate::engines::typed::TypedValue;
+use crate::server_common::{Engine, Pool, ServerStats};
+use crate::thread_pool::{ThreadPool, ThreadPoolBuilder};
+use crate::workload::Workload;
 
 /// The Future Monad, but it's blocking.
 /// It likes `Future` of Java more,
@@ -19,23 +32,60 @@ use crate::thread_pool::ThreadPool;
 /// like ECMAScript, which's behavior like Monad more
 /// (using `then` method instead of language builtin control-flow to combine),
 /// and is non-blocking.
-#[derive(Clone, Default)]
+///
+/// The value of a promise can be taken only once: by `get`, `get_timeout`,
+/// or the continuation registered by `map` and `then`.
 pub struct Promise<T> {
-    item: Arc<(Mutex<Option<T>>, Condvar)>,
+    item: Arc<(Mutex<Slot<T>>, Condvar)>,
+}
+
+type Continuation<T> = Box<dyn FnOnce(T) + Send>;
+
+struct Slot<T> {
+    item: Option<T>,
+    continuation: Option<Continuation<T>>,
+}
+
+impl<T> Default for Promise<T> {
+    fn default() -> Self {
+        Promise::new()
+    }
+}
+
+impl<T> Clone for Promise<T> {
+    fn clone(&self) -> Self {
+        Promise {
+            item: self.item.clone(),
+        }
+    }
 }
 
 impl<T> Promise<T> {
     /// Create an empty Promise.
     pub fn new() -> Self {
         Promise {
-            item: Arc::new((Mutex::new(None), Condvar::new())),
+            item: Arc::new((
+                Mutex::new(Slot {
+                    item: None,
+                    continuation: None,
+                }),
+                Condvar::new(),
+            )),
         }
     }
 
+    /// the slot is always consistent, so a panic in another holder of the lock
+    /// (like a continuation) doesn't poison the promise.
+    fn slot(&self) -> MutexGuard<'_, Slot<T>> {
+        self.item.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
     /// Fulfill an Promise.
     ///
+    /// If a continuation is registered by `map` or `then`, it runs on the current thread with the value.
+    ///
     /// # Example
-    /// ```no-run
+    /// ```no_run
     /// # use std::thread;
     /// # use kvs::benchmark_common::Promise;
     /// let promise = Promise::new();
@@ -48,36 +98,136 @@ impl<T> Promise<T> {
     /// assert_eq!(42, promise.get());
     /// ```
     pub fn fulfill(&self, item: T) {
-        let mut l = self.item.0.lock().unwrap();
-        *l = Some(item);
-        self.item.1.notify_one();
+        let mut slot = self.slot();
+        match slot.continuation.take() {
+            Some(continuation) => {
+                drop(slot);
+                continuation(item);
+            }
+            None => {
+                slot.item = Some(item);
+                self.item.1.notify_all();
+            }
+        }
     }
 
-    /// test whether the promise is fulfilled.
+    /// test whether the promise is fulfilled, and its value isn't taken yet.
     pub fn is_fulfill(&self) -> bool {
-        self.item.0.lock().unwrap().is_some()
+        self.slot().item.is_some()
     }
 
     /// blocking the current thread until the promise is fulfilled.
     pub fn get(&self) -> T {
-        let l = self.item.0.lock().unwrap();
-        let mut l = self.item.1.wait(l).unwrap();
-        l.take().unwrap()
+        let slot = self.slot();
+        let mut slot = self
+            .item
+            .1
+            .wait_while(slot, |slot| slot.item.is_none())
+            .unwrap_or_else(PoisonError::into_inner);
+        slot.item.take().unwrap()
+    }
+
+    /// like `get`, but gives up after `timeout`, and returns `None` if the promise isn't fulfilled then.
+    pub fn get_timeout(&self, timeout: Duration) -> Option<T> {
+        let slot = self.slot();
+        let (mut slot, _) = self
+            .item
+            .1
+            .wait_timeout_while(slot, timeout, |slot| slot.item.is_none())
+            .unwrap_or_else(PoisonError::into_inner);
+        slot.item.take()
+    }
+}
+
+impl<T: Send + 'static> Promise<T> {
+    /// a promise of `f` applied to the value of this promise.
+    ///
+    /// `f` runs on the thread fulfilling this promise, or the current thread if it's already fulfilled.
+    ///
+    /// # Panics
+    ///
+    /// When there is already a continuation registered on this promise.
+    pub fn map<U, F>(&self, f: F) -> Promise<U>
+        where
+            U: Send + 'static,
+            F: FnOnce(T) -> U + Send + 'static,
+    {
+        let next = Promise::new();
+        let continuation = {
+            let next = next.clone();
+            move |item| next.fulfill(f(item))
+        };
+        let mut slot = self.slot();
+        assert!(slot.continuation.is_none(), "the promise already has a continuation.");
+        match slot.item.take() {
+            Some(item) => {
+                drop(slot);
+                continuation(item);
+            }
+            None => slot.continuation = Some(Box::new(continuation)),
+        }
+        next
+    }
+
+    /// a promise of the value of the promise `f` returns, with the value of this promise.
+    /// It's useful to chain asynchronous steps, like tasks spawned by `ThreadPool::spawn_with_result`.
+    ///
+    /// # Panics
+    ///
+    /// When there is already a continuation registered on this promise.
+    pub fn then<U, F>(&self, f: F) -> Promise<U>
+        where
+            U: Send + 'static,
+            F: FnOnce(T) -> Promise<U> + Send + 'static,
+    {
+        let next = Promise::new();
+        self.map({
+            let next = next.clone();
+            move |item| {
+                f(item).map(move |value| next.fulfill(value));
+            }
+        });
+        next
+    }
+}
+
+/// a promise is also a future of its value, so that async code can await the tasks of a `ThreadPool`,
+/// see `PooledEngine`.
+///
+/// Like `map`, awaiting it takes the value, and replaces the continuation registered by `map` or `then`.
+impl<T: Send + 'static> Future for Promise<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut slot = self.slot();
+        if let Some(item) = slot.item.take() {
+            return Poll::Ready(item);
+        }
+        // weak, so that a promise never fulfilled doesn't keep itself alive by its continuation.
+        let promise = Arc::downgrade(&self.item);
+        let waker = cx.waker().clone();
+        slot.continuation = Some(Box::new(move |item| {
+            if let Some(item_slot) = promise.upgrade() {
+                let promise = Promise { item: item_slot };
+                promise.slot().item = Some(item);
+                promise.item.1.notify_all();
+            }
+            waker.wake();
+        }));
+        Poll::Pending
     }
 }
 
 #[derive(Clone, Debug)]
 /// The engine that wraps a remote `kvs-server`.
-/// When query method called, it trivially send a request to the remote server.
+/// When query method called, it trivially send a request to the remote server by a `KvsClient`.
 pub struct RemoteEngine {
-    remote: SocketAddr,
+    client: KvsClient,
 }
 
 impl Default for RemoteEngine {
     fn default() -> Self {
-        RemoteEngine {
-            remote: SocketAddr::new("127.0.0.1".parse().unwrap(), 4000),
-        }
+        RemoteEngine::with_remote(SocketAddr::new("127.0.0.1".parse().unwrap(), 4000))
     }
 }
 
@@ -91,127 +241,239 @@ impl RemoteEngine {
     /// create a new `RemoteEngine` that bind to the specified server.
     /// This method won't start server, if you need to start a server, use `spawn_new` instead.
     pub fn with_remote(remote: SocketAddr) -> Self {
-        RemoteEngine { remote }
+        RemoteEngine {
+            client: KvsClient::new(remote),
+        }
+    }
+
+    /// create a new `RemoteEngine` sending the requests by `client`, like one with credentials.
+    pub fn with_client(client: KvsClient) -> Self {
+        RemoteEngine { client }
     }
 
-    /// spawn a new server at the addr, with specified storage engine and thread pool.
+    /// spawn a new server in this process at the addr, with specified storage engine and thread pool,
+    /// the engine opens at the current directory.
     ///
     /// if the `addr` is `None`, use the default server address(localhost:4000).
+    /// The server is ready to accept requests once this returns.
+    ///
+    /// # Panics
+    ///
+    /// When failed to bind to the addr.
     ///
     /// # Example
     /// This will start a new server at localhost:4000, and return a `RemoteEngine` bind to it,
     /// with default config(KvStore, SharedQueueThreadPool).
-    /// ```no-run
-    /// let engine = spawn_new(None, Default::default(), Default::default());
+    /// ```no_run
+    /// # use kvs::benchmark_common::RemoteEngine;
+    /// let engine = RemoteEngine::spawn_new(None, Default::default(), Default::default());
     /// ```
     pub fn spawn_new(addr: Option<SocketAddr>, engine: Engine, pool: Pool) -> Self {
         let addr = addr.unwrap_or_else(|| "127.0.0.1:4000".parse().unwrap());
-        std::process::Command::cargo_bin("kvs-server")
-            .unwrap()
-            .args(&[
-                "--engine",
-                engine.as_ref(),
-                "--pool",
-                pool.as_ref(),
-                "--addr",
-                addr.to_string().as_str(),
-            ])
-            .spawn()
-            .unwrap();
-        RemoteEngine { remote: addr }
+        let listener = TcpListener::bind(addr).expect("unable to bind the server.");
+        let path = std::env::current_dir().unwrap();
+        let builder = ThreadPoolBuilder::new(num_cpus::get());
+        thread::spawn(move || server::serve_with(engine, pool, path, &ServerConfig::default(), builder, None, listener));
+        RemoteEngine::with_remote(addr)
+    }
+}
+
+impl RemoteEngine {
+    /// the statistics of the remote server, including the metrics of its engine.
+    pub fn stats(&self) -> Result<ServerStats, KvError> {
+        self.client.stats()
     }
 }
 
 impl KvsEngine for RemoteEngine {
     fn get(&self, key: String) -> Result<Option<String>, KvError> {
-        let x = std::process::Command::cargo_bin("kvs-client")
-            .unwrap()
-            .args(&[
-                "get",
-                "--addr",
-                format!("{}", self.remote).as_str(),
-                key.as_str(),
-            ])
-            .output()?;
-        let mut result = String::from_utf8(x.stdout).map_err(|err| KvError::Other {
-            reason: format!("{}", err),
-        })?;
-        if result == "Key not found\n" {
-            Ok(None)
-        } else {
-            result.pop();
-            Ok(Some(result))
-        }
+        self.client.get(key)
     }
 
     fn set(&self, key: String, value: String) -> Result<(), KvError> {
-        let x = std::process::Command::cargo_bin("kvs-client")
-            .unwrap()
-            .args(&[
-                "set",
-                "--addr",
-                format!("{}", self.remote).as_str(),
-                key.as_str(),
-                value.as_str(),
-            ])
-            .output()?;
-        if x.status.success() {
-            Ok(())
-        } else {
-            Err(KvError::Other {
-                reason: "failed to execute `set` command.".to_owned(),
-            })
-        }
+        self.client.set(key, value)
     }
 
     fn remove(&self, key: String) -> Result<(), KvError> {
-        let output = std::process::Command::cargo_bin("kvs-client")
-            .unwrap()
-            .args(&[
-                "rm",
-                "--addr",
-                format!("{}", self.remote).as_str(),
-                key.as_str(),
-            ])
-            .output()?;
-        if output.status.success() {
-            Ok(())
-        } else {
-            Err(KvError::KeyNotFound)
+        self.client.remove(key)
+    }
+
+    fn undelete(&self, key: String) -> Result<(), KvError> {
+        self.client.undelete(key)
+    }
+
+    fn set_if(&self, key: String, value: String, condition: SetCondition) -> Result<bool, KvError> {
+        self.client.set_if(key, value, condition)
+    }
+
+    fn get_typed(&self, key: String) -> Result<Option<TypedValue>, KvError> {
+        self.client.get_typed(key)
+    }
+
+    fn set_typed(&self, key: String, value: TypedValue) -> Result<(), KvError> {
+        self.client.set_typed(key, value)
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64, KvError> {
+        self.client.incr(key, delta)
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<(), KvError> {
+        self.client.rename(from, to)
+    }
+
+    fn copy(&self, from: String, to: String) -> Result<(), KvError> {
+        self.client.copy(from, to)
+    }
+
+    fn remove_prefix(&self, prefix: String) -> Result<usize, KvError> {
+        self.client.remove_prefix(prefix)
+    }
+
+    fn write_batch(&self, writes: Vec<BatchWrite>) -> Result<(), KvError> {
+        let mut transaction = self.client.transaction();
+        for write in writes {
+            match write {
+                BatchWrite::Set { key, value } => transaction.set(key, value),
+                BatchWrite::Remove { key } => transaction.remove(key),
+            };
         }
+        transaction.commit()
+    }
+
+    fn acquire_lease(&self, key: String, ttl: Duration) -> Result<Option<Lease>, KvError> {
+        self.client.acquire_lease(key, ttl)
+    }
+
+    fn release_lease(&self, key: String, token: String) -> Result<bool, KvError> {
+        self.client.release_lease(key, token)
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<(), KvError> {
+        self.client.append(key, suffix)
+    }
+
+    fn set_stream(&self, key: String, value: &mut dyn Read) -> Result<(), KvError> {
+        self.client.set_stream(key, value)
+    }
+
+    fn get_stream(&self, key: String) -> Result<Option<Box<dyn Read>>, KvError> {
+        Ok(self.client.get_stream(key)?.map(|stream| Box::new(stream) as Box<dyn Read>))
+    }
+
+    fn list_keys(&self, pattern: String, options: ListOptions) -> Result<Vec<String>, KvError> {
+        self.client.list_keys(pattern, options)
+    }
+}
+
+/// The latencies of the operations in a benchmark,
+/// since the aggregate time of criterion hides the tail latency, like the one caused by compaction.
+///
+/// It's cheap to clone, and the clones share the same records.
+#[derive(Clone, Debug, Default)]
+pub struct LatencyRecorder {
+    latencies: Arc<Mutex<Vec<Duration>>>,
+}
+
+/// The percentiles of the latencies recorded by `LatencyRecorder`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LatencyReport {
+    /// the number of operations recorded.
+    pub count: usize,
+    /// the median latency.
+    pub p50: Duration,
+    /// the 95th percentile latency.
+    pub p95: Duration,
+    /// the 99th percentile latency.
+    pub p99: Duration,
+    /// the max latency.
+    pub max: Duration,
+}
+
+impl fmt::Display for LatencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ops, p50 {:?}, p95 {:?}, p99 {:?}, max {:?}",
+            self.count, self.p50, self.p95, self.p99, self.max
+        )
     }
 }
 
-/// insert fix size of keys into a `KvsEngine`.
+impl LatencyRecorder {
+    /// create an empty recorder.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// run `f` and record how long it takes.
+    pub fn time<T>(&self, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record(start.elapsed());
+        result
+    }
+
+    /// record the latency of an operation.
+    pub fn record(&self, latency: Duration) {
+        self.latencies.lock().unwrap_or_else(PoisonError::into_inner).push(latency);
+    }
+
+    /// the percentiles of the latencies recorded until now, by the nearest-rank method.
+    pub fn report(&self) -> LatencyReport {
+        let mut latencies = self.latencies.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        if latencies.is_empty() {
+            return LatencyReport::default();
+        }
+        latencies.sort_unstable();
+        let percentile = |p: f64| {
+            let rank = (p * latencies.len() as f64).ceil() as usize;
+            latencies[rank.max(1) - 1]
+        };
+        LatencyReport {
+            count: latencies.len(),
+            p50: percentile(0.50),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+            max: latencies[latencies.len() - 1],
+        }
+    }
+
+    /// forget the latencies recorded.
+    pub fn clear(&self) {
+        self.latencies.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+}
+
+/// insert fix size of keys into a `KvsEngine`, and record the latency of each insert.
 /// it grantees that, for all `n` in the set this function returns,
 /// `store.get(format!("Key{}", n)) == format!("Value{}", n)`
 pub fn insert_keys(
     store: impl KvsEngine,
     pool: &impl ThreadPool,
     key_size: usize,
+    latencies: &LatencyRecorder,
 ) -> Arc<RwLock<HashSet<usize>>> {
     let keys = Arc::new(RwLock::new(HashSet::new()));
-    let wg: WaitGroup = WaitGroup::new();
     for i in 0..key_size {
         pool.spawn({
-            let wg = wg.clone();
             let store = store.clone();
             let keys = keys.clone();
+            let latencies = latencies.clone();
             move || {
                 let v = i;
                 keys.write().unwrap().insert(v);
-                store
-                    .set(format!("Key{}", v), format!("Value{}", v))
+                latencies
+                    .time(|| store.set(format!("Key{}", v), format!("Value{}", v)))
                     .unwrap();
-                drop(wg);
             }
         });
     }
-    wg.wait();
+    pool.wait_idle();
     keys
 }
 
-/// read a fixed size of keys from `store`.
+/// read a fixed size of keys from `store`, and record the latency of each read.
 /// This implies that for all `n` in the `keys` set,
 /// `store.get(format!("Key{}", n)) == format!("Value{}", n)`(*).
 ///
@@ -223,24 +485,133 @@ pub fn read_exist<S: BuildHasher + Sync + Send + 'static>(
     pool: &impl ThreadPool,
     times: usize,
     keys: Arc<RwLock<HashSet<usize, S>>>,
+    latencies: &LatencyRecorder,
 ) {
-    let wg = WaitGroup::new();
     let success = Arc::new(AtomicBool::new(true));
     for _ in 0..times {
         let keys = keys.clone();
         let store = store.clone();
         let success = success.clone();
-        let wg = wg.clone();
+        let latencies = latencies.clone();
         pool.spawn(move || {
             let guard = keys.read().unwrap();
             let k = guard.iter().choose(&mut thread_rng()).unwrap();
-            let v = store.get(format!("Key{}", *k)).unwrap().unwrap();
+            let v = latencies.time(|| store.get(format!("Key{}", *k))).unwrap().unwrap();
             if v != format!("Value{}", k) {
                 success.store(false, Ordering::SeqCst)
             }
-            drop(wg);
         })
     }
-    wg.wait();
+    pool.wait_idle();
     assert!(success.load(Ordering::SeqCst));
 }
+
+/// load the records of `workload` into `store`, then run its operations, both by tasks spawned on `pool`.
+///
+/// # Panics
+///
+/// When any operation fails, the task running it panics.
+pub fn run_workload(store: impl KvsEngine, pool: &impl ThreadPool, workload: &Workload) {
+    for operations in [workload.load().collect::<Vec<_>>(), workload.operations().collect()] {
+        for operation in operations {
+            let store = store.clone();
+            pool.spawn(move || operation.apply(&store).unwrap());
+        }
+        pool.wait_idle();
+    }
+}
+
+/// the total size of the files under `path`.
+pub fn dir_size(path: impl AsRef<Path>) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in WalkDir::new(path) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// The space efficiency of an engine over a benchmark run, see `SpaceProbe`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SpaceReport {
+    /// the bytes of the keys and values written by the benchmark.
+    pub logical_bytes: u64,
+    /// the bytes written by the engine for the requests, including the overhead of its format.
+    pub bytes_written: u64,
+    /// the bytes rewritten by the compactions of the engine.
+    pub compaction_bytes_written: u64,
+    /// the size of the data directory at the end.
+    pub disk_bytes: u64,
+}
+
+impl SpaceReport {
+    /// all bytes the engine wrote, per byte the benchmark wrote.
+    pub fn write_amplification(&self) -> f64 {
+        (self.bytes_written + self.compaction_bytes_written) as f64 / self.logical_bytes as f64
+    }
+
+    /// the size of the data directory, per byte the benchmark wrote.
+    pub fn space_amplification(&self) -> f64 {
+        self.disk_bytes as f64 / self.logical_bytes as f64
+    }
+}
+
+impl fmt::Display for SpaceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} logical bytes, {} written, {} by compaction, {} on disk, write amplification {:.2}, space amplification {:.2}",
+            self.logical_bytes,
+            self.bytes_written,
+            self.compaction_bytes_written,
+            self.disk_bytes,
+            self.write_amplification(),
+            self.space_amplification()
+        )
+    }
+}
+
+/// Measures the bytes an engine writes and the size of its data directory over a benchmark run.
+///
+/// The engine metrics come from `KvsEngine::metrics`, or `RemoteEngine::stats` for a remote server.
+///
+/// # Example
+/// ```no_run
+/// # use kvs::{KvsEngine, KvStore};
+/// # use kvs::benchmark_common::SpaceProbe;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let path = std::env::current_dir()?;
+/// let store = KvStore::open(&path)?;
+/// let probe = SpaceProbe::start(&path, store.metrics().snapshot());
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// println!("{}", probe.report(8, store.metrics().snapshot())?);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct SpaceProbe {
+    path: PathBuf,
+    start: EngineMetricsSnapshot,
+}
+
+impl SpaceProbe {
+    /// start measuring the engine working at `path`, whose metrics are `start` now.
+    pub fn start(path: impl AsRef<Path>, start: EngineMetricsSnapshot) -> Self {
+        SpaceProbe {
+            path: path.as_ref().to_owned(),
+            start,
+        }
+    }
+
+    /// the report since the start, with `logical_bytes` written by the benchmark, and the engine metrics now.
+    pub fn report(&self, logical_bytes: u64, now: EngineMetricsSnapshot) -> io::Result<SpaceReport> {
+        Ok(SpaceReport {
+            logical_bytes,
+            bytes_written: now.bytes_written - self.start.bytes_written,
+            compaction_bytes_written: now.compaction_bytes_written - self.start.compaction_bytes_written,
+            disk_bytes: dir_size(&self.path)?,
+        })
+    }
+}
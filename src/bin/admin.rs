@@ -0,0 +1,129 @@
+use std::io;
+use std::path::PathBuf;
+use std::process::exit;
+
+use structopt::StructOpt;
+
+use kvs::config::log4rs::{client_config, LogFilter};
+use kvs::engines::offline;
+use kvs::engines::restorable::data_dir;
+use kvs::Result;
+
+/// The stable exit codes of `kvs-admin`.
+mod exit_code {
+    /// the operation succeeded, or the data directory is intact.
+    pub const OK: i32 = 0;
+    /// the arguments or the `RUST_LOG` env var are malformed.
+    pub const BAD_USAGE: i32 = 1;
+    /// `verify` found unreadable records or missing blobs.
+    pub const CORRUPTED: i32 = 2;
+    /// failed to operate on the data directory.
+    pub const ERROR: i32 = 3;
+}
+
+#[derive(Debug, StructOpt)]
+struct Target {
+    /// the data directory of the server, whose restored data is used if any.
+    #[structopt(long = "--path", default_value = ".", parse(from_os_str))]
+    path: PathBuf,
+    /// the filter of logs written to stderr, like `debug`.
+    /// When absent, the `RUST_LOG` env var is used, and `warn` by default.
+    #[structopt(long = "--log-level", parse(try_from_str = str::parse))]
+    log_level: Option<LogFilter>,
+}
+
+/// Operate on the data directory of the `kvs` engine while the server is stopped.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "kvs-admin",
+author = env!("CARGO_PKG_AUTHORS"),
+version = env!("CARGO_PKG_VERSION"))]
+enum AdminOpt {
+    /// check that every record can be read, and the blobs of the live values exist.
+    Verify {
+        #[structopt(flatten)]
+        target: Target,
+    },
+    /// print the records of the data files readably, one per line.
+    Dump {
+        #[structopt(flatten)]
+        target: Target,
+    },
+    /// rewrite the live records into a new data file, and remove the elder ones.
+    Compact {
+        #[structopt(flatten)]
+        target: Target,
+    },
+    /// cut the data files at their first unreadable records, the records after them are lost.
+    Truncate {
+        #[structopt(flatten)]
+        target: Target,
+    },
+    /// print the counts of the files, the records and the keys.
+    Stats {
+        #[structopt(flatten)]
+        target: Target,
+    },
+}
+
+impl AdminOpt {
+    fn target(&self) -> &Target {
+        match self {
+            AdminOpt::Verify { target }
+            | AdminOpt::Dump { target }
+            | AdminOpt::Compact { target }
+            | AdminOpt::Truncate { target }
+            | AdminOpt::Stats { target } => target,
+        }
+    }
+
+    /// run the command, returns the exit code.
+    fn run(&self) -> Result<i32> {
+        let dir = data_dir(self.target().path.as_path())?;
+        match self {
+            AdminOpt::Verify { .. } => {
+                let report = offline::verify(dir)?;
+                print!("{}", report);
+                Ok(if report.is_intact() { exit_code::OK } else { exit_code::CORRUPTED })
+            }
+            AdminOpt::Dump { .. } => {
+                offline::dump(dir, &mut io::stdout().lock())?;
+                Ok(exit_code::OK)
+            }
+            AdminOpt::Compact { .. } => {
+                let written = offline::compact(dir)?;
+                println!("compacted, {} bytes rewritten.", written);
+                Ok(exit_code::OK)
+            }
+            AdminOpt::Truncate { .. } => {
+                for cut in offline::truncate(dir)? {
+                    println!("{}: cut {} bytes from offset {}.", cut.path.display(), cut.len - cut.valid_len, cut.valid_len);
+                }
+                Ok(exit_code::OK)
+            }
+            AdminOpt::Stats { .. } => {
+                print!("{}", offline::stats(dir)?);
+                Ok(exit_code::OK)
+            }
+        }
+    }
+}
+
+fn main() {
+    let opt = AdminOpt::from_args();
+    match LogFilter::resolve(opt.target().log_level.clone(), "warn") {
+        Ok(filter) => {
+            log4rs::init_config(client_config(&filter)).expect("unable to init logger.");
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(exit_code::BAD_USAGE);
+        }
+    }
+    match opt.run() {
+        Ok(code) => exit(code),
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(exit_code::ERROR);
+        }
+    }
+}
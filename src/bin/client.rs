@@ -1,53 +1,241 @@
-use std::io::Write;
+use std::env;
+use std::fs;
+use std::io;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
 
 use structopt::StructOpt;
 
-use kvs::contract::KvContractMessage;
-use kvs::contract::Response;
+use kvs::benchmark_common::RemoteEngine;
+use kvs::client::KvsClient;
+use kvs::config::log4rs::{client_config, LogFilter};
+use kvs::contract::{Request, Response};
+use kvs::engines::changes::LogPosition;
+use kvs::engines::engine::{Preload, SetCondition};
+use kvs::engines::pattern::ListOptions;
+use kvs::engines::restorable::read_archive;
+use kvs::engines::typed::TypedValue;
+use kvs::KvError;
+use kvs::workload::Workload;
+
+/// The stable exit codes of `kvs-client`,
+/// so that shell scripts can use it as a conditional primitive.
+mod exit_code {
+    /// the key is found, or the operation succeeded.
+    pub const OK: i32 = 0;
+    /// the arguments or the `RUST_LOG` env var are malformed,
+    /// or the files given by the arguments cannot be read or written.
+    pub const BAD_USAGE: i32 = 1;
+    /// the key to `get`, `rm`, `undelete`, `rename` or `copy` doesn't exist.
+    pub const KEY_NOT_FOUND: i32 = 2;
+    /// failed to connect to the server, or the connection broke during the request,
+    /// like the response is cut short or malformed.
+    pub const CONNECTION_ERROR: i32 = 3;
+    /// the server responded with an error, or a response unexpected for the request.
+    pub const SERVER_ERROR: i32 = 4;
+    /// the condition of `set --nx` or `set --xx` doesn't hold, so nothing is written,
+    /// or the key to `lease` is held by another lease, or the lease to `release` isn't the one of the key.
+    pub const CONDITION_NOT_MET: i32 = 5;
+}
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "kvs",
 about = env!("CARGO_PKG_DESCRIPTION"),
 author = env!("CARGO_PKG_AUTHORS"),
 version = env!("CARGO_PKG_VERSION"))]
-enum ClientOpt {
+struct ClientOpt {
+    #[structopt(flatten)]
+    common: CommonOpt,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+/// The options of all the commands, which may be given before or after the command.
+#[derive(Debug, StructOpt)]
+struct CommonOpt {
+    /// the server
+    #[structopt(
+    parse(try_from_str = str::parse),
+    name = "addr",
+    long = "--addr",
+    default_value = "127.0.0.1:4000",
+    global = true
+    )]
+    server: SocketAddr,
+    /// don't print anything, only report the result by exit code.
+    #[structopt(short = "q", long = "--quiet", global = true)]
+    quiet: bool,
+    /// the filter of logs written to stderr, like `debug`.
+    /// When absent, the `RUST_LOG` env var is used, and `warn` by default.
+    #[structopt(long = "--log-level", parse(try_from_str = str::parse), global = true)]
+    log_level: Option<LogFilter>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
     Set {
         /// a key string to put.
         key: String,
         /// a value string to put with the key.
-        value: String,
-        /// the server
-        #[structopt(
-        parse(try_from_str = str::parse),
-        name = "addr",
-        long = "--addr",
-        default_value = "127.0.0.1:4000"
-        )]
-        server: SocketAddr,
+        #[structopt(required_unless = "value-file")]
+        value: Option<String>,
+        /// put the content of this file instead, as is, which may be binary or multi-line.
+        /// It's put as a string if it's UTF-8, or else as bytes.
+        #[structopt(long = "--value-file", name = "value-file", conflicts_with = "value", parse(from_os_str))]
+        value_file: Option<PathBuf>,
+        /// the value or the content of the value file is in base64, put the bytes it decodes to, like a file.
+        #[structopt(long = "--base64")]
+        base64: bool,
+        /// only put when the key doesn't exist.
+        #[structopt(long = "--nx", conflicts_with = "xx")]
+        nx: bool,
+        /// only put when the key exists.
+        #[structopt(long = "--xx")]
+        xx: bool,
     },
     Get {
         /// a key string to get.
         key: String,
-        #[structopt(
-        parse(try_from_str = str::parse),
-        name = "addr",
-        long = "--addr",
-        default_value = "127.0.0.1:4000"
-        )]
-        server: SocketAddr,
+        /// write the value into this file as is instead of printing it, without a trailing newline.
+        /// The bytes are written as they're put, not in hex.
+        #[structopt(long = "--output-file", parse(from_os_str))]
+        output_file: Option<PathBuf>,
+        /// print the value in base64, or write it into the output file in base64.
+        #[structopt(long = "--base64")]
+        base64: bool,
     },
     Rm {
         /// a key string to remove.
         key: String,
-        #[structopt(
-        parse(try_from_str = str::parse),
-        name = "addr",
-        long = "--addr",
-        default_value = "127.0.0.1:4000"
-        )]
-        server: SocketAddr,
+        /// print whether the key would be removed in JSON, without removing it.
+        #[structopt(long = "--dry-run")]
+        dry_run: bool,
+    },
+    /// remove all the keys starting with a prefix by one write, and print how many keys are removed.
+    RmPrefix {
+        /// the prefix of the keys to remove.
+        prefix: String,
+        /// print how many keys would be removed and some of them in JSON, without removing them.
+        #[structopt(long = "--dry-run")]
+        dry_run: bool,
+    },
+    /// restore the value of a removed key, when the server runs with `--soft-delete`.
+    Undelete {
+        /// a key string to restore.
+        key: String,
+    },
+    /// append a string to the value of a key, or set the key to it if it doesn't exist.
+    Append {
+        /// a key string to append to.
+        key: String,
+        /// a string to append to the value.
+        suffix: String,
+    },
+    /// add to the integer of a key, or set the key to it if it doesn't exist, and print the new integer.
+    Incr {
+        /// a key string to increase.
+        key: String,
+        /// how much to add, negative to decrease.
+        #[structopt(long = "--by", default_value = "1", allow_hyphen_values = true)]
+        by: i64,
+    },
+    /// move the value of a key to another key atomically, overwriting its value if any.
+    Rename {
+        /// the key to move the value from.
+        from: String,
+        /// the key to move the value to.
+        to: String,
+    },
+    /// copy the value of a key to another key atomically, overwriting its value if any.
+    Copy {
+        /// the key to copy the value from.
+        from: String,
+        /// the key to copy the value to.
+        to: String,
+    },
+    /// take the lease of a key until it expires, and print it in JSON with its token to release it by.
+    Lease {
+        /// the key to lease.
+        key: String,
+        /// how long the lease lasts, in milliseconds.
+        #[structopt(long = "--ttl-ms", default_value = "30000")]
+        ttl_ms: u64,
+    },
+    /// release the lease of a key by its token.
+    Release {
+        /// the key leased.
+        key: String,
+        /// the token of the lease.
+        token: String,
+    },
+    /// list the keys matching a glob pattern, one per line.
+    Keys {
+        /// the glob pattern, `*` matches any string, `?` matches any character, and `\` escapes.
+        pattern: String,
+        /// list the keys in descending order.
+        #[structopt(short = "r", long = "--reverse")]
+        reverse: bool,
+        /// skip this many matched keys first.
+        #[structopt(long = "--offset", default_value = "0")]
+        offset: usize,
+        /// list at most this many keys.
+        #[structopt(long = "--limit")]
+        limit: Option<usize>,
+        /// list only the keys after this one, before it when reversed, like the last key of the previous page.
+        #[structopt(long = "--after")]
+        after: Option<String>,
+    },
+    /// replace all the data of the server by backups, when the server runs with an admin token.
+    Restore {
+        /// the directories of the full backup, then the incremental ones in order.
+        #[structopt(required = true, parse(from_os_str))]
+        backups: Vec<PathBuf>,
+        /// the admin token of the server.
+        #[structopt(long = "--token")]
+        token: String,
+    },
+    /// print the statistics of the server, in JSON.
+    Stats,
+    /// warm up the server by loading the values of some keys ahead, then print how many are loaded.
+    Preload {
+        /// the keys to load.
+        #[structopt(required_unless = "prefix", conflicts_with = "prefix")]
+        keys: Vec<String>,
+        /// load the keys starting with this prefix instead, the empty one for all the keys.
+        #[structopt(long = "--prefix")]
+        prefix: Option<String>,
+        /// the admin token of the server.
+        #[structopt(long = "--token")]
+        token: String,
+    },
+    /// run a script on the server atomically, when the server runs with a script runner, then print what it returns.
+    Eval {
+        /// the script, in the language of the script runner of the server.
+        script: String,
+        /// the arguments of the script.
+        args: Vec<String>,
+        /// a key the script touches, it may touch no others.
+        #[structopt(long = "--key", number_of_values = 1)]
+        keys: Vec<String>,
+    },
+    /// print the changes of the server since a position, one JSON per line.
+    Changes {
+        /// where to read the changes from, like `3:1024`, the `next` of the last change handled.
+        /// When absent, the changes are read from the beginning.
+        #[structopt(long = "--since", parse(try_from_str = str::parse))]
+        since: Option<LogPosition>,
+        /// wait for the new changes, instead of exiting after the last one.
+        #[structopt(short = "f", long = "--follow")]
+        follow: bool,
+    },
+    /// load and run a YCSB-style workload on the server, then print the throughput in JSON.
+    Bench {
+        #[structopt(flatten)]
+        workload: Workload,
     },
 }
 #[derive(Debug, Eq, PartialEq)]
@@ -55,54 +243,386 @@ enum Operate {
     Get,
     Set,
     Rm,
+    RmPrefix,
+    Undelete,
+    Append,
+    Incr,
+    Rename,
+    Copy,
+    Lease,
+    Release,
+    Keys,
+    Restore,
+    Stats,
+    Preload,
+    Eval,
+    Changes,
+    Bench,
 }
 
-impl ClientOpt {
+impl Command {
     fn to_operate(&self) -> Operate {
         use Operate::*;
         match self {
             Self::Set { .. } => Set,
             Self::Get { .. } => Get,
             Self::Rm { .. } => Rm,
+            Self::RmPrefix { .. } => RmPrefix,
+            Self::Undelete { .. } => Undelete,
+            Self::Append { .. } => Append,
+            Self::Incr { .. } => Incr,
+            Self::Rename { .. } => Rename,
+            Self::Copy { .. } => Copy,
+            Self::Lease { .. } => Lease,
+            Self::Release { .. } => Release,
+            Self::Keys { .. } => Keys,
+            Self::Restore { .. } => Restore,
+            Self::Stats => Stats,
+            Self::Preload { .. } => Preload,
+            Self::Eval { .. } => Eval,
+            Self::Changes { .. } => Changes,
+            Self::Bench { .. } => Bench,
         }
     }
 }
 
-fn send_to(message: KvContractMessage, addr: SocketAddr) -> std::io::Result<KvContractMessage> {
-    let bin = message.into_binary();
-    let mut stream = std::net::TcpStream::connect(addr).unwrap();
-    stream.write_all(bin.as_slice())?;
-    stream.shutdown(std::net::Shutdown::Write)?;
-    Ok(KvContractMessage::parse(stream).unwrap())
+impl Command {
+    /// send the request of this command to `server`.
+    fn send(self, server: SocketAddr) -> std::io::Result<Option<Response>> {
+        match self {
+            Self::Set { key, value, nx, xx, .. } => {
+                let value = value.expect("the value is required without a value file.");
+                let message = match (nx, xx) {
+                    (true, _) => Request::SetIf { key, value, condition: SetCondition::IfAbsent },
+                    (_, true) => Request::SetIf { key, value, condition: SetCondition::IfPresent },
+                    _ => Request::Set { key, value },
+                };
+                client(server).send(message)
+            }
+            Self::Get { key, .. } => client(server).send(Request::Get { key }),
+            Self::Rm { key, dry_run, .. } => client(server).send(dry(Request::Remove { key }, dry_run)),
+            Self::RmPrefix { prefix, dry_run, .. } => {
+                client(server).send(dry(Request::RemovePrefix { prefix }, dry_run))
+            }
+            Self::Undelete { key, .. } => client(server).send(Request::Undelete { key }),
+            Self::Append { key, suffix, .. } => client(server).send(Request::Append { key, suffix }),
+            Self::Incr { key, by, .. } => client(server).send(Request::Incr { key, delta: by }),
+            Self::Rename { from, to, .. } => client(server).send(Request::Rename { from, to }),
+            Self::Copy { from, to, .. } => client(server).send(Request::Copy { from, to }),
+            Self::Lease { key, ttl_ms, .. } => client(server).send(Request::AcquireLease { key, ttl_ms }),
+            Self::Release { key, token, .. } => {
+                client(server).send(Request::ReleaseLease { key, token })
+            }
+            Self::Keys { pattern, reverse, offset, limit, after, .. } => {
+                let options = ListOptions { reverse, offset, limit, after };
+                client(server).send(Request::Keys { pattern, options })
+            }
+            Self::Restore { backups, token, .. } => {
+                let archive = read_archive(backups.as_slice()).map_err(|err| {
+                    std::io::Error::new(err.kind(), format!("failed to read the backups: {}", err))
+                })?;
+                client(server).send(Request::Restore { token: Some(token), archive })
+            }
+            Self::Stats => client(server).send(Request::Stats),
+            Self::Preload { keys, prefix, token, .. } => {
+                let target = match prefix {
+                    Some(prefix) => Preload::Prefix(prefix),
+                    None => Preload::Keys(keys),
+                };
+                client(server).send(Request::Preload { token: Some(token), target })
+            }
+            Self::Eval { script, args, keys, .. } => client(server).send(Request::Eval { script, keys, args }),
+            Self::Changes { .. } => unreachable!("`changes` prints a streamed response, see `changes`."),
+            Self::Bench { .. } => unreachable!("`bench` sends many requests, see `bench`."),
+        }
+    }
 }
 
-impl ClientOpt {
-    fn send(self) -> std::io::Result<KvContractMessage> {
-        match self {
-            Self::Set { key, value, server } => send_to(KvContractMessage::put(key, value), server),
-            Self::Get { key, server } => send_to(KvContractMessage::get(key), server),
-            Self::Rm { key, server } => send_to(KvContractMessage::remove(key), server),
+/// `request` to run dry if `dry_run`.
+fn dry(request: Request, dry_run: bool) -> Request {
+    if dry_run {
+        Request::DryRun { request: Box::new(request) }
+    } else {
+        request
+    }
+}
+
+/// exit by the error of a request sent by `KvsClient`.
+fn report(err: KvError, quiet: bool) -> ! {
+    if !quiet {
+        eprintln!("{}", err);
+    }
+    match err {
+        KvError::KeyNotFound => exit(exit_code::KEY_NOT_FOUND),
+        KvError::OtherIOException { .. } => exit(exit_code::CONNECTION_ERROR),
+        _ => exit(exit_code::SERVER_ERROR),
+    }
+}
+
+/// exit by the failure to read or write the file given by the arguments.
+fn bad_file(path: &Path, err: io::Error, quiet: bool) -> ! {
+    if !quiet {
+        eprintln!("failed to access {}: {}", path.display(), err);
+    }
+    exit(exit_code::BAD_USAGE);
+}
+
+/// the value to set, given by the argument or the file, and decoded from base64 if `base64`.
+/// It's a string if it's UTF-8, or else bytes.
+fn input_value(value: Option<String>, value_file: Option<&Path>, base64: bool, quiet: bool) -> TypedValue {
+    let mut bytes = match value_file {
+        Some(path) => fs::read(path).unwrap_or_else(|err| bad_file(path, err, quiet)),
+        None => value.unwrap_or_default().into_bytes(),
+    };
+    if base64 {
+        let text = String::from_utf8_lossy(bytes.as_slice());
+        bytes = base64::decode(text.trim()).unwrap_or_else(|err| {
+            if !quiet {
+                eprintln!("malformed base64 value: {}", err);
+            }
+            exit(exit_code::BAD_USAGE);
+        });
+    }
+    match String::from_utf8(bytes) {
+        Ok(value) => TypedValue::String(value),
+        Err(err) => TypedValue::Bytes(err.into_bytes()),
+    }
+}
+
+/// set the value given by a file or in base64, which may be binary, see `input_value`.
+fn set_binary(server: SocketAddr, key: String, value: TypedValue, condition: Option<SetCondition>, quiet: bool) -> ! {
+    let client = client(server);
+    let written = match (value, condition) {
+        (TypedValue::String(value), Some(condition)) => client.set_if(key, value, condition),
+        (TypedValue::String(value), None) => client.set(key, value).map(|_| true),
+        (value, None) => client.set_typed(key, value).map(|_| true),
+        (_, Some(_)) => {
+            if !quiet {
+                eprintln!("the binary values cannot be put conditionally.");
+            }
+            exit(exit_code::BAD_USAGE);
         }
+    };
+    match written {
+        Ok(true) => exit(exit_code::OK),
+        Ok(false) => {
+            if !quiet {
+                println!("Condition not met");
+            }
+            exit(exit_code::CONDITION_NOT_MET);
+        }
+        Err(err) => report(err, quiet),
+    }
+}
+
+/// get the value as it's put, which may be binary, and write it into `output_file` or print it,
+/// in base64 if `base64`.
+fn get_binary(server: SocketAddr, key: String, output_file: Option<&Path>, base64: bool, quiet: bool) -> ! {
+    let value = match client(server).get_typed(key) {
+        Ok(Some(value)) => value,
+        Ok(None) => {
+            if !quiet {
+                println!("Key not found");
+            }
+            exit(exit_code::KEY_NOT_FOUND);
+        }
+        Err(err) => report(err, quiet),
+    };
+    let mut bytes = match value {
+        TypedValue::Bytes(bytes) => bytes,
+        value => value.encode().into_bytes(),
+    };
+    if base64 {
+        bytes = base64::encode(bytes.as_slice()).into_bytes();
+    }
+    match output_file {
+        Some(path) => fs::write(path, bytes).unwrap_or_else(|err| bad_file(path, err, quiet)),
+        None if !quiet => println!("{}", String::from_utf8_lossy(bytes.as_slice())),
+        None => {}
+    }
+    exit(exit_code::OK);
+}
+
+/// print the changes streamed from the server, until the last one, or forever when `follow`.
+fn changes(server: SocketAddr, since: LogPosition, follow: bool, quiet: bool) -> ! {
+    let changes = client(server).changes(since, follow).unwrap_or_else(|err| report(err, quiet));
+    for change in changes {
+        let change = change.unwrap_or_else(|err| report(err, quiet));
+        if !quiet {
+            println!("{}", serde_json::to_string(&change).expect("unable to serialize change into json."));
+        }
+    }
+    exit(exit_code::OK);
+}
+
+/// the client of `server`, sending the requests as the user in the `KVS_USER` and `KVS_SECRET` env vars if set,
+/// when the server has users, and within the milliseconds in the `KVS_TIMEOUT_MS` env var if set.
+fn client(server: SocketAddr) -> KvsClient {
+    let mut client = KvsClient::new(server);
+    if let (Ok(user), Ok(secret)) = (env::var("KVS_USER"), env::var("KVS_SECRET")) {
+        client = client.with_credentials(user, secret);
+    }
+    match env::var("KVS_TIMEOUT_MS").ok().and_then(|timeout| timeout.parse().ok()) {
+        Some(timeout_ms) => client.with_timeout(Duration::from_millis(timeout_ms)),
+        None => client,
+    }
+}
+
+/// load the records of `workload`, then run and time its operations one by one.
+fn bench(server: SocketAddr, workload: &Workload, quiet: bool) -> ! {
+    let client = RemoteEngine::with_client(client(server));
+    let mut errors = 0usize;
+    for operation in workload.load() {
+        if let Err(err) = operation.apply(&client) {
+            if !quiet {
+                eprintln!("failed to load the records: {}", err);
+            }
+            exit(exit_code::CONNECTION_ERROR);
+        }
+    }
+    let start = Instant::now();
+    for operation in workload.operations() {
+        if operation.apply(&client).is_err() {
+            errors += 1;
+        }
+    }
+    let elapsed = start.elapsed();
+    if !quiet {
+        let report = json!({
+            "operations": workload.operation_count,
+            "errors": errors,
+            "elapsed_ms": elapsed.as_millis() as u64,
+            "ops_per_sec": workload.operation_count as f64 / elapsed.as_secs_f64(),
+        });
+        println!("{}", report);
     }
+    exit(if errors == 0 { exit_code::OK } else { exit_code::SERVER_ERROR });
 }
 
-fn main() -> std::io::Result<()> {
-    let opt = ClientOpt::from_args();
-    let operate = opt.to_operate();
-    match opt.send()?.to_response().unwrap() {
+fn main() {
+    let ClientOpt { common: CommonOpt { server, quiet, log_level }, command } = ClientOpt::from_args();
+    match LogFilter::resolve(log_level, "warn") {
+        Ok(filter) => {
+            log4rs::init_config(client_config(&filter)).expect("unable to init logger.");
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(exit_code::BAD_USAGE);
+        }
+    }
+    let operate = command.to_operate();
+    if let Command::Bench { workload } = &command {
+        bench(server, workload, quiet);
+    }
+    if let Command::Changes { since, follow } = &command {
+        changes(server, since.unwrap_or_default(), *follow, quiet);
+    }
+    let command = match command {
+        Command::Set { key, value, value_file, base64, nx, xx } if value_file.is_some() || base64 => {
+            let value = input_value(value, value_file.as_deref(), base64, quiet);
+            let condition = match (nx, xx) {
+                (true, _) => Some(SetCondition::IfAbsent),
+                (_, true) => Some(SetCondition::IfPresent),
+                _ => None,
+            };
+            set_binary(server, key, value, condition, quiet);
+        }
+        Command::Get { key, output_file, base64 } if output_file.is_some() || base64 => {
+            get_binary(server, key, output_file.as_deref(), base64, quiet);
+        }
+        command => command,
+    };
+    let response = match command.send(server) {
+        Ok(Some(response)) => response,
+        Ok(None) => {
+            if !quiet {
+                eprintln!("malformed response from the server.");
+            }
+            exit(exit_code::CONNECTION_ERROR);
+        }
+        Err(err) => {
+            if !quiet {
+                eprintln!("failed to communicate with the server: {}", err);
+            }
+            exit(exit_code::CONNECTION_ERROR);
+        }
+    };
+    match response {
+        Response::NoContent if operate == Operate::Lease => {
+            if !quiet {
+                println!("Lease held");
+            }
+            exit(exit_code::CONDITION_NOT_MET);
+        }
         Response::NoContent => {
             if operate == Operate::Get {
-                println!("Key not found");
+                if !quiet {
+                    println!("Key not found");
+                }
+                exit(exit_code::KEY_NOT_FOUND);
+            }
+            exit(exit_code::OK);
+        }
+        Response::Content { content } if operate == Operate::Keys => {
+            let keys: Vec<String> = match serde_json::from_str(content.as_str()) {
+                Ok(keys) => keys,
+                Err(_) => {
+                    if !quiet {
+                        eprintln!("malformed response from the server.");
+                    }
+                    exit(exit_code::SERVER_ERROR);
+                }
+            };
+            if !quiet {
+                for key in keys {
+                    println!("{}", key);
+                }
             }
-            exit(0);
+            exit(exit_code::OK);
+        }
+        // only the conditional sets respond with content.
+        Response::Content { content } if operate == Operate::Set => {
+            if content != "true" {
+                if !quiet {
+                    println!("Condition not met");
+                }
+                exit(exit_code::CONDITION_NOT_MET);
+            }
+            exit(exit_code::OK);
+        }
+        Response::Content { content } if operate == Operate::Release => {
+            if content != "true" {
+                if !quiet {
+                    println!("Lease not held");
+                }
+                exit(exit_code::CONDITION_NOT_MET);
+            }
+            exit(exit_code::OK);
         }
         Response::Content { content } => {
-            println!("{}", content);
-            exit(0);
+            if !quiet {
+                println!("{}", content);
+            }
+            exit(exit_code::OK);
+        }
+        Response::Error { code, retryable, reason } => {
+            if !quiet {
+                eprintln!("{}", reason);
+                if retryable {
+                    eprintln!("the error is transient, retrying later may succeed.");
+                }
+            }
+            if code == KvError::KeyNotFound.code() {
+                exit(exit_code::KEY_NOT_FOUND);
+            }
+            exit(exit_code::SERVER_ERROR);
         }
-        Response::Error { reason } => {
-            eprintln!("{}", reason);
-            exit(1);
+        // the client never asks for a streamed or batch response, and the shared content is parsed as `Content`.
+        Response::Stream | Response::Batch { .. } | Response::SharedContent { .. } => {
+            if !quiet {
+                eprintln!("malformed response from the server.");
+            }
+            exit(exit_code::SERVER_ERROR);
         }
     };
 }
@@ -0,0 +1,287 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::{KvError, KvsEngine, Result};
+use crate::engines::engine::ValueWithMeta;
+use crate::engines::pattern::ListOptions;
+
+/// An operation called on an engine.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Call {
+    /// `KvsEngine::get`.
+    Get {
+        /// the key to get.
+        key: String,
+    },
+    /// `KvsEngine::set`.
+    Set {
+        /// the key to set.
+        key: String,
+        /// the value to set.
+        value: String,
+    },
+    /// `KvsEngine::remove`.
+    Remove {
+        /// the key to remove.
+        key: String,
+    },
+}
+
+impl Call {
+    /// the key this operation works on.
+    pub fn key(&self) -> &str {
+        match self {
+            Call::Get { key } | Call::Set { key, .. } | Call::Remove { key } => key.as_str(),
+        }
+    }
+}
+
+/// What an operation returned.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Outcome {
+    /// `get` returned the value.
+    Value(Option<String>),
+    /// `set` or `remove` succeeded.
+    Done,
+    /// `remove` failed with `KeyNotFound`.
+    NotFound,
+    /// the operation failed by other errors, so it may or may not take effect.
+    Unknown,
+}
+
+/// An operation with its outcome, and when it's called and returned.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Event {
+    /// the operation.
+    pub call: Call,
+    /// the outcome of the operation.
+    pub outcome: Outcome,
+    /// when the operation is called, since the recording starts.
+    pub start: Duration,
+    /// when the operation returned, since the recording starts.
+    pub end: Duration,
+}
+
+/// The operations happened on an engine, see `Recorder`.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    /// the operations, in any order.
+    pub events: Vec<Event>,
+}
+
+/// Throws when a history cannot be explained by any sequential order of its operations.
+#[derive(Debug, Clone, Error)]
+#[error("the history of key {key:?} isn't linearizable, within {} operations.", events.len())]
+pub struct Violation {
+    /// the key whose history is broken.
+    pub key: String,
+    /// the operations on the key, by the time they are called.
+    pub events: Vec<Event>,
+}
+
+/// An engine wrapper that records every operation and its outcome into a `History`.
+///
+/// # Example
+/// ```no_run
+/// # use kvs::{KvsEngine, KvStore};
+/// # use kvs::checker::Recorder;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let recorder = Recorder::new(KvStore::open(std::env::current_dir()?)?);
+/// // ... run operations on the clones of `recorder` concurrently.
+/// recorder.set("key".to_owned(), "value".to_owned())?;
+/// recorder.history().check()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Recorder<E> {
+    engine: E,
+    base: Instant,
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl<E: KvsEngine> Recorder<E> {
+    /// start recording the operations on `engine`, which should be empty.
+    pub fn new(engine: E) -> Self {
+        Recorder {
+            engine,
+            base: Instant::now(),
+            events: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// the operations recorded until now, by all the clones of this recorder.
+    pub fn history(&self) -> History {
+        History {
+            events: self.events.lock().unwrap_or_else(PoisonError::into_inner).clone(),
+        }
+    }
+
+    fn record<T>(&self, call: Call, f: impl FnOnce() -> Result<T>, outcome: impl FnOnce(&Result<T>) -> Outcome) -> Result<T> {
+        let start = self.base.elapsed();
+        let result = f();
+        let end = self.base.elapsed();
+        let event = Event {
+            call,
+            outcome: outcome(&result),
+            start,
+            end,
+        };
+        self.events.lock().unwrap_or_else(PoisonError::into_inner).push(event);
+        result
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Recorder<E> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.record(Call::Get { key: key.clone() }, || self.engine.get(key), |result| match result {
+            Ok(value) => Outcome::Value(value.clone()),
+            Err(_) => Outcome::Unknown,
+        })
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let call = Call::Set { key: key.clone(), value: value.clone() };
+        self.record(call, || self.engine.set(key, value), |result| match result {
+            Ok(_) => Outcome::Done,
+            Err(_) => Outcome::Unknown,
+        })
+    }
+
+    fn get_with_meta(&self, key: String) -> Result<Option<ValueWithMeta>> {
+        self.record(Call::Get { key: key.clone() }, || self.engine.get_with_meta(key), |result| match result {
+            Ok(found) => Outcome::Value(found.as_ref().map(|found| found.value.clone())),
+            Err(_) => Outcome::Unknown,
+        })
+    }
+
+    fn set_with_meta(&self, key: String, value: String, meta: Option<String>) -> Result<()> {
+        let call = Call::Set { key: key.clone(), value: value.clone() };
+        self.record(call, || self.engine.set_with_meta(key, value, meta), |result| match result {
+            Ok(_) => Outcome::Done,
+            Err(_) => Outcome::Unknown,
+        })
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.record(Call::Remove { key: key.clone() }, || self.engine.remove(key), |result| match result {
+            Ok(_) => Outcome::Done,
+            Err(KvError::KeyNotFound) => Outcome::NotFound,
+            Err(_) => Outcome::Unknown,
+        })
+    }
+
+    /// list the keys by the engine, without recording it, since the checker works on single keys.
+    fn list_keys(&self, pattern: String, options: ListOptions) -> Result<Vec<String>> {
+        self.engine.list_keys(pattern, options)
+    }
+}
+
+impl<E> fmt::Debug for Recorder<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder").field("base", &self.base).finish()
+    }
+}
+
+impl History {
+    /// check whether the history is linearizable, against a map that is empty at first.
+    ///
+    /// The keys are independent, so each key is checked alone, by searching an order of its operations
+    /// that respects the real-time order and explains every outcome (Wing & Gong).
+    /// Operations with `Unknown` outcome may take effect at any time after called, or never.
+    /// The search is exponential in the worst case, so keep the histories small.
+    pub fn check(&self) -> std::result::Result<(), Violation> {
+        let mut keys: Vec<&str> = self.events.iter().map(|event| event.call.key()).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        for key in keys {
+            let mut events: Vec<Event> = self
+                .events
+                .iter()
+                .filter(|event| event.call.key() == key)
+                .cloned()
+                .collect();
+            events.sort_by_key(|event| event.start);
+            if !KeySearch::new(&events).linearizable() {
+                return Err(Violation {
+                    key: key.to_owned(),
+                    events,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the sequential model of a key: apply an operation to the current value,
+/// returns the new value, or `None` if the outcome is impossible.
+fn apply(event: &Event, value: &Option<String>) -> Option<Option<String>> {
+    match (&event.call, &event.outcome) {
+        (Call::Get { .. }, Outcome::Value(read)) if read == value => Some(value.clone()),
+        (Call::Get { .. }, Outcome::Unknown) => Some(value.clone()),
+        (Call::Set { value, .. }, Outcome::Done) | (Call::Set { value, .. }, Outcome::Unknown) => {
+            Some(Some(value.clone()))
+        }
+        (Call::Remove { .. }, Outcome::Done) if value.is_some() => Some(None),
+        (Call::Remove { .. }, Outcome::NotFound) if value.is_none() => Some(None),
+        (Call::Remove { .. }, Outcome::Unknown) => Some(None),
+        _ => None,
+    }
+}
+
+struct KeySearch<'a> {
+    events: &'a [Event],
+    visited: HashSet<(Vec<bool>, Option<String>)>,
+}
+
+impl<'a> KeySearch<'a> {
+    fn new(events: &'a [Event]) -> Self {
+        KeySearch {
+            events,
+            visited: HashSet::new(),
+        }
+    }
+
+    fn is_known(event: &Event) -> bool {
+        event.outcome != Outcome::Unknown
+    }
+
+    fn linearizable(&mut self) -> bool {
+        let done = vec![false; self.events.len()];
+        self.search(done, None)
+    }
+
+    fn search(&mut self, done: Vec<bool>, value: Option<String>) -> bool {
+        let events = self.events;
+        let pending = || events.iter().zip(done.iter()).filter(|(_, done)| !**done).map(|(event, _)| event);
+        if pending().all(|event| !Self::is_known(event)) {
+            return true;
+        }
+        if !self.visited.insert((done.clone(), value.clone())) {
+            return false;
+        }
+        // an operation can go next only if it's called before every pending operation returns.
+        let deadline = pending()
+            .filter(|event| Self::is_known(event))
+            .map(|event| event.end)
+            .min()
+            .expect("there is a pending operation with known outcome.");
+        for (i, event) in events.iter().enumerate() {
+            if done[i] || event.start > deadline {
+                continue;
+            }
+            if let Some(next) = apply(event, &value) {
+                let mut done = done.clone();
+                done[i] = true;
+                if self.search(done, next) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,706 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+
+use crate::{KvError, Result};
+use crate::contract::{KvContractMessage, Request, Response};
+use crate::engines::changes::{Change, LogPosition};
+use crate::engines::engine::{BatchWrite, Preload, SetCondition, ValueWithMeta};
+use crate::engines::lease::Lease;
+use crate::engines::pattern::ListOptions;
+use crate::engines::typed::TypedValue;
+use crate::server_common::{DryRunReport, MetaValue, ServerError, ServerStats};
+use crate::value_encoding::{Encodings, ValueEncoding};
+
+/// The client of the kvs contract, that sends each request to the server in a new connection.
+///
+/// The `kvs-client` binary is a thin wrapper of it.
+#[derive(Clone, Debug)]
+pub struct KvsClient {
+    server: SocketAddr,
+    credentials: Option<Credentials>,
+    timeout: Option<Duration>,
+    encodings: Encodings,
+}
+
+/// the user sending the requests, without the secret in its `Debug`.
+#[derive(Clone)]
+struct Credentials {
+    user: String,
+    secret: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials").field("user", &self.user).finish()
+    }
+}
+
+impl KvsClient {
+    /// create a client of the server at `server`.
+    /// It doesn't connect until a request is sent.
+    pub fn new(server: SocketAddr) -> Self {
+        KvsClient { server, credentials: None, timeout: None, encodings: Encodings::default() }
+    }
+
+    /// send the requests as `user`, when the server has users, see `Acl`.
+    pub fn with_credentials(mut self, user: String, secret: String) -> Self {
+        self.credentials = Some(Credentials { user, secret });
+        self
+    }
+
+    /// attach a deadline `timeout` after it's sent to each request, see `Request::Deadline`,
+    /// so that the server skips the request or stops it once no one waits for it,
+    /// and responds with a timeout error, which is `DeadlineExceeded`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// encode the values by `encoding` before sending them, like encrypting or compressing them,
+    /// and decode the ones read in its envelopes, see `ValueEncoding`.
+    ///
+    /// The values read are decoded by the encodings named in their envelopes, and the plain ones are read as they are,
+    /// so the values written before still read while they're migrated, along with the ones of `with_decoding`.
+    /// It applies to the values of `get`, `set`, their `_many` and `_with_meta` forms, and the conditional sets,
+    /// not to the raw requests of `send` and `batch`, the streams, the typed values, nor the scripts.
+    /// `append` is refused, since the suffix cannot be appended to an encoded value.
+    pub fn with_encoding(mut self, encoding: Arc<dyn ValueEncoding>) -> Self {
+        self.encodings.write_with(encoding);
+        self
+    }
+
+    /// decode the values read in the envelopes of `encoding`, without encoding the values written by it,
+    /// like the encoding being replaced by `with_encoding`.
+    pub fn with_decoding(mut self, encoding: Arc<dyn ValueEncoding>) -> Self {
+        self.encodings.read_with(encoding);
+        self
+    }
+
+    /// the value read from the server, decoded by its encoding.
+    fn decoded(&self, value: Option<String>) -> Result<Option<String>> {
+        value.map(|value| self.encodings.decode(value)).transpose()
+    }
+
+    /// the message of `request`, wrapped with the credentials and the deadline if any.
+    fn message_of(&self, request: Request) -> Request {
+        let request = match &self.credentials {
+            Some(Credentials { user, secret }) => Request::Auth {
+                user: user.clone(),
+                secret: secret.clone(),
+                request: Box::new(request),
+            },
+            None => request,
+        };
+        match self.timeout {
+            Some(timeout) => Request::Deadline { timeout_ms: timeout.as_millis() as u64, request: Box::new(request) },
+            None => request,
+        }
+    }
+
+    /// the address of the server.
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// send a request to the server, and receive its response.
+    ///
+    /// Returns `None` when the server responds with a malformed message.
+    pub fn send(&self, request: Request) -> std::io::Result<Option<Response>> {
+        debug!("sending {:?} to {}.", request, self.server);
+        let bin = self.message_of(request).into_binary();
+        let mut stream = TcpStream::connect(self.server)?;
+        stream.write_all(bin.as_slice())?;
+        stream.shutdown(Shutdown::Write)?;
+        let response = Response::parse(stream).ok();
+        debug!("received {:?} from {}.", response, self.server);
+        Ok(response)
+    }
+
+    fn request(&self, request: Request) -> Result<Option<String>> {
+        let response = self.send(request)?;
+        Self::content_of(response)
+    }
+
+    fn content_of(response: Option<Response>) -> Result<Option<String>> {
+        match response {
+            Some(Response::NoContent) => Ok(None),
+            Some(Response::Content { content }) => Ok(Some(content)),
+            // only built by the server, and parsed as `Content`.
+            Some(Response::SharedContent { content }) => Ok(Some(String::from_utf8_lossy(&content).into_owned())),
+            Some(Response::Error { code, .. }) if code == KvError::KeyNotFound.code() => {
+                Err(KvError::KeyNotFound)
+            }
+            Some(Response::Error { code, .. }) if code == ServerError::Timeout.code() => {
+                Err(KvError::DeadlineExceeded { operation: "request" })
+            }
+            Some(Response::Stream) => Err(KvError::Other {
+                reason: "unexpected streamed response from the server.".to_owned(),
+            }),
+            Some(Response::Batch { .. }) => Err(KvError::Other {
+                reason: "unexpected batch response from the server.".to_owned(),
+            }),
+            Some(Response::Error { code, retryable, reason }) => Err(KvError::Remote { code, retryable, reason }),
+            // like a response cut short by a broken connection.
+            None => Err(KvError::OtherIOException {
+                io_error: io::Error::new(io::ErrorKind::InvalidData, "malformed response from the server."),
+            }),
+        }
+    }
+
+    /// get the value of `key`, or `None` if it doesn't exist.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let value = self.request(Request::Get { key })?;
+        self.decoded(value)
+    }
+
+    /// set `key` to `value`.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        let value = self.encodings.encode(value)?;
+        self.request(Request::Set { key, value }).map(|_| ())
+    }
+
+    /// send `requests` in a batch by one connection, and receive their responses in the same order,
+    /// each of which may be an error on its own, see `Request::Batch`.
+    pub fn batch(&self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        let count = requests.len();
+        match self.send(Request::Batch { requests })? {
+            Some(Response::Batch { responses }) if responses.len() == count => Ok(responses),
+            Some(Response::Batch { .. }) => Err(KvError::Other {
+                reason: "the server responded a batch of another size.".to_owned(),
+            }),
+            response => Self::content_of(response).and_then(|_| {
+                Err(KvError::Other { reason: "the server responded no batch.".to_owned() })
+            }),
+        }
+    }
+
+    /// collect the writes to apply atomically on the server, see `Transaction`.
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction { client: self, writes: Vec::new() }
+    }
+
+    /// get the values of `keys` in a batch, in the same order, `None` for the missing ones,
+    /// see `batch`.
+    pub fn get_many(&self, keys: Vec<String>) -> Result<Vec<Result<Option<String>>>> {
+        let requests = keys.into_iter().map(|key| Request::Get { key }).collect();
+        let responses = self.batch(requests)?;
+        Ok(responses
+            .into_iter()
+            .map(|response| Self::content_of(Some(response)).and_then(|value| self.decoded(value)))
+            .collect())
+    }
+
+    /// set the `pairs` of keys and values in a batch, returns whether each of them is set in the same order,
+    /// see `batch`.
+    pub fn set_many(&self, pairs: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        let mut requests = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            requests.push(Request::Set { key, value: self.encodings.encode(value)? });
+        }
+        let responses = self.batch(requests)?;
+        Ok(responses.into_iter().map(|response| Self::content_of(Some(response)).map(|_| ())).collect())
+    }
+
+    /// set `key` to the value read from `value` until EOF, streaming it to the server,
+    /// see `KvsEngine::set_stream`.
+    pub fn set_stream(&self, key: String, value: &mut dyn Read) -> Result<()> {
+        let message = Request::SetStream { key };
+        debug!("sending {:?} and the streamed value to {}.", message, self.server);
+        let mut stream = TcpStream::connect(self.server)?;
+        stream.write_all(self.message_of(message).into_binary().as_slice())?;
+        io::copy(value, &mut stream)?;
+        stream.shutdown(Shutdown::Write)?;
+        let response = Response::parse(stream).ok();
+        debug!("received {:?} from {}.", response, self.server);
+        Self::content_of(response).map(|_| ())
+    }
+
+    /// get the value of `key` streamed from the server, or `None` if it doesn't exist,
+    /// see `KvsEngine::get_stream`.
+    pub fn get_stream(&self, key: String) -> Result<Option<TcpStream>> {
+        let message = Request::GetStream { key };
+        debug!("sending {:?} to {}.", message, self.server);
+        let mut stream = TcpStream::connect(self.server)?;
+        stream.write_all(self.message_of(message).into_binary().as_slice())?;
+        stream.shutdown(Shutdown::Write)?;
+        let response = Response::parse_head(&mut stream).ok();
+        debug!("received {:?} from {}.", response, self.server);
+        match response {
+            Some(Response::Stream) => Ok(Some(stream)),
+            _ => Self::content_of(response).map(|_| None),
+        }
+    }
+
+    /// the changes of the server since `since`, ending at the last one,
+    /// or waiting for the new ones until the connection breaks when `follow`, see `KvsEngine::changes`.
+    pub fn changes(&self, since: LogPosition, follow: bool) -> Result<impl Iterator<Item=Result<Change>>> {
+        let message = Request::Changes { since, follow };
+        debug!("sending {:?} to {}.", message, self.server);
+        let mut stream = TcpStream::connect(self.server)?;
+        stream.write_all(self.message_of(message).into_binary().as_slice())?;
+        stream.shutdown(Shutdown::Write)?;
+        let response = Response::parse_head(&mut stream).ok();
+        debug!("received {:?} from {}.", response, self.server);
+        match response {
+            Some(Response::Stream) => Ok(BufReader::new(stream)
+                .lines()
+                .map(|line| Ok(serde_json::from_str(line?.as_str())?))),
+            _ => Err(Self::content_of(response).err().unwrap_or_else(|| KvError::Other {
+                reason: "the server responded no changes.".to_owned(),
+            })),
+        }
+    }
+
+    /// where the next write goes in the log of the server, to follow only the changes after now from,
+    /// see `KvsEngine::log_end`.
+    pub fn log_end(&self) -> Result<LogPosition> {
+        let content = self.request(Request::LogEnd)?.ok_or_else(|| KvError::Other {
+            reason: "the server responded no log position.".to_owned(),
+        })?;
+        Ok(serde_json::from_str(content.as_str())?)
+    }
+
+    /// remove `key`.
+    ///
+    /// # Error
+    ///
+    /// `KeyNotFound` if the key doesn't exist.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.request(Request::Remove { key }).map(|_| ())
+    }
+
+    /// restore the value of the removed `key`, see `KvsEngine::undelete`.
+    ///
+    /// # Error
+    ///
+    /// `KeyNotFound` if there is no removed value to restore.
+    pub fn undelete(&self, key: String) -> Result<()> {
+        self.request(Request::Undelete { key }).map(|_| ())
+    }
+
+    /// set `key` to `value` only when `condition` holds, returns whether it's written, see `KvsEngine::set_if`.
+    pub fn set_if(&self, key: String, value: String, condition: SetCondition) -> Result<bool> {
+        let value = self.encodings.encode(value)?;
+        let content = self.request(Request::SetIf { key, value, condition })?;
+        Ok(content.as_deref() == Some("true"))
+    }
+
+    /// get the value of `key` with when it's last modified and its metadata, or `None` if it doesn't exist,
+    /// see `KvsEngine::get_with_meta`.
+    pub fn get_with_meta(&self, key: String) -> Result<Option<ValueWithMeta>> {
+        let content = self.request(Request::GetWithMeta { key })?;
+        let found: Option<MetaValue> = content.map(|content| serde_json::from_str(content.as_str())).transpose()?;
+        found
+            .map(|found| {
+                let mut found = ValueWithMeta::from(found);
+                found.value = self.encodings.decode(found.value)?;
+                Ok(found)
+            })
+            .transpose()
+    }
+
+    /// set `key` to `value` only when it's last modified at `modified`, the one `get_with_meta` tells,
+    /// returns whether it's written, see `KvsEngine::set_if_unmodified`.
+    pub fn set_if_unmodified(&self, key: String, value: String, modified: SystemTime) -> Result<bool> {
+        let modified_ms = modified.duration_since(UNIX_EPOCH).map(|since| since.as_millis() as u64).unwrap_or_default();
+        let value = self.encodings.encode(value)?;
+        let content = self.request(Request::SetIfUnmodified { key, value, modified_ms })?;
+        Ok(content.as_deref() == Some("true"))
+    }
+
+    /// run `script` on the server atomically, with the `keys` it touches and `args`, returns what it returns,
+    /// see `Request::Eval`.
+    pub fn eval(&self, script: String, keys: Vec<String>, args: Vec<String>) -> Result<String> {
+        Ok(self.request(Request::Eval { script, keys, args })?.unwrap_or_default())
+    }
+
+    /// get the value of `key` with its type, or `None` if it doesn't exist, see `KvsEngine::get_typed`.
+    pub fn get_typed(&self, key: String) -> Result<Option<TypedValue>> {
+        let content = self.request(Request::GetTyped { key })?;
+        Ok(content.map(|content| serde_json::from_str(content.as_str())).transpose()?)
+    }
+
+    /// set `key` to `value` with its type kept, see `KvsEngine::set_typed`.
+    pub fn set_typed(&self, key: String, value: TypedValue) -> Result<()> {
+        self.request(Request::SetTyped { key, value }).map(|_| ())
+    }
+
+    /// add `delta` to the integer of `key`, returns the new integer, see `KvsEngine::incr`.
+    pub fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let content = self.request(Request::Incr { key, delta })?;
+        content.and_then(|content| content.parse().ok()).ok_or_else(|| KvError::Other {
+            reason: "the server responded a malformed integer.".to_owned(),
+        })
+    }
+
+    /// move the value of `from` to `to` atomically, see `KvsEngine::rename`.
+    pub fn rename(&self, from: String, to: String) -> Result<()> {
+        self.request(Request::Rename { from, to }).map(|_| ())
+    }
+
+    /// copy the value of `from` to `to` atomically, see `KvsEngine::copy`.
+    pub fn copy(&self, from: String, to: String) -> Result<()> {
+        self.request(Request::Copy { from, to }).map(|_| ())
+    }
+
+    /// remove all the keys starting with `prefix` by one write, returns how many keys are removed,
+    /// see `KvsEngine::remove_prefix`.
+    pub fn remove_prefix(&self, prefix: String) -> Result<usize> {
+        let content = self.request(Request::RemovePrefix { prefix })?;
+        content.and_then(|content| content.parse().ok()).ok_or_else(|| KvError::Other {
+            reason: "the server responded a malformed count.".to_owned(),
+        })
+    }
+
+    /// take the lease of `key` for `ttl`, or `None` if another one holds it, see `KvsEngine::acquire_lease`.
+    pub fn acquire_lease(&self, key: String, ttl: Duration) -> Result<Option<Lease>> {
+        let content = self.request(Request::AcquireLease { key, ttl_ms: ttl.as_millis() as u64 })?;
+        content
+            .map(|content| Lease::decode(content.as_str()).ok_or_else(|| KvError::Other {
+                reason: "the server responded a malformed lease.".to_owned(),
+            }))
+            .transpose()
+    }
+
+    /// release the lease of `key` by its `token`, returns whether it's released, see `KvsEngine::release_lease`.
+    pub fn release_lease(&self, key: String, token: String) -> Result<bool> {
+        let content = self.request(Request::ReleaseLease { key, token })?;
+        Ok(content.as_deref() == Some("true"))
+    }
+
+    /// append `suffix` to the value of `key`, or set the key to it if it doesn't exist, see `KvsEngine::append`.
+    ///
+    /// # Error
+    ///
+    /// `Unsupported` if the values are encoded, see `with_encoding`.
+    pub fn append(&self, key: String, suffix: String) -> Result<()> {
+        if !self.encodings.is_plain() {
+            return Err(KvError::Unsupported { operation: "append" });
+        }
+        self.request(Request::Append { key, suffix }).map(|_| ())
+    }
+
+    /// list the keys matching the glob `pattern` in ascending order, see `KeyPattern`.
+    pub fn keys(&self, pattern: String) -> Result<Vec<String>> {
+        self.list_keys(pattern, ListOptions::default())
+    }
+
+    /// list the keys matching the glob `pattern` in the order and within the bounds of `options`.
+    pub fn list_keys(&self, pattern: String, options: ListOptions) -> Result<Vec<String>> {
+        let content = self.request(Request::Keys { pattern, options })?.ok_or_else(|| KvError::Other {
+            reason: "the server responded no keys.".to_owned(),
+        })?;
+        Ok(serde_json::from_str(content.as_str())?)
+    }
+
+    /// replace all the data of the server by a backup, see `read_archive` and `KvsEngine::restore`.
+    /// `token` is the admin token of the server.
+    pub fn restore(&self, token: String, archive: BTreeMap<String, Vec<u8>>) -> Result<()> {
+        self.request(Request::Restore { token: Some(token), archive }).map(|_| ())
+    }
+
+    /// the keys `request` would touch, like a `Request::Remove` or a `Request::RemovePrefix`,
+    /// without modifying anything, see `Request::DryRun`.
+    ///
+    /// # Error
+    ///
+    /// `KeyNotFound` if it's a removal of a key that doesn't exist.
+    pub fn dry_run(&self, request: Request) -> Result<DryRunReport> {
+        let content = self.request(Request::DryRun { request: Box::new(request) })?.ok_or_else(|| KvError::Other {
+            reason: "the server responded no report.".to_owned(),
+        })?;
+        Ok(serde_json::from_str(content.as_str())?)
+    }
+
+    /// warm up the server by loading the values of `target` ahead, returns how many are loaded,
+    /// see `KvsEngine::preload`.
+    /// `token` is the admin token of the server.
+    pub fn preload(&self, token: String, target: Preload) -> Result<usize> {
+        let content = self.request(Request::Preload { token: Some(token), target })?.ok_or_else(|| KvError::Other {
+            reason: "the server responded no count.".to_owned(),
+        })?;
+        Ok(serde_json::from_str(content.as_str())?)
+    }
+
+    /// the statistics of the server.
+    pub fn stats(&self) -> Result<ServerStats> {
+        let content = self.request(Request::Stats)?.ok_or_else(|| KvError::Other {
+            reason: "the server responded no statistics.".to_owned(),
+        })?;
+        Ok(serde_json::from_str(content.as_str())?)
+    }
+}
+
+/// The writes collected on the client, then sent by `commit` in one request and applied by one write on the server,
+/// so that all or none of them are written, like `MULTI` and `EXEC` of Redis, see `KvsEngine::write_batch`.
+///
+/// Nothing is sent before `commit`, so dropping it rolls it back.
+/// The values aren't read in it, since the writes of the others may come between the reads and the commit.
+pub struct Transaction<'a> {
+    client: &'a KvsClient,
+    writes: Vec<BatchWrite>,
+}
+
+impl Transaction<'_> {
+    /// set `key` to `value` on commit.
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.writes.push(BatchWrite::Set { key, value });
+        self
+    }
+
+    /// remove `key` on commit, if it exists then.
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.writes.push(BatchWrite::Remove { key });
+        self
+    }
+
+    /// send the writes collected, and apply them on the server.
+    pub fn commit(self) -> Result<()> {
+        let encodings = &self.client.encodings;
+        let writes = self
+            .writes
+            .into_iter()
+            .map(|write| match write {
+                BatchWrite::Set { key, value } => Ok(BatchWrite::Set { key, value: encodings.encode(value)? }),
+                remove => Ok(remove),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.client.request(Request::Transaction { writes }).map(|_| ())
+    }
+}
+
+/// The values read recently by a `CachingClient`, evicting the least recently used keys beyond its capacity.
+struct ClientCache {
+    capacity: usize,
+    max_staleness: Duration,
+    clock: u64,
+    /// bumped by every invalidation, so that a value read from the server meanwhile isn't cached.
+    invalidations: u64,
+    /// whether the changes of the server are followed, the cached values are never used without it.
+    subscribed: bool,
+    /// the values by their keys, `None` for the absent keys, with when they're used and read from the server.
+    values: HashMap<String, (u64, Instant, Option<String>)>,
+    /// the keys by the time they are used.
+    recency: BTreeMap<u64, String>,
+}
+
+impl ClientCache {
+    fn get(&mut self, key: &str) -> Option<Option<String>> {
+        if !self.subscribed {
+            return None;
+        }
+        self.clock += 1;
+        let (used_at, read_at, value) = self.values.get_mut(key)?;
+        if read_at.elapsed() > self.max_staleness {
+            self.remove(key);
+            return None;
+        }
+        let key = self.recency.remove(used_at)?;
+        *used_at = self.clock;
+        self.recency.insert(self.clock, key);
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: String, value: Option<String>, read_at: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(key.as_str());
+        while self.values.len() >= self.capacity {
+            match self.recency.pop_first() {
+                Some((_, evicted)) => self.remove(evicted.as_str()),
+                None => break,
+            }
+        }
+        self.clock += 1;
+        self.recency.insert(self.clock, key.clone());
+        self.values.insert(key, (self.clock, read_at, value));
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((used_at, _, _)) = self.values.remove(key) {
+            self.recency.remove(&used_at);
+        }
+    }
+
+    fn invalidate(&mut self, key: &str) {
+        self.invalidations += 1;
+        self.remove(key);
+    }
+
+    /// forget everything, since the changes missed while unsubscribed are unknown.
+    fn unsubscribe(&mut self) {
+        self.invalidations += 1;
+        self.subscribed = false;
+        self.values.clear();
+        self.recency.clear();
+    }
+}
+
+/// A `KvsClient` keeping the values of the keys read recently in memory,
+/// for the read-mostly applications whose hot keys are read far more often than written.
+///
+/// It follows the changes of the server on another thread, see `KvsClient::changes`,
+/// and forgets the keys written by anyone as their changes arrive,
+/// and the values read longer than `max_staleness` ago anyway, which bounds the staleness when the changes lag.
+/// The cached values are never used while the changes aren't followed, like when the connection breaks,
+/// until it follows them again.
+///
+/// The changes are followed from the end of the log when it starts, and again when they're lost,
+/// since the values are forgotten anyway while they aren't followed.
+/// The following connection holds a worker of the server.
+#[derive(Clone)]
+pub struct CachingClient {
+    client: KvsClient,
+    cache: Arc<Mutex<ClientCache>>,
+}
+
+impl CachingClient {
+    /// how long to wait before following the changes again after the connection breaks.
+    const RESUBSCRIBE_INTERVAL: Duration = Duration::from_millis(500);
+
+    /// keep at most `capacity` keys read by `client` in memory, each for at most `max_staleness`.
+    ///
+    /// # Error
+    ///
+    /// when it fails to follow the changes of the server, it throws.
+    pub fn new(client: KvsClient, capacity: usize, max_staleness: Duration) -> Result<Self> {
+        let since = client.log_end()?;
+        let changes = client.changes(since, true)?;
+        let cache = Arc::new(Mutex::new(ClientCache {
+            capacity,
+            max_staleness,
+            clock: 0,
+            invalidations: 0,
+            subscribed: true,
+            values: HashMap::new(),
+            recency: BTreeMap::new(),
+        }));
+        let subscriber = client.clone();
+        let weak = Arc::downgrade(&cache);
+        thread::spawn(move || Self::follow(subscriber, weak, since, changes));
+        Ok(CachingClient { client, cache })
+    }
+
+    /// forget the keys written, until the cache is dropped,
+    /// and follow the changes again from where they break,
+    /// or from the end of the log if they're lost, like the ones dropped by a compaction.
+    fn follow(
+        client: KvsClient,
+        cache: Weak<Mutex<ClientCache>>,
+        mut since: LogPosition,
+        changes: impl Iterator<Item=Result<Change>>,
+    ) {
+        let mut changes: Box<dyn Iterator<Item=Result<Change>>> = Box::new(changes);
+        loop {
+            for change in changes.by_ref() {
+                let change = match change {
+                    Ok(change) => change,
+                    Err(err) => {
+                        warn!("failed to read the changes from {}: {}.", client.server(), err);
+                        break;
+                    }
+                };
+                if !Self::update(&cache, |cache| cache.invalidate(change.key.as_str())) {
+                    return;
+                }
+                since = change.next;
+            }
+            if !Self::update(&cache, ClientCache::unsubscribe) {
+                return;
+            }
+            changes = loop {
+                thread::sleep(Self::RESUBSCRIBE_INTERVAL);
+                if cache.strong_count() == 0 {
+                    return;
+                }
+                match client.changes(since, true) {
+                    Ok(followed) => break Box::new(followed),
+                    Err(err) => {
+                        warn!("failed to follow the changes from {} since {}: {}.", client.server(), since, err);
+                        // the values are forgotten while unsubscribed, so the changes before now are needless.
+                        if let Ok(end) = client.log_end() {
+                            since = end;
+                        }
+                    }
+                }
+            };
+            if !Self::update(&cache, |cache| cache.subscribed = true) {
+                return;
+            }
+        }
+    }
+
+    /// update the cache if it isn't dropped yet, returns whether it isn't.
+    fn update(cache: &Weak<Mutex<ClientCache>>, f: impl FnOnce(&mut ClientCache)) -> bool {
+        match cache.upgrade() {
+            Some(cache) => {
+                if let Ok(mut cache) = cache.lock() {
+                    f(&mut cache);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// the client sending the requests, whose writes are forgotten once their changes arrive.
+    pub fn client(&self) -> &KvsClient {
+        &self.client
+    }
+
+    /// get the value of `key` from memory, or from the server and keep it in memory.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let invalidations = {
+            let mut cache = self.cache.lock()?;
+            if let Some(value) = cache.get(key.as_str()) {
+                return Ok(value);
+            }
+            cache.invalidations
+        };
+        let read_at = Instant::now();
+        let value = self.client.get(key.clone())?;
+        let mut cache = self.cache.lock()?;
+        if cache.subscribed && cache.invalidations == invalidations {
+            cache.insert(key, value.clone(), read_at);
+        }
+        Ok(value)
+    }
+
+    /// whether the value of `key` is in memory, then `get` doesn't ask the server.
+    pub fn is_cached(&self, key: &str) -> bool {
+        self.cache.lock().map(|cache| cache.subscribed && cache.values.contains_key(key)).unwrap_or(false)
+    }
+
+    /// set `key` to `value`, and forget it, so that it's read from the server next time.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.invalidate(key.as_str())?;
+        self.client.set(key.clone(), value)?;
+        self.invalidate(key.as_str())
+    }
+
+    /// remove `key`, and forget it like `set`.
+    ///
+    /// # Error
+    ///
+    /// `KeyNotFound` if the key doesn't exist.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.invalidate(key.as_str())?;
+        let result = self.client.remove(key.clone());
+        self.invalidate(key.as_str())?;
+        result
+    }
+
+    /// forget the value of `key`, so that it's read from the server next time.
+    pub fn invalidate(&self, key: &str) -> Result<()> {
+        self.cache.lock()?.invalidate(key);
+        Ok(())
+    }
+}
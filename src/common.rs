@@ -1,17 +1,11 @@
 use std::io::{Seek, SeekFrom};
 
 pub(crate) trait SeekExt {
-    fn current_position(&mut self) -> std::io::Result<usize>;
     fn seek_to(&mut self, n: usize) -> std::io::Result<usize>;
     fn seek_to_end(&mut self) -> std::io::Result<usize>;
-    fn seek_to_start(&mut self) -> std::io::Result<usize>;
 }
 
 impl<R: Seek> SeekExt for R {
-    fn current_position(&mut self) -> std::io::Result<usize> {
-        self.seek(SeekFrom::Current(0)).map(|n| n as usize)
-    }
-
     fn seek_to(&mut self, n: usize) -> std::io::Result<usize> {
         self.seek(SeekFrom::Start(n as u64)).map(|n| n as usize)
     }
@@ -19,8 +13,12 @@ impl<R: Seek> SeekExt for R {
     fn seek_to_end(&mut self) -> std::io::Result<usize> {
         self.seek(SeekFrom::End(0)).map(|n| n as usize)
     }
+}
 
-    fn seek_to_start(&mut self) -> std::io::Result<usize> {
-        self.seek(SeekFrom::Start(0)).map(|n| n as usize)
+/// the error returned by a fail point configured with the `return` action.
+#[cfg(feature = "failpoints")]
+pub(crate) fn failpoint_error(name: &str) -> crate::KvError {
+    crate::KvError::Other {
+        reason: format!("fail point {} triggered.", name),
     }
 }
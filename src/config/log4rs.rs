@@ -1,39 +1,288 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log4rs::append::Append;
 use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::append::file::FileAppender;
+use log4rs::append::rolling_file::LogFile;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::append::rolling_file::policy::compound::trigger::Trigger;
+use log4rs::append::rolling_file::RollingFileAppender;
 use log4rs::config::{Appender, Config, Logger, Root};
+use log4rs::encode::Encode;
+use log4rs::encode::json::JsonEncoder;
 use log4rs::encode::pattern::PatternEncoder;
 use log::LevelFilter;
 
+use crate::config::server::{ConfigError, LogFileConfig};
+use crate::server_common::LogFormat;
+
+/// the env var to filter logs when `--log-level` isn't given, in the same syntax.
+pub const LOG_ENV: &str = "RUST_LOG";
+
+/// The level filter of logs, in the syntax of `RUST_LOG`:
+/// comma-separated directives, each of them is either a level like `debug`,
+/// which is the default level, or `target=level`, which is the level of the target and its children.
+///
+/// ```rust
+/// # use kvs::config::log4rs::LogFilter;
+/// # use log::LevelFilter;
+/// let filter: LogFilter = "warn,app::request=info".parse().unwrap();
+/// assert_eq!(filter.default_level(), LevelFilter::Warn);
+/// assert_eq!(filter.level_of("app::request"), Some(LevelFilter::Info));
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LogFilter {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    /// the filter given by the command line, or else by the `RUST_LOG` env var, or else the `fallback`.
+    ///
+    /// # Error
+    ///
+    /// When the env var or the `fallback` is malformed, returns `ConfigError::Invalid`.
+    pub fn resolve(flag: Option<LogFilter>, fallback: &str) -> Result<Self, ConfigError> {
+        match flag {
+            Some(filter) => Ok(filter),
+            None => match std::env::var(LOG_ENV) {
+                Ok(spec) => spec.parse(),
+                Err(_) => fallback.parse(),
+            },
+        }
+    }
+
+    /// the level of targets without a directive.
+    pub fn default_level(&self) -> LevelFilter {
+        self.default
+    }
+
+    /// the level given to the target by a directive, if any.
+    pub fn level_of(&self, target: &str) -> Option<LevelFilter> {
+        self.targets
+            .iter()
+            .find(|(t, _)| t == target)
+            .map(|(_, level)| *level)
+    }
+
+    fn loggers(&self) -> impl Iterator<Item=Logger> + '_ {
+        self.targets
+            .iter()
+            .filter(|(target, _)| !BUILTIN_TARGETS.contains(&target.as_str()))
+            .map(|(target, level)| Logger::builder().build(target.as_str(), *level))
+    }
+}
+
+const BUILTIN_TARGETS: [&str; 2] = ["app::error", "app::request"];
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        LogFilter {
+            default: LevelFilter::Info,
+            targets: vec![],
+        }
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_level = |level: &str| {
+            level.trim().parse::<LevelFilter>().map_err(|_| ConfigError::Invalid {
+                reason: format!("unknown log level {:?} in {:?}", level, s),
+            })
+        };
+        let mut filter = LogFilter::default();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let mut parts = directive.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(target), Some(level)) => filter
+                    .targets
+                    .push((target.trim().to_owned(), parse_level(level)?)),
+                (Some(level), None) => filter.default = parse_level(level)?,
+                _ => unreachable!(),
+            }
+        }
+        Ok(filter)
+    }
+}
+
 /// the `log4rs` default config.
 pub fn config() -> Config {
+    config_of(LogFormat::Text)
+}
+
+/// the `log4rs` config of `kvs-client`, it writes logs to stderr only,
+/// so that they won't mix with the output.
+pub fn client_config(filter: &LogFilter) -> Config {
     let stderr = ConsoleAppender::builder()
         .target(Target::Stderr)
-        .encoder(Box::new(PatternEncoder::new(concat!(
+        .encoder(encoder(LogFormat::Text))
+        .build();
+    Config::builder()
+        .appender(Appender::builder().build("stderr", Box::new(stderr)))
+        .loggers(filter.loggers())
+        .build(Root::builder().appender("stderr").build(filter.default_level()))
+        .expect("the client config should always be valid")
+}
+
+fn encoder(format: LogFormat) -> Box<dyn Encode> {
+    match format {
+        LogFormat::Text => Box::new(PatternEncoder::new(concat!(
         "{T}=>kvs[",
         env!("CARGO_PKG_VERSION"),
         "]@{d(%Y-%m-%d %H:%M:%S)}=>{t}: {m}{n}"
-        ))))
+        ))),
+        LogFormat::Json => Box::new(JsonEncoder::new()),
+    }
+}
+
+/// the `log4rs` config that writes logs in the `format`.
+///
+/// With `LogFormat::Json`, every line is a JSON object with `time`, `level`, `target`, `message`,
+/// and the `mdc` of the thread, which holds the `request_id`, `peer` and `latency_us` when serving requests.
+pub fn config_of(format: LogFormat) -> Config {
+    config_with(format, None, &LogFilter::default()).expect("the console config should always be valid")
+}
+
+/// like `config_of`, but also writes all logs into the file, if it's given,
+/// and filters the logs by the `filter`.
+///
+/// The errors in `app::error` are logged unless they are turned off explicitly,
+/// or the default level is `off`.
+///
+/// # Error
+///
+/// When the log file cannot be opened, returns `ConfigError::Invalid`.
+pub fn config_with(
+    format: LogFormat,
+    file: Option<&LogFileConfig>,
+    filter: &LogFilter,
+) -> Result<Config, ConfigError> {
+    let stderr = ConsoleAppender::builder()
+        .target(Target::Stderr)
+        .encoder(encoder(format))
         .build();
     let stdout = ConsoleAppender::builder()
         .target(Target::Stdout)
-        .encoder(Box::new(PatternEncoder::new(concat!(
-        "{T}=>kvs[",
-        env!("CARGO_PKG_VERSION"),
-        "]@{d(%Y-%m-%d %H:%M:%S)}=>{t}: {m}{n}"
-        ))))
+        .encoder(encoder(format))
         .build();
-    Config::builder()
+    let mut builder = Config::builder()
         .appender(Appender::builder().build("stdout", Box::new(stdout)))
-        .appender(Appender::builder().build("stderr", Box::new(stderr)))
+        .appender(Appender::builder().build("stderr", Box::new(stderr)));
+    let mut root = Root::builder().appender("stdout");
+    if let Some(file) = file {
+        builder = builder.appender(Appender::builder().build("file", file_appender(file, format)?));
+        // the loggers are additive, so the root appender receives all logs exactly once.
+        root = root.appender("file");
+    }
+    let error_level = filter
+        .level_of("app::error")
+        .unwrap_or_else(|| filter.default_level().min(LevelFilter::Error));
+    let request_level = filter
+        .level_of("app::request")
+        .unwrap_or_else(|| filter.default_level());
+    builder
         .logger(
             Logger::builder()
                 .appender("stderr")
-                .build("app::error", LevelFilter::Error),
+                .build("app::error", error_level),
         )
         .logger(
             Logger::builder()
                 .appender("stdout")
-                .build("app::request", LevelFilter::Info),
+                .build("app::request", request_level),
         )
-        .build(Root::builder().appender("stdout").build(LevelFilter::Info))
-        .unwrap()
+        .loggers(filter.loggers())
+        .build(root.build(filter.default_level()))
+        .map_err(|err| ConfigError::Invalid {
+            reason: err.to_string(),
+        })
+}
+
+/// the appender writing into the log file, rotated by size, by time, or both.
+///
+/// # Error
+///
+/// When the log file cannot be opened, returns `ConfigError::Invalid`.
+pub fn file_appender(file: &LogFileConfig, format: LogFormat) -> Result<Box<dyn Append>, ConfigError> {
+    let invalid = |err: &dyn fmt::Display| ConfigError::Invalid {
+        reason: format!("cannot use log file {}: {}", file.path.display(), err),
+    };
+    let mut triggers: Vec<Box<dyn Trigger>> = vec![];
+    if let Some(max_size) = file.max_size {
+        triggers.push(Box::new(SizeTrigger::new(max_size)));
+    }
+    if let Some(secs) = file.rotate_every_secs {
+        triggers.push(Box::new(IntervalTrigger::new(Duration::from_secs(secs))));
+    }
+    if triggers.is_empty() {
+        let appender = FileAppender::builder()
+            .encoder(encoder(format))
+            .build(&file.path)
+            .map_err(|err| invalid(&err))?;
+        return Ok(Box::new(appender));
+    }
+    let pattern = format!("{}.{{}}", file.path.display());
+    let roller = FixedWindowRoller::builder()
+        .build(&pattern, file.keep)
+        .map_err(|err| invalid(&err))?;
+    let policy = CompoundPolicy::new(Box::new(AnyTrigger(triggers)), Box::new(roller));
+    let appender = RollingFileAppender::builder()
+        .encoder(encoder(format))
+        .build(&file.path, Box::new(policy))
+        .map_err(|err| invalid(&err))?;
+    Ok(Box::new(appender))
+}
+
+/// rolls the file every `interval`.
+#[derive(Debug)]
+struct IntervalTrigger {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl IntervalTrigger {
+    fn new(interval: Duration) -> Self {
+        IntervalTrigger {
+            interval,
+            next: Mutex::new(Instant::now() + interval),
+        }
+    }
+}
+
+impl Trigger for IntervalTrigger {
+    fn trigger(&self, _file: &LogFile) -> Result<bool, Box<dyn Error + Sync + Send>> {
+        let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        if now < *next {
+            return Ok(false);
+        }
+        while *next <= now {
+            *next += self.interval;
+        }
+        Ok(true)
+    }
+}
+
+/// rolls the file when any of the triggers fires.
+#[derive(Debug)]
+struct AnyTrigger(Vec<Box<dyn Trigger>>);
+
+impl Trigger for AnyTrigger {
+    fn trigger(&self, file: &LogFile) -> Result<bool, Box<dyn Error + Sync + Send>> {
+        for trigger in &self.0 {
+            if trigger.trigger(file)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
 }
@@ -1,2 +1,4 @@
 /// the log4rs config.
 pub mod log4rs;
+/// the server config file.
+pub mod server;
@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::engines::codec::CodecKind;
+use crate::engines::kvs::{IndexKind, SyncPolicy, WritePath};
+use crate::engines::sled::SledOptions;
+use crate::server_common::VerifyMode;
+
+/// The content of the server config file, in TOML.
+///
+/// Every section is optional, a missing one takes its default.
+///
+/// # Example
+/// ```toml
+/// [log]
+/// level = "info"
+///
+/// [log.file]
+/// path = "logs/kvs.log"
+/// max_size = 10485760
+/// rotate_every_secs = 86400
+/// keep = 7
+///
+/// [engine]
+/// soft_delete = true
+/// blob_threshold = 65536
+/// index = "ordered"
+/// codec = "bincode"
+/// record_cache = 16777216
+/// sync = "always"
+/// write_path = "vectored"
+/// index_budget = 1073741824
+/// index_warning = 536870912
+/// slow_log_ms = 100
+/// verify_on_start = "refuse"
+/// hot_tier = 67108864
+///
+/// [engine.sled]
+/// cache_capacity = 268435456
+/// flush_every_ms = 1000
+/// mode = "fast"
+///
+/// [admin]
+/// token = "a-long-random-secret"
+/// addr = "127.0.0.1:4001"
+///
+/// [connection]
+/// read_timeout_ms = 30000
+///
+/// [memory]
+/// limit = 2147483648
+///
+/// [users.reporter]
+/// secret = "another-secret"
+/// role = "read"
+///
+/// [users.tenant-a]
+/// secret = "yet-another-secret"
+/// role = "write"
+/// prefixes = ["tenant:a:"]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// the options of logging.
+    pub log: LogConfig,
+    /// the options of the engine.
+    pub engine: EngineConfig,
+    /// the options of the admin requests.
+    pub admin: AdminConfig,
+    /// the options of the connections.
+    pub connection: ConnectionConfig,
+    /// the options of the memory the server takes.
+    pub memory: MemoryConfig,
+    /// the accounts by their names, see `Acl`.
+    /// When there is any, the requests must carry the credentials of one of them.
+    pub users: BTreeMap<String, UserConfig>,
+}
+
+/// The `[engine]` section of the config file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    /// make `remove` recoverable by `undelete` until the next compaction, see `KvStore::with_soft_delete`.
+    /// Only the `kvs` engine supports it.
+    pub soft_delete: bool,
+    /// write the values longer than this many bytes into their own blob files, see `KvStore::with_blob_threshold`.
+    /// Only the `kvs` engine supports it.
+    pub blob_threshold: Option<usize>,
+    /// the kind of the index, `hash` or `ordered`, see `KvStore::with_index`.
+    /// The kind recorded in the data directory is used when it's absent.
+    /// Only the `kvs` engine supports it.
+    pub index: Option<IndexKind>,
+    /// the codec of the records, `json`, `json-crc` or `bincode`, see `KvStore::open_with_codec`.
+    /// A data directory keeps the codec it's created with, the recorded one is used when it's absent.
+    /// Only the `kvs` engine supports it.
+    pub codec: Option<CodecKind>,
+    /// keep this many bytes of the records read recently in memory, see `KvStore::with_record_cache`.
+    /// Only the `kvs` engine supports it.
+    pub record_cache: Option<usize>,
+    /// when the writes are made durable, `never` or `always`, see `KvStore::with_sync`.
+    /// The `sled` engine flushes every write whatever it is, unless `flush_every_ms` is set for it.
+    pub sync: SyncPolicy,
+    /// how the writes are appended, `buffered` or `vectored`, see `KvStore::with_write_path`.
+    /// Only the `kvs` engine supports it.
+    pub write_path: WritePath,
+    /// refuse to write new keys once the index would take more than this many bytes of memory,
+    /// see `KvStore::with_index_budget`.
+    /// Only the `kvs` engine supports it.
+    pub index_budget: Option<u64>,
+    /// warn in the logs once the index takes more than this many bytes of memory,
+    /// see `KvStore::with_index_warning`.
+    /// Only the `kvs` engine supports it.
+    pub index_warning: Option<u64>,
+    /// warn about the operations taking longer than this many milliseconds, with the requests they serve,
+    /// see `KvStore::with_slow_log`.
+    /// Only the `kvs` engine supports it.
+    pub slow_log_ms: Option<u64>,
+    /// verify the data before serving, `off`, `warn` or `refuse`, see `VerifyMode`.
+    /// Only the `kvs` engine supports it.
+    pub verify_on_start: VerifyMode,
+    /// keep the values read recently in memory up to this many bytes, in front of the engine,
+    /// see `Tiered`.
+    pub hot_tier: Option<usize>,
+    /// the `[engine.sled]` section, the tuning of sled, see `SledEngine::open_with`.
+    /// Only the `sled` engine supports it.
+    pub sled: SledOptions,
+}
+
+/// The `[admin]` section of the config file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// the secret the admin requests, `preload` and `restore`, must carry.
+    /// The admin requests are refused when it's absent.
+    pub token: Option<String>,
+    /// the address to serve the admin requests, `stats`, `preload` and `restore`, on, apart from the clients,
+    /// see `KvServer::admin_listener`. They're served with the others when it's absent.
+    pub addr: Option<SocketAddr>,
+}
+
+/// The `[connection]` section of the config file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+    /// close the connections whose requests aren't read in this many milliseconds, see `KvServer::read_timeout`.
+    /// `DEFAULT_READ_TIMEOUT` of the server is used when it's absent.
+    pub read_timeout_ms: Option<u64>,
+}
+
+/// The `[memory]` section of the config file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryConfig {
+    /// refuse the writes and the scans once the server takes more than this many bytes of memory,
+    /// see `KvServer::memory_limit`. The memory isn't limited when it's absent.
+    pub limit: Option<u64>,
+}
+
+/// A `[users.<name>]` section of the config file, an account and what it may do.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    /// the secret the requests of the user carry.
+    pub secret: String,
+    /// the requests the user may send, `read` by default.
+    #[serde(default)]
+    pub role: Role,
+    /// the prefixes of the keys the user may touch, all the keys when it's empty.
+    /// A restricted user may list the keys by the patterns under the prefixes only, and never read the changes.
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+}
+
+/// What a user may request, each role includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// the reads, like `get`, `keys`, `stats` and `changes`.
+    #[default]
+    Read,
+    /// the writes, like `set`, `rm` and `rename`.
+    Write,
+    /// the admin requests, like `restore`, which still carry the admin token.
+    Admin,
+}
+
+impl AsRef<str> for Role {
+    fn as_ref(&self) -> &str {
+        match self {
+            Role::Read => "read",
+            Role::Write => "write",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// The `[log]` section of the config file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// the filter of logs in the syntax of `RUST_LOG`, like `warn,app::request=info`.
+    /// The `--log-level` option and the `RUST_LOG` env var take precedence over it.
+    pub level: Option<String>,
+    /// besides the console, also write logs into a file.
+    pub file: Option<LogFileConfig>,
+}
+
+/// The `[log.file]` section of the config file.
+///
+/// When neither `max_size` nor `rotate_every_secs` is set, the file is never rotated.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogFileConfig {
+    /// the active log file, the rotated files are `{path}.0`(the newest), `{path}.1`, and so on.
+    pub path: PathBuf,
+    /// rotate the file before it grows beyond this size, in bytes.
+    pub max_size: Option<u64>,
+    /// rotate the file every this many seconds since the server starts.
+    pub rotate_every_secs: Option<u64>,
+    /// how many rotated files to keep, the elder ones are deleted.
+    #[serde(default = "LogFileConfig::default_keep")]
+    pub keep: u32,
+}
+
+impl LogFileConfig {
+    fn default_keep() -> u32 {
+        7
+    }
+}
+
+/// The error of loading the config file, or building the config from it.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// failed to read the config file.
+    #[error("Failed to read the config file {}: {io_error}", path.display())]
+    Read {
+        /// the config file.
+        path: PathBuf,
+        /// the inner error.
+        #[source]
+        io_error: std::io::Error,
+    },
+    /// the config file isn't a valid TOML, or has unknown fields.
+    #[error("Malformed config file: {0}")]
+    Parse(#[from] toml::de::Error),
+    /// the config is well-formed but cannot be applied, like the log file cannot be opened.
+    #[error("Bad config: {reason}")]
+    Invalid {
+        /// why it cannot be applied.
+        reason: String,
+    },
+}
+
+impl ServerConfig {
+    /// parse the config from TOML text.
+    pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// load the config from a TOML file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|io_error| ConfigError::Read {
+            path: path.to_owned(),
+            io_error,
+        })?;
+        Self::from_toml(&text)
+    }
+}
@@ -1,11 +1,18 @@
-use failure::Fail;
+use thiserror::Error as ThisError;
 
 /// the error type of contract.
-#[derive(Debug, Fail)]
+#[derive(Debug, ThisError)]
 pub enum Error {
     /// the contract data from TCP is malformed.
-    #[fail(display = "Failed to parse the format of binary data.")]
+    #[error("Failed to parse the format of binary data.")]
     MalformedBinary,
+    /// the contract data from TCP is well-formed, but not a message of the contract,
+    /// like a request of an unknown operation, or without a field of it.
+    #[error("Bad message: {reason}")]
+    BadMessage {
+        /// why it isn't a message.
+        reason: String,
+    },
 }
 /// the `Result` type of our contract.
 pub type Result<T> = std::result::Result<T, Error>;
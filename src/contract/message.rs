@@ -1,190 +1,392 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
 use std::io::Read;
 
+use bytes::Bytes;
 use log::error;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
+use serde::de::DeserializeOwned;
+use serde_json::error::Category;
 
-use super::{Error::MalformedBinary, Result};
+use crate::engines::changes::LogPosition;
+use crate::engines::engine::{BatchWrite, Preload, SetCondition};
+use crate::engines::pattern::ListOptions;
+use crate::engines::typed::TypedValue;
 
-/// the struct of the contract based on TCP to connect with the KvServer.
-/// It is simply json.
+use super::{Error::{BadMessage, MalformedBinary}, Result};
+
+/// the files of `Request::Restore` by their names, each in base64.
+mod files_in_base64 {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(files: &BTreeMap<String, Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(files.iter().map(|(name, content)| (name, base64::encode(content.as_slice()))))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, Vec<u8>>, D::Error> {
+        let encoded = BTreeMap::<String, String>::deserialize(deserializer)?;
+        encoded
+            .into_iter()
+            .map(|(name, content)| match base64::decode(content.as_str()) {
+                Ok(content) => Ok((name, content)),
+                Err(err) => Err(D::Error::custom(format!("the file {} isn't in base64: {}", name, err))),
+            })
+            .collect()
+    }
+}
+
+/// the messages of the contract based on TCP to connect with the KvServer, which are `Request` and `Response`.
+/// It is simply json, tagged by the `op` of the requests and the `status` of the responses.
 /// I use json for this just for keep it simple(!),
 /// hence I can reuse the escape implement in json.
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
-pub struct KvContractMessage {
-    /// the operate type, see the constant below.
-    pub operate_type: u8,
-    /// the parameter of the message.
-    pub param: HashMap<String, String>,
+///
+/// ```rust
+/// # use std::io::Cursor;
+/// # use kvs::contract::{KvContractMessage, Request};
+/// let request = Request::Get { key: "hello".to_owned() };
+/// let bin = request.clone().into_binary();
+/// assert_eq!(bin, br#"{"op":"get","key":"hello"}"#.to_vec());
+/// assert_eq!(Request::parse(Cursor::new(bin)).unwrap(), request);
+/// ```
+pub trait KvContractMessage: Serialize + DeserializeOwned + Debug + Sized {
+    /// whether the message is followed by a streamed body, like the value of a streamed set request.
+    fn has_body(&self) -> bool;
+
+    /// parse an contact message from a stream.
+    ///
+    /// # Error
+    ///
+    /// if the binary format isn't right, throw `MalformedBinary`,
+    /// and if it isn't a message of this type, throw `BadMessage`.
+    fn parse(mut raw: impl Read) -> Result<Self> {
+        let message = Self::parse_head(&mut raw)?;
+        Self::parse_end(raw)?;
+        Ok(message)
+    }
+
+    /// parse an contact message from the head of a stream, leaving the rest unread,
+    /// which is the streamed body of the message when it `has_body`, or should be empty, see `parse_end`.
+    ///
+    /// # Error
+    ///
+    /// if the binary format isn't right, throw `MalformedBinary`,
+    /// and if it isn't a message of this type, throw `BadMessage`.
+    fn parse_head(raw: impl Read) -> Result<Self> {
+        let parsed = serde_json::Deserializer::from_reader(raw).into_iter::<Self>().next();
+        match parsed {
+            Some(Ok(message)) => Ok(message),
+            // well-formed json, but not a message, like an unknown `op` or a missing field.
+            Some(Err(err)) if err.classify() == Category::Data => {
+                error!(target: "app::error", "failed to parse request, bad message: {}.", err);
+                Err(BadMessage { reason: err.to_string() })
+            }
+            Some(Err(err)) => {
+                error!(target: "app::error", "failed to parse request, exception: {}.", err);
+                Err(MalformedBinary)
+            }
+            None => {
+                error!(target: "app::error", "failed to parse request, the stream is empty.");
+                Err(MalformedBinary)
+            }
+        }
+    }
+
+    /// make sure that nothing but whitespaces follows the message in the stream.
+    ///
+    /// # Error
+    ///
+    /// if anything else follows, throw `MalformedBinary`.
+    fn parse_end(mut raw: impl Read) -> Result<()> {
+        let mut rest = Vec::new();
+        match raw.read_to_end(&mut rest) {
+            Ok(_) if rest.iter().all(u8::is_ascii_whitespace) => Ok(()),
+            Ok(_) => {
+                error!(target: "app::error", "failed to parse request, trailing {} bytes.", rest.len());
+                Err(MalformedBinary)
+            }
+            Err(err) => {
+                error!(target: "app::error", "failed to parse request, exception: {}.", err);
+                Err(MalformedBinary)
+            }
+        }
+    }
+
+    /// serialize the message into binary from.
+    /// Even now it's just simply JSON text(!).
+    fn into_binary(self) -> Vec<u8> {
+        serde_json::to_vec(&self).expect("unable to serialize self into json.")
+    }
 }
 
-/// the request view of a message.
-#[derive(Eq, PartialEq, Debug)]
-pub enum Request<'a> {
-    /// get request view.
+/// the requests of the contract.
+#[derive(Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    /// get request.
     Get {
         /// the key to get.
-        key: &'a str,
+        key: String,
     },
-    /// set request view.
+    /// set request.
     Set {
         /// the key to set.
-        key: &'a str,
+        key: String,
+        /// the value to set.
+        value: String,
+    },
+    /// conditional set request, whose response content is whether it's written.
+    SetIf {
+        /// the key to set.
+        key: String,
+        /// the value to set.
+        value: String,
+        /// when to write.
+        condition: SetCondition,
+    },
+    /// get request with when the value is last modified and its metadata,
+    /// whose response content is the `MetaValue` in JSON.
+    GetWithMeta {
+        /// the key to get.
+        key: String,
+    },
+    /// conditional set request on the modified time of the value, like `If-Unmodified-Since` of HTTP,
+    /// whose response content is whether it's written, see `KvsEngine::set_if_unmodified`.
+    SetIfUnmodified {
+        /// the key to set.
+        key: String,
         /// the value to set.
-        value: &'a str,
+        value: String,
+        /// the milliseconds since the unix epoch when the value is last modified, the `modified_ms` of a `MetaValue`.
+        modified_ms: u64,
     },
-    /// rm request view.
+    /// rm request.
     Remove {
         /// the key to remove.
-        key: &'a str,
+        key: String,
+    },
+    /// undelete request.
+    Undelete {
+        /// the key to restore.
+        key: String,
+    },
+    /// append request.
+    Append {
+        /// the key to append to.
+        key: String,
+        /// the string to append.
+        suffix: String,
+    },
+    /// typed get request, whose response content is the value in JSON, tagged by its type.
+    GetTyped {
+        /// the key to get.
+        key: String,
+    },
+    /// typed set request.
+    SetTyped {
+        /// the key to set.
+        key: String,
+        /// the value to set, with its type.
+        value: TypedValue,
+    },
+    /// incr request, whose response content is the new integer.
+    Incr {
+        /// the key to increase.
+        key: String,
+        /// how much to add, negative to decrease.
+        delta: i64,
+    },
+    /// rename request.
+    Rename {
+        /// the key to move the value from.
+        from: String,
+        /// the key to move the value to.
+        to: String,
+    },
+    /// copy request.
+    Copy {
+        /// the key to copy the value from.
+        from: String,
+        /// the key to copy the value to.
+        to: String,
+    },
+    /// remove-prefix request, whose response content is how many keys are removed.
+    RemovePrefix {
+        /// the prefix of the keys to remove.
+        prefix: String,
+    },
+    /// lease request, whose response content is the lease taken, or no content if the key is held.
+    AcquireLease {
+        /// the key to lease.
+        key: String,
+        /// how long the lease lasts, in milliseconds.
+        ttl_ms: u64,
+    },
+    /// release request, whose response content is whether it's released.
+    ReleaseLease {
+        /// the key leased.
+        key: String,
+        /// the token of the lease.
+        token: String,
+    },
+    /// stats request.
+    Stats,
+    /// preload request, whose response content is how many values are loaded, see `KvsEngine::preload`.
+    Preload {
+        /// the admin token of the server.
+        token: Option<String>,
+        /// the keys to load.
+        target: Preload,
+    },
+    /// keys request.
+    Keys {
+        /// the glob pattern of the keys to list.
+        pattern: String,
+        /// the order and the bounds of the listing, all the keys in ascending order when absent.
+        #[serde(default)]
+        options: ListOptions,
+    },
+    /// streamed set request, the value follows the message, see `KvContractMessage::parse_head`.
+    SetStream {
+        /// the key to set.
+        key: String,
+    },
+    /// streamed get request.
+    GetStream {
+        /// the key to get.
+        key: String,
+    },
+    /// changes request, the changes are streamed after the response as JSON lines.
+    Changes {
+        /// where to read the changes from, the beginning when absent.
+        #[serde(default)]
+        since: LogPosition,
+        /// whether to wait for the new changes instead of ending at the last one.
+        #[serde(default)]
+        follow: bool,
+    },
+    /// log end request, the position to follow only the changes after now from, in JSON.
+    LogEnd,
+    /// restore request.
+    Restore {
+        /// the admin token of the server.
+        token: Option<String>,
+        /// the files of the backup by their names, in base64 since the data files may be binary.
+        #[serde(with = "files_in_base64")]
+        archive: BTreeMap<String, Vec<u8>>,
+    },
+    /// dry-run request, whose response content is the `DryRunReport` in JSON of the keys the request would touch,
+    /// like `rm` or `rm-prefix`, without modifying anything.
+    DryRun {
+        /// the request to run dry.
+        request: Box<Request>,
+    },
+    /// batch request, whose response is a `Response::Batch` of the responses of `requests` in the same order.
+    /// Each of them is handled on its own, so a missing key or a failed write fails its own response only,
+    /// and the others aren't rolled back.
+    /// The requests streaming, the admin requests and the ones wrapping others cannot be batched,
+    /// their responses are `BadRequest` errors.
+    Batch {
+        /// the requests to handle in order.
+        requests: Vec<Request>,
+    },
+    /// transaction request, applying `writes` by one write, so that all or none of them are written,
+    /// like `MULTI` and `EXEC` of Redis, see `KvsEngine::write_batch`.
+    Transaction {
+        /// the writes to apply in order.
+        writes: Vec<BatchWrite>,
+    },
+    /// run a script on the server atomically, like `EVAL` of Redis, whose response content is what it returns,
+    /// see `ScriptRunner`. It's refused by the servers without a script runner.
+    Eval {
+        /// the script, in the language of the script runner of the server.
+        script: String,
+        /// the keys the script touches, it may touch no others.
+        keys: Vec<String>,
+        /// the arguments of the script.
+        args: Vec<String>,
+    },
+    /// another request to answer within `timeout_ms` since the server accepts its connection,
+    /// or else the server skips it or stops it, and responds with a timeout error, see `KvsClient::with_timeout`.
+    /// It wraps the others, including `Auth`, since it's unwrapped before the interceptors.
+    Deadline {
+        /// how long the client waits for the response, in milliseconds.
+        timeout_ms: u64,
+        /// the request to answer in time.
+        request: Box<Request>,
+    },
+    /// another request sent by a user, checked by the `Acl` of the server.
+    Auth {
+        /// the name of the user.
+        user: String,
+        /// the secret of the user.
+        secret: String,
+        /// the request of the user.
+        request: Box<Request>,
     },
 }
 
-/// the response view of a message.
-#[derive(Eq, PartialEq, Debug)]
-pub enum Response<'a> {
+/// the responses of the contract.
+#[derive(Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Response {
     /// response with no content.
     NoContent,
     /// response with some message.
     Content {
         /// content of the message.
-        content: &'a str,
+        content: String,
+    },
+    /// response with the content shared from the engine, like the value of a `Request::Get`,
+    /// which is written into the message as it is, without copying it into a `String`.
+    ///
+    /// It's sent as `Content`, so the peers only parse `Content` from it.
+    #[serde(rename = "content", skip_deserializing)]
+    SharedContent {
+        /// content of the message, in UTF-8.
+        #[serde(serialize_with = "serialize_shared")]
+        content: Bytes,
+    },
+    /// response with the content streamed after the message, see `KvContractMessage::parse_head`.
+    Stream,
+    /// response of a `Request::Batch`, the responses of its requests in the same order.
+    Batch {
+        /// the response of each request.
+        responses: Vec<Response>,
     },
     /// response with error.
     Error {
+        /// the numeric code of this error, see `ServerError::code`.
+        code: u16,
+        /// whether the request may succeed when retried later.
+        #[serde(default)]
+        retryable: bool,
         /// reason of this error.
-        reason: &'a str,
+        reason: String,
     },
 }
 
-impl KvContractMessage {
-    pub(crate) const GET: u8 = 0;
-    pub(crate) const PUT: u8 = 1;
-    pub(crate) const REMOVE: u8 = 2;
-
-    pub(crate) const RESPONSE_WITH_CONTENT: u8 = 253;
-    pub(crate) const RESPONSE_NO_CONTENT: u8 = 254;
-    pub(crate) const RESPONSE_ERR: u8 = 255;
-}
-
-impl KvContractMessage {
-    /// create an message that represents an get request.
-    pub fn get(key: String) -> Self {
-        KvContractMessage {
-            operate_type: Self::GET,
-            param: vec![("key".to_owned(), key)].into_iter().collect(),
-        }
-    }
-
-    /// create an message that represents an set request.
-    pub fn put(key: String, value: String) -> Self {
-        KvContractMessage {
-            operate_type: Self::PUT,
-            param: vec![("key".to_owned(), key), ("value".to_owned(), value)]
-                .into_iter()
-                .collect(),
-        }
-    }
-
-    /// create an message that represents an remove request.
-    pub fn remove(key: String) -> Self {
-        KvContractMessage {
-            operate_type: Self::REMOVE,
-            param: vec![("key".to_owned(), key)].into_iter().collect(),
-        }
-    }
-
-    /// create an ok response message, with no content.
-    pub fn response_no_content() -> Self {
-        KvContractMessage {
-            operate_type: Self::RESPONSE_NO_CONTENT,
-            param: HashMap::new(),
-        }
-    }
-
-    /// create an error response message.
-    pub fn response_err(reason: String) -> Self {
-        KvContractMessage {
-            operate_type: Self::RESPONSE_ERR,
-            param: vec![("reason".to_owned(), reason)].into_iter().collect(),
-        }
-    }
-
-    /// create a success response with some content.
-    pub fn response_content(content: String) -> Self {
-        KvContractMessage {
-            operate_type: Self::RESPONSE_WITH_CONTENT,
-            param: vec![("content".to_owned(), content)].into_iter().collect(),
-        }
-    }
-
-    /// parse an contact message from a stream.
-    ///
-    /// # Error
-    ///
-    /// if the binary format isn't right, throw `MalformedBinary`.
-    pub fn parse(mut raw: (impl Read)) -> Result<Self> {
-        serde_json::from_reader(&mut raw).map_err(|err| {
-            error!(target: "app::error", "failed to parse request, exception: {}.", err);
-            MalformedBinary
-        })
-    }
-
-    /// serialize the message into binary from.
-    /// Even now it's just simply JSON text(!).
-    pub fn into_binary(self) -> Vec<u8> {
-        let serialized = serde_json::to_string(&self).expect("unable to serialize self into json.");
-        serialized.into_bytes()
+/// write the content of `Response::SharedContent` as the string of `Response::Content` without copying it.
+fn serialize_shared<S: Serializer>(content: &Bytes, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    match std::str::from_utf8(content) {
+        Ok(content) => serializer.serialize_str(content),
+        Err(_) => serializer.serialize_str(String::from_utf8_lossy(content).as_ref()),
     }
+}
 
-    /// match the raw message as `Request`.
-    ///
-    /// ```rust
-    /// # use kvs::contract::{KvContractMessage, Request};
-    /// let message = KvContractMessage::get("hello".to_owned());
-    /// assert_eq!(message.to_request(), Some(Request::Get { key: "hello" }));
-    /// ```
-    ///
-    /// # Error
-    ///
-    /// When failed to parse it as an request message, return `None`.
-    pub fn to_request(&self) -> Option<Request> {
-        match self.operate_type {
-            Self::PUT => self.param.get("key").and_then(|key| {
-                self.param.get("value").map(|value| Request::Set {
-                    key: key.as_str(),
-                    value: value.as_str(),
-                })
-            }),
-            Self::GET => self
-                .param
-                .get("key")
-                .map(|key| Request::Get { key: key.as_str() }),
-            Self::REMOVE => self
-                .param
-                .get("key")
-                .map(|key| Request::Remove { key: key.as_str() }),
-            _ => None,
+impl KvContractMessage for Request {
+    fn has_body(&self) -> bool {
+        match self {
+            Request::Auth { request, .. } | Request::Deadline { request, .. } => request.has_body(),
+            request => matches!(request, Request::SetStream { .. }),
         }
     }
+}
 
-    /// match the raw message as `Response`.
-    ///
-    /// # Error
-    ///
-    /// When failed to parse it as an response message, return `None`.
-    pub fn to_response(&self) -> Option<Response> {
-        match self.operate_type {
-            Self::RESPONSE_NO_CONTENT => Some(Response::NoContent),
-            Self::RESPONSE_WITH_CONTENT => {
-                self.param.get("content").map(|content| Response::Content {
-                    content: content.as_str(),
-                })
-            }
-            Self::RESPONSE_ERR => self.param.get("reason").map(|reason| Response::Error {
-                reason: reason.as_str(),
-            }),
-            _ => None,
-        }
+impl KvContractMessage for Response {
+    fn has_body(&self) -> bool {
+        matches!(self, Response::Stream)
     }
 }
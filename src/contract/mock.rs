@@ -0,0 +1,127 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Default)]
+struct PipeState {
+    buf: VecDeque<u8>,
+    closed: bool,
+}
+
+/// one direction of a duplex stream.
+#[derive(Clone, Default)]
+struct Pipe(Arc<(Mutex<PipeState>, Condvar)>);
+
+impl Pipe {
+    fn state(&self) -> MutexGuard<'_, PipeState> {
+        (self.0).0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn close(&self) {
+        self.state().closed = true;
+        (self.0).1.notify_all();
+    }
+}
+
+/// An end of an in-memory duplex stream, made by `duplex`.
+///
+/// Like a `TcpStream`, what's written to an end can be read from the other end,
+/// and the reads block until there is data, or the other end shuts down its writing.
+///
+/// # Example
+/// ```rust
+/// # use std::io::{Read, Write};
+/// # use kvs::contract::mock::duplex;
+/// let (mut client, mut server) = duplex();
+/// client.write_all(b"hello").unwrap();
+/// client.shutdown_write();
+/// let mut received = String::new();
+/// server.read_to_string(&mut received).unwrap();
+/// assert_eq!(received, "hello");
+/// ```
+pub struct MockStream {
+    incoming: Pipe,
+    outgoing: Pipe,
+    chunk_size: Option<usize>,
+    delay: Option<Duration>,
+}
+
+/// create a pair of connected `MockStream`s.
+pub fn duplex() -> (MockStream, MockStream) {
+    let (a, b) = (Pipe::default(), Pipe::default());
+    let end = |incoming: &Pipe, outgoing: &Pipe| MockStream {
+        incoming: incoming.clone(),
+        outgoing: outgoing.clone(),
+        chunk_size: None,
+        delay: None,
+    };
+    (end(&a, &b), end(&b, &a))
+}
+
+impl MockStream {
+    /// deliver at most `size` bytes by each read of this end, to test the readers that assume a whole message per read.
+    pub fn chunked(mut self, size: usize) -> Self {
+        assert!(size > 0, "the chunk size must be positive.");
+        self.chunk_size = Some(size);
+        self
+    }
+
+    /// sleep `delay` before each read of this end, like a slow network.
+    pub fn delayed(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// like `TcpStream::shutdown(Shutdown::Write)`, the other end reads EOF after the data written.
+    pub fn shutdown_write(&self) {
+        self.outgoing.close();
+    }
+
+    /// the bytes written by the other end and not read yet.
+    pub fn pending(&self) -> Vec<u8> {
+        self.incoming.state().buf.iter().cloned().collect()
+    }
+}
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(delay) = self.delay {
+            thread::sleep(delay);
+        }
+        let state = self.incoming.state();
+        let mut state = (self.incoming.0)
+            .1
+            .wait_while(state, |state| state.buf.is_empty() && !state.closed)
+            .unwrap_or_else(PoisonError::into_inner);
+        let limit = self.chunk_size.unwrap_or(buf.len()).min(buf.len());
+        let n = limit.min(state.buf.len());
+        for (dst, src) in buf.iter_mut().zip(state.buf.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.outgoing.state();
+        if state.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "the stream is shut down."));
+        }
+        state.buf.extend(buf);
+        (self.outgoing.0).1.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MockStream {
+    fn drop(&mut self) {
+        self.outgoing.close();
+    }
+}
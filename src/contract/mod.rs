@@ -3,3 +3,5 @@ pub use message::{KvContractMessage, Request, Response};
 
 mod errors;
 mod message;
+/// in-memory streams, to test the contract and the server without TCP.
+pub mod mock;
@@ -0,0 +1,280 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::engines::kvs::parse_gen;
+use crate::engines::storage::{DataFile, ReadFile, Storage};
+
+/// where `ArchiveStorage` uploads the sealed data files, like a bucket of an object store.
+///
+/// The objects are named by the files, so an object store holds the archive of one data directory.
+pub trait ObjectStore: Debug + Send + Sync {
+    /// upload the object `name` from `content`, replacing the one of the same name.
+    fn put(&self, name: &str, content: &mut dyn Read) -> io::Result<()>;
+    /// download the object `name`.
+    fn get(&self, name: &str) -> io::Result<Box<dyn Read + Send>>;
+    /// delete the object `name`.
+    fn delete(&self, name: &str) -> io::Result<()>;
+    /// the length of the object `name` in bytes.
+    fn size(&self, name: &str) -> io::Result<u64>;
+    /// the names of all the objects.
+    fn list(&self) -> io::Result<Vec<String>>;
+}
+
+/// the object store of the files in a directory, like a mounted bucket.
+#[derive(Debug, Clone)]
+pub struct DirObjectStore {
+    dir: PathBuf,
+}
+
+impl DirObjectStore {
+    /// the object store in `dir`, created if not exists.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(DirObjectStore { dir: dir.as_ref().to_owned() })
+    }
+}
+
+impl ObjectStore for DirObjectStore {
+    fn put(&self, name: &str, content: &mut dyn Read) -> io::Result<()> {
+        // uploaded aside, so that the readers never see half an object.
+        let temp = self.dir.join(format!("{}.uploading", name));
+        let mut file = File::create(&temp)?;
+        io::copy(content, &mut file)?;
+        file.sync_all()?;
+        fs::rename(&temp, self.dir.join(name))
+    }
+
+    fn get(&self, name: &str) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(File::open(self.dir.join(name))?))
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.dir.join(name))
+    }
+
+    fn size(&self, name: &str) -> io::Result<u64> {
+        Ok(fs::metadata(self.dir.join(name))?.len())
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if !name.ends_with(".uploading") {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// the storage of a data directory that moves its cold data files to an `ObjectStore`,
+/// and downloads them back to the local storage when they're read again, which caches them until the next archival.
+///
+/// Only the sealed data files are archived, the data file being written, the blobs and the checkpoints stay local.
+/// A data file removed by a compaction is deleted from the object store too.
+///
+/// # Example
+/// ```no_run
+/// # use std::sync::Arc;
+/// # use std::time::Duration;
+/// # use kvs::KvStore;
+/// # use kvs::engines::archive::{ArchiveStorage, DirObjectStore};
+/// # use kvs::engines::storage::FsStorage;
+/// # fn main() -> kvs::Result<()> {
+/// let dir = std::env::current_dir()?;
+/// let bucket = Arc::new(DirObjectStore::open("/mnt/bucket")?);
+/// let storage = Arc::new(ArchiveStorage::new(&dir, Arc::new(FsStorage), bucket));
+/// let store = KvStore::open_with_storage(&dir, storage.clone())?;
+/// // the data files unread for an hour are moved to the bucket.
+/// storage.archive_cold(Duration::from_secs(3600))?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ArchiveStorage {
+    dir: PathBuf,
+    local: Arc<dyn Storage>,
+    archive: Arc<dyn ObjectStore>,
+    /// when the local data files are created or read last, the ones not here are cold.
+    touched: Mutex<HashMap<String, Instant>>,
+    /// held while downloading, so that a data file is downloaded once by the concurrent reads.
+    fetching: Mutex<()>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl ArchiveStorage {
+    /// the storage of the data directory `dir`, keeping the files in `local` and archiving them to `archive`.
+    pub fn new(dir: impl AsRef<Path>, local: Arc<dyn Storage>, archive: Arc<dyn ObjectStore>) -> Self {
+        ArchiveStorage {
+            dir: dir.as_ref().to_owned(),
+            local,
+            archive,
+            touched: Mutex::new(HashMap::new()),
+            fetching: Mutex::new(()),
+        }
+    }
+
+    /// the name of the data file at `path` if it can be archived, i.e. it's a data file of the directory.
+    fn archivable(&self, path: &Path) -> Option<String> {
+        if path.parent() != Some(self.dir.as_path()) {
+            return None;
+        }
+        let name = path.file_name()?.to_str()?;
+        parse_gen(name).map(|_| name.to_owned())
+    }
+
+    fn touch(&self, name: String) {
+        lock(&self.touched).insert(name, Instant::now());
+    }
+
+    /// download the data file `name` to `path` if it's only in the archive.
+    fn fetch(&self, name: &str, path: &Path) -> io::Result<()> {
+        let _fetching = lock(&self.fetching);
+        match self.local.size(path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            found => return found.map(|_| ()),
+        }
+        let mut object = self.archive.get(name)?;
+        let temp = self.dir.join(format!("{}.fetching", name));
+        let mut file = self.local.create(&temp)?;
+        io::copy(&mut object, &mut file)?;
+        file.sync()?;
+        self.local.rename(&temp, path)?;
+        info!("fetched the archived data file {}.", name);
+        Ok(())
+    }
+
+    /// the names of the archived data files.
+    fn archived(&self) -> io::Result<Vec<String>> {
+        Ok(self.archive.list()?.into_iter().filter(|name| parse_gen(name).is_some()).collect())
+    }
+
+    /// upload the sealed data files not read since `idle` ago, then remove them from the local storage.
+    /// The data file of the latest epoch is being written, so it's never archived.
+    ///
+    /// Returns the names of the data files archived.
+    pub fn archive_cold(&self, idle: Duration) -> io::Result<Vec<String>> {
+        let local = self.local.list(&self.dir)?;
+        let latest = local.iter().chain(self.archived()?.iter()).filter_map(|name| parse_gen(name)).max();
+        let mut archived = Vec::new();
+        for name in local {
+            let sealed = parse_gen(name.as_str()).is_some_and(|epoch| Some(epoch) < latest);
+            let hot = lock(&self.touched).get(&name).is_some_and(|touched| touched.elapsed() < idle);
+            if !sealed || hot {
+                continue;
+            }
+            let path = self.dir.join(name.as_str());
+            let mut file = match self.local.open_read(&path) {
+                Ok(file) => file,
+                // removed by a compaction meanwhile.
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            self.archive.put(name.as_str(), &mut file)?;
+            match self.local.remove(&path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    // the compaction removing it missed the object just uploaded.
+                    warn!("the data file {} is removed while archiving it.", name);
+                    self.archive.delete(name.as_str()).or_else(|err| match err.kind() {
+                        io::ErrorKind::NotFound => Ok(()),
+                        _ => Err(err),
+                    })?;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+            lock(&self.touched).remove(&name);
+            archived.push(name);
+        }
+        if !archived.is_empty() {
+            self.local.sync_dir(&self.dir)?;
+            info!("archived the cold data files {:?}.", archived);
+        }
+        Ok(archived)
+    }
+}
+
+impl Storage for ArchiveStorage {
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn DataFile>> {
+        if let Some(name) = self.archivable(path) {
+            self.fetch(name.as_str(), path).or_else(|err| match err.kind() {
+                io::ErrorKind::NotFound => Ok(()),
+                _ => Err(err),
+            })?;
+            self.touch(name);
+        }
+        self.local.open_append(path)
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        if let Some(name) = self.archivable(path) {
+            self.fetch(name.as_str(), path)?;
+            self.touch(name);
+        }
+        self.local.open_read(path)
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn DataFile>> {
+        self.local.create(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.local.rename(from, to)?;
+        if let Some(name) = self.archivable(to) {
+            self.touch(name);
+        }
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let name = match self.archivable(path) {
+            Some(name) => name,
+            None => return self.local.remove(path),
+        };
+        lock(&self.touched).remove(&name);
+        let removed = self.local.remove(path);
+        let deleted = self.archive.delete(name.as_str());
+        match (removed, deleted) {
+            (Err(err), _) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            // either copy removed is enough, and neither found is the `NotFound` of the local one.
+            (removed, Err(err)) if err.kind() == io::ErrorKind::NotFound => removed,
+            (_, deleted) => deleted,
+        }
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        match (self.local.size(path), self.archivable(path)) {
+            (Err(err), Some(name)) if err.kind() == io::ErrorKind::NotFound => self.archive.size(name.as_str()),
+            (size, _) => size,
+        }
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        if dir != self.dir {
+            return self.local.list(dir);
+        }
+        let mut names: BTreeSet<String> = self.local.list(dir)?.into_iter().collect();
+        names.extend(self.archived()?);
+        Ok(names.into_iter().collect())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.local.sync_dir(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.local.create_dir_all(dir)
+    }
+}
@@ -0,0 +1,250 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::common::SeekExt;
+use crate::engines::codec::RecordCodec;
+use crate::engines::kvs::{CompactionGate, filename_of, KvCommand, KvStore};
+use crate::engines::storage::{ReadFile, Storage};
+
+use super::errors::{ErrorContext, KvError, Result, ResultExt};
+
+/// A place in the data files of a `KvStore`, the changes after it are read by `KvStore::changes`.
+///
+/// The default position is the beginning of the data files.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub struct LogPosition {
+    /// the epoch of the data file.
+    pub epoch: u64,
+    /// the offset in the data file.
+    pub offset: usize,
+}
+
+impl Display for LogPosition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.epoch, self.offset)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Error)]
+#[error("Malformed log position: {0}, it should be like `3:1024`")]
+/// Throws when we cannot parse the command line or the request to a log position.
+pub struct MalformedLogPosition(String);
+
+impl FromStr for LogPosition {
+    type Err = MalformedLogPosition;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let malformed = || MalformedLogPosition(s.to_owned());
+        let mut parts = s.splitn(2, ':');
+        let epoch = parts.next().and_then(|epoch| epoch.parse().ok()).ok_or_else(malformed)?;
+        let offset = parts.next().and_then(|offset| offset.parse().ok()).ok_or_else(malformed)?;
+        Ok(LogPosition { epoch, offset })
+    }
+}
+
+/// A write read from the data files, see `KvStore::changes`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    /// the key written.
+    pub key: String,
+    /// the value set, `None` for a removal.
+    pub value: Option<String>,
+    /// the user metadata set with the value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<String>,
+    /// the milliseconds since the unix epoch when it's written, if the record tracks it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<u64>,
+    /// where the record ends, to read the changes after it from.
+    pub next: LogPosition,
+}
+
+/// The changes in the data files of a `KvStore` from a `LogPosition`, in the order they're written,
+/// made by `KvStore::changes`.
+///
+/// The records rewritten by the compactions aren't changes, so they're skipped,
+/// unless it reads from the beginning, where the eldest data file may be the output of a compaction,
+/// then they're the changes that make up the data before it.
+pub struct Changes {
+    storage: Arc<dyn Storage>,
+    path: PathBuf,
+    current_epoch: Arc<AtomicU64>,
+    tail_epoch: Arc<AtomicU64>,
+    compacting: Arc<CompactionGate>,
+    codec: Arc<dyn RecordCodec>,
+    position: LogPosition,
+    file: Option<BufReader<Box<dyn ReadFile>>>,
+    /// the record being written, read partially.
+    partial: Vec<u8>,
+    follow: bool,
+}
+
+impl Changes {
+    /// how long it waits before looking for the new records again, when it follows the writes.
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    pub(crate) fn new(
+        storage: Arc<dyn Storage>,
+        path: PathBuf,
+        current_epoch: Arc<AtomicU64>,
+        tail_epoch: Arc<AtomicU64>,
+        compacting: Arc<CompactionGate>,
+        codec: Arc<dyn RecordCodec>,
+        since: LogPosition,
+    ) -> Self {
+        Changes {
+            storage,
+            path,
+            current_epoch,
+            tail_epoch,
+            compacting,
+            codec,
+            position: since,
+            file: None,
+            partial: Vec::new(),
+            follow: false,
+        }
+    }
+
+    /// wait for the new writes at the end of the data files, instead of ending there.
+    pub fn follow(mut self) -> Self {
+        self.follow = true;
+        self
+    }
+
+    /// where the next change is read from.
+    pub fn position(&self) -> LogPosition {
+        self.position
+    }
+
+    fn context(&self) -> impl FnOnce() -> ErrorContext {
+        let file_name = filename_of(self.position.epoch);
+        let offset = self.position.offset;
+        move || ErrorContext {
+            operation: "changes",
+            file_name,
+            offset,
+            key: None,
+        }
+    }
+
+    /// open the data file of the position, returns `false` if it doesn't exist.
+    fn open(&mut self) -> Result<bool> {
+        let file = match self.storage.open_read(&self.path.join(filename_of(self.position.epoch))) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err).with_context(self.context()),
+        };
+        let mut file = BufReader::new(file);
+        file.seek_to(self.position.offset).with_context(self.context())?;
+        self.file = Some(file);
+        Ok(true)
+    }
+
+    /// read the next whole record from the open data file, if any.
+    fn read_record(&mut self) -> Result<Option<Change>> {
+        let context = self.context();
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        if !self.codec.read_record(file, &mut self.partial).with_context(context)? {
+            return Ok(None);
+        }
+        let command = self.codec.decode(self.partial.as_slice()).with_context(self.context())?;
+        self.position.offset += self.partial.len();
+        self.partial.clear();
+        self.change_of(command).map(Some)
+    }
+
+    fn change_of(&self, command: KvCommand) -> Result<Change> {
+        let next = self.position;
+        Ok(match command {
+            KvCommand::Put { key, value, modified, meta, blob, .. } => {
+                let value = match blob {
+                    // the blob is dropped by a compaction if the key is written again before it.
+                    Some(blob) => self.storage.read_to_string(&self.path.join(blob.as_str())).with_context(|| ErrorContext {
+                        operation: "changes",
+                        file_name: blob.clone(),
+                        offset: 0,
+                        key: Some(key.clone()),
+                    })?,
+                    None => value,
+                };
+                Change { key, value: Some(value), meta, modified, next }
+            }
+            KvCommand::Rm { key, .. } => Change { key, value: None, meta: None, modified: None, next },
+        })
+    }
+
+    /// move on to the data file written after the current one.
+    ///
+    /// # Error
+    ///
+    /// If a compaction drops the data files it hasn't read, it throws,
+    /// then the changes should be read from the beginning again.
+    fn advance(&mut self) -> Result<()> {
+        // wait for the compaction or the ingestion publishing its data file,
+        // so that the output of a compaction is told by the tail epoch it moves.
+        self.compacting.wait_idle();
+        let from = self.position.epoch;
+        let tail = self.tail_epoch.load(Ordering::SeqCst);
+        if from + 1 < tail {
+            return Err(KvError::Other {
+                reason: format!("the changes after {} are dropped by a compaction", self.position),
+            });
+        }
+        let next = KvStore::enumerate_epoch_files(self.storage.as_ref(), &self.path)
+            .map(|(_, epoch)| epoch)
+            .filter(|epoch| *epoch > from && *epoch != tail)
+            .min()
+            .unwrap_or_else(|| self.current_epoch.load(Ordering::SeqCst));
+        self.position = LogPosition { epoch: next, offset: 0 };
+        self.file = None;
+        self.partial.clear();
+        Ok(())
+    }
+
+    fn next_change(&mut self) -> Result<Option<Change>> {
+        loop {
+            // the writes never go back to a file once they move on from it.
+            let sealed = self.position.epoch < self.current_epoch.load(Ordering::SeqCst);
+            if self.file.is_some() || self.open()? {
+                if let Some(change) = self.read_record()? {
+                    return Ok(Some(change));
+                }
+            }
+            if sealed {
+                // the last records may be written right before the writes move on, so they're read again above.
+                if self.partial.is_empty() {
+                    self.advance()?;
+                    continue;
+                }
+                return Err(KvError::Other {
+                    reason: format!("torn record at {}", self.position),
+                });
+            }
+            if !self.follow {
+                return Ok(None);
+            }
+            thread::sleep(Self::POLL_INTERVAL);
+        }
+    }
+}
+
+impl Iterator for Changes {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_change().transpose()
+    }
+}
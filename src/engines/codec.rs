@@ -0,0 +1,351 @@
+use std::io::{self, BufRead, Read};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::engines::kvs::{BinLocation, KvCommand, KvStore};
+use crate::engines::storage::Storage;
+use crate::engines::typed::ValueType;
+
+use super::errors::{KvError, Result};
+
+/// the file recording the name of the `RecordCodec` of the data directory.
+pub(crate) const CODEC_FILE: &str = "kvs-codec";
+
+/// How the records of the data files of a `KvStore` are encoded, see `KvStore::open_with_codec`.
+///
+/// The records are framed, so that they're read one by one from the data files,
+/// and the torn one at the end of a file is told after a crash.
+/// They're checksummed too, so that the corrupted ones are told, except by the legacy `JsonLines`.
+/// A data directory keeps the codec it's created with, recorded by its name.
+pub trait RecordCodec: Send + Sync {
+    /// the name recorded in the data directory, unique among the codecs.
+    fn name(&self) -> &str;
+
+    /// the whole record of `command`, with its framing.
+    fn encode(&self, command: &KvCommand) -> Vec<u8>;
+
+    /// the command in `record`, a whole one read by `read_record`.
+    ///
+    /// # Error
+    ///
+    /// If the record is corrupted, like its checksum doesn't match, it throws.
+    fn decode(&self, record: &[u8]) -> Result<KvCommand>;
+
+    /// read the rest of the record begun in `record` from `reader`, returns whether it's whole now.
+    ///
+    /// If not, the reader ends in the middle of the record, which is torn or still being written,
+    /// and it can be continued by calling it again with the same `record`.
+    /// An empty `record` after it means the reader ends right after the last record.
+    fn read_record(&self, reader: &mut dyn BufRead, record: &mut Vec<u8>) -> io::Result<bool>;
+}
+
+/// The legacy records in JSON, one per line, readable by hand but without checksums,
+/// so a corrupted record that still parses isn't told, see `CheckedJsonLines`.
+///
+/// The data directories created before the codecs are in it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonLines;
+
+impl RecordCodec for JsonLines {
+    fn name(&self) -> &str {
+        "json"
+    }
+
+    fn encode(&self, command: &KvCommand) -> Vec<u8> {
+        let mut record = serde_json::to_vec(command).expect("unable to serialize command into json.");
+        record.push(b'\n');
+        record
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<KvCommand> {
+        Ok(serde_json::from_slice(record)?)
+    }
+
+    fn read_record(&self, reader: &mut dyn BufRead, record: &mut Vec<u8>) -> io::Result<bool> {
+        reader.read_until(b'\n', record)?;
+        Ok(record.ends_with(b"\n"))
+    }
+}
+
+/// The records in JSON, one per line, each after the CRC32 checksum of its JSON in hex and a space,
+/// like `0a1b2c3d {"Put":{..}}`, which are still readable by hand, and tell the corrupted records.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CheckedJsonLines;
+
+impl CheckedJsonLines {
+    /// the checksum and the space before each record.
+    const HEADER: usize = 9;
+}
+
+impl RecordCodec for CheckedJsonLines {
+    fn name(&self) -> &str {
+        "json-crc"
+    }
+
+    fn encode(&self, command: &KvCommand) -> Vec<u8> {
+        let json = serde_json::to_vec(command).expect("unable to serialize command into json.");
+        let mut record = Vec::with_capacity(Self::HEADER + json.len() + 1);
+        record.extend_from_slice(format!("{:08x} ", checksum(&json)).as_bytes());
+        record.extend_from_slice(&json);
+        record.push(b'\n');
+        record
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<KvCommand> {
+        let corrupted = |reason: &str| KvError::CorruptedRecord { reason: reason.to_owned() };
+        let line = record.strip_suffix(b"\n").unwrap_or(record);
+        if line.len() < Self::HEADER || line[Self::HEADER - 1] != b' ' {
+            return Err(corrupted("the record has no checksum"));
+        }
+        let (header, json) = line.split_at(Self::HEADER);
+        let expected = std::str::from_utf8(&header[..Self::HEADER - 1])
+            .ok()
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| corrupted("the checksum isn't in hex"))?;
+        if expected != checksum(json) {
+            return Err(corrupted("the checksum doesn't match"));
+        }
+        serde_json::from_slice(json).map_err(|err| corrupted(err.to_string().as_str()))
+    }
+
+    fn read_record(&self, reader: &mut dyn BufRead, record: &mut Vec<u8>) -> io::Result<bool> {
+        JsonLines.read_record(reader, record)
+    }
+}
+
+/// The records in bincode, each after the length and the CRC32 checksum of it in little endian,
+/// which are smaller and faster to read than `JsonLines`, and tell the corrupted records.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+/// `KvCommand` without skipping the absent fields, which bincode cannot tell,
+/// borrowing the strings to encode, and owning them when decoded.
+#[derive(Serialize, Deserialize)]
+enum BincodeCommand<S> {
+    Put {
+        key: S,
+        value: S,
+        modified: Option<u64>,
+        meta: Option<S>,
+        prev: Option<BinLocation>,
+        blob: Option<S>,
+        batch: Option<usize>,
+    },
+    Rm {
+        key: S,
+        prev: Option<BinLocation>,
+        batch: Option<usize>,
+    },
+    /// a `Put` of a typed value, appended after the others so that the records without types decode as before.
+    PutTyped {
+        key: S,
+        value: S,
+        modified: Option<u64>,
+        meta: Option<S>,
+        prev: Option<BinLocation>,
+        blob: Option<S>,
+        batch: Option<usize>,
+        kind: ValueType,
+    },
+}
+
+impl<'a> From<&'a KvCommand> for BincodeCommand<&'a str> {
+    fn from(command: &'a KvCommand) -> Self {
+        match command {
+            KvCommand::Put { key, value, modified, meta, prev, blob, batch, kind: Some(kind) } => BincodeCommand::PutTyped {
+                key,
+                value,
+                modified: *modified,
+                meta: meta.as_deref(),
+                prev: *prev,
+                blob: blob.as_deref(),
+                batch: *batch,
+                kind: *kind,
+            },
+            KvCommand::Put { key, value, modified, meta, prev, blob, batch, kind: None } => BincodeCommand::Put {
+                key,
+                value,
+                modified: *modified,
+                meta: meta.as_deref(),
+                prev: *prev,
+                blob: blob.as_deref(),
+                batch: *batch,
+            },
+            KvCommand::Rm { key, prev, batch } => BincodeCommand::Rm { key, prev: *prev, batch: *batch },
+        }
+    }
+}
+
+impl From<BincodeCommand<String>> for KvCommand {
+    fn from(command: BincodeCommand<String>) -> Self {
+        match command {
+            BincodeCommand::Put { key, value, modified, meta, prev, blob, batch } => {
+                KvCommand::Put { key, value, modified, meta, prev, blob, batch, kind: None }
+            }
+            BincodeCommand::PutTyped { key, value, modified, meta, prev, blob, batch, kind } => {
+                KvCommand::Put { key, value, modified, meta, prev, blob, batch, kind: Some(kind) }
+            }
+            BincodeCommand::Rm { key, prev, batch } => KvCommand::Rm { key, prev, batch },
+        }
+    }
+}
+
+impl Bincode {
+    /// the length and the checksum before each record.
+    const HEADER: usize = 8;
+}
+
+/// the CRC32 of `payload`.
+fn checksum(payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// read from `reader` until `record` has `len` bytes, returns `false` if the reader ends before it.
+fn fill(reader: &mut dyn BufRead, record: &mut Vec<u8>, len: usize) -> io::Result<bool> {
+    if record.len() < len {
+        (&mut *reader).take((len - record.len()) as u64).read_to_end(record)?;
+    }
+    Ok(record.len() >= len)
+}
+
+impl RecordCodec for Bincode {
+    fn name(&self) -> &str {
+        "bincode"
+    }
+
+    fn encode(&self, command: &KvCommand) -> Vec<u8> {
+        let payload = bincode::serialize(&BincodeCommand::from(command)).expect("unable to serialize command into bincode.");
+        let mut record = Vec::with_capacity(Self::HEADER + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        record
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<KvCommand> {
+        let corrupted = |reason: String| KvError::CorruptedRecord { reason };
+        if record.len() < Self::HEADER {
+            return Err(corrupted(format!("the record has only {} bytes", record.len())));
+        }
+        let (header, payload) = record.split_at(Self::HEADER);
+        let word = |at: usize| u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]]);
+        if word(0) as usize != payload.len() {
+            return Err(corrupted(format!("the record should have {} bytes, not {}", word(0), payload.len())));
+        }
+        if word(4) != checksum(payload) {
+            return Err(corrupted("the checksum doesn't match".to_owned()));
+        }
+        bincode::deserialize::<BincodeCommand<String>>(payload)
+            .map(KvCommand::from)
+            .map_err(|err| corrupted(err.to_string()))
+    }
+
+    fn read_record(&self, reader: &mut dyn BufRead, record: &mut Vec<u8>) -> io::Result<bool> {
+        if !fill(reader, record, Self::HEADER)? {
+            return Ok(false);
+        }
+        let len = u32::from_le_bytes([record[0], record[1], record[2], record[3]]) as usize;
+        fill(reader, record, Self::HEADER + len)
+    }
+}
+
+/// The built-in `RecordCodec`s, to pick one by its name, like in the server config.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CodecKind {
+    /// `JsonLines`.
+    #[default]
+    Json,
+    /// `CheckedJsonLines`.
+    #[serde(rename = "json-crc")]
+    JsonCrc,
+    /// `Bincode`.
+    Bincode,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Error)]
+#[error("No such codec: {0}")]
+/// Throws when we cannot parse the config file or the data directory to a built-in codec.
+pub struct NoSuchCodec(String);
+
+impl FromStr for CodecKind {
+    type Err = NoSuchCodec;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(CodecKind::Json),
+            "json-crc" => Ok(CodecKind::JsonCrc),
+            "bincode" => Ok(CodecKind::Bincode),
+            _ => Err(NoSuchCodec(s.to_owned())),
+        }
+    }
+}
+
+impl AsRef<str> for CodecKind {
+    fn as_ref(&self) -> &str {
+        match self {
+            CodecKind::Json => "json",
+            CodecKind::JsonCrc => "json-crc",
+            CodecKind::Bincode => "bincode",
+        }
+    }
+}
+
+impl CodecKind {
+    /// the codec of this kind.
+    pub fn codec(self) -> Arc<dyn RecordCodec> {
+        match self {
+            CodecKind::Json => Arc::new(JsonLines),
+            CodecKind::JsonCrc => Arc::new(CheckedJsonLines),
+            CodecKind::Bincode => Arc::new(Bincode),
+        }
+    }
+}
+
+/// the name of the codec recorded in the data directory `path`,
+/// the elder data directories without it are in `JsonLines`.
+fn recorded_in(storage: &dyn Storage, path: &Path) -> Result<String> {
+    match storage.read_to_string(&path.join(CODEC_FILE)) {
+        Ok(recorded) => Ok(recorded.trim().to_owned()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(JsonLines.name().to_owned()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// the built-in codec recorded in the data directory `path`.
+///
+/// # Error
+///
+/// If it's another codec, it throws, since it can only be read by `KvStore::open_with_codec`.
+pub(crate) fn codec_of(storage: &dyn Storage, path: &Path) -> Result<Arc<dyn RecordCodec>> {
+    let name = recorded_in(storage, path)?;
+    name.parse().map(CodecKind::codec).map_err(|_| KvError::Other {
+        reason: format!("the data files are in the codec {:?}, open them by `KvStore::open_with_codec`", name),
+    })
+}
+
+/// record `codec` in the data directory `path`, unless it's there already.
+///
+/// # Error
+///
+/// If the data directory has data files in another codec, it throws.
+pub(crate) fn record_codec(storage: &dyn Storage, path: &Path, codec: &dyn RecordCodec) -> Result<()> {
+    let recorded = recorded_in(storage, path)?;
+    if recorded == codec.name() {
+        return Ok(());
+    }
+    let codec_file = path.join(CODEC_FILE);
+    if storage.size(&codec_file).is_ok() || KvStore::enumerate_epoch_files(storage, path).next().is_some() {
+        return Err(KvError::Other {
+            reason: format!("the data files are in the codec {:?}, not {:?}", recorded, codec.name()),
+        });
+    }
+    storage.write_atomic(&codec_file, codec.name().as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::time::Instant;
+
+use super::errors::{KvError, Result};
+
+thread_local! {
+    static CURRENT: RefCell<Option<RequestContext>> = const { RefCell::new(None) };
+}
+
+/// The request an engine operation is serving, set by the server on the thread handling the request,
+/// so that the engine can tell which request its logs and slow-operation warnings are about,
+/// and stop its long scans once the request passes its deadline.
+///
+/// The engines read it by `RequestContext::current`, so the `KvsEngine` methods needn't take it.
+///
+/// # Example
+/// ```rust
+/// # use kvs::engines::context::RequestContext;
+/// let context = RequestContext { request_id: 42, peer: "127.0.0.1:50000".to_owned(), deadline: None };
+/// let _entered = context.enter();
+/// assert_eq!(RequestContext::current().map(|current| current.request_id), Some(42));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestContext {
+    /// the id of the request, which is also in the `mdc` of the request logs.
+    pub request_id: u64,
+    /// the address of the client.
+    pub peer: String,
+    /// when the request should be answered, see `KvServer::timeout`.
+    pub deadline: Option<Instant>,
+}
+
+impl RequestContext {
+    /// the context of the request handled by the current thread, if any.
+    pub fn current() -> Option<RequestContext> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    /// make it the context of the current thread, until the returned guard is dropped,
+    /// then the context before it is back.
+    pub fn enter(self) -> EnteredContext {
+        let previous = CURRENT.with(|current| current.replace(Some(self)));
+        EnteredContext { previous }
+    }
+
+    /// whether the request passes its deadline.
+    pub fn is_expired(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+impl fmt::Display for RequestContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "request {} from {}", self.request_id, self.peer)
+    }
+}
+
+/// Restores the context of the thread when dropped, see `RequestContext::enter`.
+pub struct EnteredContext {
+    previous: Option<RequestContext>,
+}
+
+impl Drop for EnteredContext {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| current.replace(previous));
+    }
+}
+
+/// fail with `DeadlineExceeded` if the request handled by the current thread passes its deadline,
+/// checked by the long scans between their steps, since no one waits for their results then.
+pub(crate) fn check_deadline(operation: &'static str) -> Result<()> {
+    let expired = CURRENT.with(|current| current.borrow().as_ref().is_some_and(RequestContext::is_expired));
+    if expired {
+        return Err(KvError::DeadlineExceeded { operation });
+    }
+    Ok(())
+}
+
+/// describe the request handled by the current thread for the engine logs, like ` (request 3 from 127.0.0.1:50000)`,
+/// or nothing outside of requests.
+pub(crate) fn describe_current() -> String {
+    CURRENT.with(|current| match current.borrow().as_ref() {
+        Some(context) => format!(" ({})", context),
+        None => String::new(),
+    })
+}
@@ -1,24 +1,172 @@
-use std::io::{Read, Write};
+use std::collections::BTreeMap;
+use std::io::{self, Read};
 use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
-use crate::engines::errors::KvError::IllegalWorkingDirectory;
+use bytes::Bytes;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::engines::changes::{Change, LogPosition};
+use crate::engines::errors::KvError::{self, IllegalWorkingDirectory};
+use crate::engines::lease::Lease;
+use crate::engines::metrics::EngineMetrics;
+use crate::engines::context;
+use crate::engines::pattern::{KeyPattern, ListOptions};
+use crate::engines::storage::Storage;
+use crate::engines::typed::TypedValue;
 
 use super::errors::Result;
 
-pub(crate) fn check_engine<P: AsRef<Path>>(path: P, engine_name: &str) -> Result<()> {
-    if std::fs::metadata(path.as_ref().join(".engine")).is_err() {
-        let mut f = std::fs::File::create(path.as_ref().join(".engine"))?;
-        f.write_all(engine_name.as_bytes())?;
+/// the file marking which engine the data directory belongs to.
+const ENGINE_FILE: &str = ".engine";
+
+/// the engines that mark their data directories.
+const ENGINE_NAMES: [&str; 2] = ["kvs", "sled"];
+
+/// the engine whose data is in `path`, if any.
+fn engine_of_data(storage: &dyn Storage, path: &Path) -> Result<Option<&'static str>> {
+    for name in storage.list(path)? {
+        if name.starts_with("kvs-data-") || name.starts_with("kvs-blob-") {
+            return Ok(Some("kvs"));
+        }
+        if name == "conf" || name == "db" || name.starts_with("snap.") {
+            return Ok(Some("sled"));
+        }
     }
-    let mut f = std::fs::File::open(path.as_ref().join(".engine"))?;
-    let mut buf = String::new();
-    f.read_to_string(&mut buf)?;
-    if buf.to_lowercase().as_str() != engine_name {
+    Ok(None)
+}
+
+/// write the marker of `engine_name` aside and rename it, so that a crash leaves either no marker or the whole of it.
+fn mark_engine(storage: &dyn Storage, path: &Path, engine_name: &str) -> Result<()> {
+    storage.write_atomic(&path.join(ENGINE_FILE), engine_name.as_bytes())?;
+    storage.sync_dir(path)?;
+    Ok(())
+}
+
+/// make sure that `path` is a data directory of `engine_name`, marking it if it's new.
+///
+/// A marker that is empty or names no engine, like one written by a crash before it's made atomic,
+/// is recovered from the data in the directory, or marked again when there is none.
+pub(crate) fn check_engine<P: AsRef<Path>>(storage: &dyn Storage, path: P, engine_name: &str) -> Result<()> {
+    let path = path.as_ref();
+    let recorded = match storage.read_to_string(&path.join(ENGINE_FILE)) {
+        Ok(content) => {
+            let recorded = content.trim().to_lowercase();
+            if ENGINE_NAMES.contains(&recorded.as_str()) {
+                Some(recorded)
+            } else {
+                warn!("the engine marker in {} is damaged: {:?}, recovering it.", path.display(), content);
+                None
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+    let recorded = match recorded {
+        Some(recorded) => recorded,
+        None => {
+            let found = engine_of_data(storage, path)?.unwrap_or(engine_name);
+            if found != engine_name {
+                return Err(IllegalWorkingDirectory);
+            }
+            mark_engine(storage, path, engine_name)?;
+            engine_name.to_owned()
+        }
+    };
+    if recorded != engine_name {
         return Err(IllegalWorkingDirectory);
     }
     Ok(())
 }
 
+/// A value with when it's last modified, and the metadata set with it, see `KvsEngine::get_with_meta`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ValueWithMeta {
+    /// the value.
+    pub value: String,
+    /// when the value is set, `None` if the engine or the record doesn't track it.
+    pub modified: Option<SystemTime>,
+    /// the user metadata set with the value by `set_with_meta`.
+    pub meta: Option<String>,
+}
+
+/// When a conditional set writes, see `KvsEngine::set_if`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum SetCondition {
+    /// only when the key doesn't exist, like `SET NX` of Redis.
+    #[serde(rename = "nx")]
+    IfAbsent,
+    /// only when the key exists, like `SET XX` of Redis.
+    #[serde(rename = "xx")]
+    IfPresent,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Error)]
+#[error("No such set condition: {0}")]
+/// Throws when we cannot parse the command line or the request to a set condition.
+pub struct NoSuchSetCondition(String);
+
+impl FromStr for SetCondition {
+    type Err = NoSuchSetCondition;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nx" => Ok(SetCondition::IfAbsent),
+            "xx" => Ok(SetCondition::IfPresent),
+            _ => Err(NoSuchSetCondition(s.to_owned())),
+        }
+    }
+}
+
+impl AsRef<str> for SetCondition {
+    fn as_ref(&self) -> &str {
+        match self {
+            SetCondition::IfAbsent => "nx",
+            SetCondition::IfPresent => "xx",
+        }
+    }
+}
+
+/// A write in a `KvsEngine::write_batch`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BatchWrite {
+    /// set `key` to `value`.
+    Set {
+        /// the key to set.
+        key: String,
+        /// the value to set.
+        value: String,
+    },
+    /// remove `key`, if it exists.
+    Remove {
+        /// the key to remove.
+        key: String,
+    },
+}
+
+impl BatchWrite {
+    /// the key written.
+    pub fn key(&self) -> &str {
+        match self {
+            BatchWrite::Set { key, .. } | BatchWrite::Remove { key } => key.as_str(),
+        }
+    }
+}
+
+/// What `KvsEngine::preload` loads.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Preload {
+    /// the values of these keys.
+    Keys(Vec<String>),
+    /// the values of the keys starting with it, the empty prefix for all of them.
+    Prefix(String),
+}
+
 /// The engine of out `KvServer`.
 /// This is the basic abstract of an Key-value database.
 ///
@@ -26,6 +174,9 @@ pub(crate) fn check_engine<P: AsRef<Path>>(path: P, engine_name: &str) -> Result
 /// It grantees that it's cheap to `Clone` it, so you needn't share it with `Arc`.
 ///
 /// The semantic of `get`, `set`, `remove` are same as what you thinks.
+///
+/// The operations reading and writing at once, like `set_if`, `rename` and `incr`, throw `Unsupported` by default,
+/// since they cannot be atomic by `get`, `set` and `remove`, so the engines supporting them override them.
 pub trait KvsEngine: Send + Clone + 'static {
     /// get value from store by key.
     /// when the key not exists, return `None`.
@@ -38,4 +189,276 @@ pub trait KvsEngine: Send + Clone + 'static {
     ///
     /// When the key not found, it should throw `KeyNotFound`.
     fn remove(&self, key: String) -> Result<()>;
+    /// restore the value of the removed key, if the engine keeps it.
+    ///
+    /// # Error
+    ///
+    /// `KeyNotFound` if there is no removed value to restore.
+    /// The default implementation throws `Unsupported`.
+    fn undelete(&self, key: String) -> Result<()> {
+        let _ = key;
+        Err(KvError::Unsupported { operation: "undelete" })
+    }
+    /// replace all the data of the engine by a backup, the files of the backup by their names,
+    /// see `read_archive`.
+    ///
+    /// # Error
+    ///
+    /// The default implementation throws `Unsupported`, see `Restorable` for an implementation.
+    fn restore(&self, archive: BTreeMap<String, Vec<u8>>) -> Result<()> {
+        let _ = archive;
+        Err(KvError::Unsupported { operation: "restore" })
+    }
+    /// get the value of `key`, or set it to `default()` and return that if the key doesn't exist,
+    /// atomically, so that the concurrent calls on an absent key all return the same value.
+    ///
+    /// # Error
+    ///
+    /// The default implementation throws `Unsupported`.
+    fn get_or_insert_with(&self, key: String, default: impl FnOnce() -> String) -> Result<String> {
+        let _ = (key, default);
+        Err(KvError::Unsupported { operation: "get_or_insert_with" })
+    }
+    /// set `key` to `value` only when `condition` holds, checked and written atomically.
+    /// Returns whether it's written.
+    ///
+    /// # Error
+    ///
+    /// The default implementation throws `Unsupported`.
+    fn set_if(&self, key: String, value: String, condition: SetCondition) -> Result<bool> {
+        let _ = (key, value, condition);
+        Err(KvError::Unsupported { operation: "set_if" })
+    }
+    /// set `key` to `value` only when it's last modified at `modified`, as `get_with_meta` tells,
+    /// checked and written atomically, like `If-Unmodified-Since` of HTTP, so that a write based on a stale read fails.
+    /// Returns whether it's written. The absent keys, and the ones whose modified time isn't tracked, aren't written.
+    ///
+    /// # Error
+    ///
+    /// The default implementation throws `Unsupported`.
+    fn set_if_unmodified(&self, key: String, value: String, modified: SystemTime) -> Result<bool> {
+        let _ = (key, value, modified);
+        Err(KvError::Unsupported { operation: "set_if_unmodified" })
+    }
+    /// set `key` to `value` only when the key doesn't exist, see `set_if`.
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        self.set_if(key, value, SetCondition::IfAbsent)
+    }
+    /// set `key` to `value` only when the key exists, see `set_if`.
+    fn set_xx(&self, key: String, value: String) -> Result<bool> {
+        self.set_if(key, value, SetCondition::IfPresent)
+    }
+    /// move the value of `from` with its metadata to `to` atomically, overwriting the value of `to` if any,
+    /// so that no one sees both or neither of them, even after a crash.
+    /// Renaming a key to itself does nothing.
+    ///
+    /// # Error
+    ///
+    /// when `from` doesn't exist, will throw `KeyNotFound`.
+    /// The default implementation throws `Unsupported`.
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        let _ = (from, to);
+        Err(KvError::Unsupported { operation: "rename" })
+    }
+    /// copy the value of `from` with its metadata to `to` atomically, overwriting the value of `to` if any.
+    ///
+    /// # Error
+    ///
+    /// when `from` doesn't exist, will throw `KeyNotFound`.
+    /// The default implementation throws `Unsupported`.
+    fn copy(&self, from: String, to: String) -> Result<()> {
+        let _ = (from, to);
+        Err(KvError::Unsupported { operation: "copy" })
+    }
+    /// remove all the keys starting with `prefix` by one write, like the keys of a session or a tenant.
+    /// Returns how many keys are removed, and removing none isn't an error.
+    ///
+    /// # Error
+    ///
+    /// The default implementation throws `Unsupported`.
+    fn remove_prefix(&self, prefix: String) -> Result<usize> {
+        let _ = prefix;
+        Err(KvError::Unsupported { operation: "remove_prefix" })
+    }
+    /// apply `writes` in order by one write, so that all or none of them are written, like `MULTI` and `EXEC` of Redis.
+    /// The removes of the absent keys do nothing instead of failing the others.
+    ///
+    /// # Error
+    ///
+    /// The default implementation throws `Unsupported`.
+    fn write_batch(&self, writes: Vec<BatchWrite>) -> Result<()> {
+        let _ = writes;
+        Err(KvError::Unsupported { operation: "write_batch" })
+    }
+    /// take the lease of `key` for `ttl`, when the key is absent or its lease expires, checked and written atomically,
+    /// so that at most one holds the key at a time, like a lock expiring by itself.
+    /// Returns the lease kept as the value of the key, or `None` if another one holds it.
+    ///
+    /// # Error
+    ///
+    /// when the value of `key` isn't a lease, it throws.
+    /// The default implementation throws `Unsupported`.
+    fn acquire_lease(&self, key: String, ttl: Duration) -> Result<Option<Lease>> {
+        let _ = (key, ttl);
+        Err(KvError::Unsupported { operation: "acquire_lease" })
+    }
+    /// remove the lease of `key` if it's the one of `token`, even if it expires but no one takes the key since.
+    /// Returns whether it's released.
+    ///
+    /// # Error
+    ///
+    /// The default implementation throws `Unsupported`.
+    fn release_lease(&self, key: String, token: String) -> Result<bool> {
+        let _ = (key, token);
+        Err(KvError::Unsupported { operation: "release_lease" })
+    }
+    /// append `suffix` to the value of `key` as one write, or set the key to `suffix` if it doesn't exist,
+    /// so that the concurrent appends to a key are never lost.
+    ///
+    /// # Error
+    ///
+    /// The default implementation throws `Unsupported`.
+    fn append(&self, key: String, suffix: String) -> Result<()> {
+        let _ = (key, suffix);
+        Err(KvError::Unsupported { operation: "append" })
+    }
+    /// get value from store by key, with when it's last modified and its metadata.
+    ///
+    /// The default implementation doesn't track the modified time nor the metadata.
+    fn get_with_meta(&self, key: String) -> Result<Option<ValueWithMeta>> {
+        Ok(self.get(key)?.map(|value| ValueWithMeta {
+            value,
+            modified: None,
+            meta: None,
+        }))
+    }
+    /// set value to store with specified key, along with the user metadata `meta`.
+    ///
+    /// # Error
+    ///
+    /// The default implementation throws `Unsupported` if `meta` isn't `None`.
+    fn set_with_meta(&self, key: String, value: String, meta: Option<String>) -> Result<()> {
+        match meta {
+            None => self.set(key, value),
+            Some(_) => Err(KvError::Unsupported {
+                operation: "set_with_meta",
+            }),
+        }
+    }
+    /// get the value of `key` as reference-counted bytes, like `get`,
+    /// which the engine may share with its caches instead of copying them for each read.
+    /// The bytes are always the UTF-8 of a string.
+    ///
+    /// The default implementation `get`s the value, and takes its buffer without copying it.
+    fn get_bytes(&self, key: String) -> Result<Option<Bytes>> {
+        Ok(self.get(key)?.map(Bytes::from))
+    }
+    /// get the value of `key` with its type, the values set by `set` are strings.
+    ///
+    /// The default implementation keeps no type, so all the values are strings.
+    fn get_typed(&self, key: String) -> Result<Option<TypedValue>> {
+        Ok(self.get(key)?.map(TypedValue::String))
+    }
+    /// set `key` to `value` with its type kept, see `get_typed`.
+    /// The value read by `get` is its string form, see `TypedValue::encode`.
+    ///
+    /// # Error
+    ///
+    /// The default implementation throws `Unsupported` unless `value` is a string, since it keeps no type.
+    fn set_typed(&self, key: String, value: TypedValue) -> Result<()> {
+        match value {
+            TypedValue::String(value) => self.set(key, value),
+            _ => Err(KvError::Unsupported { operation: "set_typed" }),
+        }
+    }
+    /// add `delta` to the integer of `key` as one write, or set the key to `delta` if it doesn't exist,
+    /// then returns the new integer, which is typed an integer from then on.
+    /// A string of an integer in decimal, like one set by `set`, counts as an integer.
+    ///
+    /// # Error
+    ///
+    /// when the value of `key` isn't an integer, will throw `WrongType`, and when the sum overflows, it throws.
+    /// The default implementation throws `Unsupported`.
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let _ = (key, delta);
+        Err(KvError::Unsupported { operation: "incr" })
+    }
+    /// set the value of `key` read from `value` until EOF,
+    /// so that a large value needn't be in memory as a whole.
+    /// The value should be UTF-8, or `get` fails to read it.
+    ///
+    /// The default implementation reads the whole value, then `set`s it.
+    fn set_stream(&self, key: String, value: &mut dyn Read) -> Result<()> {
+        let mut buf = String::new();
+        value.read_to_string(&mut buf)?;
+        self.set(key, buf)
+    }
+    /// get the value of `key` as a stream, see `set_stream`.
+    /// when the key not exists, return `None`.
+    ///
+    /// The default implementation `get`s the whole value.
+    fn get_stream(&self, key: String) -> Result<Option<Box<dyn Read>>> {
+        Ok(self.get(key)?.map(|value| Box::new(io::Cursor::new(value.into_bytes())) as Box<dyn Read>))
+    }
+    /// list the keys matching the glob `pattern` in ascending order, like Redis `KEYS`.
+    /// See `KeyPattern` for the syntax.
+    fn keys(&self, pattern: String) -> Result<Vec<String>> {
+        self.list_keys(pattern, ListOptions::default())
+    }
+    /// list the keys matching the glob `pattern` in the order and within the bounds of `options`,
+    /// like the last 10 keys under a prefix.
+    fn list_keys(&self, pattern: String, options: ListOptions) -> Result<Vec<String>>;
+    /// the writes since `since` in the order they're written, ending at the last one,
+    /// or waiting for the new ones forever when `follow`, see `Changes`.
+    ///
+    /// # Error
+    ///
+    /// The default implementation throws `Unsupported`, since the engine may keep no log of the writes.
+    fn changes(&self, since: LogPosition, follow: bool) -> Result<Box<dyn Iterator<Item=Result<Change>> + Send>> {
+        let _ = (since, follow);
+        Err(KvError::Unsupported { operation: "changes" })
+    }
+    /// where the next write goes in the log of `changes`, to follow only the writes after now.
+    ///
+    /// # Error
+    ///
+    /// The default implementation throws `Unsupported`, like `changes`.
+    fn log_end(&self) -> Result<LogPosition> {
+        Err(KvError::Unsupported { operation: "log_end" })
+    }
+    /// the live counters of the operations on this engine, shared by all its clones.
+    ///
+    /// The default implementation counts nothing.
+    fn metrics(&self) -> EngineMetrics {
+        EngineMetrics::default()
+    }
+    /// load the values of `target` ahead, so that the first reads of them are fast,
+    /// like warming up the hot keys after a restart, before serving the clients.
+    /// Returns how many values are loaded, the missing keys aren't counted.
+    ///
+    /// The default implementation reads them by `get_bytes`, which warms the page cache of the OS at least.
+    fn preload(&self, target: Preload) -> Result<usize> {
+        let keys = match target {
+            Preload::Keys(keys) => keys,
+            Preload::Prefix(prefix) => {
+                self.list_keys(format!("{}*", KeyPattern::escape(&prefix)), ListOptions::default())?
+            }
+        };
+        let mut loaded = 0;
+        for key in keys {
+            context::check_deadline("preload")?;
+            if self.get_bytes(key)?.is_some() {
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+    /// close the engine gracefully before the process exits, like when the server shuts down:
+    /// make the writes durable, so that the next open needn't recover from a crash.
+    /// The clones may still be used after it, but their writes are recovered by the next open like after a crash.
+    ///
+    /// The default implementation does nothing, for the engines writing durably anyway.
+    fn close(&self) -> Result<()> {
+        Ok(())
+    }
 }
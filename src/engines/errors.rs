@@ -1,7 +1,11 @@
+use std::fmt;
+use std::io;
 use std::sync::PoisonError;
 
-use failure::Fail;
 use rayon::ThreadPoolBuildError;
+use thiserror::Error;
+
+use super::typed::ValueType;
 
 /// The result type used in the `KvEngine` context.
 pub type Result<T> = std::result::Result<T, KvError>;
@@ -9,62 +13,205 @@ pub type Result<T> = std::result::Result<T, KvError>;
 /// The Error type of `KvEngine` context.
 /// It has some variant that warps other types, like `std::io::Error`, `serde_json::Error`.
 /// This error type implements the `From` trait of those error types.
-#[derive(Debug, Fail)]
+#[derive(Debug, Error)]
 pub enum KvError {
-    #[fail(
-        display = "Failed to open file {} because error [{}].",
-        file_name, io_error
-    )]
+    #[error("Failed to open file {file_name} because error [{io_error}].")]
     /// failed to open an db file.
     FailToOpenFile {
         /// the filename which failed to open.
         file_name: String,
-        #[cause]
+        #[source]
         /// the original io exception.
         io_error: std::io::Error,
     },
-    #[fail(
-        display = "Failed because some unexpected IO exception [{}].",
-        io_error
-    )]
+    #[error("Failed because some unexpected IO exception [{io_error}].")]
     /// Failed because generic io exception, like broken pipe, removed file.
     /// It warps `std::io::Error`.
     OtherIOException {
-        #[cause]
+        #[source]
         /// the inner error.
         io_error: std::io::Error,
     },
-    #[fail(display = "Failed to build an rayon thread pool: {}", error)]
+    #[error("Failed to build an rayon thread pool: {error}")]
     /// The error for `RayonThreadPool`, it wraps `rayon_core::ThreadPoolBuildError`.
     RayonThreadPoolFailedToBuild {
-        #[cause]
+        #[source]
         /// the inner error.
         error: ThreadPoolBuildError,
     },
-    #[fail(display = "Failed to parse file because error [{}]", serde_error)]
+    #[error("Failed to parse file because error [{serde_error}]")]
     /// The `KvStore` meet malformed datafile.
     /// It wraps `serde_json::Error`
     FailToParseFile {
-        #[cause]
+        #[source]
         /// the inner error.
         serde_error: serde_json::Error,
     },
     /// Throws when trying to delete a non-exist key.
-    #[fail(display = "Key not found")]
+    #[error("Key not found")]
     KeyNotFound,
     /// The most generic exception type for some 'WTF'(What a Terrible Failure) condition.
-    #[fail(display = "other exception: {}", reason)]
+    #[error("other exception: {reason}")]
     Other {
         /// the reason, you can write anything you want here.
         /// ...even it's an anti-pattern to use string-structured data structure.
         reason: String,
     },
     /// Throws when trying to open an engine on directory that is 'dominated' by other engine.
-    #[fail(display = "illegal working directory: another instance is working here.")]
+    #[error("illegal working directory: another instance is working here.")]
     IllegalWorkingDirectory,
     /// Throws when meeting some bad things during play with some concurrent data-structures or locks.
-    #[fail(display = "when operate with lock, something bad happens.")]
+    #[error("when operate with lock, something bad happens.")]
     ConcurrentError,
+    /// An error occurs when handling a record of the data files,
+    /// with the location of the record, so that a corrupted record can be found and repaired by hand.
+    #[error("{source} (during {context})")]
+    WithContext {
+        /// where the error occurs.
+        context: ErrorContext,
+        /// the original error.
+        source: Box<KvError>,
+    },
+    /// Throws when a task spawned by `ThreadPool::spawn_with_result` panicked.
+    #[error("the task panicked: {reason}")]
+    TaskPanicked {
+        /// the panic message, if it is a string.
+        reason: String,
+    },
+    /// Throws when writing a new key would grow the in-memory index beyond its budget,
+    /// see `KvStore::with_index_budget`. The present keys can still be overwritten.
+    #[error("the index is full: adding the key needs more than the budget of {budget} bytes.")]
+    IndexFull {
+        /// the budget of the index, in bytes.
+        budget: u64,
+    },
+    /// Throws when a record of the data files cannot be decoded by its `RecordCodec`,
+    /// like its checksum doesn't match.
+    #[error("corrupted record: {reason}")]
+    CorruptedRecord {
+        /// what's wrong with the record.
+        reason: String,
+    },
+    /// Throws when a long operation, like `list_keys`, stops since its request passes the deadline,
+    /// see `RequestContext`.
+    #[error("{operation} stopped, since the request passes its deadline.")]
+    DeadlineExceeded {
+        /// the name of the operation.
+        operation: &'static str,
+    },
+    /// Throws when the engine doesn't support an optional operation, like `set_with_meta` of sled.
+    #[error("the engine doesn't support {operation}.")]
+    Unsupported {
+        /// the name of the operation.
+        operation: &'static str,
+    },
+    /// Throws when an operation meets a value of a type it cannot handle, like `incr` on bytes.
+    #[error("wrong type: the value is {}, not {}.", .found.as_ref(), .expected.as_ref())]
+    WrongType {
+        /// the type the operation needs.
+        expected: ValueType,
+        /// the type of the value.
+        found: ValueType,
+    },
+    /// Throws when the server responds with an error, which the client has no variant of its own for,
+    /// like the server is overloaded, see `ServerError`.
+    #[error("the server failed: {reason} (code {code})")]
+    Remote {
+        /// the code of the error on the server, see `code`.
+        code: u16,
+        /// whether the server tells the request may succeed when retried later.
+        retryable: bool,
+        /// the message of the error on the server.
+        reason: String,
+    },
+}
+
+/// Where an error occurs in the data files.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ErrorContext {
+    /// the operation that failed, like `load_command` or `build_index`.
+    pub operation: &'static str,
+    /// the data file being read.
+    pub file_name: String,
+    /// the offset of the record in the file.
+    pub offset: usize,
+    /// the key of the record, if it's known.
+    pub key: Option<String>,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}:{}", self.operation, self.file_name, self.offset)?;
+        if let Some(key) = &self.key {
+            write!(f, " with key {:?}", key)?;
+        }
+        Ok(())
+    }
+}
+
+/// attach an `ErrorContext` to the error of a `Result`.
+pub(crate) trait ResultExt<T> {
+    fn with_context(self, context: impl FnOnce() -> ErrorContext) -> Result<T>;
+}
+
+impl<T, E: Into<KvError>> ResultExt<T> for std::result::Result<T, E> {
+    fn with_context(self, context: impl FnOnce() -> ErrorContext) -> Result<T> {
+        self.map_err(|err| KvError::WithContext {
+            context: context(),
+            source: Box::new(err.into()),
+        })
+    }
+}
+
+impl KvError {
+    /// the stable numeric code of this error, which is sent to the client along with the error message.
+    ///
+    /// `1xx` are failures of the storage or the environment, `2xx` are logical errors of the request,
+    /// and `1` is for anything else. A `Remote` error keeps the code from the server, like the `3xx` of `ServerError`.
+    /// A code never changes its meaning once assigned.
+    pub fn code(&self) -> u16 {
+        match self {
+            KvError::Other { .. } => 1,
+            KvError::FailToOpenFile { .. } => 101,
+            KvError::OtherIOException { .. } => 102,
+            KvError::FailToParseFile { .. } => 103,
+            KvError::IllegalWorkingDirectory => 104,
+            KvError::ConcurrentError => 105,
+            KvError::RayonThreadPoolFailedToBuild { .. } => 106,
+            KvError::TaskPanicked { .. } => 107,
+            KvError::IndexFull { .. } => 108,
+            KvError::CorruptedRecord { .. } => 109,
+            KvError::DeadlineExceeded { .. } => 110,
+            KvError::KeyNotFound => 201,
+            KvError::Unsupported { .. } => 202,
+            KvError::WrongType { .. } => 203,
+            KvError::WithContext { source, .. } => source.code(),
+            KvError::Remote { code, .. } => *code,
+        }
+    }
+
+    /// whether the same operation may succeed when retried later,
+    /// i.e. the error is caused by a transient condition instead of the request or the data.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            KvError::FailToOpenFile { io_error, .. } | KvError::OtherIOException { io_error } => {
+                is_transient(io_error)
+            }
+            KvError::ConcurrentError | KvError::DeadlineExceeded { .. } => true,
+            KvError::WithContext { source, .. } => source.is_retryable(),
+            KvError::Remote { retryable, .. } => *retryable,
+            _ => false,
+        }
+    }
+}
+
+fn is_transient(io_error: &io::Error) -> bool {
+    const TRANSIENT: [io::ErrorKind; 3] = [
+        io::ErrorKind::Interrupted,
+        io::ErrorKind::WouldBlock,
+        io::ErrorKind::TimedOut,
+    ];
+    TRANSIENT.contains(&io_error.kind())
 }
 
 impl From<serde_json::Error> for KvError {
@@ -1,54 +1,80 @@
 use core::sync::atomic::Ordering;
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::collections::hash_map::RandomState;
-use std::fs::{File, OpenOptions};
+use std::fmt::{self, Display, Formatter};
 use std::hash::BuildHasher;
-use std::io::{BufRead, BufReader, Read, Write};
+use std::io::{self, BufReader, IoSlice, Read, Write};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, atomic::AtomicU64, Mutex};
+use std::str::FromStr;
+use std::sync::{Arc, atomic::AtomicU64, Condvar, Mutex, MutexGuard, PoisonError, RwLock};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use bytes::Bytes;
+use fail::fail_point;
 use lockfree::map::Map;
+use log::warn;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use walkdir::WalkDir;
+use thiserror::Error;
 
 use lazy_static::lazy_static;
 
+#[cfg(feature = "failpoints")]
+use crate::common::failpoint_error;
 use crate::common::SeekExt;
-use crate::engines::engine::KvsEngine;
+use crate::engines::changes::{Change, Changes, LogPosition};
+use crate::engines::codec::{self, CODEC_FILE, RecordCodec};
+use crate::engines::context;
+use crate::engines::engine::{BatchWrite, KvsEngine, Preload, SetCondition, ValueWithMeta};
+use crate::engines::lease::Lease;
+use crate::engines::metrics::EngineMetrics;
+use crate::engines::pattern::{KeyPattern, ListOptions};
+use crate::engines::storage::{DataFile, FsStorage, ReadFile, Storage};
+use crate::engines::typed::{TypedValue, ValueType};
 
 use super::engine;
-use super::errors::{KvError, Result};
+use super::errors::{ErrorContext, KvError, Result, ResultExt};
 use super::errors::KvError::KeyNotFound;
 
 use self::KvCommand::{Put, Rm};
 
-fn filename_of(epoch: u64) -> String {
+pub(crate) fn filename_of(epoch: u64) -> String {
     format!("kvs-data-{}", epoch)
 }
 
-fn into_result<T>(option: Option<T>) -> std::result::Result<T, ()> {
-    match option {
-        Some(x) => Ok(x),
-        None => Err(()),
-    }
+/// the data file of `epoch` being written by a compaction or `KvStore::ingest`, renamed to `filename_of(epoch)` once it's done.
+fn compacting_filename_of(epoch: u64) -> String {
+    format!("kvs-data-{}.compacting", epoch)
+}
+
+const CHECKPOINT_FILE: &str = "kvs-checkpoint";
+
+/// the marker of a clean shutdown, holding the epoch of the checkpoint made by `close`,
+/// removed by the next open, so that only the opens after a `close` find it.
+const CLEAN_FILE: &str = "kvs-clean";
+
+/// the file recording the `IndexKind` of the data directory.
+const INDEX_FILE: &str = "kvs-index";
+
+/// the blob file of the value whose record is written at `offset` of the data file `epoch`.
+fn blob_name_of(epoch: u64, offset: usize) -> String {
+    format!("kvs-blob-{}-{}", epoch, offset)
 }
 
-fn read_file_of(base: impl AsRef<Path>, epoch: u64) -> Result<File> {
+fn read_file_of(storage: &dyn Storage, base: impl AsRef<Path>, epoch: u64) -> Result<Box<dyn DataFile>> {
     let filename = base.as_ref().join(filename_of(epoch));
-    OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&filename)
+    storage
+        .open_append(&filename)
         .map_err(|e| KvError::FailToOpenFile {
             file_name: filename_of(epoch),
             io_error: e,
         })
 }
 
-fn parse_gen(filename: &str) -> Option<u64> {
+pub(crate) fn parse_gen(filename: &str) -> Option<u64> {
     lazy_static! {
         static ref PATTERN: Regex = Regex::new(r"^kvs-data-(\d+)$").unwrap();
     }
@@ -58,13 +84,35 @@ fn parse_gen(filename: &str) -> Option<u64> {
         .map(|cap| cap[1].to_string().parse::<u64>().unwrap())
 }
 
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
-struct BinLocation {
+/// the blob file written by `set_stream` before its record, named by a sequence of this process.
+fn staged_blob_name(seq: u64) -> String {
+    format!("kvs-blob-{}.tmp", seq)
+}
+
+/// the epoch of the data file whose record points to the blob file `filename`.
+fn parse_blob_epoch(filename: &str) -> Option<u64> {
+    lazy_static! {
+        static ref PATTERN: Regex = Regex::new(r"^kvs-blob-(\d+)-\d+$").unwrap();
+    }
+    PATTERN
+        .captures(filename)
+        .and_then(|cap| cap[1].parse::<u64>().ok())
+}
+
+/// Where a record is in the data files, linking a version of a key to the previous one in `KvCommand`.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, Serialize, Deserialize)]
+pub struct BinLocation {
     offset: usize,
     length: usize,
     epoch: u64,
 }
 
+impl Display for BinLocation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", filename_of(self.epoch), self.offset)
+    }
+}
+
 macro_rules! bin_loc {
     (Gen[$gen: expr] $start: expr => $len: expr ) => {
         BinLocation {
@@ -75,10 +123,361 @@ macro_rules! bin_loc {
     };
 }
 
+/// The kind of the in-memory index of a `KvStore`, see `KvStore::with_index`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexKind {
+    /// a hash map like bitcask, the fastest for point lookups.
+    #[default]
+    Hash,
+    /// a B-tree map, which keeps the keys in order,
+    /// so that a key listing scans only the keys with the literal prefix of its pattern.
+    Ordered,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Error)]
+#[error("No such index kind: {0}")]
+/// Throws when we cannot parse the command line or the data directory to an index kind.
+pub struct NoSuchIndexKind(String);
+
+impl FromStr for IndexKind {
+    type Err = NoSuchIndexKind;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "hash" => Ok(IndexKind::Hash),
+            "ordered" => Ok(IndexKind::Ordered),
+            _ => Err(NoSuchIndexKind(s.to_owned())),
+        }
+    }
+}
+
+impl AsRef<str> for IndexKind {
+    fn as_ref(&self) -> &str {
+        match self {
+            IndexKind::Hash => "hash",
+            IndexKind::Ordered => "ordered",
+        }
+    }
+}
+
+impl IndexKind {
+    /// the kind recorded in the data directory `path`, the default one if it isn't recorded.
+    fn recorded_in(storage: &dyn Storage, path: &Path) -> Result<Self> {
+        match storage.read_to_string(&path.join(INDEX_FILE)) {
+            Ok(recorded) => recorded.trim().parse().map_err(|err: NoSuchIndexKind| KvError::Other {
+                reason: err.to_string(),
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(IndexKind::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// When a `KvStore` makes its writes durable, see `KvStore::with_sync`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncPolicy {
+    /// leave the writes to the OS, a crash of the machine may lose the recent ones.
+    #[default]
+    Never,
+    /// sync each write before it's acknowledged.
+    Always,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Error)]
+#[error("No such sync policy: {0}")]
+/// Throws when we cannot parse the command line or the config file to a sync policy.
+pub struct NoSuchSyncPolicy(String);
+
+impl FromStr for SyncPolicy {
+    type Err = NoSuchSyncPolicy;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "never" => Ok(SyncPolicy::Never),
+            "always" => Ok(SyncPolicy::Always),
+            _ => Err(NoSuchSyncPolicy(s.to_owned())),
+        }
+    }
+}
+
+impl AsRef<str> for SyncPolicy {
+    fn as_ref(&self) -> &str {
+        match self {
+            SyncPolicy::Never => "never",
+            SyncPolicy::Always => "always",
+        }
+    }
+}
+
+/// How a `KvStore` appends the records of its writes, see `KvStore::with_write_path`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WritePath {
+    /// each write copies its records together and appends them by one write, under the lock of the writer.
+    #[default]
+    Buffered,
+    /// the concurrent writes of single keys are queued, and the one taking the writer appends all of them
+    /// by vectored writes from their encodings, syncing them once, then indexes them in order.
+    Vectored,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Error)]
+#[error("No such write path: {0}")]
+/// Throws when we cannot parse the command line or the config file to a write path.
+pub struct NoSuchWritePath(String);
+
+impl FromStr for WritePath {
+    type Err = NoSuchWritePath;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "buffered" => Ok(WritePath::Buffered),
+            "vectored" => Ok(WritePath::Vectored),
+            _ => Err(NoSuchWritePath(s.to_owned())),
+        }
+    }
+}
+
+impl AsRef<str> for WritePath {
+    fn as_ref(&self) -> &str {
+        match self {
+            WritePath::Buffered => "buffered",
+            WritePath::Vectored => "vectored",
+        }
+    }
+}
+
+/// the in-memory index from the keys to their last records.
+enum Index<B: BuildHasher = RandomState> {
+    Hash(Map<String, BinLocation, B>),
+    Ordered(RwLock<BTreeMap<String, BinLocation>>),
+}
+
+impl Index {
+    fn new(kind: IndexKind) -> Self {
+        match kind {
+            IndexKind::Hash => Index::Hash(Map::new()),
+            IndexKind::Ordered => Index::Ordered(RwLock::new(BTreeMap::new())),
+        }
+    }
+}
+
+impl<B: BuildHasher> Index<B> {
+    fn kind(&self) -> IndexKind {
+        match self {
+            Index::Hash(_) => IndexKind::Hash,
+            Index::Ordered(_) => IndexKind::Ordered,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<BinLocation> {
+        match self {
+            Index::Hash(map) => map.get(key).map(|entry| *entry.val()),
+            Index::Ordered(tree) => tree.read().unwrap_or_else(PoisonError::into_inner).get(key).copied(),
+        }
+    }
+
+    fn insert(&self, key: String, location: BinLocation) -> Option<BinLocation> {
+        match self {
+            Index::Hash(map) => map.insert(key, location).map(|old| *old.val()),
+            Index::Ordered(tree) => tree.write().unwrap_or_else(PoisonError::into_inner).insert(key, location),
+        }
+    }
+
+    /// point `key` to `new`, unless it points to a record in a later file.
+    /// Returns the length of the record overridden, which is garbage now.
+    fn override_record(&self, key: &str, new: BinLocation) -> Option<u64> {
+        match self.get(key) {
+            Some(old) if old.epoch > new.epoch => Some(new.length as u64),
+            _ => self.insert(key.to_owned(), new).map(|old| old.length as u64),
+        }
+    }
+
+    /// the keys and the locations of their last records, in the order of the keys if it's ordered.
+    /// The ordered index is copied, so that it isn't locked during the iteration.
+    fn entries(&self) -> Box<dyn Iterator<Item=(String, BinLocation)> + '_> {
+        match self {
+            Index::Hash(map) => Box::new(map.iter().map(|entry| (entry.key().to_owned(), *entry.val()))),
+            Index::Ordered(tree) => {
+                let entries: Vec<(String, BinLocation)> = tree
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .iter()
+                    .map(|(key, location)| (key.to_owned(), *location))
+                    .collect();
+                Box::new(entries.into_iter())
+            }
+        }
+    }
+
+    /// the entries whose keys start with `prefix` in order, or `None` if the index isn't ordered.
+    fn scan_prefix(&self, prefix: &str) -> Option<Vec<(String, BinLocation)>> {
+        match self {
+            Index::Hash(_) => None,
+            Index::Ordered(tree) => Some(
+                tree.read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+                    .take_while(|(key, _)| key.starts_with(prefix))
+                    .map(|(key, location)| (key.to_owned(), *location))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// An event of the compactions of a `KvStore`, see `KvStore::with_compaction_listener`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CompactionEvent {
+    /// a compaction starts, the writes go on to a new data file meanwhile.
+    Started {
+        /// the epoch of the data file the live records are rewritten into.
+        epoch: u64,
+    },
+    /// the compaction is going on, reported every `KvStore::COMPACTION_PROGRESS_KEYS` keys rewritten.
+    Progress {
+        /// the epoch of the data file the live records are rewritten into.
+        epoch: u64,
+        /// the bytes rewritten so far.
+        bytes_written: u64,
+    },
+    /// the compaction is published, the elder data files are dropped once no reader uses them.
+    Finished {
+        /// the epoch of the data file the live records are rewritten into.
+        epoch: u64,
+        /// the bytes rewritten.
+        bytes_written: u64,
+        /// the records in the elder data files that aren't rewritten.
+        records_dropped: u64,
+        /// how long the compaction takes.
+        elapsed: Duration,
+    },
+    /// the compaction failed, the elder data files are kept.
+    Failed {
+        /// the epoch of the data file the live records are rewritten into.
+        epoch: u64,
+        /// why it failed.
+        reason: String,
+    },
+}
+
+/// the callback of the compaction events.
+type CompactionListener = dyn Fn(&CompactionEvent) + Send + Sync;
+
+/// A durable marker of a `KvStore`, made by `KvStore::checkpoint`.
+///
+/// The data files before `epoch` are sealed, they are never written again.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// the first data file written after the checkpoint.
+    pub epoch: u64,
+}
+
+impl Checkpoint {
+    /// where the writes after the checkpoint start, to read them by `KvsEngine::changes`.
+    pub fn position(&self) -> LogPosition {
+        LogPosition { epoch: self.epoch, offset: 0 }
+    }
+}
+
+/// the content of the checkpoint file: the index when the checkpoint is made.
+#[derive(Serialize, Deserialize)]
+struct CheckpointFile {
+    checkpoint: Checkpoint,
+    steal: u64,
+    index: Vec<(String, BinLocation)>,
+    /// the `checksum_of` the index, absent in the checkpoints made before it's added.
+    #[serde(default)]
+    checksum: Option<u64>,
+}
+
+/// the FNV-1a hash of `index`, to detect the checkpoint files damaged on the disk.
+fn checksum_of(index: &[(String, BinLocation)]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    let mut hash = OFFSET_BASIS;
+    for (key, location) in index {
+        let fields = [location.epoch, location.offset as u64, location.length as u64];
+        let bytes = key.as_bytes().iter().copied()
+            .chain(std::iter::once(0))
+            .chain(fields.iter().flat_map(|field| field.to_le_bytes()));
+        for byte in bytes {
+            hash = (hash ^ byte as u64).wrapping_mul(PRIME);
+        }
+    }
+    hash
+}
+
+/// An entry of the index saved by a checkpoint, see `saved_index`.
+pub(crate) struct SavedEntry {
+    pub(crate) key: String,
+    pub(crate) epoch: u64,
+    pub(crate) offset: usize,
+    pub(crate) length: usize,
+}
+
+/// the epoch of the last checkpoint in `path`, and the index saved by it, for `offline::verify`.
+/// `None` if there's no checkpoint, or the reason why the opens ignore it if it's malformed or damaged.
+pub(crate) fn saved_index(storage: &dyn Storage, path: &Path) -> Option<std::result::Result<(u64, Vec<SavedEntry>), String>> {
+    let content = storage.read(&path.join(CHECKPOINT_FILE)).ok()?;
+    let loaded: CheckpointFile = match serde_json::from_slice(content.as_slice()) {
+        Ok(loaded) => loaded,
+        Err(err) => return Some(Err(format!("{}: malformed, {}", CHECKPOINT_FILE, err))),
+    };
+    if loaded.checksum.is_some_and(|checksum| checksum != checksum_of(loaded.index.as_slice())) {
+        return Some(Err(format!("{}: the index doesn't match its checksum", CHECKPOINT_FILE)));
+    }
+    let entries = loaded
+        .index
+        .into_iter()
+        .map(|(key, location)| SavedEntry { key, epoch: location.epoch, offset: location.offset, length: location.length })
+        .collect();
+    Some(Ok((loaded.checkpoint.epoch, entries)))
+}
+
+impl CheckpointFile {
+    /// load the last checkpoint in `path`, if it exists and every record it refers to is in `files`,
+    /// which are the lengths of the data files by their epochs.
+    fn load(storage: &dyn Storage, path: &Path, files: &HashMap<u64, u64>) -> Option<Self> {
+        let content = storage.read(&path.join(CHECKPOINT_FILE)).ok()?;
+        let loaded: CheckpointFile = match serde_json::from_slice(content.as_slice()) {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                warn!("ignoring the malformed checkpoint: {}.", err);
+                return None;
+            }
+        };
+        if loaded.checksum.is_some_and(|checksum| checksum != checksum_of(loaded.index.as_slice())) {
+            warn!("ignoring the damaged checkpoint at epoch {}.", loaded.checkpoint.epoch);
+            return None;
+        }
+        let exists = |location: &BinLocation| {
+            files
+                .get(&location.epoch)
+                .is_some_and(|len| (location.offset + location.length) as u64 <= *len)
+        };
+        if !loaded.index.iter().all(|(_, location)| exists(location)) {
+            warn!("ignoring the stale checkpoint at epoch {}.", loaded.checkpoint.epoch);
+            return None;
+        }
+        Some(loaded)
+    }
+
+    /// save it as the checkpoint in `path`.
+    fn save(&self, storage: &dyn Storage, path: &Path) -> Result<()> {
+        // written aside and renamed, so that a crash leaves either the old checkpoint or the new one.
+        storage.write_atomic(&path.join(CHECKPOINT_FILE), serde_json::to_vec(self)?.as_slice())?;
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 /// The default engine.
 ///
-/// It implements the in-memory Hash index like bitcask.
+/// It keeps an in-memory index of the keys like bitcask, a hash map by default,
+/// or a B-tree map keeping the keys in order, see `IndexKind` and `with_index`.
 /// Using epoch-based garbage collection.
 ///
 /// **Be aware**:
@@ -87,68 +486,302 @@ macro_rules! bin_loc {
 /// So it doesn't implement `Sync` trait.
 /// When you want to share it between threads, simply `copy` it instead of use `Arc`.
 pub struct KvStore<B1: BuildHasher = RandomState, B2: BuildHasher = RandomState> {
-    index: Arc<Map<String, BinLocation, B1>>,
+    index: Arc<Index<B1>>,
     reader: RefCell<KvReader<B2>>,
     writer: Arc<Mutex<KvWriter>>,
     current_epoch: Arc<AtomicU64>,
     tail_epoch: Arc<AtomicU64>,
     path: PathBuf,
     steal: Arc<AtomicU64>,
+    metrics: EngineMetrics,
+    storage: Arc<dyn Storage>,
+    history_versions: usize,
+    soft_delete: bool,
+    blob_threshold: Option<usize>,
+    sync: SyncPolicy,
+    write_path: WritePath,
+    /// the writes waiting for the writer, with `WritePath::Vectored`.
+    append_queue: Arc<Mutex<Vec<QueuedAppend>>>,
+    /// whether a compaction is running, the writes don't start another one meanwhile.
+    compacting: Arc<CompactionGate>,
+    compaction_listeners: Vec<Arc<CompactionListener>>,
+    record_cache: Option<Arc<Mutex<RecordCache>>>,
+    /// the estimated memory used by the index, see `index_bytes`.
+    index_bytes: Arc<AtomicU64>,
+    /// the count of the keys in the index, see `index_keys`.
+    index_keys: Arc<AtomicU64>,
+    index_budget: Option<u64>,
+    index_warning: Option<u64>,
+    slow_threshold: Option<Duration>,
+    codec: Arc<dyn RecordCodec>,
+}
+
+/// The records read by the lookups recently, evicting the least recently used ones beyond its capacity in bytes.
+///
+/// The records are cached by their locations, which never hold another record since the data files are append-only,
+/// so the writes don't invalidate them, and the ones no key points to any more just age out.
+struct RecordCache {
+    capacity: usize,
+    used: usize,
+    clock: u64,
+    records: HashMap<BinLocation, (u64, CachedRecord)>,
+    /// the locations by the time they are used.
+    recency: BTreeMap<u64, BinLocation>,
+}
+
+/// A record in the `RecordCache`, whose value is kept apart as bytes,
+/// so that `KvsEngine::get_bytes` shares it instead of copying it.
+#[derive(Clone)]
+struct CachedRecord {
+    /// the record without its value.
+    command: KvCommand,
+    value: Bytes,
+}
+
+impl CachedRecord {
+    fn new(mut command: KvCommand) -> Self {
+        let value = match &mut command {
+            Put { value, .. } => Bytes::from(std::mem::take(value)),
+            Rm { .. } => Bytes::new(),
+        };
+        CachedRecord { command, value }
+    }
+
+    /// the whole record, with a copy of its value.
+    fn command(&self) -> KvCommand {
+        let mut command = self.command.clone();
+        if let Put { value, .. } = &mut command {
+            *value = String::from_utf8_lossy(&self.value).into_owned();
+        }
+        command
+    }
+}
+
+impl RecordCache {
+    fn new(capacity: usize) -> Self {
+        RecordCache {
+            capacity,
+            used: 0,
+            clock: 0,
+            records: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, location: BinLocation) -> Option<CachedRecord> {
+        self.clock += 1;
+        let (used_at, record) = self.records.get_mut(&location)?;
+        self.recency.remove(used_at);
+        *used_at = self.clock;
+        self.recency.insert(self.clock, location);
+        Some(record.clone())
+    }
+
+    /// cache `record` read from `location`, unless it's larger than the whole cache.
+    fn insert(&mut self, location: BinLocation, record: CachedRecord) {
+        if location.length > self.capacity || self.records.contains_key(&location) {
+            return;
+        }
+        while self.used + location.length > self.capacity {
+            let (_, evicted) = match self.recency.pop_first() {
+                Some(eldest) => eldest,
+                None => break,
+            };
+            self.records.remove(&evicted);
+            self.used -= evicted.length;
+        }
+        self.clock += 1;
+        self.used += location.length;
+        self.records.insert(location, (self.clock, record));
+        self.recency.insert(self.clock, location);
+    }
+}
+
+/// Whether a compaction is running, or an ingestion, a checkpoint or a backup holding the compactions off.
+/// The ones waiting for it sleep until it's left, instead of polling it.
+#[derive(Debug, Default)]
+pub(crate) struct CompactionGate {
+    busy: Mutex<bool>,
+    idle: Condvar,
+}
+
+impl CompactionGate {
+    /// enter it if nobody is in it, returns whether it's entered.
+    fn try_enter(&self) -> bool {
+        let mut busy = self.busy.lock().unwrap_or_else(PoisonError::into_inner);
+        !std::mem::replace(&mut *busy, true)
+    }
+
+    /// wait until nobody is in it, then enter it.
+    fn enter(&self) {
+        let mut busy = self.wait_until_left();
+        *busy = true;
+    }
+
+    /// leave it, waking the ones waiting for it.
+    fn leave(&self) {
+        *self.busy.lock().unwrap_or_else(PoisonError::into_inner) = false;
+        self.idle.notify_all();
+    }
+
+    /// wait until nobody is in it, without entering it.
+    pub(crate) fn wait_idle(&self) {
+        drop(self.wait_until_left());
+    }
+
+    fn wait_until_left(&self) -> MutexGuard<'_, bool> {
+        let busy = self.busy.lock().unwrap_or_else(PoisonError::into_inner);
+        self.idle.wait_while(busy, |busy| *busy).unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// where a write queued by `KvStore::save_queued` gets the bytes it writes, or its error.
+type AppendSlot = Arc<Mutex<Option<Result<u64>>>>;
+
+/// a write of a single command waiting for the writer, see `WritePath::Vectored`.
+struct QueuedAppend {
+    command: KvCommand,
+    written: AppendSlot,
+}
+
+fn put_appended(slot: &AppendSlot, written: Result<u64>) {
+    *slot.lock().unwrap_or_else(PoisonError::into_inner) = Some(written);
+}
+
+/// write all of `slices` to `file`, by as few vectored writes as the OS takes them.
+fn write_all_vectored(file: &mut dyn DataFile, mut slices: &mut [IoSlice<'_>]) -> io::Result<()> {
+    while !slices.is_empty() {
+        match file.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
 }
 
 struct KvWriter {
-    file: File,
+    file: Box<dyn DataFile>,
     path: PathBuf,
     current_epoch: u64,
+    storage: Arc<dyn Storage>,
+    codec: Arc<dyn RecordCodec>,
 }
 
 impl KvWriter {
     pub fn write_command(&mut self, command: KvCommand) -> Result<BinLocation> {
-        let serialized = Self::serialize_command(&command);
+        Ok(self.write_commands(std::slice::from_ref(&command))?.remove(0))
+    }
+
+    /// append `commands` by one write, returns their locations.
+    pub fn write_commands(&mut self, commands: &[KvCommand]) -> Result<Vec<BinLocation>> {
         let writer = &mut self.file;
         let offset = writer.seek_to_end()?;
-        writer.write_all(serialized.as_bytes())?;
-        writer.flush()?;
-        Ok(bin_loc! { Gen[self.current_epoch] offset => serialized.as_bytes().len() })
+        let mut serialized = Vec::new();
+        let mut locations = Vec::with_capacity(commands.len());
+        for command in commands {
+            let record = self.codec.encode(command);
+            locations.push(bin_loc! { Gen[self.current_epoch] offset + serialized.len() => record.len() });
+            serialized.extend_from_slice(record.as_slice());
+        }
+        if let Err(err) = writer.write_all(serialized.as_slice()).and_then(|_| writer.flush()) {
+            // a torn record in the middle of the file breaks the index on reopening, so try to drop it.
+            let _ = writer.set_len(offset as u64);
+            return Err(err.into());
+        }
+        fail_point!("kvs::after_append", |_| Err(failpoint_error("kvs::after_append")));
+        Ok(locations)
     }
 
-    pub fn open(p: impl AsRef<Path>, gen: u64) -> Result<Self> {
-        let file = read_file_of(&p, gen)?;
+    /// append `commands` by vectored writes from their encodings without copying them together,
+    /// returns their locations.
+    pub fn write_commands_vectored(&mut self, commands: &[KvCommand]) -> io::Result<Vec<BinLocation>> {
+        let offset = self.file.seek_to_end()?;
+        let records: Vec<Vec<u8>> = commands.iter().map(|command| self.codec.encode(command)).collect();
+        let mut locations = Vec::with_capacity(records.len());
+        let mut end = offset;
+        for record in records.iter() {
+            locations.push(bin_loc! { Gen[self.current_epoch] end => record.len() });
+            end += record.len();
+        }
+        let mut slices: Vec<IoSlice<'_>> = records.iter().map(|record| IoSlice::new(record.as_slice())).collect();
+        if let Err(err) = write_all_vectored(self.file.as_mut(), slices.as_mut_slice()) {
+            // like `write_commands`, drop the torn records.
+            let _ = self.file.set_len(offset as u64);
+            return Err(err);
+        }
+        fail_point!("kvs::after_append", |_| Err(io::Error::other(
+            failpoint_error("kvs::after_append").to_string()
+        )));
+        Ok(locations)
+    }
+
+    pub fn open(storage: Arc<dyn Storage>, codec: Arc<dyn RecordCodec>, p: impl AsRef<Path>, gen: u64) -> Result<Self> {
+        let file = read_file_of(storage.as_ref(), &p, gen)?;
+        Ok(KvWriter {
+            file,
+            path: p.as_ref().to_owned(),
+            current_epoch: gen,
+            storage,
+            codec,
+        })
+    }
+
+    /// open the writer of a compaction or an ingestion to `gen`, which writes aside until `KvWriter::publish`.
+    pub fn open_compacting(storage: Arc<dyn Storage>, codec: Arc<dyn RecordCodec>, p: impl AsRef<Path>, gen: u64) -> Result<Self> {
+        let filename = p.as_ref().join(compacting_filename_of(gen));
+        let file = storage.open_append(&filename).map_err(|e| KvError::FailToOpenFile {
+            file_name: compacting_filename_of(gen),
+            io_error: e,
+        })?;
         Ok(KvWriter {
             file,
             path: p.as_ref().to_owned(),
             current_epoch: gen,
+            storage,
+            codec,
         })
     }
 
+    /// make the file written aside durable, then rename it to the data file of its epoch,
+    /// so that a crash leaves either no data file of the epoch or the whole of it.
+    pub fn publish(&mut self) -> Result<()> {
+        self.file.sync()?;
+        fail_point!("kvs::before_compaction_rename", |_| Err(failpoint_error("kvs::before_compaction_rename")));
+        self.storage.rename(
+            &self.path.join(compacting_filename_of(self.current_epoch)),
+            &self.path.join(filename_of(self.current_epoch)),
+        )?;
+        self.storage.sync_dir(&self.path)?;
+        Ok(())
+    }
+
     pub fn set_epoch(&mut self, epoch: u64) -> Result<()> {
-        let new_file = read_file_of(&self.path, epoch)?;
+        let new_file = read_file_of(self.storage.as_ref(), &self.path, epoch)?;
         self.file = new_file;
         self.current_epoch = epoch;
         Ok(())
     }
-
-    /// support method for serialize one command.
-    pub fn serialize_command(command: &KvCommand) -> String {
-        let mut serialized = serde_json::to_string(&command).unwrap();
-        serialized.push('\n');
-        serialized
-    }
 }
 
 struct KvReader<B: BuildHasher = RandomState> {
-    readers: BTreeMap<u64, File>,
+    readers: BTreeMap<u64, Box<dyn ReadFile>>,
     tail_epoch: Arc<AtomicU64>,
     root: PathBuf,
+    storage: Arc<dyn Storage>,
     active: Arc<Map<u64, AtomicU64, B>>,
+    codec: Arc<dyn RecordCodec>,
 }
 
 impl<B: BuildHasher> Clone for KvReader<B> {
     fn clone(&self) -> Self {
         KvReader::open(
+            self.storage.clone(),
             self.root.clone(),
             self.tail_epoch.clone(),
             self.active.clone(),
+            self.codec.clone(),
         ).unwrap()
     }
 }
@@ -167,14 +800,13 @@ impl<B: BuildHasher> KvReader<B> {
     fn open_epoch(
         &mut self,
         epoch: u64,
-    ) -> Result<&mut File> {
+    ) -> Result<&mut Box<dyn ReadFile>> {
         if epoch < self.tail_epoch.load(Ordering::SeqCst) {
             panic!("KV_READER: trying to open an file that elder than current epoch!");
         }
-        if self.readers.get(&epoch).is_none() {
-            self.readers.insert(epoch, OpenOptions::new()
-                .read(true)
-                .open(self.root.join(filename_of(epoch).as_str()))
+        if !self.readers.contains_key(&epoch) {
+            self.readers.insert(epoch, self.storage
+                .open_read(&self.root.join(filename_of(epoch).as_str()))
                 .map_err(|e| KvError::FailToOpenFile {
                     file_name: filename_of(epoch),
                     io_error: e,
@@ -197,7 +829,7 @@ impl<B: BuildHasher> KvReader<B> {
         }
         if count.val().load(Ordering::SeqCst) == 0 && epoch < self.tail_epoch.load(Ordering::SeqCst) {
             self.active.remove(&epoch);
-            std::fs::remove_file(self.root.join(filename_of(epoch)));
+            self.storage.remove(&self.root.join(filename_of(epoch)));
         }
         Ok(())
     }
@@ -216,55 +848,175 @@ impl<B: BuildHasher> KvReader<B> {
         Ok(())
     }
 
-    /// load a command from one `BinLocation`.
-    pub fn load_command(&mut self, location: BinLocation) -> Result<KvCommand> {
+    /// load the command of `key` from one `BinLocation`.
+    ///
+    /// # Error
+    ///
+    /// The error is wrapped in `KvError::WithContext` with the location of the record.
+    pub fn load_command(&mut self, key: &str, location: BinLocation) -> Result<KvCommand> {
+        self.read_command(location).with_context(|| ErrorContext {
+            operation: "load_command",
+            file_name: filename_of(location.epoch),
+            offset: location.offset,
+            key: Some(key.to_owned()),
+        })
+    }
+
+    fn read_command(&mut self, location: BinLocation) -> Result<KvCommand> {
         self.forget_old_time()?;
 
         let reader = self.open_epoch(location.epoch)?;
         let mut buf = vec![0u8; location.length];
         reader.seek_to(location.offset)?;
         reader.read_exact(buf.as_mut_slice())?;
-        let r = serde_json::from_slice(buf.as_slice());
-        r.map_err(|e| e.into())
+        self.codec.decode(buf.as_slice())
     }
 
     pub fn open(
+        storage: Arc<dyn Storage>,
         path: impl AsRef<Path>,
         epoch: Arc<AtomicU64>,
         active: Arc<Map<u64, AtomicU64, B>>,
+        codec: Arc<dyn RecordCodec>,
     ) -> Result<Self> {
         Ok(KvReader {
             readers: BTreeMap::new(),
             root: path.as_ref().to_owned(),
+            storage,
             tail_epoch: epoch,
             active,
+            codec,
         })
     }
 }
 
 impl KvStore {
     const STEAL_THRESHOLDS: u64 = 1024 * 1024 * 8; // 8MB
+    /// how many keys a compaction rewrites between its `CompactionEvent::Progress` events.
+    pub const COMPACTION_PROGRESS_KEYS: usize = 1024;
+    /// the estimated memory an index entry takes besides its key, see `index_bytes`.
+    pub const INDEX_ENTRY_OVERHEAD: u64 = 64;
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-enum KvCommand {
-    Put { key: String, value: String },
-    Rm { key: String },
+/// A record of the data files of a `KvStore`, encoded by its `RecordCodec`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum KvCommand {
+    /// a value set.
+    Put {
+        /// the key set.
+        key: String,
+        /// the value set.
+        value: String,
+        /// the milliseconds since the unix epoch when it's written, absent in the records of older versions.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        modified: Option<u64>,
+        /// the user metadata set with the value.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        meta: Option<String>,
+        /// the previous version of the key, only written when the store retains history.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prev: Option<BinLocation>,
+        /// the blob file of the value when it's spilled, then `value` is empty.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        blob: Option<String>,
+        /// the count of the records written right after it as one batch, see `KvStore::save_batch_locked`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        batch: Option<usize>,
+        /// the type of the value set by `set_typed`, absent for the strings.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        kind: Option<ValueType>,
+    },
+    /// a key removed.
+    Rm {
+        /// the key removed.
+        key: String,
+        /// the value removed, only written when the store retains history or makes the removals recoverable.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prev: Option<BinLocation>,
+        /// like the `batch` of `Put`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        batch: Option<usize>,
+    },
 }
 
 impl KvCommand {
-    fn set(key: String, value: String) -> Self {
-        Self::Put { key, value }
+    fn set(key: String, value: String, meta: Option<String>) -> Self {
+        let modified = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .ok();
+        Self::Put { key, value, modified, meta, prev: None, blob: None, batch: None, kind: None }
+    }
+
+    fn set_typed(key: String, value: TypedValue, meta: Option<String>) -> Self {
+        let mut command = Self::set(key, value.encode(), meta);
+        if let Put { kind, .. } = &mut command {
+            // keep the records of the strings as they're without types.
+            *kind = Some(value.value_type()).filter(|value_type| *value_type != ValueType::String);
+        }
+        command
     }
 
     fn remove(key: String) -> Self {
-        Self::Rm { key }
+        Self::Rm { key, prev: None, batch: None }
+    }
+
+    pub(crate) fn prev(&self) -> Option<BinLocation> {
+        match self {
+            KvCommand::Put { prev, .. } | KvCommand::Rm { prev, .. } => *prev,
+        }
+    }
+
+    fn with_prev(mut self, location: Option<BinLocation>) -> Self {
+        match &mut self {
+            KvCommand::Put { prev, .. } | KvCommand::Rm { prev, .. } => *prev = location,
+        }
+        self
+    }
+
+    fn batch(&self) -> Option<usize> {
+        match self {
+            KvCommand::Put { batch, .. } | KvCommand::Rm { batch, .. } => *batch,
+        }
+    }
+
+    fn in_batch(mut self, following: Option<usize>) -> Self {
+        match &mut self {
+            KvCommand::Put { batch, .. } | KvCommand::Rm { batch, .. } => *batch = following,
+        }
+        self
+    }
+
+    fn into_value(self) -> Option<ValueWithMeta> {
+        match self {
+            Rm { .. } => None,
+            Put { value, modified, meta, .. } => Some(ValueWithMeta {
+                value,
+                modified: modified.map(|millis| UNIX_EPOCH + Duration::from_millis(millis)),
+                meta,
+            }),
+        }
+    }
+
+    /// the value with its type, and the metadata of it.
+    fn into_typed(self) -> Result<Option<(TypedValue, Option<String>)>> {
+        match self {
+            Rm { .. } => Ok(None),
+            Put { key, value, meta, kind, .. } => {
+                let value_type = kind.unwrap_or_default();
+                let value = TypedValue::decode(value_type, value).ok_or_else(|| KvError::CorruptedRecord {
+                    reason: format!("the value of {:?} isn't of type {}.", key, value_type.as_ref()),
+                })?;
+                Ok(Some((value, meta)))
+            }
+        }
     }
 
-    fn key(&self) -> &str {
+    /// the key the record writes.
+    pub fn key(&self) -> &str {
         match self {
             KvCommand::Put { key, .. } => key,
-            KvCommand::Rm { key } => key,
+            KvCommand::Rm { key, .. } => key,
         }
             .as_str()
     }
@@ -277,153 +1029,770 @@ impl KvsEngine for KvStore {
     ///
     /// when IO/serialize error happens during read data before the log, we will
     fn get(&self, key: String) -> Result<Option<String>> {
-        let cache = self.index.get(key.as_str());
-        if cache.is_none() {
-            return Ok(None);
-        }
-        let pos = cache.unwrap();
-        let cmd = self.reader.borrow_mut().load_command(pos.val().clone())?;
-        match cmd {
-            Rm { .. } => Ok(None),
-            Put { value, .. } => Ok(Some(value)),
-        }
+        Ok(self.get_with_meta(key)?.map(|found| found.value))
     }
 
-    /// Put a value into the KvStore.
-    /// This operation will be automatically persisted into the log file.
-    ///
-    /// # Error
-    ///
-    /// when IO/serialize error happens during save the command into log, will throw error about them.
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let command = KvCommand::set(key.clone(), value);
-        self.save_command(command)?;
-        Ok(())
+    /// get a value from the KvStore, with the time and the metadata written in its record.
+    fn get_with_meta(&self, key: String) -> Result<Option<ValueWithMeta>> {
+        self.timed("get", || {
+            let cache = self.index.get(key.as_str());
+            if cache.is_none() {
+                self.metrics.record_get(false);
+                return Ok(None);
+            }
+            let pos = cache.unwrap();
+            let command = self.load_record(&key, pos)?;
+            let value = self.load_blob(command)?.into_value();
+            self.metrics.record_get(value.is_some());
+            Ok(value)
+        })
     }
 
-    /// Remove an value from the KvStore
-    ///
-    /// # Error
-    ///
-    /// when the key isn't present, will throw `KeyNotFound`.
-    /// when IO/serialize error happens during save the command into log, will throw error about them.
-    fn remove(&self, key: String) -> Result<()> {
-        if self.index.get(key.as_str()).is_none() {
-            return Err(KeyNotFound);
-        }
+    /// get the value without copying it out of the record cache, see `with_record_cache`.
+    fn get_bytes(&self, key: String) -> Result<Option<Bytes>> {
+        self.timed("get", || {
+            let location = match self.index.get(key.as_str()) {
+                Some(location) => location,
+                None => {
+                    self.metrics.record_get(false);
+                    return Ok(None);
+                }
+            };
+            let value = self.load_value_bytes(&key, location)?;
+            self.metrics.record_get(value.is_some());
+            Ok(value)
+        })
+    }
+
+    /// decode the value by the type written in its record.
+    fn get_typed(&self, key: String) -> Result<Option<TypedValue>> {
+        self.timed("get", || {
+            let location = match self.index.get(key.as_str()) {
+                Some(location) => location,
+                None => {
+                    self.metrics.record_get(false);
+                    return Ok(None);
+                }
+            };
+            let command = self.load_record(&key, location)?;
+            let value = self.load_blob(command)?.into_typed()?.map(|(value, _)| value);
+            self.metrics.record_get(value.is_some());
+            Ok(value)
+        })
+    }
 
-        let command = KvCommand::remove(key.clone());
-        self.save_command(command)?;
+    fn set_typed(&self, key: String, value: TypedValue) -> Result<()> {
+        let written = self.save_command(KvCommand::set_typed(key, value, None))?;
+        self.metrics.record_set(written);
         Ok(())
     }
-}
 
-struct InitIndex {
-    index: Map<String, BinLocation>,
+    /// check and write the sum under the lock of the writer, keeping the metadata like `append`.
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let writer = self.writer.lock()?;
+        let (current, meta) = match self.load_typed_locked(key.as_str())? {
+            Some((value, meta)) => {
+                let current = value.as_integer().ok_or(KvError::WrongType {
+                    expected: ValueType::Integer,
+                    found: value.value_type(),
+                })?;
+                (current, meta)
+            }
+            None => (0, None),
+        };
+        let sum = current.checked_add(delta).ok_or_else(|| KvError::Other {
+            reason: format!("adding {} to {} overflows.", delta, current),
+        })?;
+        let written = self.save_command_locked(writer, KvCommand::set_typed(key, TypedValue::Integer(sum), meta))?;
+        self.metrics.record_set(written);
+        Ok(sum)
+    }
+
+    /// look up and write under the lock of the writer, so that no other write to the key comes between.
+    fn get_or_insert_with(&self, key: String, default: impl FnOnce() -> String) -> Result<String> {
+        let writer = self.writer.lock()?;
+        if let Some(location) = self.index.get(key.as_str()) {
+            let command = self.load_record(&key, location)?;
+            if let Some(found) = self.load_blob(command)?.into_value() {
+                self.metrics.record_get(true);
+                return Ok(found.value);
+            }
+        }
+        self.metrics.record_get(false);
+        let value = default();
+        let written = self.save_command_locked(writer, KvCommand::set(key, value.clone(), None))?;
+        self.metrics.record_set(written);
+        Ok(value)
+    }
+
+    /// check and write under the lock of the writer, the removed keys are absent.
+    fn set_if(&self, key: String, value: String, condition: SetCondition) -> Result<bool> {
+        let writer = self.writer.lock()?;
+        let present = match self.index.get(key.as_str()) {
+            Some(location) => matches!(self.load_record(&key, location)?, Put { .. }),
+            None => false,
+        };
+        if present != (condition == SetCondition::IfPresent) {
+            return Ok(false);
+        }
+        let written = self.save_command_locked(writer, KvCommand::set(key, value, None))?;
+        self.metrics.record_set(written);
+        Ok(true)
+    }
+
+    /// check and write under the lock of the writer, like `set_if`.
+    /// The modified time is in milliseconds, so the new one is moved after the old one if they're the same,
+    /// then a stale write in the same millisecond still fails.
+    fn set_if_unmodified(&self, key: String, value: String, modified: SystemTime) -> Result<bool> {
+        let writer = self.writer.lock()?;
+        let current = match self.load_value_locked(key.as_str())? {
+            Some(current) => current,
+            None => return Ok(false),
+        };
+        let last = match current.modified {
+            Some(last) if last == modified => last,
+            _ => return Ok(false),
+        };
+        let mut command = KvCommand::set(key, value, None);
+        if let Put { modified: Some(millis), .. } = &mut command {
+            let last = last.duration_since(UNIX_EPOCH).map(|since| since.as_millis() as u64).unwrap_or_default();
+            *millis = (*millis).max(last + 1);
+        }
+        let written = self.save_command_locked(writer, command)?;
+        self.metrics.record_set(written);
+        Ok(true)
+    }
+
+    /// check and write under the lock of the writer, like `set_if`.
+    fn acquire_lease(&self, key: String, ttl: Duration) -> Result<Option<Lease>> {
+        let writer = self.writer.lock()?;
+        let current = self.load_value_locked(key.as_str())?;
+        if !Lease::is_free(key.as_str(), current.as_ref().map(|current| current.value.as_str()))? {
+            return Ok(None);
+        }
+        let lease = Lease::new(ttl);
+        let written = self.save_command_locked(writer, KvCommand::set(key, lease.encode(), None))?;
+        self.metrics.record_set(written);
+        Ok(Some(lease))
+    }
+
+    fn release_lease(&self, key: String, token: String) -> Result<bool> {
+        let writer = self.writer.lock()?;
+        let held = self
+            .load_value_locked(key.as_str())?
+            .and_then(|current| Lease::decode(current.value.as_str()))
+            .is_some_and(|lease| lease.token == token);
+        if !held {
+            return Ok(false);
+        }
+        let written = self.save_command_locked(writer, KvCommand::remove(key))?;
+        self.metrics.record_remove(true, written);
+        Ok(true)
+    }
+
+    /// write the value to `to` and remove `from` by one batch under the lock of the writer,
+    /// so that a crash during it leaves neither of them written.
+    /// It's a new write, so the modified time is renewed.
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        let writer = self.writer.lock()?;
+        let (value, meta) = self.load_typed_locked(from.as_str())?.ok_or(KeyNotFound)?;
+        if from == to {
+            return Ok(());
+        }
+        let commands = vec![KvCommand::set_typed(to, value, meta), KvCommand::remove(from)];
+        let written = self.save_batch_locked(writer, commands)?;
+        self.metrics.record_set(written);
+        Ok(())
+    }
+
+    /// write the value to `to` under the lock of the writer, with the modified time renewed.
+    fn copy(&self, from: String, to: String) -> Result<()> {
+        let writer = self.writer.lock()?;
+        let (value, meta) = self.load_typed_locked(from.as_str())?.ok_or(KeyNotFound)?;
+        let written = self.save_command_locked(writer, KvCommand::set_typed(to, value, meta))?;
+        self.metrics.record_set(written);
+        Ok(())
+    }
+
+    /// write the removals of the present keys under `prefix` by one batch under the lock of the writer,
+    /// so that a crash during it leaves all or none of them removed.
+    /// Like `list_keys`, the ordered index scans only the keys under the prefix, and the hash index walks all of them.
+    /// It stops before writing anything if the request passes its deadline during the scan.
+    fn remove_prefix(&self, prefix: String) -> Result<usize> {
+        self.timed("remove_prefix", || {
+            let writer = self.writer.lock()?;
+            let entries = match self.index.scan_prefix(prefix.as_str()) {
+                Some(scanned) => scanned,
+                None => self.index.entries().filter(|(key, _)| key.starts_with(prefix.as_str())).collect(),
+            };
+            let mut commands = Vec::new();
+            for (key, location) in entries {
+                context::check_deadline("remove_prefix")?;
+                // skip the keys removed already, kept in the soft-delete mode.
+                if let Put { .. } = self.load_record(&key, location)? {
+                    commands.push(KvCommand::remove(key));
+                }
+            }
+            let removed = commands.len();
+            if removed == 0 {
+                return Ok(0);
+            }
+            let written = self.save_batch_locked(writer, commands)?;
+            self.metrics.record_removes(removed as u64, written);
+            Ok(removed)
+        })
+    }
+
+    /// write the batch by one append under the lock of the writer, like `remove_prefix`,
+    /// with the last write of each key only, since a batch writes a key once.
+    fn write_batch(&self, writes: Vec<BatchWrite>) -> Result<()> {
+        self.timed("write_batch", || {
+            let writer = self.writer.lock()?;
+            let last: HashMap<&str, usize> = writes.iter().enumerate().map(|(nth, write)| (write.key(), nth)).collect();
+            let kept: HashSet<usize> = last.into_values().collect();
+            let (mut sets, mut removes) = (0, 0);
+            let mut commands = Vec::new();
+            for (nth, write) in writes.into_iter().enumerate() {
+                if !kept.contains(&nth) {
+                    continue;
+                }
+                match write {
+                    BatchWrite::Set { key, value } => {
+                        sets += 1;
+                        commands.push(KvCommand::set(key, value, None));
+                    }
+                    BatchWrite::Remove { key } => {
+                        // skip the keys absent, or removed already and kept in the soft-delete mode.
+                        let present = match self.index.get(key.as_str()) {
+                            Some(location) => matches!(self.load_record(&key, location)?, Put { .. }),
+                            None => false,
+                        };
+                        if present {
+                            removes += 1;
+                            commands.push(KvCommand::remove(key));
+                        }
+                    }
+                }
+            }
+            if commands.is_empty() {
+                return Ok(());
+            }
+            let written = self.save_batch_locked(writer, commands)?;
+            self.metrics.record_sets(sets, written);
+            self.metrics.record_removes(removes, 0);
+            Ok(())
+        })
+    }
+
+    /// write the whole new value as one record under the lock of the writer, keeping the metadata of the old one.
+    fn append(&self, key: String, suffix: String) -> Result<()> {
+        let writer = self.writer.lock()?;
+        let old = self.load_value_locked(key.as_str())?;
+        let (value, meta) = match old {
+            Some(old) => (old.value + suffix.as_str(), old.meta),
+            None => (suffix, None),
+        };
+        let written = self.save_command_locked(writer, KvCommand::set(key, value, meta))?;
+        self.metrics.record_set(written);
+        Ok(())
+    }
+
+    /// Put a value into the KvStore.
+    /// This operation will be automatically persisted into the log file.
+    ///
+    /// # Error
+    ///
+    /// when IO/serialize error happens during save the command into log, will throw error about them.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_with_meta(key, value, None)
+    }
+
+    /// Put a value into the KvStore, with the current time and `meta` in its record.
+    fn set_with_meta(&self, key: String, value: String, meta: Option<String>) -> Result<()> {
+        self.timed("set", || {
+            let command = KvCommand::set(key, value, meta);
+            let written = self.save_command(command)?;
+            self.metrics.record_set(written);
+            Ok(())
+        })
+    }
+
+    /// write the value into a blob file as it's read, whatever `with_blob_threshold` is,
+    /// then append the record pointing to it.
+    fn set_stream(&self, key: String, value: &mut dyn Read) -> Result<()> {
+        lazy_static! {
+            static ref NEXT_STAGED: AtomicU64 = AtomicU64::new(0);
+        }
+        // stage it aside, so that the writes of others aren't blocked by reading it.
+        let staged = staged_blob_name(NEXT_STAGED.fetch_add(1, Ordering::SeqCst));
+        let staged_path = self.path.join(staged.as_str());
+        let copied = self.storage.create(&staged_path).and_then(|mut file| {
+            let copied = io::copy(value, &mut file)?;
+            if self.sync == SyncPolicy::Always {
+                file.sync()?;
+            }
+            Ok(copied)
+        });
+        if let Err(err) = copied {
+            let _ = self.storage.remove(&staged_path);
+            return Err(err.into());
+        }
+        let command = match KvCommand::set(key, String::new(), None) {
+            Put { key, value, modified, meta, prev, batch, kind, .. } => Put { key, value, modified, meta, prev, blob: Some(staged), batch, kind },
+            Rm { .. } => unreachable!("`KvCommand::set` makes a `Put`."),
+        };
+        let written = self.save_command(command)?;
+        self.metrics.record_set(written);
+        Ok(())
+    }
+
+    /// get the value from its blob file if it's spilled, without reading it as a whole.
+    fn get_stream(&self, key: String) -> Result<Option<Box<dyn Read>>> {
+        let location = match self.index.get(key.as_str()) {
+            Some(location) => location,
+            None => {
+                self.metrics.record_get(false);
+                return Ok(None);
+            }
+        };
+        let stream: Option<Box<dyn Read>> = match self.load_record(&key, location)? {
+            Put { blob: Some(blob), .. } => Some(self.storage.open_read(&self.path.join(blob))?),
+            Put { value, .. } => Some(Box::new(io::Cursor::new(value.into_bytes()))),
+            Rm { .. } => None,
+        };
+        self.metrics.record_get(stream.is_some());
+        Ok(stream)
+    }
+
+    /// Remove an value from the KvStore
+    ///
+    /// # Error
+    ///
+    /// when the key isn't present, will throw `KeyNotFound`.
+    /// when IO/serialize error happens during save the command into log, will throw error about them.
+    fn remove(&self, key: String) -> Result<()> {
+        self.timed("remove", || {
+            if self.index.get(key.as_str()).is_none() {
+                self.metrics.record_remove(false, 0);
+                return Err(KeyNotFound);
+            }
+
+            let command = KvCommand::remove(key.clone());
+            let written = self.save_command(command)?;
+            self.metrics.record_remove(true, written);
+            Ok(())
+        })
+    }
+
+    /// list the keys matching `pattern`.
+    ///
+    /// The hash index has no order, so it walks and sorts the whole index whatever the pattern is,
+    /// and the ordered index scans only the keys with the literal prefix of the pattern, see `with_index`.
+    /// It only reads the last records of the matched keys until the bounds are reached,
+    /// to skip the removed ones, and stops reading them if the request passes its deadline.
+    /// The keys before the cursor of `options` are skipped by the index alone, since they're compared by themselves.
+    fn list_keys(&self, pattern: String, options: ListOptions) -> Result<Vec<String>> {
+        self.timed("list_keys", || {
+            let pattern = KeyPattern::new(pattern.as_str());
+            let wanted = |key: &str| pattern.matches(key) && options.is_after_cursor(key);
+            let mut matched: Vec<(String, BinLocation)> = match self.index.scan_prefix(pattern.literal_prefix()) {
+                Some(scanned) => scanned.into_iter().filter(|(key, _)| wanted(key)).collect(),
+                None => {
+                    let mut matched: Vec<(String, BinLocation)> =
+                        self.index.entries().filter(|(key, _)| wanted(key)).collect();
+                    matched.sort_unstable();
+                    matched
+                }
+            };
+            if options.reverse {
+                matched.reverse();
+            }
+            let mut skipped = 0;
+            let mut keys = Vec::new();
+            for (key, location) in matched {
+                if keys.len() >= options.max_len() {
+                    break;
+                }
+                context::check_deadline("list_keys")?;
+                if let Rm { .. } = self.load_record(&key, location)? {
+                    continue;
+                }
+                if skipped < options.offset {
+                    skipped += 1;
+                    continue;
+                }
+                keys.push(key);
+            }
+            Ok(keys)
+        })
+    }
+
+    /// restore the value removed in the soft-delete mode, with its metadata, see `with_soft_delete`.
+    /// It's a new write, so the modified time is renewed.
+    /// Undeleting a present key does nothing.
+    ///
+    /// # Error
+    ///
+    /// when the key isn't removed in the soft-delete mode, or the removed value is dropped by a compaction,
+    /// will throw `KeyNotFound`.
+    fn undelete(&self, key: String) -> Result<()> {
+        let mut latest = true;
+        let mut present = false;
+        let mut removed = None;
+        // removing a removed key links the removals, so skip all of them.
+        self.walk_versions(key.as_str(), |command| {
+            let is_latest = std::mem::replace(&mut latest, false);
+            match command {
+                Put { .. } if is_latest => {
+                    present = true;
+                    false
+                }
+                Put { .. } => {
+                    removed = Some(command);
+                    false
+                }
+                Rm { .. } => true,
+            }
+        })?;
+        if present {
+            return Ok(());
+        }
+        match removed.map(|command| self.load_blob(command)).transpose()?.and_then(KvCommand::into_value) {
+            Some(removed) => self.set_with_meta(key, removed.value, removed.meta),
+            None => Err(KeyNotFound),
+        }
+    }
+
+    /// the records in the data files since `since`, see `Changes`.
+    /// The changes since the default position start from the eldest data file.
+    ///
+    /// # Error
+    ///
+    /// If a compaction drops the data files since `since`, it throws,
+    /// then the changes should be read from the beginning again.
+    fn changes(&self, since: LogPosition, follow: bool) -> Result<Box<dyn Iterator<Item=Result<Change>> + Send>> {
+        let tail = self.tail_epoch.load(Ordering::SeqCst);
+        let since = if since == LogPosition::default() { LogPosition { epoch: tail, offset: 0 } } else { since };
+        if since.epoch < tail {
+            return Err(KvError::Other {
+                reason: format!("the changes since {} are dropped by a compaction", since),
+            });
+        }
+        let changes = Changes::new(
+            self.storage.clone(),
+            self.path.clone(),
+            self.current_epoch.clone(),
+            self.tail_epoch.clone(),
+            self.compacting.clone(),
+            self.codec.clone(),
+            since,
+        );
+        Ok(Box::new(if follow { changes.follow() } else { changes }))
+    }
+
+    /// the end of the data file being written, under the lock of the writer so that no record is half written.
+    fn log_end(&self) -> Result<LogPosition> {
+        let mut writer = self.writer.lock()?;
+        let offset = writer.file.seek_to_end()?;
+        Ok(LogPosition { epoch: writer.current_epoch, offset })
+    }
+
+    fn metrics(&self) -> EngineMetrics {
+        self.metrics.clone()
+    }
+
+    /// load the records through the cache if `with_record_cache` is set, which may evict the others,
+    /// or else read them so that they're in the page cache of the OS.
+    /// The values spilled into blob files aren't loaded.
+    fn preload(&self, target: Preload) -> Result<usize> {
+        self.timed("preload", || {
+            let entries = match target {
+                Preload::Keys(keys) => keys
+                    .into_iter()
+                    .filter_map(|key| self.index.get(key.as_str()).map(|location| (key, location)))
+                    .collect(),
+                Preload::Prefix(prefix) => match self.index.scan_prefix(prefix.as_str()) {
+                    Some(scanned) => scanned,
+                    None => self.index.entries().filter(|(key, _)| key.starts_with(prefix.as_str())).collect(),
+                },
+            };
+            let mut loaded = 0;
+            for (key, location) in entries {
+                context::check_deadline("preload")?;
+                // skip the keys removed already, kept in the soft-delete mode.
+                if let Put { .. } = self.load_record(&key, location)? {
+                    loaded += 1;
+                }
+            }
+            Ok(loaded)
+        })
+    }
+
+    fn close(&self) -> Result<()> {
+        self.close_cleanly().map(|_| ())
+    }
+}
+
+struct InitIndex {
+    index: Index,
     epoch: u64,
     tail_epoch: u64,
     steal: u64,
 }
 
 impl InitIndex {
-    fn new() -> Self {
+    fn new(kind: IndexKind) -> Self {
         InitIndex {
-            index: Map::new(),
+            index: Index::new(kind),
             epoch: 0,
-            tail_epoch: u64::max_value(),
+            tail_epoch: u64::MAX,
             steal: 0,
         }
     }
 
     fn override_record(&mut self, key: &str, new: BinLocation) -> Option<u64> {
-        match self.index.get(key) {
-            Some(ref old) if old.val().epoch > new.epoch => Some(new.length as u64),
-            _ => {
-                self.index
-                    .insert(key.to_owned(), new)
-                    .map(|old| old.val().length as u64)
-            }
-        }
+        self.index.override_record(key, new)
     }
 }
 
 impl KvStore {
-    fn enumerate_epoch_files(p: impl AsRef<Path>) -> impl Iterator<Item=(PathBuf, u64)> {
-        WalkDir::new(p)
+    pub(crate) fn enumerate_epoch_files(storage: &dyn Storage, p: impl AsRef<Path>) -> impl Iterator<Item=(PathBuf, u64)> {
+        let p = p.as_ref().to_owned();
+        // only the files right in it, the restored data directories may be under it, see `Restorable`.
+        storage
+            .list(&p)
+            .unwrap_or_default()
             .into_iter()
-            .filter(|entry| {
-                entry
-                    .as_ref()
-                    .map_err(|_| ())
-                    .and_then(|entry| {
-                        into_result(
-                            entry
-                                .file_name()
-                                .to_str()
-                                .map(|s| s.starts_with("kvs-data-")),
-                        )
-                    })
-                    .unwrap_or(false)
-            })
-            .map(|file| {
-                let file = file.unwrap();
-                let path = file.path().to_owned();
-                let gen = file.file_name().to_str().and_then(parse_gen).unwrap();
-                (path, gen)
-            })
-    }
-
-    /// build the in-memory index from file.
-    fn build_index(path: impl AsRef<Path>) -> Result<InitIndex> {
-        let entries: Vec<(PathBuf, u64)> = KvStore::enumerate_epoch_files(path).collect();
-        let mut res = InitIndex::new();
+            // not the unfinished files of compactions, like `kvs-data-3.compacting`.
+            .filter_map(move |name| parse_gen(name.as_str()).map(|gen| (p.join(name), gen)))
+    }
+
+    /// build the in-memory index of the kind recorded in the data directory from file.
+    /// When `salvage`, the files are cut at their corrupted records like the torn ones, instead of failing.
+    fn build_index(
+        storage: &dyn Storage,
+        path: impl AsRef<Path>,
+        codec: &dyn RecordCodec,
+        salvage: bool,
+    ) -> Result<InitIndex> {
+        let entries: Vec<(PathBuf, u64)> = KvStore::enumerate_epoch_files(storage, path.as_ref()).collect();
+        let mut res = InitIndex::new(IndexKind::recorded_in(storage, path.as_ref())?);
         if entries.is_empty() {
             res.epoch = 1;
             res.tail_epoch = 0;
             return Ok(res);
         }
+        let lengths: HashMap<u64, u64> = entries
+            .iter()
+            .filter_map(|(filename, epoch)| storage.size(filename).ok().map(|len| (*epoch, len)))
+            .collect();
+        let replay_from = match CheckpointFile::load(storage, path.as_ref(), &lengths) {
+            Some(checkpoint) => {
+                for (key, location) in checkpoint.index {
+                    res.override_record(key.as_str(), location);
+                }
+                res.steal = checkpoint.steal;
+                checkpoint.checkpoint.epoch
+            }
+            None => 0,
+        };
+        // after a clean shutdown, the checkpoint has all the records, unless a clone of the closed store writes after it.
+        let clean = KvStore::take_clean_marker(storage, path.as_ref())? == Some(replay_from)
+            && entries.iter().all(|(_, epoch)| *epoch < replay_from || lengths.get(epoch) == Some(&0));
+        if !clean {
+            warn!("recovering from an unclean shutdown, replaying the data files since epoch {}.", replay_from);
+        }
 
         for (filename, epoch) in entries {
-            let mut buf = String::new();
-            let mut reader = BufReader::new(File::open(filename)?);
-            let mut x;
+            let mut buf = Vec::new();
+            let mut offset = 0;
+            let context = |offset| {
+                move || ErrorContext {
+                    operation: "build_index",
+                    file_name: filename_of(epoch),
+                    offset,
+                    key: None,
+                }
+            };
             if epoch > res.epoch {
                 res.epoch = epoch;
             }
             if epoch < res.tail_epoch {
                 res.tail_epoch = epoch;
             }
-            while {
-                x = reader.read_line(&mut buf)?;
-                x > 0
-            } {
-                let json: KvCommand = serde_json::from_slice(buf.as_bytes())?;
-                let offset = reader.current_position()?;
-                if let Some(n) =
-                res.override_record(json.key(), bin_loc! {Gen[epoch] offset - x => x })
-                {
-                    res.steal += n
+            // the records before the checkpoint are in its index already.
+            if epoch < replay_from || clean {
+                continue;
+            }
+            // opened only when replayed, since a storage like `ArchiveStorage` may have to download it.
+            let mut reader = BufReader::new(storage.open_read(&filename)?);
+            // the records of the batch being read, and where it starts, indexed once it's read completely.
+            let mut batch: Vec<(String, BinLocation)> = Vec::new();
+            let mut batch_start = 0;
+            let mut batch_left = 0;
+            let mut torn = false;
+            loop {
+                let record = codec
+                    .read_record(&mut reader, &mut buf)
+                    .map_err(KvError::from)
+                    .and_then(|whole| if whole { codec.decode(buf.as_slice()).map(Some) } else { Ok(None) })
+                    .with_context(context(offset));
+                let x = buf.len();
+                let command = match record {
+                    Ok(_) if x == 0 => break,
+                    Ok(Some(command)) => command,
+                    Ok(None) => {
+                        torn = true;
+                        break;
+                    }
+                    Err(err) if salvage => {
+                        warn!("salvaging the data file {}: {}.", filename_of(epoch), err);
+                        torn = true;
+                        break;
+                    }
+                    Err(err) => return Err(err),
                 };
+                if let Some(following) = command.batch() {
+                    batch_start = offset;
+                    batch_left = following + 1;
+                }
+                batch.push((command.key().to_owned(), bin_loc! {Gen[epoch] offset => x }));
+                batch_left = batch_left.saturating_sub(1);
+                if batch_left == 0 {
+                    for (key, location) in batch.drain(..) {
+                        if let Some(n) = res.override_record(key.as_str(), location) {
+                            res.steal += n
+                        };
+                    }
+                }
+                offset += x;
                 buf.clear();
             }
+            // a torn record or batch by a crash during writing, it has never been acknowledged.
+            let cut = if batch_left > 0 { Some(batch_start) } else if torn { Some(offset) } else { None };
+            if let Some(cut) = cut {
+                warn!("dropping the torn records from {}:{}.", filename_of(epoch), cut);
+                storage
+                    .open_append(&filename)
+                    .and_then(|mut file| file.set_len(cut as u64))
+                    .with_context(context(cut))?;
+            }
         }
         Ok(res)
     }
 
     fn override_record(&self, key: &str, location: BinLocation) -> Option<u64> {
-        let idx = self.index.as_ref();
-        match idx.get(key) {
-            Some(ref old) if old.val().epoch > location.epoch => Some(location.length as u64),
-            _ => {
-                idx
-                    .insert(key.to_owned(), location)
-                    .map(|old| old.val().length as u64)
+        self.index.override_record(key, location)
+    }
+
+    /// the estimated memory the index entry of `key` takes.
+    fn index_entry_bytes(key: &str) -> u64 {
+        key.len() as u64 + Self::INDEX_ENTRY_OVERHEAD
+    }
+
+    /// count the new entry of `key` in the index,
+    /// warning once its estimated memory crosses `with_index_warning`.
+    fn grow_index(&self, key: &str) {
+        let bytes = Self::index_entry_bytes(key);
+        let before = self.index_bytes.fetch_add(bytes, Ordering::SeqCst);
+        let keys = self.index_keys.fetch_add(1, Ordering::SeqCst) + 1;
+        self.metrics.record_index(keys, before + bytes);
+        match self.index_warning {
+            Some(threshold) if before <= threshold && before + bytes > threshold => {
+                warn!("the index of {} keys takes about {} bytes, beyond {}.", keys, before + bytes, threshold)
+            }
+            _ => {}
+        }
+    }
+
+    /// fail with `IndexFull` if `bytes` more in the index would exceed `with_index_budget`.
+    fn reserve_index(&self, bytes: u64) -> Result<()> {
+        match self.index_budget {
+            Some(budget) if self.index_bytes.load(Ordering::SeqCst) + bytes > budget => Err(KvError::IndexFull { budget }),
+            _ => Ok(()),
+        }
+    }
+
+    /// the value of `key` with its blob read, the caller holds the writer so that it's still the latest.
+    fn load_value_locked(&self, key: &str) -> Result<Option<ValueWithMeta>> {
+        match self.index.get(key) {
+            Some(location) => {
+                let command = self.load_record(key, location)?;
+                Ok(self.load_blob(command)?.into_value())
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// run `operation`, warning if it takes longer than `with_slow_log`.
+    fn timed<T>(&self, operation: &'static str, run: impl FnOnce() -> Result<T>) -> Result<T> {
+        let threshold = match self.slow_threshold {
+            Some(threshold) => threshold,
+            None => return run(),
+        };
+        let start = Instant::now();
+        let result = run();
+        let elapsed = start.elapsed();
+        if elapsed > threshold {
+            warn!(target: "app::slow", "slow {}: took {:?}{}.", operation, elapsed, context::describe_current());
+        }
+        result
+    }
+
+    /// like `load_value_locked`, but the value is decoded by its type.
+    fn load_typed_locked(&self, key: &str) -> Result<Option<(TypedValue, Option<String>)>> {
+        match self.index.get(key) {
+            Some(location) => {
+                let command = self.load_record(key, location)?;
+                self.load_blob(command)?.into_typed()
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// load the record of `key` at `location` through the cache, if `with_record_cache` is set.
+    fn load_record(&self, key: &str, location: BinLocation) -> Result<KvCommand> {
+        match &self.record_cache {
+            Some(_) => Ok(self.load_cached_record(key, location)?.command()),
+            None => self.reader.borrow_mut().load_command(key, location),
+        }
+    }
+
+    /// load the value of `key` at `location` as bytes, shared with the cache if `with_record_cache` is set,
+    /// or taken from the record read without copying it.
+    fn load_value_bytes(&self, key: &str, location: BinLocation) -> Result<Option<Bytes>> {
+        let command = match &self.record_cache {
+            Some(_) => {
+                let record = self.load_cached_record(key, location)?;
+                match record.command {
+                    Put { blob: None, .. } => return Ok(Some(record.value)),
+                    command => command,
+                }
             }
+            None => self.reader.borrow_mut().load_command(key, location)?,
+        };
+        Ok(self.load_blob(command)?.into_value().map(|found| Bytes::from(found.value)))
+    }
+
+    /// load the record of `key` at `location` through the cache, which must be set.
+    fn load_cached_record(&self, key: &str, location: BinLocation) -> Result<CachedRecord> {
+        let cache = self.record_cache.as_ref().expect("the record cache isn't set.");
+        if let Some(record) = cache.lock()?.get(location) {
+            self.metrics.record_cache(true);
+            return Ok(record);
         }
+        self.metrics.record_cache(false);
+        let record = CachedRecord::new(self.reader.borrow_mut().load_command(key, location)?);
+        let mut cache = cache.lock()?;
+        let before = cache.used;
+        cache.insert(location, record.clone());
+        self.metrics.record_cache_resized(before, cache.used);
+        Ok(record)
     }
 
     fn add_steal(&self, size: u64) -> Result<()> {
-        self.steal.fetch_add(size, Ordering::SeqCst);
+        let steal = self.steal.fetch_add(size, Ordering::SeqCst) + size;
+        self.metrics.record_stale_bytes(steal);
         Ok(())
     }
 
@@ -433,51 +1802,548 @@ impl KvStore {
 
     fn reset_steal(&self) -> Result<()> {
         self.steal.store(0, Ordering::SeqCst);
+        self.metrics.record_stale_bytes(0);
         Ok(())
     }
 
+    /// the bytes of the data files from the tail epoch on, and the blob files.
+    fn count_data_bytes(&self) -> Result<u64> {
+        let tail = self.tail_epoch.load(Ordering::SeqCst);
+        let data_files = KvStore::enumerate_epoch_files(self.storage.as_ref(), &self.path)
+            .filter(|(_, epoch)| *epoch >= tail)
+            .map(|(file, _)| file);
+        let blob_files = KvStore::enumerate_blob_files(self.storage.as_ref(), &self.path)?.into_iter().map(|(file, _)| file);
+        let mut bytes = 0;
+        for file in data_files.chain(blob_files) {
+            bytes += match self.storage.size(&file) {
+                Ok(len) => len,
+                // dropped by a compaction meanwhile.
+                Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+                Err(err) => return Err(err.into()),
+            };
+        }
+        Ok(bytes)
+    }
+
     /// save a command into data file, and update the index.
-    fn save_command(&self, command: KvCommand) -> Result<()> {
+    /// returns the bytes written.
+    fn save_command(&self, command: KvCommand) -> Result<u64> {
+        if self.write_path == WritePath::Vectored {
+            return self.save_queued(command);
+        }
+        let writer = self.writer.lock()?;
+        self.save_command_locked(writer, command)
+    }
+
+    /// queue `command`, then append it along with the others queued meanwhile, see `WritePath::Vectored`.
+    /// The one taking the writer appends the group, unless its command is already appended by another one.
+    fn save_queued(&self, command: KvCommand) -> Result<u64> {
+        let written: AppendSlot = Arc::new(Mutex::new(None));
+        self.append_queue.lock()?.push(QueuedAppend { command, written: written.clone() });
         let mut writer = self.writer.lock()?;
-        let key = command.key().to_owned();
-        let new = writer.write_command(command)?;
-        if let Some(n) = self.override_record(key.as_str(), new) {
-            self.add_steal(n)?;
-            if self.get_steal()? > Self::STEAL_THRESHOLDS {
-                drop(writer);
-                self.compact_file()?;
+        if let Some(appended) = written.lock()?.take() {
+            return appended;
+        }
+        let group = self.take_group(&written)?;
+        let compact = self.save_group(&mut writer, group);
+        drop(writer);
+        let appended = written.lock()?.take().expect("the command taking the group is appended first.");
+        if compact? {
+            self.compact_file()?;
+        }
+        appended
+    }
+
+    /// take the queued commands of distinct keys, the one of `own` first, then the others in order.
+    /// The ones of the keys taken are left to the next group, since each of them is indexed over the one before it.
+    fn take_group(&self, own: &AppendSlot) -> Result<Vec<QueuedAppend>> {
+        let mut queue = self.append_queue.lock()?;
+        let nth = queue
+            .iter()
+            .position(|queued| Arc::ptr_eq(&queued.written, own))
+            .expect("a command not appended is still queued.");
+        let mut group = vec![queue.remove(nth)];
+        let mut keys: HashSet<String> = group.iter().map(|queued| queued.command.key().to_owned()).collect();
+        let mut left = Vec::new();
+        for queued in queue.drain(..) {
+            if keys.insert(queued.command.key().to_owned()) {
+                group.push(queued);
+            } else {
+                left.push(queued);
+            }
+        }
+        *queue = left;
+        Ok(group)
+    }
+
+    /// append the commands of `group` by vectored writes and sync them once, then index them in order,
+    /// putting the bytes each one writes, or its error, into its slot.
+    /// returns whether the garbage is enough to compact.
+    fn save_group(&self, writer: &mut KvWriter, group: Vec<QueuedAppend>) -> Result<bool> {
+        let mut new_keys_bytes = 0;
+        let mut records = Vec::with_capacity(group.len());
+        let mut prepared = Vec::with_capacity(group.len());
+        for QueuedAppend { command, written: slot } in group {
+            let old = self.index.get(command.key());
+            let linked = self.history_versions > 1 || (self.soft_delete && matches!(command, Rm { .. }));
+            let command = if linked { command.with_prev(old) } else { command };
+            let bytes = if old.is_none() { Self::index_entry_bytes(command.key()) } else { 0 };
+            let spilled = self
+                .reserve_index(new_keys_bytes + bytes)
+                .and_then(|_| self.spill(writer, command, records.len()));
+            match spilled {
+                Ok((command, blob_bytes)) => {
+                    new_keys_bytes += bytes;
+                    records.push(command);
+                    prepared.push((slot, old, blob_bytes));
+                }
+                Err(err) => put_appended(&slot, Err(err)),
+            }
+        }
+        if records.is_empty() {
+            return Ok(false);
+        }
+        fail_point!("kvs::before_append", |_| {
+            for (slot, ..) in prepared.drain(..) {
+                put_appended(&slot, Err(failpoint_error("kvs::before_append")));
+            }
+            Ok(false)
+        });
+        let appended = writer.write_commands_vectored(records.as_slice()).and_then(|locations| {
+            if self.sync == SyncPolicy::Always {
+                writer.file.sync()?;
+                // the data file is just created by the write.
+                if locations.first().is_some_and(|new| new.offset == 0) {
+                    self.storage.sync_dir(self.path.as_path())?;
+                }
+            }
+            Ok(locations)
+        });
+        let locations = match appended {
+            Ok(locations) => locations,
+            Err(err) => {
+                for (slot, ..) in prepared {
+                    put_appended(&slot, Err(io::Error::new(err.kind(), err.to_string()).into()));
+                }
+                return Ok(false);
             }
         };
+        let written = locations.iter().map(|new| new.length as u64).sum::<u64>()
+            + prepared.iter().map(|(_, _, blob_bytes)| blob_bytes).sum::<u64>();
+        self.metrics.record_data_appended(written);
+        fail_point!("kvs::before_index_update", |_| {
+            for (slot, ..) in prepared.drain(..) {
+                put_appended(&slot, Err(failpoint_error("kvs::before_index_update")));
+            }
+            Ok(false)
+        });
+        let mut overridden = false;
+        for ((command, (slot, old, blob_bytes)), new) in records.iter().zip(prepared).zip(locations) {
+            let indexed = self.index_appended(command.key(), old, new);
+            overridden |= *indexed.as_ref().unwrap_or(&false);
+            put_appended(&slot, indexed.map(|_| new.length as u64 + blob_bytes));
+        }
+        Ok(overridden && self.get_steal()? > Self::STEAL_THRESHOLDS)
+    }
+
+    /// index the record of `key` just appended at `new` over its `old` one, counting the garbage it makes.
+    /// returns whether it overrides a record.
+    fn index_appended(&self, key: &str, old: Option<BinLocation>, new: BinLocation) -> Result<bool> {
+        if old.is_none() {
+            self.grow_index(key);
+        }
+        match self.override_record(key, new) {
+            Some(n) => {
+                self.add_steal(n)?;
+                if let Some(old) = old.filter(|_| self.blob_threshold.is_some()) {
+                    // the blob of the overwritten value is garbage too, count it to trigger the compaction in time.
+                    self.add_steal(self.blob_len(key, old)?)?;
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// like `save_command`, with the writer locked by the caller.
+    fn save_command_locked(&self, writer: MutexGuard<'_, KvWriter>, command: KvCommand) -> Result<u64> {
+        self.save_batch_locked(writer, vec![command])
+    }
+
+    /// save the commands of distinct keys by one append, and update the index after all of them are written.
+    /// The first record counts the ones after it, so that the open drops the whole batch if a crash tears any of it.
+    /// returns the bytes written.
+    fn save_batch_locked(&self, mut writer: MutexGuard<'_, KvWriter>, commands: Vec<KvCommand>) -> Result<u64> {
+        let following = Some(commands.len().saturating_sub(1)).filter(|following| *following > 0);
+        let mut new_keys_bytes = 0;
+        let mut spilled = 0;
+        let mut olds = Vec::with_capacity(commands.len());
+        let mut records = Vec::with_capacity(commands.len());
+        for (nth, command) in commands.into_iter().enumerate() {
+            let old = self.index.get(command.key());
+            let linked = self.history_versions > 1 || (self.soft_delete && matches!(command, Rm { .. }));
+            let command = if linked { command.with_prev(old) } else { command };
+            let command = if nth == 0 { command.in_batch(following) } else { command };
+            if old.is_none() {
+                new_keys_bytes += Self::index_entry_bytes(command.key());
+                self.reserve_index(new_keys_bytes)?;
+            }
+            let (command, blob_bytes) = self.spill(&mut writer, command, nth)?;
+            spilled += blob_bytes;
+            olds.push(old);
+            records.push(command);
+        }
+        fail_point!("kvs::before_append", |_| Err(failpoint_error("kvs::before_append")));
+        let locations = writer.write_commands(records.as_slice())?;
+        if self.sync == SyncPolicy::Always {
+            writer.file.sync()?;
+            // the data file is just created by the write.
+            if locations.first().is_some_and(|new| new.offset == 0) {
+                self.storage.sync_dir(self.path.as_path())?;
+            }
+        }
+        let written = locations.iter().map(|new| new.length as u64).sum::<u64>() + spilled;
+        self.metrics.record_data_appended(written);
+        fail_point!("kvs::before_index_update", |_| Err(failpoint_error("kvs::before_index_update")));
+        let mut overridden = false;
+        for ((command, old), new) in records.iter().zip(olds).zip(locations) {
+            overridden |= self.index_appended(command.key(), old, new)?;
+        }
+        if overridden && self.get_steal()? > Self::STEAL_THRESHOLDS {
+            drop(writer);
+            self.compact_file()?;
+        }
+        Ok(written)
+    }
+
+    /// write the value of a `Put` into a blob file, if it's longer than `with_blob_threshold`,
+    /// or move the blob staged by `set_stream` to its name.
+    /// Returns the command to append, and the bytes of the blob written.
+    ///
+    /// `nth` is the position of the command in its batch, since the records of a batch are placed at once.
+    fn spill(&self, writer: &mut KvWriter, command: KvCommand, nth: usize) -> Result<(KvCommand, u64)> {
+        match command {
+            Put { key, value, modified, meta, prev, blob: Some(staged), batch, kind } => {
+                let name = blob_name_of(writer.current_epoch, writer.file.seek_to_end()? + nth);
+                self.storage.rename(&self.path.join(staged.as_str()), &self.path.join(name.as_str()))?;
+                self.sync_blob_created()?;
+                let spilled = self.storage.size(&self.path.join(name.as_str()))?;
+                Ok((Put { key, value, modified, meta, prev, blob: Some(name), batch, kind }, spilled))
+            }
+            Put { key, value, modified, meta, prev, blob: None, batch, kind }
+            if self.blob_threshold.is_some_and(|threshold| value.len() > threshold) =>
+                {
+                    // named after a byte the batch takes, which is unique, since each record takes more than one.
+                    let name = blob_name_of(writer.current_epoch, writer.file.seek_to_end()? + nth);
+                    let mut file = self.storage.create(&self.path.join(name.as_str()))?;
+                    file.write_all(value.as_bytes())?;
+                    if self.sync == SyncPolicy::Always {
+                        file.sync()?;
+                    }
+                    self.sync_blob_created()?;
+                    let spilled = value.len() as u64;
+                    Ok((Put { key, value: String::new(), modified, meta, prev, blob: Some(name), batch, kind }, spilled))
+                }
+            command => Ok((command, 0)),
+        }
+    }
+
+    /// make the blob file just created or renamed durable before the record pointing to it, if `with_sync` is `Always`.
+    fn sync_blob_created(&self) -> Result<()> {
+        if self.sync == SyncPolicy::Always {
+            self.storage.sync_dir(self.path.as_path())?;
+        }
+        Ok(())
+    }
+
+    /// the length of the blob the record at `location` refers to, `0` if it's not spilled.
+    fn blob_len(&self, key: &str, location: BinLocation) -> Result<u64> {
+        match self.reader.borrow_mut().load_command(key, location)? {
+            Put { blob: Some(blob), .. } => Ok(self.storage.size(&self.path.join(blob)).unwrap_or(0)),
+            _ => Ok(0),
+        }
+    }
+
+    /// the command with the value read from its blob file, if it's spilled.
+    fn load_blob(&self, command: KvCommand) -> Result<KvCommand> {
+        match command {
+            Put { key, modified, meta, prev, blob: Some(blob), batch, kind, .. } => {
+                let value = self.storage.read_to_string(&self.path.join(blob.as_str())).with_context(|| ErrorContext {
+                    operation: "load_blob",
+                    file_name: blob.clone(),
+                    offset: 0,
+                    key: Some(key.clone()),
+                })?;
+                Ok(Put { key, value, modified, meta, prev, blob: None, batch, kind })
+            }
+            command => Ok(command),
+        }
+    }
+
+    /// remove the files left unfinished by a crash: the blobs staged by `set_stream` whose records are never written,
+    /// and the files of the compactions never published, whose records are still in the elder files.
+    fn remove_unfinished_files(storage: &dyn Storage, p: impl AsRef<Path>) -> Result<()> {
+        for name in storage.list(p.as_ref())? {
+            let staged_blob = name.starts_with("kvs-blob-") && name.ends_with(".tmp");
+            let compacting = name.starts_with("kvs-data-") && name.ends_with(".compacting");
+            if staged_blob || compacting {
+                storage.remove(&p.as_ref().join(name))?;
+            }
+        }
         Ok(())
     }
 
+    /// remove the marker of a clean shutdown in `path` if any, and returns the epoch of the checkpoint it holds,
+    /// so that a crash after this open isn't taken for a clean shutdown.
+    fn take_clean_marker(storage: &dyn Storage, path: &Path) -> Result<Option<u64>> {
+        let marker = path.join(CLEAN_FILE);
+        let content = match storage.read_to_string(&marker) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        storage.remove(&marker)?;
+        storage.sync_dir(path)?;
+        Ok(content.trim().parse().ok())
+    }
+
+    /// the blob files in `p`, with the epochs of the data files referring to them when they're written.
+    pub(crate) fn enumerate_blob_files(storage: &dyn Storage, p: impl AsRef<Path>) -> Result<Vec<(PathBuf, u64)>> {
+        let mut blobs = Vec::new();
+        for name in storage.list(p.as_ref())? {
+            if let Some(epoch) = parse_blob_epoch(name.as_str()) {
+                blobs.push((p.as_ref().join(name), epoch));
+            }
+        }
+        Ok(blobs)
+    }
+
     /// Compact the file.
-    /// This will merge all the indices, only save the last put or rm operation in the log.
+    /// This will merge all the indices, only save the last put or rm operations in the log, as many as `with_history` retains.
     /// This should be called maybe, so that the log file will not grow too fast.
+    ///
+    /// It copies the live records in the background, while the writes go on to a new data file,
+    /// and does nothing if a compaction is running already.
     fn compact_file(&self) -> Result<()> {
-        let epoch = self.current_epoch.fetch_add(2, Ordering::SeqCst);
-        let compact_to_epoch = epoch + 1;
-        let new_write_to_epoch = epoch + 2;
-        let writer = KvWriter::open(&self.path, compact_to_epoch)?;
+        if !self.compacting.try_enter() {
+            return Ok(());
+        }
+        let writer = match self.switch_epochs() {
+            Ok(writer) => writer,
+            Err(err) => {
+                self.compacting.leave();
+                return Err(err);
+            }
+        };
         self.reset_steal()?;
         let this = self.clone();
         thread::spawn(move || {
-            this.compact_file_to_writer(writer).unwrap();
-            this.tail_epoch.fetch_add(2, Ordering::SeqCst);
+            // the failure is reported by `run_compaction`.
+            let _ = this.run_compaction(writer);
+            this.compacting.leave();
+        });
+        Ok(())
+    }
+
+    /// compact the data files in the current thread, and return the bytes rewritten.
+    ///
+    /// The data files before the compaction are obsolete after it,
+    /// they are dropped once no reader uses them, or by hand when the store is closed.
+    /// Fails if a compaction is running already.
+    pub fn compact(&self) -> Result<u64> {
+        if !self.compacting.try_enter() {
+            return Err(KvError::Other {
+                reason: "a compaction is running already".to_owned(),
+            });
+        }
+        let result = self.switch_epochs().and_then(|writer| {
+            self.reset_steal()?;
+            self.run_compaction(writer)
         });
+        self.compacting.leave();
+        result
+    }
+
+    /// write `pairs`, sorted by their keys without duplicates, into a new data file at once,
+    /// then point the index to them, which is much faster than `set` for loading lots of keys.
+    ///
+    /// The file is written aside and published by a rename like a compaction,
+    /// so that a crash leaves either none of the pairs or all of them.
+    /// The writes during it win over the pairs, and the values aren't spilled into blob files.
+    /// It waits for the running compaction to finish first, and the compactions wait for it.
+    /// Returns the bytes written.
+    pub fn ingest(&self, pairs: impl IntoIterator<Item=(String, String)>) -> Result<u64> {
+        // a compaction meanwhile would drop the new file before the index points to it.
+        self.compacting.enter();
+        let result = self.switch_epochs().and_then(|writer| self.ingest_to_writer(writer, pairs));
+        self.compacting.leave();
+        result
+    }
+
+    /// returns the bytes written, the unfinished file of a failed ingestion is removed by the next open.
+    fn ingest_to_writer(&self, mut writer: KvWriter, pairs: impl IntoIterator<Item=(String, String)>) -> Result<u64> {
+        // write in large chunks instead of flushing each record.
+        const CHUNK_SIZE: usize = 1024 * 1024;
+        let linked = self.history_versions > 1;
+        let epoch = writer.current_epoch;
+        let mut chunk = Vec::new();
+        let mut offset = 0;
+        let mut ingested: Vec<(String, BinLocation)> = Vec::new();
+        let mut new_keys_bytes = 0;
+        for (key, value) in pairs {
+            if let Some((last, _)) = ingested.last().filter(|(last, _)| *last >= key) {
+                return Err(KvError::Other {
+                    reason: format!("the keys to ingest aren't sorted or unique: {:?} after {:?}", key, last),
+                });
+            }
+            if self.index.get(key.as_str()).is_none() {
+                new_keys_bytes += Self::index_entry_bytes(key.as_str());
+                self.reserve_index(new_keys_bytes)?;
+            }
+            let mut command = KvCommand::set(key.clone(), value, None);
+            if linked {
+                command = command.with_prev(self.index.get(key.as_str()));
+            }
+            let serialized = writer.codec.encode(&command);
+            ingested.push((key, bin_loc! { Gen[epoch] offset => serialized.len() }));
+            offset += serialized.len();
+            chunk.extend_from_slice(serialized.as_slice());
+            if chunk.len() >= CHUNK_SIZE {
+                writer.file.write_all(chunk.as_slice())?;
+                chunk.clear();
+            }
+        }
+        writer.file.write_all(chunk.as_slice())?;
+        writer.file.flush()?;
+        writer.publish()?;
+        self.metrics.record_data_appended(offset as u64);
+        for (key, location) in ingested {
+            self.metrics.record_set(location.length as u64);
+            if self.index.get(key.as_str()).is_none() {
+                self.grow_index(key.as_str());
+            }
+            if let Some(n) = self.override_record(key.as_str(), location) {
+                self.add_steal(n)?;
+            }
+        }
+        Ok(offset as u64)
+    }
+
+    /// rewrite the live records by `writer`, then record and report the result, returns the bytes written.
+    fn run_compaction(&self, writer: KvWriter) -> Result<u64> {
+        let compact_to_epoch = writer.current_epoch;
+        let start = Instant::now();
+        self.notify(CompactionEvent::Started { epoch: compact_to_epoch });
+        let compacted = self
+            .compact_file_to_writer(writer)
+            .and_then(|(written, rewritten)| Ok((written, self.count_records_before(compact_to_epoch)?, rewritten)));
+        match compacted {
+            Ok((written, records, rewritten)) => {
+                let elapsed = start.elapsed();
+                self.metrics.record_compaction(elapsed, written);
+                self.tail_epoch.fetch_max(compact_to_epoch, Ordering::SeqCst);
+                // the compaction is done anyway, a failure to count the files just leaves the stale figure.
+                if let Ok(bytes) = self.count_data_bytes() {
+                    self.metrics.record_data_bytes(bytes);
+                }
+                self.notify(CompactionEvent::Finished {
+                    epoch: compact_to_epoch,
+                    bytes_written: written,
+                    records_dropped: records.saturating_sub(rewritten),
+                    elapsed,
+                });
+                Ok(written)
+            }
+            // the elder files are kept, and the unfinished file is removed by the next open.
+            Err(err) => {
+                warn!("failed to compact into epoch {}: {}.", compact_to_epoch, err);
+                self.metrics.record_compaction_failure();
+                self.notify(CompactionEvent::Failed { epoch: compact_to_epoch, reason: err.to_string() });
+                Err(err)
+            }
+        }
+    }
+
+    /// move the writes to a new data file, and open the writer of the compaction to the epoch before it.
+    fn switch_epochs(&self) -> Result<KvWriter> {
+        // switch the epochs under the lock, so that no write goes to the files to be dropped,
+        // and a checkpoint never sees the epochs half switched.
         let mut w = self.writer.lock()?;
+        let epoch = self.current_epoch.fetch_add(2, Ordering::SeqCst);
+        let compact_to_epoch = epoch + 1;
+        let new_write_to_epoch = epoch + 2;
+        let writer = KvWriter::open_compacting(self.storage.clone(), self.codec.clone(), &self.path, compact_to_epoch)?;
         w.set_epoch(new_write_to_epoch)?;
-        Ok(())
+        Ok(writer)
     }
 
-    fn compact_file_to_writer(&self, mut writer: KvWriter) -> Result<()> {
-        let idx = self.index.as_ref();
-        for kv in idx.iter() {
-            let command = self.reader.borrow_mut().load_command(*kv.val())?;
-            let new_location = writer.write_command(command)?;
-            self.override_record(kv.key().as_str(), new_location);
+    /// send `event` to the listeners registered by `with_compaction_listener`.
+    fn notify(&self, event: CompactionEvent) {
+        for listener in self.compaction_listeners.iter() {
+            listener(&event);
         }
-        Ok(())
+    }
+
+    /// the records in the data files from the tail to `epoch`, which a compaction to `epoch` drops.
+    fn count_records_before(&self, epoch: u64) -> Result<u64> {
+        let tail = self.tail_epoch.load(Ordering::SeqCst);
+        let mut records = 0;
+        let files = KvStore::enumerate_epoch_files(self.storage.as_ref(), &self.path).filter(|(_, e)| tail <= *e && *e < epoch);
+        for (file, _) in files {
+            let mut reader = BufReader::new(self.storage.open_read(&file)?);
+            let mut record = Vec::new();
+            while self.codec.read_record(&mut reader, &mut record)? {
+                records += 1;
+                record.clear();
+            }
+        }
+        Ok(records)
+    }
+
+    /// returns the bytes written and the count of records rewritten.
+    ///
+    /// The index points to the compacted records only after the file is published,
+    /// since the readers cannot open it before that.
+    fn compact_file_to_writer(&self, mut writer: KvWriter) -> Result<(u64, u64)> {
+        let mut written = 0;
+        let mut rewritten = 0;
+        let mut compacted = Vec::new();
+        let mut kept_blobs = HashSet::new();
+        for (n, (key, _)) in self.index.entries().enumerate() {
+            if n > 0 && n % Self::COMPACTION_PROGRESS_KEYS == 0 {
+                self.notify(CompactionEvent::Progress { epoch: writer.current_epoch, bytes_written: written });
+            }
+            // rewrite the retained versions from the eldest, linking each to the one before it.
+            let mut versions = self.versions(key.as_str())?;
+            let mut prev = None;
+            while let Some(command) = versions.pop() {
+                // the blobs stay, only the records pointing to them are rewritten.
+                if let Put { blob: Some(blob), .. } = &command {
+                    kept_blobs.insert(self.path.join(blob));
+                }
+                // the batches are complete once written, and the compacted file is published as a whole.
+                let new_location = writer.write_command(command.with_prev(prev).in_batch(None))?;
+                written += new_location.length as u64;
+                rewritten += 1;
+                prev = Some(new_location);
+            }
+            if let Some(new_location) = prev {
+                compacted.push((key, new_location));
+            }
+            fail_point!("kvs::mid_compaction", |_| Err(failpoint_error("kvs::mid_compaction")));
+        }
+        // the elder files are dropped after it, so the compacted records must be durable.
+        writer.publish()?;
+        for (key, new_location) in compacted {
+            self.override_record(key.as_str(), new_location);
+        }
+        // the blobs written before the compaction and not kept are only referred by the dropped records.
+        for (blob, epoch) in KvStore::enumerate_blob_files(self.storage.as_ref(), &self.path)? {
+            if epoch < writer.current_epoch && !kept_blobs.contains(&blob) {
+                let _ = self.storage.remove(&blob);
+            }
+        }
+        Ok((written, rewritten))
     }
 
     /// make an KvStore by an database file.
@@ -488,25 +2354,418 @@ impl KvStore {
     /// During the process of building the index, we may meet some deserialize/IO exception, which will also be thrown,
     /// sealed in the `OtherIOException` variant.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        engine::check_engine::<&P>(&path, "kvs")?;
-        let init = KvStore::build_index(path.as_ref())?;
-        let writer = Arc::new(Mutex::new(KvWriter::open(path.as_ref(), init.epoch)?));
+        Self::open_with_storage(path, Arc::new(FsStorage))
+    }
+
+    /// like `open`, but keeps the files of the data directory `path` in `storage`,
+    /// e.g. a `MemStorage` to keep them in memory, or a `FaultyStorage` to test the crash consistency.
+    pub fn open_with_storage<P: AsRef<Path>>(path: P, storage: Arc<dyn Storage>) -> Result<Self> {
+        engine::check_engine::<&P>(storage.as_ref(), &path, "kvs")?;
+        let codec = codec::codec_of(storage.as_ref(), path.as_ref())?;
+        Self::open_checked(path.as_ref(), storage, codec, false)
+    }
+
+    /// like `open_with_storage`, but cuts the data files at their first corrupted records instead of failing,
+    /// like the torn ones after a crash, so that a damaged data directory opens anyway.
+    /// The records after them are lost, `offline::verify` tells them before.
+    pub fn open_salvaging<P: AsRef<Path>>(path: P, storage: Arc<dyn Storage>) -> Result<Self> {
+        engine::check_engine::<&P>(storage.as_ref(), &path, "kvs")?;
+        let codec = codec::codec_of(storage.as_ref(), path.as_ref())?;
+        Self::open_checked(path.as_ref(), storage, codec, true)
+    }
+
+    /// like `open`, but encodes the records by `codec`, and records it in the data directory,
+    /// so that the later opens use it too, like `CodecKind::Bincode.codec()` or one of another crate.
+    ///
+    /// # Error
+    ///
+    /// The data files are never converted, so if the data directory has them in another codec, it throws.
+    pub fn open_with_codec<P: AsRef<Path>>(path: P, codec: Arc<dyn RecordCodec>) -> Result<Self> {
+        engine::check_engine::<&P>(&FsStorage, &path, "kvs")?;
+        codec::record_codec(&FsStorage, path.as_ref(), codec.as_ref())?;
+        Self::open_checked(path.as_ref(), Arc::new(FsStorage), codec, false)
+    }
+
+    /// open the data directory checked to be of the kvs engine, in `codec`, salvaging it if `salvage`.
+    fn open_checked(
+        path: &Path,
+        storage: Arc<dyn Storage>,
+        codec: Arc<dyn RecordCodec>,
+        salvage: bool,
+    ) -> Result<Self> {
+        KvStore::remove_unfinished_files(storage.as_ref(), path)?;
+        let init = KvStore::build_index(storage.as_ref(), path, codec.as_ref(), salvage)?;
+        let writer = Arc::new(Mutex::new(KvWriter::open(storage.clone(), codec.clone(), path, init.epoch)?));
         let epoch = Arc::new(AtomicU64::new(init.epoch));
         let tail_epoch = Arc::new(AtomicU64::new(init.tail_epoch));
         let reader = KvReader::open(
-            path.as_ref(),
+            storage.clone(),
+            path,
             tail_epoch.clone(),
             Arc::new(Map::new()),
+            codec.clone(),
         )?;
+        let (index_keys, index_bytes) = init
+            .index
+            .entries()
+            .fold((0, 0), |(keys, bytes), (key, _)| (keys + 1, bytes + Self::index_entry_bytes(key.as_str())));
         let store = KvStore {
             reader: RefCell::new(reader),
             writer,
             tail_epoch,
             current_epoch: epoch,
-            path: Path::new(path.as_ref()).to_owned(),
+            path: path.to_owned(),
             index: Arc::new(init.index),
-            steal: Arc::new(AtomicU64::new(init.steal as u64)),
+            steal: Arc::new(AtomicU64::new(init.steal)),
+            metrics: EngineMetrics::default(),
+            storage,
+            history_versions: 1,
+            soft_delete: false,
+            blob_threshold: None,
+            sync: SyncPolicy::default(),
+            write_path: WritePath::default(),
+            append_queue: Arc::new(Mutex::new(Vec::new())),
+            compacting: Arc::new(CompactionGate::default()),
+            compaction_listeners: Vec::new(),
+            record_cache: None,
+            index_bytes: Arc::new(AtomicU64::new(index_bytes)),
+            index_keys: Arc::new(AtomicU64::new(index_keys)),
+            index_budget: None,
+            index_warning: None,
+            slow_threshold: None,
+            codec,
         };
+        store.metrics.record_stale_bytes(init.steal);
+        store.metrics.record_data_bytes(store.count_data_bytes()?);
+        store.metrics.record_index(index_keys, index_bytes);
         Ok(store)
     }
+
+    /// retain the last `versions` versions of each key, including the removals,
+    /// so that they can be queried by `history` and `get_version`, and compaction keeps them.
+    ///
+    /// Each record then links to the previous version of its key.
+    /// The versions written before enabling it aren't linked, and the ones written during a compaction
+    /// may lose their elder versions.
+    /// Call it before cloning the store, since the clones don't share the setting.
+    pub fn with_history(mut self, versions: usize) -> Self {
+        self.history_versions = versions.max(1);
+        self
+    }
+
+    /// make the removals recoverable by `undelete`, until a compaction drops the removed values.
+    ///
+    /// Each removal then links to the value it removes, like `with_history` does.
+    /// Call it before cloning the store, since the clones don't share the setting.
+    pub fn with_soft_delete(mut self) -> Self {
+        self.soft_delete = true;
+        self
+    }
+
+    /// write the values longer than `bytes` into their own blob files, leaving only pointers in the data files,
+    /// so that the compactions and the opens don't copy nor read them.
+    ///
+    /// The blobs of the overwritten values are removed by the next compaction.
+    /// Call it before cloning the store, since the clones don't share the setting.
+    pub fn with_blob_threshold(mut self, bytes: usize) -> Self {
+        self.blob_threshold = Some(bytes);
+        self
+    }
+
+    /// make the writes durable by `policy`, `SyncPolicy::Never` by default.
+    ///
+    /// With `with_blob_threshold`, the data files work as a log of small records,
+    /// so syncing a write costs about its key and metadata, plus its value only if it's short enough to be inlined.
+    /// The spilled values are synced once into their own blob files, which the compactions and the opens never copy.
+    /// Call it before cloning the store, since the clones don't share the setting.
+    pub fn with_sync(mut self, policy: SyncPolicy) -> Self {
+        self.sync = policy;
+        self
+    }
+
+    /// append the records of the writes by `write_path`, `WritePath::Buffered` by default.
+    ///
+    /// With `WritePath::Vectored`, the `set`s and `remove`s running concurrently are appended together
+    /// by vectored writes, taking fewer syscalls than one write each, and with `SyncPolicy::Always`, one sync for all.
+    /// The other writes, like the batches and the conditional ones, are still appended one by one.
+    /// Call it before cloning the store, since the clones don't share the setting.
+    pub fn with_write_path(mut self, write_path: WritePath) -> Self {
+        self.write_path = write_path;
+        self
+    }
+
+    /// use the index of `kind`, and record it in the data directory, so that the later opens use it too.
+    ///
+    /// The ordered index makes the key listings scan only the keys with the literal prefix of their patterns,
+    /// at the cost of slower point lookups and writes.
+    /// Call it before cloning the store, since the clones don't share the index.
+    pub fn with_index(mut self, kind: IndexKind) -> Result<Self> {
+        if self.index.kind() != kind {
+            let index = Index::new(kind);
+            for (key, location) in self.index.entries() {
+                index.insert(key, location);
+            }
+            self.index = Arc::new(index);
+        }
+        if IndexKind::recorded_in(self.storage.as_ref(), self.path.as_path())? != kind {
+            self.storage.write_atomic(&self.path.join(INDEX_FILE), kind.as_ref().as_bytes())?;
+        }
+        Ok(self)
+    }
+
+    /// call `listener` on each `CompactionEvent`, in the thread of the compaction,
+    /// so it should return quickly, e.g. by logging or counting.
+    /// Call it before cloning the store, since the clones don't share the listeners.
+    pub fn with_compaction_listener(mut self, listener: impl Fn(&CompactionEvent) + Send + Sync + 'static) -> Self {
+        self.compaction_listeners.push(Arc::new(listener));
+        self
+    }
+
+    /// keep the records read by `get`, `get_with_meta`, `get_stream` and `list_keys` recently in memory,
+    /// up to `bytes` of them, so that the lookups of the hot keys don't read the data files again.
+    ///
+    /// The values spilled into blob files are still read from them, only their records are cached.
+    /// The hits and the misses are counted in the metrics.
+    /// Call it before cloning the store, so that the clones share the cache.
+    pub fn with_record_cache(mut self, bytes: usize) -> Self {
+        self.record_cache = Some(Arc::new(Mutex::new(RecordCache::new(bytes))));
+        self
+    }
+
+    /// refuse to write new keys with `KvError::IndexFull` once the index would take more than `bytes` of memory,
+    /// so that the store stops growing predictably instead of running out of memory.
+    ///
+    /// The memory is estimated by `index_bytes`. The present keys can still be overwritten or removed,
+    /// but the removed keys stay in the index.
+    /// Call it before cloning the store, since the clones don't share the setting.
+    pub fn with_index_budget(mut self, bytes: u64) -> Self {
+        self.index_budget = Some(bytes);
+        self
+    }
+
+    /// warn in the logs once the index takes more than `bytes` of memory, estimated by `index_bytes`,
+    /// ahead of `with_index_budget` refusing the new keys, or of running out of memory without one.
+    ///
+    /// It warns again after each reopen while the index is still beyond it.
+    /// Call it before cloning the store, since the clones don't share the setting.
+    pub fn with_index_warning(mut self, bytes: u64) -> Self {
+        let current = self.index_bytes();
+        if current > bytes {
+            warn!("the index of {} keys takes about {} bytes, beyond {}.", self.index_keys(), current, bytes);
+        }
+        self.index_warning = Some(bytes);
+        self
+    }
+
+    /// warn about the `get`, `set`, `remove`, `list_keys` and `remove_prefix` taking longer than `threshold`,
+    /// along with the request they serve, see `RequestContext`.
+    ///
+    /// Call it before cloning the store, since the clones don't share the setting.
+    pub fn with_slow_log(mut self, threshold: Duration) -> Self {
+        self.slow_threshold = Some(threshold);
+        self
+    }
+
+    /// the estimated memory taken by the index: each key, plus `INDEX_ENTRY_OVERHEAD` for its entry.
+    pub fn index_bytes(&self) -> u64 {
+        self.index_bytes.load(Ordering::SeqCst)
+    }
+
+    /// the count of the keys in the index, including the removed ones it still holds.
+    pub fn index_keys(&self) -> u64 {
+        self.index_keys.load(Ordering::SeqCst)
+    }
+
+    /// the kind of the index in use, see `with_index`.
+    pub fn index_kind(&self) -> IndexKind {
+        self.index.kind()
+    }
+
+    /// where the next write goes, to read the changes after it by `KvsEngine::changes`.
+    pub fn log_position(&self) -> Result<LogPosition> {
+        let mut writer = self.writer.lock()?;
+        let offset = writer.file.seek_to_end()?;
+        Ok(LogPosition { epoch: writer.current_epoch, offset })
+    }
+
+    /// the retained versions of `key`, from the latest to the eldest, `None` for a removal.
+    /// It has at most the number of versions set by `with_history`, 1 by default.
+    pub fn history(&self, key: String) -> Result<Vec<Option<ValueWithMeta>>> {
+        self.versions(key.as_str())?
+            .into_iter()
+            .map(|command| Ok(self.load_blob(command)?.into_value()))
+            .collect()
+    }
+
+    /// the `n`th retained version of `key`, `0` for the latest.
+    /// Returns `None` if the version is a removal, or isn't retained.
+    pub fn get_version(&self, key: String, n: usize) -> Result<Option<ValueWithMeta>> {
+        Ok(self.history(key)?.into_iter().nth(n).flatten())
+    }
+
+    /// make a checkpoint: seal the current data file, and save the index durably,
+    /// so that the later opens only replay the data files since the checkpoint.
+    ///
+    /// A compaction makes the earlier checkpoints stale, then the opens replay all the data files again,
+    /// until the next checkpoint.
+    pub fn checkpoint(&self) -> Result<Checkpoint> {
+        // an ingestion meanwhile would point the index to its file after the checkpoint seals past it.
+        self.compacting.enter();
+        let result = self.save_checkpoint();
+        self.compacting.leave();
+        result
+    }
+
+    /// seal the current data file and save the checkpoint, the callers hold off the compactions and the ingestions.
+    fn save_checkpoint(&self) -> Result<Checkpoint> {
+        let content = self.seal()?;
+        content.save(self.storage.as_ref(), &self.path)?;
+        Ok(content.checkpoint)
+    }
+
+    /// seal the current data file, and take the index at that point, under the lock of the writes.
+    fn seal(&self) -> Result<CheckpointFile> {
+        let mut writer = self.writer.lock()?;
+        writer.file.sync()?;
+        let index: Vec<(String, BinLocation)> = self.index.entries().collect();
+        let checkpoint = Checkpoint {
+            epoch: self.current_epoch.fetch_add(1, Ordering::SeqCst) + 1,
+        };
+        writer.set_epoch(checkpoint.epoch)?;
+        Ok(CheckpointFile {
+            checkpoint,
+            steal: self.get_steal()?,
+            checksum: Some(checksum_of(index.as_slice())),
+            index,
+        })
+    }
+
+    /// close the store gracefully: wait for the running compaction, then make a checkpoint,
+    /// so that the next open loads the index from it instead of replaying the data files.
+    ///
+    /// The clones of the store should be dropped before, since their writes after it are replayed by the next open.
+    pub fn close(self) -> Result<Checkpoint> {
+        self.close_cleanly()
+    }
+
+    /// make the checkpoint of `close`, then mark the shutdown clean, see `was_clean`.
+    fn close_cleanly(&self) -> Result<Checkpoint> {
+        self.compacting.enter();
+        let checkpoint = self.save_checkpoint().and_then(|checkpoint| {
+            let mut file = self.storage.create(&self.path.join(CLEAN_FILE))?;
+            file.write_all(checkpoint.epoch.to_string().as_bytes())?;
+            file.sync()?;
+            self.storage.sync_dir(&self.path)?;
+            Ok(checkpoint)
+        });
+        self.compacting.leave();
+        checkpoint
+    }
+
+    /// back up the whole store into the directory `dest`, see `backup_incremental`.
+    pub fn backup(&self, dest: impl AsRef<Path>) -> Result<Checkpoint> {
+        self.backup_incremental(&Checkpoint { epoch: 0 }, dest)
+    }
+
+    /// back up the records written since the checkpoint `since` into the directory `dest`,
+    /// by making a new checkpoint, and copying the data files sealed between them, with their blob files.
+    ///
+    /// Returns the new checkpoint, the `since` of the next backup.
+    /// To restore, copy the files of the full backup and then the incremental ones in order into a directory,
+    /// and open it.
+    ///
+    /// It runs while serving the writes: the backup is the data at the new checkpoint,
+    /// whose position marks where the writes it misses start, see `Checkpoint::position`,
+    /// and the compactions wait until the copies are done, so that they don't drop the files being copied.
+    pub fn backup_incremental(&self, since: &Checkpoint, dest: impl AsRef<Path>) -> Result<Checkpoint> {
+        self.compacting.enter();
+        let result = self.copy_backup(since, dest.as_ref());
+        self.compacting.leave();
+        result
+    }
+
+    /// make a checkpoint, then copy the files since `since` to it into `dest`, with the compactions held off.
+    fn copy_backup(&self, since: &Checkpoint, dest: &Path) -> Result<Checkpoint> {
+        let content = self.seal()?;
+        content.save(self.storage.as_ref(), &self.path)?;
+        let checkpoint = content.checkpoint;
+        // the data files before the tail are dropped by the last compaction, its file has the live records of them,
+        // while the blobs it keeps are named by the epochs they're written at.
+        let files_since = since.epoch.max(self.tail_epoch.load(Ordering::SeqCst));
+        let storage = self.storage.as_ref();
+        storage.create_dir_all(dest)?;
+        storage.copy(&self.path.join(".engine"), &dest.join(".engine"))?;
+        for recorded in [INDEX_FILE, CODEC_FILE] {
+            match storage.copy(&self.path.join(recorded), &dest.join(recorded)) {
+                // the default index and codec aren't recorded.
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                copied => {
+                    copied?;
+                }
+            }
+        }
+        let mut files: Vec<(PathBuf, u64)> = KvStore::enumerate_epoch_files(storage, &self.path)
+            .filter(|(_, epoch)| files_since <= *epoch && *epoch < checkpoint.epoch)
+            .collect();
+        files.sort_by_key(|(_, epoch)| *epoch);
+        let blobs = KvStore::enumerate_blob_files(storage, &self.path)?
+            .into_iter()
+            .filter(|(_, epoch)| since.epoch <= *epoch && *epoch < checkpoint.epoch)
+            .map(|(blob, _)| blob);
+        for file in files.into_iter().map(|(file, _)| file).chain(blobs) {
+            let name = file.file_name().expect("enumerated files have names");
+            storage.copy(&file, &dest.join(name))?;
+        }
+        // the checkpoint of the backup itself, since another one may be made meanwhile,
+        // it makes the restored store open fast, if no compaction happens since the full backup.
+        content.save(storage, dest)?;
+        Ok(checkpoint)
+    }
+
+    /// the data files before `checkpoint` that no key or retained version refers to now,
+    /// so that they can be archived or deleted safely.
+    ///
+    /// The live records in the elder files are rewritten by compactions, which make the files obsolete.
+    pub fn obsolete_segments(&self, checkpoint: &Checkpoint) -> Result<Vec<PathBuf>> {
+        let linked = self.history_versions > 1 || self.soft_delete;
+        let mut referenced = HashSet::new();
+        for (key, location) in self.index.entries() {
+            referenced.insert(location.epoch);
+            if linked {
+                self.walk_versions(key.as_str(), |command| {
+                    referenced.extend(command.prev().map(|prev| prev.epoch));
+                    true
+                })?;
+            }
+        }
+        let mut segments: Vec<(PathBuf, u64)> = KvStore::enumerate_epoch_files(self.storage.as_ref(), &self.path)
+            .filter(|(_, epoch)| *epoch < checkpoint.epoch && !referenced.contains(epoch))
+            .collect();
+        segments.sort_by_key(|(_, epoch)| *epoch);
+        Ok(segments.into_iter().map(|(path, _)| path).collect())
+    }
+
+    /// the records of the retained versions of `key`, from the latest.
+    fn versions(&self, key: &str) -> Result<Vec<KvCommand>> {
+        let mut versions = Vec::new();
+        self.walk_versions(key, |command| {
+            versions.push(command);
+            versions.len() < self.history_versions
+        })?;
+        Ok(versions)
+    }
+
+    /// visit the linked versions of `key` from the latest, until `visit` returns `false`.
+    fn walk_versions(&self, key: &str, mut visit: impl FnMut(KvCommand) -> bool) -> Result<()> {
+        let mut next = self.index.get(key);
+        // the elder versions may be dropped with their files by a compaction.
+        while let Some(location) = next.filter(|location| location.epoch >= self.tail_epoch.load(Ordering::SeqCst)) {
+            let command = self.reader.borrow_mut().load_command(key, location)?;
+            next = command.prev();
+            if !visit(command) {
+                break;
+            }
+        }
+        Ok(())
+    }
 }
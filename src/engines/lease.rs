@@ -0,0 +1,65 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::errors::{KvError, Result};
+
+/// A lease of a key taken by `KvsEngine::acquire_lease`,
+/// kept as the value of the key in JSON until it's released, or taken again once it expires.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Lease {
+    /// the secret of the holder, to release the lease by.
+    pub token: String,
+    /// the milliseconds since the unix epoch when it expires.
+    pub expires: u64,
+}
+
+/// the milliseconds since the unix epoch.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+impl Lease {
+    /// a lease with a random token, expiring `ttl` later.
+    pub fn new(ttl: Duration) -> Self {
+        Lease {
+            token: format!("{:016x}", rand::random::<u64>()),
+            expires: now_millis() + ttl.as_millis() as u64,
+        }
+    }
+
+    /// the lease kept as `value`, `None` if it isn't one.
+    pub fn decode(value: &str) -> Option<Self> {
+        serde_json::from_str(value).ok()
+    }
+
+    /// the value of the key holding the lease.
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("unable to serialize lease into json.")
+    }
+
+    /// whether it's expired, then another one can take the key.
+    pub fn is_expired(&self) -> bool {
+        now_millis() >= self.expires
+    }
+
+    /// whether `key` can be leased, when its value is `current`: absent, or an expired lease.
+    ///
+    /// # Error
+    ///
+    /// when the value isn't a lease, it throws, since the key is used for something else.
+    pub(crate) fn is_free(key: &str, current: Option<&str>) -> Result<bool> {
+        match current {
+            None => Ok(true),
+            Some(value) => match Self::decode(value) {
+                Some(lease) => Ok(lease.is_expired()),
+                None => Err(KvError::Other {
+                    reason: format!("the value of {:?} isn't a lease", key),
+                }),
+            },
+        }
+    }
+}
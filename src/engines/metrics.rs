@@ -0,0 +1,212 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Default, Debug)]
+struct Counters {
+    gets: AtomicU64,
+    sets: AtomicU64,
+    removes: AtomicU64,
+    misses: AtomicU64,
+    bytes_written: AtomicU64,
+    compactions: AtomicU64,
+    compaction_micros_total: AtomicU64,
+    compaction_micros_max: AtomicU64,
+    compaction_bytes_written: AtomicU64,
+    compaction_failures: AtomicU64,
+    last_compaction: AtomicU64,
+    stale_bytes: AtomicU64,
+    data_bytes: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    cache_bytes: AtomicU64,
+    index_keys: AtomicU64,
+    index_bytes: AtomicU64,
+}
+
+/// The live counters of the operations on an engine.
+///
+/// Like `PoolMetrics`, it's cheap to `Clone` it, and all clones share the same counters.
+/// Engines that don't count their operations report zeros.
+#[derive(Clone, Default, Debug)]
+pub struct EngineMetrics(Arc<Counters>);
+
+/// A point-in-time view of `EngineMetrics`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct EngineMetricsSnapshot {
+    /// the count of `get`.
+    pub gets: u64,
+    /// the count of `set`.
+    pub sets: u64,
+    /// the count of `remove`.
+    pub removes: u64,
+    /// the count of `get` and `remove` on absent keys.
+    pub misses: u64,
+    /// the bytes written into the storage, including the overhead of the format.
+    pub bytes_written: u64,
+    /// the count of finished compactions.
+    pub compactions: u64,
+    /// the total time spent on compactions, in microseconds.
+    pub compaction_micros_total: u64,
+    /// the longest compaction, in microseconds.
+    pub compaction_micros_max: u64,
+    /// the bytes rewritten into the storage by compactions.
+    #[serde(default)]
+    pub compaction_bytes_written: u64,
+    /// the count of failed compactions.
+    #[serde(default)]
+    pub compaction_failures: u64,
+    /// the milliseconds since the unix epoch when the last compaction finished, 0 if none did.
+    #[serde(default)]
+    pub last_compaction: u64,
+    /// the bytes in the storage that no key uses any more, to be dropped by the next compaction.
+    #[serde(default)]
+    pub stale_bytes: u64,
+    /// the bytes of the files in the storage, including the stale ones.
+    #[serde(default)]
+    pub data_bytes: u64,
+    /// the count of the records found in the record cache, or the values found in the hot tier of `Tiered`.
+    #[serde(default)]
+    pub cache_hits: u64,
+    /// the count of the records read from the storage, since they aren't in the record cache or the hot tier.
+    #[serde(default)]
+    pub cache_misses: u64,
+    /// the memory taken by the record cache and the hot tier of `Tiered`, in bytes.
+    #[serde(default)]
+    pub cache_bytes: u64,
+    /// the count of the keys in the in-memory index, including the removed ones it still holds.
+    #[serde(default)]
+    pub index_keys: u64,
+    /// the estimated memory taken by the in-memory index, in bytes.
+    #[serde(default)]
+    pub index_bytes: u64,
+}
+
+impl EngineMetrics {
+    /// record a `get`, `found` tells whether the key exists.
+    pub fn record_get(&self, found: bool) {
+        self.0.gets.fetch_add(1, Ordering::Relaxed);
+        if !found {
+            self.0.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// record a `set` that writes `bytes` into the storage.
+    pub fn record_set(&self, bytes: u64) {
+        self.0.sets.fetch_add(1, Ordering::Relaxed);
+        self.0.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// record `count` sets written together, which write `bytes` into the storage.
+    pub fn record_sets(&self, count: u64, bytes: u64) {
+        self.0.sets.fetch_add(count, Ordering::Relaxed);
+        self.0.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// record a `remove`, `found` tells whether the key exists, and `bytes` are written into the storage.
+    pub fn record_remove(&self, found: bool, bytes: u64) {
+        self.0.removes.fetch_add(1, Ordering::Relaxed);
+        if !found {
+            self.0.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        self.0.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// record `count` removes of present keys written together, which write `bytes` into the storage.
+    pub fn record_removes(&self, count: u64, bytes: u64) {
+        self.0.removes.fetch_add(count, Ordering::Relaxed);
+        self.0.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// record a finished compaction, which takes `elapsed` and rewrites `bytes` into the storage.
+    pub fn record_compaction(&self, elapsed: Duration, bytes: u64) {
+        let micros = elapsed.as_micros() as u64;
+        self.0.compactions.fetch_add(1, Ordering::Relaxed);
+        self.0.compaction_bytes_written.fetch_add(bytes, Ordering::Relaxed);
+        self.0.compaction_micros_total.fetch_add(micros, Ordering::Relaxed);
+        self.0.compaction_micros_max.fetch_max(micros, Ordering::Relaxed);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.0.last_compaction.store(now.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// record the bytes in the storage that no key uses any more.
+    pub fn record_stale_bytes(&self, bytes: u64) {
+        self.0.stale_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    /// record the bytes of the files in the storage, after they're counted again.
+    pub fn record_data_bytes(&self, bytes: u64) {
+        self.0.data_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    /// record `bytes` appended to the files in the storage.
+    pub fn record_data_appended(&self, bytes: u64) {
+        self.0.data_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// record a failed compaction.
+    pub fn record_compaction_failure(&self) {
+        self.0.compaction_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// record a lookup in the record cache, `hit` tells whether the record is found in it.
+    pub fn record_cache(&self, hit: bool) {
+        if hit {
+            self.0.cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.0.cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// record that a cache takes `after` bytes of memory, instead of `before`.
+    pub fn record_cache_resized(&self, before: usize, after: usize) {
+        if after >= before {
+            self.0.cache_bytes.fetch_add((after - before) as u64, Ordering::Relaxed);
+        } else {
+            self.0.cache_bytes.fetch_sub((before - after) as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// record the size of the in-memory index: `keys` in it, taking about `bytes` of memory.
+    pub fn record_index(&self, keys: u64, bytes: u64) {
+        self.0.index_keys.store(keys, Ordering::Relaxed);
+        self.0.index_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    /// take a snapshot of all counters.
+    pub fn snapshot(&self) -> EngineMetricsSnapshot {
+        let c = &self.0;
+        EngineMetricsSnapshot {
+            gets: c.gets.load(Ordering::Relaxed),
+            sets: c.sets.load(Ordering::Relaxed),
+            removes: c.removes.load(Ordering::Relaxed),
+            misses: c.misses.load(Ordering::Relaxed),
+            bytes_written: c.bytes_written.load(Ordering::Relaxed),
+            compactions: c.compactions.load(Ordering::Relaxed),
+            compaction_micros_total: c.compaction_micros_total.load(Ordering::Relaxed),
+            compaction_micros_max: c.compaction_micros_max.load(Ordering::Relaxed),
+            compaction_bytes_written: c.compaction_bytes_written.load(Ordering::Relaxed),
+            compaction_failures: c.compaction_failures.load(Ordering::Relaxed),
+            last_compaction: c.last_compaction.load(Ordering::Relaxed),
+            stale_bytes: c.stale_bytes.load(Ordering::Relaxed),
+            data_bytes: c.data_bytes.load(Ordering::Relaxed),
+            cache_hits: c.cache_hits.load(Ordering::Relaxed),
+            cache_misses: c.cache_misses.load(Ordering::Relaxed),
+            cache_bytes: c.cache_bytes.load(Ordering::Relaxed),
+            index_keys: c.index_keys.load(Ordering::Relaxed),
+            index_bytes: c.index_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl EngineMetricsSnapshot {
+    /// the share of the storage that no key uses any more, 0 for an empty storage.
+    pub fn stale_ratio(&self) -> f64 {
+        if self.data_bytes == 0 {
+            return 0.0;
+        }
+        self.stale_bytes as f64 / self.data_bytes as f64
+    }
+}
@@ -1,8 +1,34 @@
+/// archiving the cold data files of the kvs engine to an object store, see `ArchiveStorage`.
+pub mod archive;
+/// tailing the data files of the kvs engine, see `KvsEngine::changes`.
+pub mod changes;
+/// the encodings of the records in the data files of the kvs engine, see `KvStore::open_with_codec`.
+pub mod codec;
+/// the request an engine operation serves, see `RequestContext`.
+pub mod context;
 /// the engine abstraction.
 pub mod engine;
 /// the error type.
 pub mod errors;
+/// the counters of engine operations.
+pub mod metrics;
 /// the kvs engine implementation (default).
 pub mod kvs;
+/// the leases of keys with expiry, see `KvsEngine::acquire_lease`.
+pub mod lease;
+/// the offline tools on the data directories of the kvs engine, for `kvs-admin`.
+pub mod offline;
+/// the glob patterns and the bounds of key listings, see `KvsEngine::list_keys`.
+pub mod pattern;
+/// running the operations of a blocking engine on a thread pool for async code, see `PooledEngine`.
+pub mod pooled;
+/// swapping the data of an engine with a backup while serving, see `KvsEngine::restore`.
+pub mod restorable;
 /// the sled engine implementation.
 pub mod sled;
+/// keeping the hot values in memory in front of a disk engine.
+pub mod tiered;
+/// the values with explicit types, see `KvsEngine::get_typed`.
+pub mod typed;
+/// where the kvs engine keeps its files, on the disk or in memory, and the fault injection of it.
+pub mod storage;
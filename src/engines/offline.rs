@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::engines::codec::{self, RecordCodec};
+use crate::engines::errors::{KvError, Result};
+use crate::engines::kvs::{self, filename_of, KvCommand, KvStore, SavedEntry};
+use crate::engines::storage::FsStorage;
+
+/// The result of reading a data file, see `verify`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FileReport {
+    /// the data file.
+    pub path: PathBuf,
+    /// the epoch of the data file.
+    pub epoch: u64,
+    /// the readable records from the start.
+    pub records: u64,
+    /// the length of the file.
+    pub len: u64,
+    /// the length of the readable records from the start, less than `len` when the file has a corrupt tail.
+    pub valid_len: u64,
+    /// why the record at `valid_len` cannot be read.
+    pub error: Option<String>,
+}
+
+impl FileReport {
+    /// whether every record of the file can be read.
+    pub fn is_intact(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// The result of checking a data directory, see `verify`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Report {
+    /// the data files, by their epochs.
+    pub files: Vec<FileReport>,
+    /// the blob files the live values are in, but don't exist.
+    pub missing_blobs: Vec<String>,
+    /// how the index saved by the last checkpoint differs from the records, which the opens would load wrong.
+    pub index_errors: Vec<String>,
+}
+
+impl Report {
+    /// whether every record can be read, every live value can be found, and the saved index matches the records.
+    pub fn is_intact(&self) -> bool {
+        self.files.iter().all(FileReport::is_intact) && self.missing_blobs.is_empty() && self.index_errors.is_empty()
+    }
+
+    /// the count of the records read.
+    pub fn records(&self) -> u64 {
+        self.files.iter().map(|file| file.records).sum()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for file in self.files.iter() {
+            match &file.error {
+                None => writeln!(f, "{}: {} records, ok", filename_of(file.epoch), file.records)?,
+                Some(error) => writeln!(
+                    f,
+                    "{}: {} records, unreadable from offset {} of {}: {}",
+                    filename_of(file.epoch),
+                    file.records,
+                    file.valid_len,
+                    file.len,
+                    error
+                )?,
+            }
+        }
+        for blob in self.missing_blobs.iter() {
+            writeln!(f, "{}: missing", blob)?;
+        }
+        for error in self.index_errors.iter() {
+            writeln!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// The statistics of a data directory, see `stats`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// the count of the data files.
+    pub data_files: u64,
+    /// the total length of the data files.
+    pub data_bytes: u64,
+    /// the readable records in the data files.
+    pub records: u64,
+    /// the keys whose last records are values.
+    pub live_keys: u64,
+    /// the keys whose last records are removals.
+    pub removed_keys: u64,
+    /// the count of the blob files.
+    pub blobs: u64,
+    /// the total length of the blob files.
+    pub blob_bytes: u64,
+}
+
+impl Stats {
+    /// the records that aren't the last ones of their keys, which a compaction drops.
+    pub fn garbage_records(&self) -> u64 {
+        self.records.saturating_sub(self.live_keys + self.removed_keys)
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "data files: {} ({} bytes)", self.data_files, self.data_bytes)?;
+        writeln!(f, "records: {} ({} garbage)", self.records, self.garbage_records())?;
+        writeln!(f, "keys: {} live, {} removed", self.live_keys, self.removed_keys)?;
+        writeln!(f, "blobs: {} ({} bytes)", self.blobs, self.blob_bytes)
+    }
+}
+
+/// fail unless `dir` is a data directory of the `kvs` engine, without creating anything in it.
+fn check_data_dir(dir: &Path) -> Result<()> {
+    match fs::read_to_string(dir.join(".engine")) {
+        Ok(engine) if engine.trim().eq_ignore_ascii_case("kvs") => Ok(()),
+        Ok(_) => Err(KvError::IllegalWorkingDirectory),
+        Err(io_error) => Err(KvError::FailToOpenFile {
+            file_name: dir.join(".engine").to_string_lossy().into_owned(),
+            io_error,
+        }),
+    }
+}
+
+/// the codec of the records, and the data files by their epochs.
+type DataFiles = (Arc<dyn RecordCodec>, Vec<(PathBuf, u64)>);
+
+/// the data files in `dir` by their epochs, and the codec of their records.
+fn data_files(dir: &Path) -> Result<DataFiles> {
+    check_data_dir(dir)?;
+    let mut files: Vec<(PathBuf, u64)> = KvStore::enumerate_epoch_files(&FsStorage, dir).collect();
+    files.sort_by_key(|(_, epoch)| *epoch);
+    Ok((codec::codec_of(&FsStorage, dir)?, files))
+}
+
+/// read the records of a data file from the start, until the end or the first unreadable one,
+/// `visit` takes the offset of each record and the record.
+fn scan(
+    codec: &dyn RecordCodec,
+    path: &Path,
+    epoch: u64,
+    mut visit: impl FnMut(usize, KvCommand),
+) -> Result<FileReport> {
+    let len = fs::metadata(path)?.len();
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut report = FileReport { path: path.to_owned(), epoch, records: 0, len, valid_len: 0, error: None };
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        let whole = match codec.read_record(&mut reader, &mut buf) {
+            Ok(whole) => whole,
+            Err(err) => {
+                report.error = Some(err.to_string());
+                break;
+            }
+        };
+        let read = buf.len();
+        if read == 0 {
+            break;
+        }
+        if !whole {
+            report.error = Some("the record is torn".to_owned());
+            break;
+        }
+        match codec.decode(buf.as_slice()) {
+            Ok(command) => visit(report.valid_len as usize, command),
+            Err(err) => {
+                report.error = Some(err.to_string());
+                break;
+            }
+        }
+        report.records += 1;
+        report.valid_len += read as u64;
+    }
+    Ok(report)
+}
+
+/// the last records of the keys, replaying the data files by their epochs like `KvStore::open` does.
+fn replay(dir: &Path) -> Result<(Vec<FileReport>, HashMap<String, KvCommand>)> {
+    let mut last = HashMap::new();
+    let mut reports = Vec::new();
+    let (codec, files) = data_files(dir)?;
+    for (path, epoch) in files {
+        reports.push(scan(codec.as_ref(), path.as_path(), epoch, |_, command| {
+            last.insert(command.key().to_owned(), command);
+        })?);
+    }
+    Ok((reports, last))
+}
+
+/// check that every record of the data files in `dir` can be read, and the blobs of the live values exist,
+/// and that the index saved by the last checkpoint points each key to its last record before the checkpoint,
+/// unless the checkpoint is stale since a compaction, then the opens ignore it.
+pub fn verify(dir: impl AsRef<Path>) -> Result<Report> {
+    let dir = dir.as_ref();
+    let (codec, data_files) = data_files(dir)?;
+    let saved = kvs::saved_index(&FsStorage, dir);
+    let checkpoint_epoch = match &saved {
+        Some(Ok((epoch, _))) => Some(*epoch),
+        _ => None,
+    };
+    let mut last = HashMap::new();
+    // the last records of the keys at the checkpoint, by their epochs and offsets.
+    let mut at_checkpoint = HashMap::new();
+    let mut files = Vec::new();
+    for (path, epoch) in data_files {
+        let sealed = checkpoint_epoch.is_some_and(|checkpoint| epoch < checkpoint);
+        files.push(scan(codec.as_ref(), path.as_path(), epoch, |offset, command| {
+            if sealed {
+                at_checkpoint.insert(command.key().to_owned(), (epoch, offset));
+            }
+            last.insert(command.key().to_owned(), command);
+        })?);
+    }
+    let mut missing_blobs: Vec<String> = last
+        .into_values()
+        .filter_map(|command| match command {
+            KvCommand::Put { blob: Some(blob), .. } if !dir.join(blob.as_str()).is_file() => Some(blob),
+            _ => None,
+        })
+        .collect();
+    missing_blobs.sort();
+    let index_errors = match saved {
+        None => Vec::new(),
+        Some(Err(reason)) => vec![reason],
+        Some(Ok((epoch, entries))) => check_saved_index(epoch, entries, &files, at_checkpoint),
+    };
+    Ok(Report { files, missing_blobs, index_errors })
+}
+
+/// how the index saved by the checkpoint at `epoch` differs from `at_checkpoint`, the last records before it,
+/// nothing if it refers to the data files dropped by a compaction since, like the opens.
+fn check_saved_index(
+    epoch: u64,
+    entries: Vec<SavedEntry>,
+    files: &[FileReport],
+    mut at_checkpoint: HashMap<String, (u64, usize)>,
+) -> Vec<String> {
+    let lengths: HashMap<u64, u64> = files.iter().map(|file| (file.epoch, file.len)).collect();
+    let stale = entries.iter().any(|entry| {
+        lengths.get(&entry.epoch).is_none_or(|len| (entry.offset + entry.length) as u64 > *len)
+    });
+    if stale {
+        return Vec::new();
+    }
+    let mut errors = Vec::new();
+    for entry in entries {
+        match at_checkpoint.remove(entry.key.as_str()) {
+            Some(last) if last == (entry.epoch, entry.offset) => {}
+            Some((last_epoch, last_offset)) => errors.push(format!(
+                "the checkpoint at epoch {} points {:?} to {}:{}, but its last record is at {}:{}",
+                epoch,
+                entry.key,
+                filename_of(entry.epoch),
+                entry.offset,
+                filename_of(last_epoch),
+                last_offset
+            )),
+            None => errors.push(format!(
+                "the checkpoint at epoch {} points {:?} to {}:{}, but it has no record before",
+                epoch,
+                entry.key,
+                filename_of(entry.epoch),
+                entry.offset
+            )),
+        }
+    }
+    let mut missing: Vec<String> = at_checkpoint.into_keys().collect();
+    missing.sort();
+    errors.extend(missing.into_iter().map(|key| format!("the checkpoint at epoch {} misses {:?}", epoch, key)));
+    errors
+}
+
+/// whether `dir` holds the data of the `kvs` engine, so that there's anything to `verify`.
+pub fn is_data_dir(dir: impl AsRef<Path>) -> bool {
+    check_data_dir(dir.as_ref()).is_ok()
+}
+
+/// write the records of the data files in `dir` to `out` readably, one per line,
+/// with the data file and the offset of each record, stopping at the unreadable part of each file.
+pub fn dump(dir: impl AsRef<Path>, out: &mut impl Write) -> Result<()> {
+    let (codec, files) = data_files(dir.as_ref())?;
+    for (path, epoch) in files {
+        writeln!(out, "# {}", filename_of(epoch))?;
+        let mut written = Ok(());
+        let report = scan(codec.as_ref(), path.as_path(), epoch, |offset, command| {
+            if written.is_ok() {
+                written = writeln!(out, "{}", describe(offset, &command));
+            }
+        })?;
+        written?;
+        if let Some(error) = report.error {
+            writeln!(out, "# unreadable from offset {} of {}: {}", report.valid_len, report.len, error)?;
+        }
+    }
+    Ok(())
+}
+
+fn describe(offset: usize, command: &KvCommand) -> String {
+    let mut line = match command {
+        KvCommand::Put { key, value, modified, meta, blob, .. } => {
+            let mut line = format!("{}\tset {:?}", offset, key);
+            match blob {
+                Some(blob) => line.push_str(format!(" in {}", blob).as_str()),
+                None => line.push_str(format!(" = {:?}", value).as_str()),
+            }
+            if let Some(meta) = meta {
+                line.push_str(format!(" meta {:?}", meta).as_str());
+            }
+            if let Some(modified) = modified {
+                line.push_str(format!(" at {}ms", modified).as_str());
+            }
+            line
+        }
+        KvCommand::Rm { key, .. } => format!("{}\trm {:?}", offset, key),
+    };
+    if let Some(prev) = command.prev() {
+        line.push_str(format!(" after {}", prev).as_str());
+    }
+    line
+}
+
+/// cut the data files in `dir` at their first unreadable records, like the torn ones written during a crash,
+/// so that the store can be opened again. The records after them are lost.
+///
+/// Returns the reports of the files cut, before cutting them.
+pub fn truncate(dir: impl AsRef<Path>) -> Result<Vec<FileReport>> {
+    let mut cut = Vec::new();
+    let (codec, files) = data_files(dir.as_ref())?;
+    for (path, epoch) in files {
+        let report = scan(codec.as_ref(), path.as_path(), epoch, |_, _| {})?;
+        if !report.is_intact() {
+            let file = OpenOptions::new().write(true).open(path.as_path())?;
+            file.set_len(report.valid_len)?;
+            file.sync_all()?;
+            cut.push(report);
+        }
+    }
+    Ok(cut)
+}
+
+/// compact the data files in `dir`, and remove the ones before the compaction.
+/// The store must be closed, and its files readable, see `truncate`.
+///
+/// Returns the bytes rewritten.
+pub fn compact(dir: impl AsRef<Path>) -> Result<u64> {
+    let dir = dir.as_ref();
+    let (_, before) = data_files(dir)?;
+    let store = KvStore::open(dir)?;
+    let written = store.compact()?;
+    drop(store);
+    // every file before the compaction is obsolete, since no one else reads them.
+    for (path, _) in before {
+        if let Err(err) = fs::remove_file(path.as_path()) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                return Err(err.into());
+            }
+        }
+    }
+    Ok(written)
+}
+
+/// count the files, the records and the keys in `dir`.
+pub fn stats(dir: impl AsRef<Path>) -> Result<Stats> {
+    let dir = dir.as_ref();
+    let (files, last) = replay(dir)?;
+    let mut stats = Stats::default();
+    for file in files {
+        stats.data_files += 1;
+        stats.data_bytes += file.len;
+        stats.records += file.records;
+    }
+    for command in last.values() {
+        match command {
+            KvCommand::Put { .. } => stats.live_keys += 1,
+            KvCommand::Rm { .. } => stats.removed_keys += 1,
+        }
+    }
+    for (blob, _) in KvStore::enumerate_blob_files(&FsStorage, dir)? {
+        stats.blobs += 1;
+        stats.blob_bytes += fs::metadata(blob)?.len();
+    }
+    Ok(stats)
+}
@@ -0,0 +1,166 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Token {
+    Char(char),
+    /// `?`
+    Any,
+    /// `*`
+    Star,
+}
+
+/// A glob pattern of keys, like the one of Redis `KEYS`.
+///
+/// `*` matches any string, `?` matches any single character, and `\` escapes the next character.
+///
+/// # Example
+/// ```rust
+/// # use kvs::engines::pattern::KeyPattern;
+/// let pattern = KeyPattern::new("user:*:name");
+/// assert!(pattern.matches("user:42:name"));
+/// assert!(!pattern.matches("user:42:age"));
+/// assert_eq!(pattern.literal_prefix(), "user:");
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct KeyPattern {
+    tokens: Vec<Token>,
+    prefix: String,
+}
+
+impl KeyPattern {
+    /// parse a glob pattern, every string is a valid pattern.
+    /// A trailing `\` matches a `\` itself.
+    pub fn new(pattern: &str) -> Self {
+        let mut tokens = Vec::new();
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            tokens.push(match c {
+                '*' => Token::Star,
+                '?' => Token::Any,
+                '\\' => Token::Char(chars.next().unwrap_or('\\')),
+                c => Token::Char(c),
+            });
+        }
+        let prefix = tokens
+            .iter()
+            .map_while(|token| match token {
+                Token::Char(c) => Some(*c),
+                _ => None,
+            })
+            .collect();
+        KeyPattern { tokens, prefix }
+    }
+
+    /// the glob of `literal` itself, with `*`, `?` and `\` escaped,
+    /// like `format!("{}*", KeyPattern::escape(prefix))` for the keys starting with `prefix`.
+    pub fn escape(literal: &str) -> String {
+        let mut escaped = String::with_capacity(literal.len());
+        for c in literal.chars() {
+            if matches!(c, '*' | '?' | '\\') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+
+    /// the characters every matched key starts with,
+    /// so that engines with ordered index can scan only the keys with this prefix.
+    pub fn literal_prefix(&self) -> &str {
+        self.prefix.as_str()
+    }
+
+    /// whether `key` matches this pattern entirely.
+    pub fn matches(&self, key: &str) -> bool {
+        let key: Vec<char> = key.chars().collect();
+        let tokens = &self.tokens;
+        let (mut t, mut k) = (0, 0);
+        // the last `*`, and where the key was when it matched nothing, to backtrack to.
+        let mut star: Option<(usize, usize)> = None;
+        while k < key.len() {
+            match tokens.get(t) {
+                Some(Token::Star) => {
+                    star = Some((t, k));
+                    t += 1;
+                    continue;
+                }
+                Some(Token::Any) => {
+                    t += 1;
+                    k += 1;
+                    continue;
+                }
+                Some(Token::Char(c)) if *c == key[k] => {
+                    t += 1;
+                    k += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            match star {
+                Some((star_at, matched_to)) => {
+                    star = Some((star_at, matched_to + 1));
+                    t = star_at + 1;
+                    k = matched_to + 1;
+                }
+                None => return false,
+            }
+        }
+        tokens[t..].iter().all(|token| *token == Token::Star)
+    }
+}
+
+/// The order and the bounds of a key listing, see `KvsEngine::list_keys`.
+///
+/// To list the keys page by page, continue each page `after` the last key of the previous one, see `next_page`.
+/// The cursor is a key rather than a position in the index or the data files,
+/// so it stays valid across the writes and the compactions between the pages:
+/// the keys present during the whole listing are listed exactly once,
+/// while the `offset` of a page shifts when the keys before it are written or removed.
+///
+/// # Example
+/// ```rust
+/// # use kvs::engines::pattern::ListOptions;
+/// // the last 2 keys.
+/// let options = ListOptions { reverse: true, limit: Some(2), ..ListOptions::default() };
+/// // the 2 keys before them.
+/// let keys = vec!["user:42".to_owned(), "user:33".to_owned()];
+/// assert_eq!(options.next_page(&keys).unwrap().after, Some("user:33".to_owned()));
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ListOptions {
+    /// list the keys in descending order.
+    pub reverse: bool,
+    /// skip this many matched keys first.
+    pub offset: usize,
+    /// list at most this many keys, or all of them when `None`.
+    pub limit: Option<usize>,
+    /// list only the keys after this one in the order of the listing, before it when reversed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+}
+
+impl ListOptions {
+    /// the most keys to list, after skipping `offset` keys.
+    pub fn max_len(&self) -> usize {
+        self.limit.unwrap_or(usize::MAX)
+    }
+
+    /// whether `key` comes after the cursor `after` in the order of the listing, always true without a cursor.
+    pub fn is_after_cursor(&self, key: &str) -> bool {
+        match self.after.as_deref() {
+            None => true,
+            Some(after) if self.reverse => key < after,
+            Some(after) => key > after,
+        }
+    }
+
+    /// the options of the page after `keys`, listed by these options,
+    /// or `None` if it's the last page, since it has fewer keys than `limit`.
+    pub fn next_page(&self, keys: &[String]) -> Option<ListOptions> {
+        if keys.len() < self.max_len() {
+            return None;
+        }
+        Some(ListOptions { offset: 0, after: keys.last().cloned(), ..self.clone() })
+    }
+}
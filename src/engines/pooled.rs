@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use crate::benchmark_common::Promise;
+use crate::engines::engine::KvsEngine;
+use crate::engines::errors::Result;
+use crate::engines::pattern::ListOptions;
+use crate::thread_pool::ThreadPool;
+
+/// A blocking `KvsEngine` whose operations run on a `ThreadPool`, returning the futures of their results,
+/// so that async code doesn't do the file IO of the engine on its reactor threads.
+///
+/// The pool can be the one serving the blocking `KvServer`, like a `TokioThreadPool`,
+/// whose runtime also runs the async code, so that both share one pool configuration.
+/// Each operation runs on a clone of the engine, which is cheap, see `KvsEngine`.
+/// It's `Sync` only if the engine is, which `KvStore` isn't, so move a clone of it into each spawned future.
+///
+/// # Example
+/// ```rust
+/// # use std::sync::Arc;
+/// # use tempfile::TempDir;
+/// # use kvs::KvStore;
+/// # use kvs::engines::pooled::PooledEngine;
+/// # use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+/// # let temp_dir = TempDir::new().unwrap();
+/// let pool = Arc::new(SharedQueueThreadPool::new(2).unwrap());
+/// let engine = PooledEngine::new(KvStore::open(temp_dir.path()).unwrap(), pool);
+/// async fn greet(engine: &PooledEngine<KvStore, SharedQueueThreadPool>) -> kvs::Result<Option<String>> {
+///     engine.set("hello".to_owned(), "world".to_owned()).await?;
+///     engine.get("hello".to_owned()).await
+/// }
+/// ```
+pub struct PooledEngine<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: Arc<P>,
+}
+
+impl<E: KvsEngine, P: ThreadPool> Clone for PooledEngine<E, P> {
+    fn clone(&self) -> Self {
+        PooledEngine {
+            engine: self.engine.clone(),
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<E: KvsEngine, P: ThreadPool> PooledEngine<E, P> {
+    /// run the operations of `engine` on `pool`.
+    pub fn new(engine: E, pool: Arc<P>) -> Self {
+        PooledEngine { engine, pool }
+    }
+
+    /// the engine wrapped, for the operations that can block the current thread.
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    /// run `operation` with a clone of the engine on the pool,
+    /// for the operations of `KvsEngine` without a shorthand here.
+    ///
+    /// # Error
+    ///
+    /// When the operation panics, the future resolves to `TaskPanicked`, see `ThreadPool::spawn_with_result`.
+    pub fn run<T, F>(&self, operation: F) -> Promise<Result<T>>
+        where
+            T: Send + 'static,
+            F: FnOnce(E) -> Result<T> + Send + 'static,
+    {
+        let engine = self.engine.clone();
+        self.pool
+            .spawn_with_result(move || operation(engine))
+            .map(|result| result.and_then(|result| result))
+    }
+
+    /// see `KvsEngine::get`.
+    pub fn get(&self, key: String) -> Promise<Result<Option<String>>> {
+        self.run(move |engine| engine.get(key))
+    }
+
+    /// see `KvsEngine::set`.
+    pub fn set(&self, key: String, value: String) -> Promise<Result<()>> {
+        self.run(move |engine| engine.set(key, value))
+    }
+
+    /// see `KvsEngine::remove`.
+    pub fn remove(&self, key: String) -> Promise<Result<()>> {
+        self.run(move |engine| engine.remove(key))
+    }
+
+    /// see `KvsEngine::list_keys`.
+    pub fn list_keys(&self, pattern: String, options: ListOptions) -> Promise<Result<Vec<String>>> {
+        self.run(move |engine| engine.list_keys(pattern, options))
+    }
+}
@@ -0,0 +1,287 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use log::info;
+
+use crate::engines::changes::{Change, LogPosition};
+use crate::engines::context;
+use crate::engines::engine::{BatchWrite, KvsEngine, Preload, SetCondition, ValueWithMeta};
+use crate::engines::errors::{KvError, Result};
+use crate::engines::lease::Lease;
+use crate::engines::metrics::EngineMetrics;
+use crate::engines::pattern::ListOptions;
+use crate::engines::typed::TypedValue;
+
+/// the file in the root directory naming the data directory in use, written by a restore.
+const CURRENT_FILE: &str = "CURRENT";
+
+/// the data directory under `root`: the one named by its `CURRENT` file after a restore, or `root` itself.
+pub fn data_dir(root: impl AsRef<Path>) -> Result<PathBuf> {
+    let root = root.as_ref();
+    match fs::read_to_string(root.join(CURRENT_FILE)) {
+        Ok(name) => Ok(root.join(name.trim())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(root.to_owned()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// read the backups made by `KvStore::backup` and `KvStore::backup_incremental` into an archive to restore,
+/// the files by their names, the later backups overwrite the same files of the earlier ones.
+pub fn read_archive(backups: &[impl AsRef<Path>]) -> io::Result<BTreeMap<String, Vec<u8>>> {
+    let mut archive = BTreeMap::new();
+    for backup in backups {
+        for entry in fs::read_dir(backup)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            archive.insert(name, fs::read(entry.path())?);
+        }
+    }
+    Ok(archive)
+}
+
+/// whether `name` is a file right in a directory, not `..` nor a path.
+fn is_plain_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+}
+
+/// how to open the engine in a data directory.
+type Opener<E> = dyn Fn(&Path) -> Result<E> + Send + Sync;
+
+struct Shared<E> {
+    /// the operations hold it for reading, and the swap for writing,
+    /// so that no operation runs across a swap.
+    gate: RwLock<()>,
+    current: Mutex<E>,
+    /// how many times the engine is swapped, the clones refresh their engines when it changes.
+    generation: AtomicU64,
+}
+
+/// A `KvsEngine` whose data can be replaced by a backup while serving, see `KvsEngine::restore`.
+///
+/// The restored data goes into a new directory under the root directory,
+/// then the `CURRENT` file in the root switches to it by a rename,
+/// so that a crash leaves either the old data or the new one, and `data_dir` finds it after a restart.
+/// The replaced directory is kept, remove it by hand once it isn't needed.
+///
+/// # Example
+/// ```no_run
+/// # use kvs::KvStore;
+/// # use kvs::engines::restorable::Restorable;
+/// # fn main() -> kvs::Result<()> {
+/// let engine = Restorable::open(std::env::current_dir()?, |path| KvStore::open(path))?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Restorable<E> {
+    root: PathBuf,
+    open: Arc<Opener<E>>,
+    shared: Arc<Shared<E>>,
+    local: RefCell<(u64, E)>,
+}
+
+impl<E: KvsEngine> Clone for Restorable<E> {
+    fn clone(&self) -> Self {
+        Restorable {
+            root: self.root.clone(),
+            open: self.open.clone(),
+            shared: self.shared.clone(),
+            local: RefCell::new(self.local.borrow().clone()),
+        }
+    }
+}
+
+impl<E: KvsEngine> Restorable<E> {
+    /// open the engine in the data directory under `root` by `open`, which also opens the restored data.
+    pub fn open(root: impl AsRef<Path>, open: impl Fn(&Path) -> Result<E> + Send + Sync + 'static) -> Result<Self> {
+        let root = root.as_ref().to_owned();
+        let engine = open(data_dir(&root)?.as_path())?;
+        Ok(Restorable {
+            root,
+            open: Arc::new(open),
+            shared: Arc::new(Shared {
+                gate: RwLock::new(()),
+                current: Mutex::new(engine.clone()),
+                generation: AtomicU64::new(0),
+            }),
+            local: RefCell::new((0, engine)),
+        })
+    }
+
+    fn with_engine<T>(&self, f: impl FnOnce(&E) -> Result<T>) -> Result<T> {
+        let _gate = self.shared.gate.read()?;
+        let generation = self.shared.generation.load(Ordering::SeqCst);
+        if self.local.borrow().0 != generation {
+            let current = self.shared.current.lock()?.clone();
+            *self.local.borrow_mut() = (generation, current);
+        }
+        f(&self.local.borrow().1)
+    }
+
+    /// write `archive` into the new directory `dir`, and open it.
+    fn stage(&self, dir: &Path, archive: &BTreeMap<String, Vec<u8>>) -> Result<E> {
+        for (name, content) in archive {
+            if !is_plain_file_name(name) {
+                return Err(KvError::Other {
+                    reason: format!("illegal file name in the archive: {}", name),
+                });
+            }
+            let mut file = File::create(dir.join(name))?;
+            file.write_all(content.as_slice())?;
+            file.sync_all()?;
+        }
+        (self.open)(dir)
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Restorable<E> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.with_engine(|engine| engine.get(key))
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.with_engine(|engine| engine.set(key, value))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.with_engine(|engine| engine.remove(key))
+    }
+
+    fn undelete(&self, key: String) -> Result<()> {
+        self.with_engine(|engine| engine.undelete(key))
+    }
+
+    fn set_stream(&self, key: String, value: &mut dyn Read) -> Result<()> {
+        self.with_engine(|engine| engine.set_stream(key, value))
+    }
+
+    fn get_stream(&self, key: String) -> Result<Option<Box<dyn Read>>> {
+        self.with_engine(|engine| engine.get_stream(key))
+    }
+
+    fn get_or_insert_with(&self, key: String, default: impl FnOnce() -> String) -> Result<String> {
+        self.with_engine(|engine| engine.get_or_insert_with(key, default))
+    }
+
+    fn set_if(&self, key: String, value: String, condition: SetCondition) -> Result<bool> {
+        self.with_engine(|engine| engine.set_if(key, value, condition))
+    }
+
+    fn set_if_unmodified(&self, key: String, value: String, modified: SystemTime) -> Result<bool> {
+        self.with_engine(|engine| engine.set_if_unmodified(key, value, modified))
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        self.with_engine(|engine| engine.rename(from, to))
+    }
+
+    fn copy(&self, from: String, to: String) -> Result<()> {
+        self.with_engine(|engine| engine.copy(from, to))
+    }
+
+    fn remove_prefix(&self, prefix: String) -> Result<usize> {
+        self.with_engine(|engine| engine.remove_prefix(prefix))
+    }
+
+    fn write_batch(&self, writes: Vec<BatchWrite>) -> Result<()> {
+        self.with_engine(|engine| engine.write_batch(writes))
+    }
+
+    fn acquire_lease(&self, key: String, ttl: Duration) -> Result<Option<Lease>> {
+        self.with_engine(|engine| engine.acquire_lease(key, ttl))
+    }
+
+    fn release_lease(&self, key: String, token: String) -> Result<bool> {
+        self.with_engine(|engine| engine.release_lease(key, token))
+    }
+
+    fn get_bytes(&self, key: String) -> Result<Option<Bytes>> {
+        self.with_engine(|engine| engine.get_bytes(key))
+    }
+
+    fn get_typed(&self, key: String) -> Result<Option<TypedValue>> {
+        self.with_engine(|engine| engine.get_typed(key))
+    }
+
+    fn set_typed(&self, key: String, value: TypedValue) -> Result<()> {
+        self.with_engine(|engine| engine.set_typed(key, value))
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.with_engine(|engine| engine.incr(key, delta))
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<()> {
+        self.with_engine(|engine| engine.append(key, suffix))
+    }
+
+    fn get_with_meta(&self, key: String) -> Result<Option<ValueWithMeta>> {
+        self.with_engine(|engine| engine.get_with_meta(key))
+    }
+
+    fn set_with_meta(&self, key: String, value: String, meta: Option<String>) -> Result<()> {
+        self.with_engine(|engine| engine.set_with_meta(key, value, meta))
+    }
+
+    fn list_keys(&self, pattern: String, options: ListOptions) -> Result<Vec<String>> {
+        self.with_engine(|engine| engine.list_keys(pattern, options))
+    }
+
+    /// the changes of the data in use, a restore swapping in another data directory doesn't move them.
+    fn changes(&self, since: LogPosition, follow: bool) -> Result<Box<dyn Iterator<Item=Result<Change>> + Send>> {
+        self.with_engine(|engine| engine.changes(since, follow))
+    }
+
+    fn log_end(&self) -> Result<LogPosition> {
+        self.with_engine(|engine| engine.log_end())
+    }
+
+    /// open the archive in a new data directory, then swap it in, once the operations running are done.
+    /// The writes during the restore are lost with the replaced data.
+    fn restore(&self, archive: BTreeMap<String, Vec<u8>>) -> Result<()> {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let name = format!("restore-{}", since_epoch.as_millis());
+        let dir = self.root.join(name.as_str());
+        fs::create_dir(&dir)?;
+        let engine = match self.stage(&dir, &archive) {
+            Ok(engine) => engine,
+            Err(err) => {
+                fs::remove_dir_all(&dir)?;
+                return Err(err);
+            }
+        };
+        let _gate = self.shared.gate.write()?;
+        // switch by a rename, so that a crash leaves either the old data directory or the new one.
+        let temp = self.root.join(format!("{}.tmp", CURRENT_FILE));
+        let mut file = File::create(&temp)?;
+        file.write_all(name.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, self.root.join(CURRENT_FILE))?;
+        *self.shared.current.lock()? = engine;
+        self.shared.generation.fetch_add(1, Ordering::SeqCst);
+        info!("restored {} files into {}{}.", archive.len(), dir.display(), context::describe_current());
+        Ok(())
+    }
+
+    fn metrics(&self) -> EngineMetrics {
+        self.shared.current.lock().map(|engine| engine.metrics()).unwrap_or_default()
+    }
+
+    fn preload(&self, target: Preload) -> Result<usize> {
+        self.with_engine(|engine| engine.preload(target))
+    }
+
+    fn close(&self) -> Result<()> {
+        self.with_engine(|engine| engine.close())
+    }
+}
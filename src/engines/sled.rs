@@ -1,17 +1,85 @@
 use std::path::Path;
-use std::sync::{Arc, RwLock};
+use std::str::FromStr;
+use std::time::Duration;
 
-use sled::Db;
+use serde::Deserialize;
+use sled::transaction::{ConflictableTransactionResult, TransactionError};
+use sled::{Batch, Config, Db, IVec, Mode};
 use sled::Error::Io;
+use thiserror::Error;
 
-use crate::{KvError, KvsEngine};
+use crate::{EngineMetrics, KvError, KvsEngine};
 
+use super::context;
+use super::engine::{BatchWrite, SetCondition};
 use super::errors::Result;
+use super::lease::Lease;
+use super::pattern::{KeyPattern, ListOptions};
+use super::storage::FsStorage;
+use super::typed::ValueType;
+
+/// How sled places its data on the disk, see `SledOptions`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SledMode {
+    /// compact the files more eagerly, which keeps them small.
+    #[default]
+    Small,
+    /// compact the files less often, which is faster but lets them grow.
+    Fast,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Error)]
+#[error("No such sled mode: {0}")]
+/// Throws when we cannot parse the command line or the config file to a sled mode.
+pub struct NoSuchSledMode(String);
+
+impl FromStr for SledMode {
+    type Err = NoSuchSledMode;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "small" => Ok(SledMode::Small),
+            "fast" => Ok(SledMode::Fast),
+            _ => Err(NoSuchSledMode(s.to_owned())),
+        }
+    }
+}
+
+impl AsRef<str> for SledMode {
+    fn as_ref(&self) -> &str {
+        match self {
+            SledMode::Small => "small",
+            SledMode::Fast => "fast",
+        }
+    }
+}
+
+/// The tuning of sled, see `SledEngine::open_with`. The absent ones take the defaults of sled.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SledOptions {
+    /// the bytes of the pages cached in memory, 1GiB by default.
+    pub cache_capacity: Option<u64>,
+    /// flush the writes in the background every this many milliseconds,
+    /// instead of flushing each of them before it returns.
+    /// The writes since the last flush are lost by a crash.
+    pub flush_every_ms: Option<u64>,
+    /// how sled places its data on the disk.
+    pub mode: SledMode,
+}
 
 #[derive(Clone)]
 /// the adapter that wraps `sled::Db` to `KvsEngine`.
+///
+/// `Db` is thread-safe and cheap to clone, so the operations run concurrently without any lock,
+/// and the ones touching more than a key are sled transactions or batches.
+/// The writes are flushed to the disk before they return, unless `SledOptions::flush_every_ms` is set,
+/// the reads never flush.
 pub struct SledEngine {
-    db: Arc<RwLock<Db>>,
+    db: Db,
+    metrics: EngineMetrics,
+    flush_writes: bool,
 }
 
 impl From<sled::Error> for KvError {
@@ -22,14 +90,51 @@ impl From<sled::Error> for KvError {
     }
 }
 
+impl From<TransactionError> for KvError {
+    fn from(error: TransactionError) -> KvError {
+        match error {
+            TransactionError::Storage(error) => error.into(),
+            error => KvError::Other {
+                reason: format!("{}", error),
+            },
+        }
+    }
+}
+
+/// decode a value or a key stored by the engine.
+fn decode(bytes: IVec) -> Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|utf8_error| KvError::Other {
+        reason: format!("decode from sled binary failed since: {}", utf8_error),
+    })
+}
+
 impl SledEngine {
     /// open the `SledEngine` engine to some path.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        super::engine::check_engine::<&P>(&path, "sled")?;
+        Self::open_with(path, SledOptions::default())
+    }
 
-        Db::open(&path)
+    /// open the `SledEngine` engine to some path, tuned by `options`.
+    pub fn open_with<P: AsRef<Path>>(path: P, options: SledOptions) -> Result<Self> {
+        super::engine::check_engine::<&P>(&FsStorage, &path, "sled")?;
+
+        let mut config = Config::new().path(path.as_ref());
+        if let Some(bytes) = options.cache_capacity {
+            config = config.cache_capacity(bytes);
+        }
+        if options.flush_every_ms.is_some() {
+            config = config.flush_every_ms(options.flush_every_ms);
+        }
+        config = config.mode(match options.mode {
+            SledMode::Small => Mode::LowSpace,
+            SledMode::Fast => Mode::HighThroughput,
+        });
+        config
+            .open()
             .map(|db| SledEngine {
-                db: Arc::new(RwLock::new(db)),
+                db,
+                metrics: EngineMetrics::default(),
+                flush_writes: options.flush_every_ms.is_none(),
             })
             .map_err(|err| {
                 if let Io(io_error) = err {
@@ -44,34 +149,289 @@ impl SledEngine {
                 }
             })
     }
+
+    /// write `pairs` by one `sled::Batch`, which is applied atomically, then flush once.
+    /// Unlike `KvStore::ingest`, the keys needn't be sorted.
+    ///
+    /// Returns the bytes of the keys and the values written.
+    pub fn ingest(&self, pairs: impl IntoIterator<Item=(String, String)>) -> Result<u64> {
+        let mut batch = Batch::default();
+        let mut written = 0;
+        for (key, value) in pairs {
+            let bytes = (key.len() + value.len()) as u64;
+            written += bytes;
+            self.metrics.record_set(bytes);
+            batch.insert(key.as_str(), value.as_str());
+        }
+        self.db.apply_batch(batch)?;
+        self.flush()?;
+        Ok(written)
+    }
+}
+
+impl SledEngine {
+    /// flush the writes before they return, unless sled flushes them in the background.
+    fn flush(&self) -> Result<()> {
+        if self.flush_writes {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
 }
 
 impl KvsEngine for SledEngine {
     fn get(&self, key: String) -> Result<Option<String>> {
-        let db = self.db.read()?;
-        if let Some(v) = db.get(key)? {
-            return Ok(Some(String::from_utf8(v.to_owned().to_vec()).map_err(
-                |utf8_error| KvError::Other {
-                    reason: format!("decode from sled binary failed since: {}", utf8_error),
-                },
-            )?));
-        }
-        db.flush()?;
-        Ok(None)
+        let found = self.db.get(key)?;
+        self.metrics.record_get(found.is_some());
+        found.map(decode).transpose()
     }
 
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.db.write()?.insert(key, value.as_str())?;
+        let written = (key.len() + value.len()) as u64;
+        self.db.insert(key, value.as_str())?;
+        self.flush()?;
+        self.metrics.record_set(written);
         Ok(())
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        let db = self.db.write()?;
-        let result = match db.remove(key)? {
+        let written = key.len() as u64;
+        let result = match self.db.remove(key)? {
             None => Err(KvError::KeyNotFound),
             Some(_) => Ok(()),
         };
-        db.flush()?;
+        if result.is_ok() {
+            self.flush()?;
+        }
+        self.metrics.record_remove(result.is_ok(), if result.is_ok() { written } else { 0 });
         result
     }
+
+    /// the default value may be computed but discarded, if another one is inserted meanwhile.
+    fn get_or_insert_with(&self, key: String, default: impl FnOnce() -> String) -> Result<String> {
+        if let Some(value) = self.get(key.clone())? {
+            return Ok(value);
+        }
+        let value = default();
+        match self.db.compare_and_swap(key.as_str(), None::<&[u8]>, Some(value.as_str()))? {
+            Ok(()) => {
+                self.flush()?;
+                self.metrics.record_set((key.len() + value.len()) as u64);
+                Ok(value)
+            }
+            Err(error) => error.current.map(decode).transpose().map(Option::unwrap_or_default),
+        }
+    }
+
+    fn set_if(&self, key: String, value: String, condition: SetCondition) -> Result<bool> {
+        let written = loop {
+            // compare with the current value, so that a write meanwhile fails the swap.
+            let current = self.db.get(key.as_str())?;
+            match (condition, &current) {
+                (SetCondition::IfAbsent, Some(_)) | (SetCondition::IfPresent, None) => break false,
+                _ => {}
+            }
+            if self.db.compare_and_swap(key.as_str(), current, Some(value.as_str()))?.is_ok() {
+                break true;
+            }
+        };
+        if written {
+            self.flush()?;
+            self.metrics.record_set((key.len() + value.len()) as u64);
+        }
+        Ok(written)
+    }
+
+    /// compare and swap until no write meanwhile, like `set_if`.
+    fn acquire_lease(&self, key: String, ttl: Duration) -> Result<Option<Lease>> {
+        let lease = loop {
+            let current = self.db.get(key.as_str())?;
+            let value = current.clone().map(decode).transpose()?;
+            if !Lease::is_free(key.as_str(), value.as_deref())? {
+                return Ok(None);
+            }
+            let lease = Lease::new(ttl);
+            if self.db.compare_and_swap(key.as_str(), current, Some(lease.encode().as_str()))?.is_ok() {
+                break lease;
+            }
+        };
+        self.flush()?;
+        self.metrics.record_set((key.len() + lease.encode().len()) as u64);
+        Ok(Some(lease))
+    }
+
+    fn release_lease(&self, key: String, token: String) -> Result<bool> {
+        loop {
+            let current = self.db.get(key.as_str())?;
+            let value = current.clone().map(decode).transpose()?;
+            let held = value.as_deref().and_then(Lease::decode).is_some_and(|lease| lease.token == token);
+            if !held {
+                return Ok(false);
+            }
+            if self.db.compare_and_swap(key.as_str(), current, None as Option<&[u8]>)?.is_ok() {
+                break;
+            }
+        }
+        self.flush()?;
+        self.metrics.record_remove(true, key.len() as u64);
+        Ok(true)
+    }
+
+    /// both keys are written by one transaction.
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        if from == to {
+            return self.get(from).and_then(|found| found.map(|_| ()).ok_or(KvError::KeyNotFound));
+        }
+        let written = self.db.transaction(|tx| -> ConflictableTransactionResult<Option<u64>, sled::Error> {
+            match tx.get(from.as_str())? {
+                Some(value) => {
+                    let written = (from.len() + to.len() + value.len()) as u64;
+                    tx.insert(to.as_str(), value)?;
+                    tx.remove(from.as_str())?;
+                    Ok(Some(written))
+                }
+                None => Ok(None),
+            }
+        })?;
+        let written = written.ok_or(KvError::KeyNotFound)?;
+        self.flush()?;
+        self.metrics.record_set(written);
+        Ok(())
+    }
+
+    fn copy(&self, from: String, to: String) -> Result<()> {
+        let value = self.db.get(from.as_str())?.ok_or(KvError::KeyNotFound)?;
+        let written = (to.len() + value.len()) as u64;
+        self.db.insert(to.as_str(), value)?;
+        self.flush()?;
+        self.metrics.record_set(written);
+        Ok(())
+    }
+
+    /// the keys under `prefix` are removed by one batch, which sled applies atomically,
+    /// so all or none of them are removed, but the ones written under it after the scan are kept.
+    fn remove_prefix(&self, prefix: String) -> Result<usize> {
+        let mut batch = Batch::default();
+        let mut removed = 0;
+        let mut written = 0;
+        for entry in self.db.scan_prefix(prefix.as_str()) {
+            context::check_deadline("remove_prefix")?;
+            let (key, _) = entry?;
+            written += key.len() as u64;
+            removed += 1;
+            batch.remove(key);
+        }
+        if removed == 0 {
+            return Ok(0);
+        }
+        self.db.apply_batch(batch)?;
+        self.flush()?;
+        self.metrics.record_removes(removed as u64, written);
+        Ok(removed)
+    }
+
+    /// the writes are applied atomically by one batch, like `remove_prefix`.
+    fn write_batch(&self, writes: Vec<BatchWrite>) -> Result<()> {
+        let mut batch = Batch::default();
+        let (mut sets, mut removes, mut written) = (0, 0, 0);
+        for write in writes {
+            match write {
+                BatchWrite::Set { key, value } => {
+                    sets += 1;
+                    written += (key.len() + value.len()) as u64;
+                    batch.insert(key.as_str(), value.as_str());
+                }
+                BatchWrite::Remove { key } => {
+                    removes += 1;
+                    written += key.len() as u64;
+                    batch.remove(key.as_str());
+                }
+            }
+        }
+        self.db.apply_batch(batch)?;
+        self.flush()?;
+        self.metrics.record_sets(sets, written);
+        self.metrics.record_removes(removes, 0);
+        Ok(())
+    }
+
+    /// compare and swap until no write meanwhile, like `set_if`, sled keeps no type so the integers are strings.
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let sum = loop {
+            let current = self.db.get(key.as_str())?;
+            let value = match current.clone().map(decode).transpose()? {
+                Some(value) => value.parse::<i64>().map_err(|_| KvError::WrongType {
+                    expected: ValueType::Integer,
+                    found: ValueType::String,
+                })?,
+                None => 0,
+            };
+            let sum = value.checked_add(delta).ok_or_else(|| KvError::Other {
+                reason: format!("adding {} to {} overflows.", delta, value),
+            })?;
+            if self.db.compare_and_swap(key.as_str(), current, Some(sum.to_string().as_str()))?.is_ok() {
+                break sum;
+            }
+        };
+        self.flush()?;
+        self.metrics.record_set((key.len() + sum.to_string().len()) as u64);
+        Ok(sum)
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<()> {
+        let written = (key.len() + suffix.len()) as u64;
+        self.db.update_and_fetch(key.as_str(), |old| {
+            let mut value = old.map(<[u8]>::to_vec).unwrap_or_default();
+            value.extend_from_slice(suffix.as_bytes());
+            Some(value)
+        })?;
+        self.flush()?;
+        self.metrics.record_set(written);
+        Ok(())
+    }
+
+    /// list the keys matching `pattern`,
+    /// scanning only the keys with its literal prefix, from the end when reversed, until the bounds are reached,
+    /// or the request passes its deadline.
+    /// The keys before the cursor of `options` are skipped without decoding them.
+    fn list_keys(&self, pattern: String, options: ListOptions) -> Result<Vec<String>> {
+        let pattern = KeyPattern::new(pattern.as_str());
+        let scan = self.db.scan_prefix(pattern.literal_prefix());
+        let entries: Box<dyn Iterator<Item=_>> = if options.reverse { Box::new(scan.rev()) } else { Box::new(scan) };
+        let after = options.after.as_deref().map(str::as_bytes);
+        // the keys are ordered by their bytes, which is the order of the strings too.
+        let entries = entries.skip_while(|entry| match (entry, after) {
+            (Ok((key, _)), Some(after)) => if options.reverse { &key[..] >= after } else { &key[..] <= after },
+            _ => false,
+        });
+        let mut skipped = 0;
+        let mut keys = Vec::new();
+        for entry in entries {
+            if keys.len() >= options.max_len() {
+                break;
+            }
+            context::check_deadline("list_keys")?;
+            let (key, _) = entry?;
+            let key = decode(key)?;
+            if !pattern.matches(key.as_str()) {
+                continue;
+            }
+            if skipped < options.offset {
+                skipped += 1;
+                continue;
+            }
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    fn metrics(&self) -> EngineMetrics {
+        self.metrics.clone()
+    }
+
+    fn close(&self) -> Result<()> {
+        // even if the writes aren't flushed one by one.
+        self.db.flush()?;
+        Ok(())
+    }
 }
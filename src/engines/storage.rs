@@ -0,0 +1,494 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// a data file that `KvStore` appends records to.
+pub trait DataFile: Read + Write + Seek + Send {
+    /// truncate or extend the file to `size` bytes, like `File::set_len`.
+    fn set_len(&mut self, size: u64) -> io::Result<()>;
+    /// make the written data durable, like `File::sync_data`.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl DataFile for File {
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+/// a file that `KvStore` reads from, like the data files being looked up.
+pub trait ReadFile: Read + Seek + Send {}
+
+impl<R: Read + Seek + Send> ReadFile for R {}
+
+/// where `KvStore` keeps the files of its data directory:
+/// the data files, the blob files, the checkpoints and the markers.
+///
+/// The paths are the files in the directory passed to `KvStore::open_with_storage`,
+/// and the errors are the ones of `std::fs`, e.g. `io::ErrorKind::NotFound` for a missing file.
+pub trait Storage: Debug + Send + Sync {
+    /// open the data file at `path` for appending, create it if not exists.
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn DataFile>>;
+    /// open the file at `path` for reading.
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadFile>>;
+    /// create the file at `path` for writing, truncate it if exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn DataFile>>;
+    /// move the file `from` to `to`, replacing `to` if exists.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// remove the file at `path`, the files opened keep reading it.
+    fn remove(&self, path: &Path) -> io::Result<()>;
+    /// the length of the file at `path` in bytes.
+    fn size(&self, path: &Path) -> io::Result<u64>;
+    /// the names of the files in the directory `dir`.
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>>;
+    /// make the creations, renames and removals of the files in `dir` durable.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+    /// create the directory `dir` and its parents, if not exist.
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+
+    /// read the whole file at `path`.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.open_read(path)?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    /// read the whole file at `path` as a string.
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// write `content` as the whole file at `path` durably, aside and renamed,
+    /// so that a crash leaves either the old file or the new one.
+    fn write_atomic(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        let mut file = self.create(&temp)?;
+        file.write_all(content)?;
+        file.sync()?;
+        self.rename(&temp, path)
+    }
+
+    /// copy the file `from` to `to`, returns the bytes copied.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        let mut source = self.open_read(from)?;
+        let mut file = self.create(to)?;
+        let copied = io::copy(&mut source, &mut file)?;
+        file.sync()?;
+        Ok(copied)
+    }
+}
+
+/// the storage of plain files, the default one of `KvStore`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FsStorage;
+
+fn open_append_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl Storage for FsStorage {
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn DataFile>> {
+        Ok(Box::new(open_append_file(path)?))
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn DataFile>> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        Ok(names)
+    }
+
+    /// directories cannot be opened as files but on unix,
+    /// elsewhere the renames are durable once the files are synced.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        if cfg!(unix) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+}
+
+/// the storage of the files in memory, which are lost once it's dropped,
+/// for the tests and the stores that needn't survive the process.
+///
+/// The clones share the files, so a store can be reopened on the files of another one.
+///
+/// # Example
+/// ```
+/// # use std::sync::Arc;
+/// # use kvs::{KvsEngine, KvStore};
+/// # use kvs::engines::storage::MemStorage;
+/// # fn main() -> kvs::Result<()> {
+/// let storage = MemStorage::new();
+/// let store = KvStore::open_with_storage("/kvs", Arc::new(storage.clone()))?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// drop(store);
+/// let store = KvStore::open_with_storage("/kvs", Arc::new(storage))?;
+/// assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemStorage {
+    files: Arc<Mutex<BTreeMap<PathBuf, MemData>>>,
+}
+
+type MemData = Arc<Mutex<Vec<u8>>>;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} is not found in the memory.", path.display()))
+}
+
+impl MemStorage {
+    /// a storage without files.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn data_of(&self, path: &Path) -> io::Result<MemData> {
+        lock(&self.files).get(path).cloned().ok_or_else(|| not_found(path))
+    }
+}
+
+impl Storage for MemStorage {
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn DataFile>> {
+        let data = lock(&self.files).entry(path.to_owned()).or_default().clone();
+        Ok(Box::new(MemFile { data, position: 0, append: true }))
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        Ok(Box::new(MemFile { data: self.data_of(path)?, position: 0, append: false }))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn DataFile>> {
+        let data = lock(&self.files).entry(path.to_owned()).or_default().clone();
+        lock(&data).clear();
+        Ok(Box::new(MemFile { data, position: 0, append: false }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut files = lock(&self.files);
+        let data = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_owned(), data);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        lock(&self.files).remove(path).map(|_| ()).ok_or_else(|| not_found(path))
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        let data = self.data_of(path)?;
+        let len = lock(&data).len();
+        Ok(len as u64)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        Ok(lock(&self.files)
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .filter_map(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn create_dir_all(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// a file of `MemStorage`, the files opened from the same path share the bytes.
+#[derive(Debug)]
+pub struct MemFile {
+    data: MemData,
+    position: u64,
+    append: bool,
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = lock(&self.data);
+        let start = (self.position as usize).min(data.len());
+        let read = buf.len().min(data.len() - start);
+        buf[..read].copy_from_slice(&data[start..start + read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (lock(&self.data).len() as u64, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+        match base.checked_add_signed(offset) {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "seeking to a negative position.")),
+        }
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = lock(&self.data);
+        if self.append {
+            self.position = data.len() as u64;
+        }
+        let start = self.position as usize;
+        let end = start + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        self.position = end as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl DataFile for MemFile {
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        lock(&self.data).resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// what a `FaultyFile` does to the write exceeding its budget.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Fault {
+    /// fail the write with the error kind and write nothing, like a full disk.
+    Fail(io::ErrorKind),
+    /// write the bytes within the budget, then fail this and any later changes to the file,
+    /// like the machine crashed in the middle of the write.
+    Torn,
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    budget: Option<u64>,
+    fault: Option<Fault>,
+    crashed: bool,
+}
+
+/// the storage that makes `FaultyFile`s on plain files, sharing one write budget,
+/// so that crash-consistency tests can break the writes of a `KvStore` at any byte.
+///
+/// # Example
+/// ```no_run
+/// # use std::sync::Arc;
+/// # use kvs::{KvsEngine, KvStore};
+/// # use kvs::engines::storage::{Fault, FaultyStorage};
+/// # fn main() -> kvs::Result<()> {
+/// let storage = FaultyStorage::new();
+/// let store = KvStore::open_with_storage(std::env::current_dir()?, Arc::new(storage.clone()))?;
+/// storage.fail_after(16, Fault::Torn);
+/// assert!(store.set("key".to_owned(), "value".to_owned()).is_err());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct FaultyStorage {
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FaultyStorage {
+    /// a storage without faults, until `fail_after` is called.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, FaultState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// allow `bytes` more bytes to be written, then inject `fault`.
+    pub fn fail_after(&self, bytes: u64, fault: Fault) {
+        let mut state = self.state();
+        state.budget = Some(bytes);
+        state.fault = Some(fault);
+        state.crashed = false;
+    }
+
+    /// remove the fault, so that the files work normally again.
+    pub fn heal(&self) {
+        *self.state() = FaultState::default();
+    }
+
+    /// wrap a file, so that its writes are limited by the budget of this storage.
+    pub fn wrap<F: DataFile>(&self, inner: F) -> FaultyFile<F> {
+        FaultyFile {
+            inner,
+            storage: self.clone(),
+        }
+    }
+}
+
+/// the files are created and appended to in the budget, the other operations are the ones of `FsStorage`.
+impl Storage for FaultyStorage {
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn DataFile>> {
+        Ok(Box::new(self.wrap(open_append_file(path)?)))
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        FsStorage.open_read(path)
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn DataFile>> {
+        Ok(Box::new(self.wrap(File::create(path)?)))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        FsStorage.rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        FsStorage.remove(path)
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        FsStorage.size(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        FsStorage.list(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        FsStorage.sync_dir(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        FsStorage.create_dir_all(dir)
+    }
+}
+
+/// a file that fails or truncates writes after its `FaultyStorage` runs out of budget.
+#[derive(Debug)]
+pub struct FaultyFile<F> {
+    inner: F,
+    storage: FaultyStorage,
+}
+
+fn crashed() -> io::Error {
+    io::Error::other("the file is crashed by fault injection.")
+}
+
+impl<F: DataFile> Read for FaultyFile<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<F: DataFile> Seek for FaultyFile<F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl<F: DataFile> Write for FaultyFile<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.storage.state();
+        if state.crashed {
+            return Err(crashed());
+        }
+        let budget = match state.budget {
+            Some(budget) if budget < buf.len() as u64 => budget,
+            Some(budget) => {
+                let written = self.inner.write(buf)?;
+                state.budget = Some(budget - written as u64);
+                return Ok(written);
+            }
+            None => return self.inner.write(buf),
+        };
+        match state.fault {
+            Some(Fault::Torn) => {
+                state.crashed = true;
+                self.inner.write_all(&buf[..budget as usize])?;
+                state.budget = Some(0);
+                Err(crashed())
+            }
+            Some(Fault::Fail(kind)) => Err(io::Error::new(kind, "the write is failed by fault injection.")),
+            None => self.inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.storage.state().crashed {
+            return Err(crashed());
+        }
+        self.inner.flush()
+    }
+}
+
+impl<F: DataFile> DataFile for FaultyFile<F> {
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        if self.storage.state().crashed {
+            return Err(crashed());
+        }
+        self.inner.set_len(size)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.storage.state().crashed {
+            return Err(crashed());
+        }
+        self.inner.sync()
+    }
+}
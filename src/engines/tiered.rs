@@ -0,0 +1,326 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::engines::changes::{Change, LogPosition};
+use crate::engines::context;
+use crate::engines::engine::{BatchWrite, KvsEngine, Preload, SetCondition, ValueWithMeta};
+use crate::engines::errors::Result;
+use crate::engines::lease::Lease;
+use crate::engines::metrics::EngineMetrics;
+use crate::engines::pattern::{KeyPattern, ListOptions};
+use crate::engines::typed::TypedValue;
+
+/// The values read recently, evicting the least recently used ones beyond its capacity in bytes.
+struct HotTier {
+    capacity: usize,
+    used: usize,
+    clock: u64,
+    /// bumped before and after every write, so that a value read from the disk engine meanwhile isn't promoted.
+    writes: u64,
+    values: HashMap<String, (u64, ValueWithMeta)>,
+    /// the keys by the time they are used.
+    recency: BTreeMap<u64, String>,
+}
+
+impl HotTier {
+    fn new(capacity: usize) -> Self {
+        HotTier {
+            capacity,
+            used: 0,
+            clock: 0,
+            writes: 0,
+            values: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    fn size_of(key: &str, value: &ValueWithMeta) -> usize {
+        key.len() + value.value.len() + value.meta.as_ref().map_or(0, String::len)
+    }
+
+    fn get(&mut self, key: &str) -> Option<ValueWithMeta> {
+        self.clock += 1;
+        let (used_at, value) = self.values.get_mut(key)?;
+        let key = self.recency.remove(used_at)?;
+        *used_at = self.clock;
+        self.recency.insert(self.clock, key);
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: String, value: ValueWithMeta) {
+        let size = Self::size_of(key.as_str(), &value);
+        if size > self.capacity {
+            return;
+        }
+        self.remove(key.as_str());
+        while self.used + size > self.capacity {
+            match self.recency.pop_first() {
+                Some((_, evicted)) => self.remove(evicted.as_str()),
+                None => break,
+            }
+        }
+        self.clock += 1;
+        self.used += size;
+        self.recency.insert(self.clock, key.clone());
+        self.values.insert(key, (self.clock, value));
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((used_at, value)) = self.values.remove(key) {
+            self.recency.remove(&used_at);
+            self.used -= Self::size_of(key, &value);
+        }
+    }
+
+    fn remove_prefix(&mut self, prefix: &str) {
+        let keys: Vec<String> = self.values.keys().filter(|key| key.starts_with(prefix)).cloned().collect();
+        for key in keys {
+            self.remove(key.as_str());
+        }
+    }
+
+    fn clear(&mut self) {
+        self.used = 0;
+        self.values.clear();
+        self.recency.clear();
+    }
+}
+
+/// A `KvsEngine` keeping the values of the keys read recently in memory, in front of a disk engine,
+/// for the read-heavy workloads whose hot keys fit in memory while the whole keyspace doesn't.
+///
+/// The writes go to the disk engine before they return, and drop the keys they touch from memory,
+/// so the cold keys are evicted by just forgetting them, and a crash loses nothing.
+/// The reads of the keys in memory don't touch the disk engine,
+/// and they count in the `cache_hits` of the metrics of the disk engine.
+///
+/// # Example
+/// ```no_run
+/// # use kvs::KvStore;
+/// # use kvs::engines::tiered::Tiered;
+/// # fn main() -> kvs::Result<()> {
+/// let engine = Tiered::new(KvStore::open(std::env::current_dir()?)?, 64 * 1024 * 1024);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Tiered<E> {
+    cold: E,
+    hot: Arc<Mutex<HotTier>>,
+}
+
+impl<E: KvsEngine> Tiered<E> {
+    /// keep at most `capacity` bytes of keys and values in memory in front of `cold`.
+    pub fn new(cold: E, capacity: usize) -> Self {
+        Tiered {
+            cold,
+            hot: Arc::new(Mutex::new(HotTier::new(capacity))),
+        }
+    }
+
+    /// the bytes of the keys and values in memory.
+    pub fn hot_bytes(&self) -> usize {
+        self.hot.lock().map(|hot| hot.used).unwrap_or_default()
+    }
+
+    /// get the value from memory, or from the disk engine and keep it in memory.
+    fn lookup(&self, key: String) -> Result<Option<ValueWithMeta>> {
+        let writes = {
+            let mut hot = self.hot.lock()?;
+            if let Some(found) = hot.get(key.as_str()) {
+                let metrics = self.cold.metrics();
+                metrics.record_get(true);
+                metrics.record_cache(true);
+                return Ok(Some(found));
+            }
+            hot.writes
+        };
+        self.cold.metrics().record_cache(false);
+        let found = self.cold.get_with_meta(key.clone())?;
+        if let Some(found) = &found {
+            let mut hot = self.hot.lock()?;
+            if hot.writes == writes {
+                let before = hot.used;
+                hot.insert(key, found.clone());
+                self.cold.metrics().record_cache_resized(before, hot.used);
+            }
+        }
+        Ok(found)
+    }
+
+    /// run the write `f` on the disk engine, dropping `keys` from memory before and after it.
+    fn write<T>(&self, keys: &[&str], f: impl FnOnce(&E) -> Result<T>) -> Result<T> {
+        let forget = || -> Result<()> {
+            let mut hot = self.hot.lock()?;
+            let before = hot.used;
+            hot.writes += 1;
+            for key in keys {
+                hot.remove(key);
+            }
+            self.cold.metrics().record_cache_resized(before, hot.used);
+            Ok(())
+        };
+        forget()?;
+        let result = f(&self.cold);
+        forget()?;
+        result
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Tiered<E> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.lookup(key)?.map(|found| found.value))
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.write(&[key.as_str()], |cold| cold.set(key.clone(), value))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.write(&[key.as_str()], |cold| cold.remove(key.clone()))
+    }
+
+    fn undelete(&self, key: String) -> Result<()> {
+        self.write(&[key.as_str()], |cold| cold.undelete(key.clone()))
+    }
+
+    /// forget everything in memory, since the data is replaced as a whole.
+    fn restore(&self, archive: BTreeMap<String, Vec<u8>>) -> Result<()> {
+        let forget = || -> Result<()> {
+            let mut hot = self.hot.lock()?;
+            let before = hot.used;
+            hot.writes += 1;
+            hot.clear();
+            self.cold.metrics().record_cache_resized(before, hot.used);
+            Ok(())
+        };
+        forget()?;
+        let result = self.cold.restore(archive);
+        forget()?;
+        result
+    }
+
+    fn get_or_insert_with(&self, key: String, default: impl FnOnce() -> String) -> Result<String> {
+        self.write(&[key.as_str()], |cold| cold.get_or_insert_with(key.clone(), default))
+    }
+
+    fn set_if(&self, key: String, value: String, condition: SetCondition) -> Result<bool> {
+        self.write(&[key.as_str()], |cold| cold.set_if(key.clone(), value, condition))
+    }
+
+    fn set_if_unmodified(&self, key: String, value: String, modified: SystemTime) -> Result<bool> {
+        self.write(&[key.as_str()], |cold| cold.set_if_unmodified(key.clone(), value, modified))
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        self.write(&[from.as_str(), to.as_str()], |cold| cold.rename(from.clone(), to.clone()))
+    }
+
+    fn copy(&self, from: String, to: String) -> Result<()> {
+        self.write(&[to.as_str()], |cold| cold.copy(from.clone(), to.clone()))
+    }
+
+    fn write_batch(&self, writes: Vec<BatchWrite>) -> Result<()> {
+        let keys: Vec<String> = writes.iter().map(|write| write.key().to_owned()).collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        self.write(keys.as_slice(), |cold| cold.write_batch(writes))
+    }
+
+    /// forget the keys under `prefix` in memory before and after it, like `write`.
+    fn remove_prefix(&self, prefix: String) -> Result<usize> {
+        let forget = || -> Result<()> {
+            let mut hot = self.hot.lock()?;
+            let before = hot.used;
+            hot.writes += 1;
+            hot.remove_prefix(prefix.as_str());
+            self.cold.metrics().record_cache_resized(before, hot.used);
+            Ok(())
+        };
+        forget()?;
+        let result = self.cold.remove_prefix(prefix.clone());
+        forget()?;
+        result
+    }
+
+    fn acquire_lease(&self, key: String, ttl: Duration) -> Result<Option<Lease>> {
+        self.write(&[key.as_str()], |cold| cold.acquire_lease(key.clone(), ttl))
+    }
+
+    fn release_lease(&self, key: String, token: String) -> Result<bool> {
+        self.write(&[key.as_str()], |cold| cold.release_lease(key.clone(), token))
+    }
+
+    /// the typed values are read from the disk engine, since the memory keeps no type.
+    fn get_typed(&self, key: String) -> Result<Option<TypedValue>> {
+        self.cold.get_typed(key)
+    }
+
+    fn set_typed(&self, key: String, value: TypedValue) -> Result<()> {
+        self.write(&[key.as_str()], |cold| cold.set_typed(key.clone(), value))
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.write(&[key.as_str()], |cold| cold.incr(key.clone(), delta))
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<()> {
+        self.write(&[key.as_str()], |cold| cold.append(key.clone(), suffix))
+    }
+
+    fn get_with_meta(&self, key: String) -> Result<Option<ValueWithMeta>> {
+        self.lookup(key)
+    }
+
+    fn set_with_meta(&self, key: String, value: String, meta: Option<String>) -> Result<()> {
+        self.write(&[key.as_str()], |cold| cold.set_with_meta(key.clone(), value, meta))
+    }
+
+    fn set_stream(&self, key: String, value: &mut dyn Read) -> Result<()> {
+        self.write(&[key.as_str()], |cold| cold.set_stream(key.clone(), value))
+    }
+
+    /// the streamed values are large, so they're always read from the disk engine.
+    fn get_stream(&self, key: String) -> Result<Option<Box<dyn Read>>> {
+        self.cold.get_stream(key)
+    }
+
+    fn list_keys(&self, pattern: String, options: ListOptions) -> Result<Vec<String>> {
+        self.cold.list_keys(pattern, options)
+    }
+
+    fn changes(&self, since: LogPosition, follow: bool) -> Result<Box<dyn Iterator<Item=Result<Change>> + Send>> {
+        self.cold.changes(since, follow)
+    }
+
+    fn log_end(&self) -> Result<LogPosition> {
+        self.cold.log_end()
+    }
+
+    fn metrics(&self) -> EngineMetrics {
+        self.cold.metrics()
+    }
+
+    /// read the values through memory, so that they're kept in it as far as they fit.
+    fn preload(&self, target: Preload) -> Result<usize> {
+        let keys = match target {
+            Preload::Keys(keys) => keys,
+            Preload::Prefix(prefix) => {
+                self.cold.list_keys(format!("{}*", KeyPattern::escape(&prefix)), ListOptions::default())?
+            }
+        };
+        let mut loaded = 0;
+        for key in keys {
+            context::check_deadline("preload")?;
+            if self.lookup(key)?.is_some() {
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    fn close(&self) -> Result<()> {
+        self.cold.close()
+    }
+}
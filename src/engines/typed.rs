@@ -0,0 +1,131 @@
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+
+/// The type of a value, kept with it by `KvsEngine::set_typed`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    /// UTF-8 text, the type of the values set by `KvsEngine::set`.
+    #[default]
+    String,
+    /// a signed 64-bit integer, see `KvsEngine::incr`.
+    Integer,
+    /// arbitrary binary data.
+    Bytes,
+}
+
+impl AsRef<str> for ValueType {
+    fn as_ref(&self) -> &str {
+        match self {
+            ValueType::String => "string",
+            ValueType::Integer => "integer",
+            ValueType::Bytes => "bytes",
+        }
+    }
+}
+
+/// A value with its type, see `KvsEngine::get_typed`.
+///
+/// In the contract it's tagged by its type, like `{"integer":42}`, and the bytes are in base64, like `{"bytes":"AP8K"}`,
+/// taking 4/3 of their size. The arrays of numbers sent for the bytes by the earlier versions are accepted too.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TypedValue {
+    /// UTF-8 text.
+    String(String),
+    /// a signed 64-bit integer.
+    Integer(i64),
+    /// arbitrary binary data.
+    Bytes(#[serde(with = "bytes_in_base64")] Vec<u8>),
+}
+
+/// the bytes of `TypedValue::Bytes` in base64, or the array of numbers of the earlier versions.
+mod bytes_in_base64 {
+    use std::fmt;
+
+    use serde::{Deserializer, Serializer};
+    use serde::de::{Error, SeqAccess, Visitor};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(base64::encode(bytes).as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_any(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("the bytes in base64, or an array of them")
+        }
+
+        fn visit_str<E: Error>(self, encoded: &str) -> Result<Vec<u8>, E> {
+            base64::decode(encoded).map_err(|err| E::custom(format!("the bytes aren't in base64: {}", err)))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+impl TypedValue {
+    /// the type of the value.
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            TypedValue::String(_) => ValueType::String,
+            TypedValue::Integer(_) => ValueType::Integer,
+            TypedValue::Bytes(_) => ValueType::Bytes,
+        }
+    }
+
+    /// the value as it's stored, and read by `KvsEngine::get`: the integers in decimal, and the bytes in lowercase hex.
+    ///
+    /// The records keep the values as strings, so the bytes take twice their size in the data files,
+    /// and a plain `get` of them returns the hex, only `get_typed` returns the bytes themselves.
+    pub fn encode(&self) -> String {
+        match self {
+            TypedValue::String(value) => value.clone(),
+            TypedValue::Integer(value) => value.to_string(),
+            TypedValue::Bytes(value) => value.iter().fold(String::with_capacity(value.len() * 2), |mut hex, byte| {
+                let _ = write!(hex, "{:02x}", byte);
+                hex
+            }),
+        }
+    }
+
+    /// the value of `value_type` stored as `stored`, or `None` if it's malformed, see `encode`.
+    pub fn decode(value_type: ValueType, stored: String) -> Option<Self> {
+        match value_type {
+            ValueType::String => Some(TypedValue::String(stored)),
+            ValueType::Integer => stored.parse().ok().map(TypedValue::Integer),
+            ValueType::Bytes => stored
+                .as_bytes()
+                .chunks(2)
+                .map(|pair| {
+                    let hex = std::str::from_utf8(pair).ok().filter(|hex| hex.len() == 2)?;
+                    u8::from_str_radix(hex, 16).ok()
+                })
+                .collect::<Option<Vec<u8>>>()
+                .map(TypedValue::Bytes),
+        }
+    }
+
+    /// the integer of the value, the strings of an integer in decimal count as integers, see `KvsEngine::incr`.
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            TypedValue::Integer(value) => Some(*value),
+            TypedValue::String(value) => value.parse().ok(),
+            TypedValue::Bytes(_) => None,
+        }
+    }
+}
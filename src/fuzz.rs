@@ -0,0 +1,57 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::{KvsEngine, KvStore};
+use crate::contract::{KvContractMessage, Request, Response};
+use crate::engines::codec::{self, CodecKind};
+use crate::engines::kvs::filename_of;
+use crate::engines::pattern::ListOptions;
+use crate::engines::storage::{MemStorage, Storage};
+
+/// parse `data` as a request like the server does, and as a response like the client does,
+/// and check that a request parsed is parsed the same from its own binary.
+///
+/// It panics only on the bugs of the parsers, whatever `data` is.
+pub fn parse_contract(data: &[u8]) {
+    if let Ok(request) = Request::parse(data) {
+        let reparsed = Request::parse(request.clone().into_binary().as_slice());
+        assert_eq!(reparsed.ok(), Some(request), "a request isn't parsed the same from its own binary.");
+    }
+    let _ = Response::parse(data);
+    let mut head = data;
+    if Request::parse_head(&mut head).is_ok() {
+        let _ = Request::parse_end(head);
+    }
+}
+
+/// open a data directory in memory whose only data file is the rest of `data`, in the codec its first byte picks,
+/// by `KvStore::open_salvaging`, then read every key indexed.
+/// And the salvaged data file is opened as it is after that.
+///
+/// It panics only on the bugs of the log reader, whatever `data` is.
+pub fn read_data_file(data: &[u8]) {
+    let (codec, content) = match data.split_first() {
+        Some((&pick, content)) => match pick % 3 {
+            0 => (CodecKind::Json.codec(), content),
+            1 => (CodecKind::Bincode.codec(), content),
+            _ => (CodecKind::JsonCrc.codec(), content),
+        },
+        None => return,
+    };
+    let storage = MemStorage::default();
+    let dir = Path::new("/fuzz");
+    storage.create_dir_all(dir).expect("unable to create the directory in memory.");
+    codec::record_codec(&storage, dir, codec.as_ref()).expect("unable to record the codec.");
+    storage.write_atomic(&dir.join(filename_of(1)), content).expect("unable to write the data file.");
+
+    let store = KvStore::open_salvaging(dir, Arc::new(storage.clone())).expect("the salvaging open fails.");
+    let keys = store.list_keys("*".to_owned(), ListOptions::default()).expect("unable to list the keys indexed.");
+    for key in keys.iter() {
+        // the blobs the records point to don't exist, so the reads of them fail.
+        let _ = store.get(key.to_owned());
+    }
+    drop(store);
+    let store = KvStore::open_with_storage(dir, Arc::new(storage)).expect("the salvaged data file doesn't open.");
+    let reopened = store.list_keys("*".to_owned(), ListOptions::default()).expect("unable to list the keys indexed.");
+    assert_eq!(reopened, keys, "the salvaged data file opens with other keys.");
+}
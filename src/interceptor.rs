@@ -0,0 +1,373 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use log::{info, warn};
+
+use crate::config::server::{Role, UserConfig};
+use crate::contract::{Request, Response};
+use crate::engines::engine::BatchWrite;
+use crate::engines::pattern::KeyPattern;
+use crate::server_common::{MemoryUsage, Result, ServerError};
+
+/// A concern around the handling of each request, like auth, rate limiting, metrics or logging,
+/// added to a `KvServer` by `KvServer::intercept`.
+///
+/// It gets the request before the engine, and the response after it,
+/// so it can refuse, rewrite or observe either of them.
+/// The streamed content after a `Response::Stream` isn't seen by the interceptors.
+///
+/// # Example
+/// ```rust
+/// # use std::sync::atomic::{AtomicU64, Ordering};
+/// # use kvs::contract::{Request, Response};
+/// # use kvs::interceptor::{Interceptor, Next};
+/// # use kvs::server_common::{Result, ServerError};
+/// /// refuse the writes, serving the reads only.
+/// struct ReadOnly;
+///
+/// impl Interceptor for ReadOnly {
+///     fn intercept(&self, request: Request, next: Next<'_>) -> Result<Response> {
+///         match request {
+///             Request::Get { .. } | Request::GetStream { .. } | Request::Keys { .. } | Request::Stats => next.run(request),
+///             _ => Err(ServerError::Unauthorized),
+///         }
+///     }
+/// }
+/// ```
+pub trait Interceptor: Send + Sync {
+    /// handle `request` by `next`, the rest of the chain, or answer it without `next`.
+    fn intercept(&self, request: Request, next: Next<'_>) -> Result<Response>;
+}
+
+impl<F> Interceptor for F
+    where
+        F: Fn(Request, Next<'_>) -> Result<Response> + Send + Sync,
+{
+    fn intercept(&self, request: Request, next: Next<'_>) -> Result<Response> {
+        self(request, next)
+    }
+}
+
+/// The rest of the interceptors after the running one, and the handler of the requests after all of them.
+pub struct Next<'a> {
+    rest: &'a [Arc<dyn Interceptor>],
+    handler: &'a mut dyn FnMut(Request) -> Result<Response>,
+}
+
+impl<'a> Next<'a> {
+    /// run `handler` wrapped by `interceptors`, the first one outermost.
+    pub(crate) fn new(interceptors: &'a [Arc<dyn Interceptor>], handler: &'a mut dyn FnMut(Request) -> Result<Response>) -> Self {
+        Next { rest: interceptors, handler }
+    }
+
+    /// pass `request` to the rest of the chain.
+    pub fn run(self, request: Request) -> Result<Response> {
+        match self.rest.split_first() {
+            Some((first, rest)) => first.intercept(request, Next { rest, handler: self.handler }),
+            None => (self.handler)(request),
+        }
+    }
+}
+
+/// log each request before it's handled, the outermost interceptor of a `KvServer`.
+pub struct RequestLog;
+
+impl Interceptor for RequestLog {
+    fn intercept(&self, request: Request, next: Next<'_>) -> Result<Response> {
+        info!(target: "app::request", "handling request {}.", Redacted(&request));
+        next.run(request)
+    }
+}
+
+/// a request to log, without its secrets.
+struct Redacted<'a>(&'a Request);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            // the archive is too large to log, and the token is a secret.
+            Request::Restore { archive, .. } => write!(f, "to restore {} files", archive.len()),
+            Request::Auth { user, request, .. } => write!(f, "{} of user {}", Redacted(request), user),
+            Request::DryRun { request } => write!(f, "dry run of {}", Redacted(request)),
+            Request::Batch { requests } => {
+                write!(f, "batch of [")?;
+                for (n, request) in requests.iter().enumerate() {
+                    let separator = if n > 0 { ", " } else { "" };
+                    write!(f, "{}{}", separator, Redacted(request))?;
+                }
+                write!(f, "]")
+            }
+            Request::Deadline { timeout_ms, request } => write!(f, "{} within {}ms", Redacted(request), timeout_ms),
+            // the script may be long.
+            Request::Eval { script, keys, .. } => write!(f, "eval of {} bytes on keys {:?}", script.len(), keys),
+            request => write!(f, "{:?}", request),
+        }
+    }
+}
+
+/// log the destructive requests done, like `rm` and `rm-prefix`, with the user sending them,
+/// into `app::audit`, right after the logging of a `KvServer`.
+///
+/// The dry runs and the failed requests aren't logged, since they modify nothing.
+pub struct Audit;
+
+impl Audit {
+    /// what `request` does if it's destructive.
+    fn action_of(request: &Request) -> Option<String> {
+        match request {
+            Request::Remove { key } => Some(format!("removed the key {:?}", key)),
+            Request::RemovePrefix { prefix } => Some(format!("removed the keys under the prefix {:?}", prefix)),
+            Request::Transaction { writes } => {
+                let removed: Vec<&str> = writes
+                    .iter()
+                    .filter_map(|write| match write {
+                        BatchWrite::Remove { key } => Some(key.as_str()),
+                        BatchWrite::Set { .. } => None,
+                    })
+                    .collect();
+                if removed.is_empty() {
+                    None
+                } else {
+                    Some(format!("removed the keys {:?} in a transaction", removed))
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Interceptor for Audit {
+    fn intercept(&self, request: Request, next: Next<'_>) -> Result<Response> {
+        let (user, inner) = match &request {
+            Request::Auth { user, request, .. } => (Some(user.clone()), request.as_ref()),
+            request => (None, request),
+        };
+        let actions: Vec<Option<String>> = match inner {
+            Request::Batch { requests } => requests.iter().map(Self::action_of).collect(),
+            request => vec![Self::action_of(request)],
+        };
+        let response = next.run(request)?;
+        let responses = match &response {
+            Response::Batch { responses } => responses.iter().collect(),
+            response => vec![response],
+        };
+        for (action, response) in actions.into_iter().zip(responses) {
+            let count = match response {
+                Response::Content { content } => format!(", {} keys", content),
+                // the failed ones in a batch.
+                Response::Error { .. } => continue,
+                _ => String::new(),
+            };
+            match (user.as_deref(), action) {
+                (Some(user), Some(action)) => info!(target: "app::audit", "user {} {}{}.", user, action, count),
+                (None, Some(action)) => info!(target: "app::audit", "{}{}.", action, count),
+                (_, None) => {}
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// check the credentials and the permissions of the users, configured in the `[users]` of the config file,
+/// right after the auditing of a `KvServer`, see `KvServer::users`.
+///
+/// The requests of a user are wrapped in `Request::Auth`, which is unwrapped for the interceptors after it.
+/// Without any user, the requests needn't be wrapped, like before there were users.
+pub struct Acl {
+    users: BTreeMap<String, UserConfig>,
+}
+
+impl Acl {
+    /// create an interceptor that admits the requests of `users`.
+    pub fn new(users: BTreeMap<String, UserConfig>) -> Self {
+        Acl { users }
+    }
+}
+
+impl Interceptor for Acl {
+    fn intercept(&self, request: Request, next: Next<'_>) -> Result<Response> {
+        let (user, secret, request) = match request {
+            Request::Auth { user, secret, request } => (user, secret, *request),
+            request if self.users.is_empty() => return next.run(request),
+            _ => return Err(ServerError::Unauthorized),
+        };
+        let account = self
+            .users
+            .get(user.as_str())
+            .filter(|account| same_secret(account.secret.as_str(), secret.as_str()))
+            .ok_or(ServerError::Unauthorized)?;
+        if !permits(account, &request)? {
+            return Err(ServerError::Forbidden);
+        }
+        next.run(request)
+    }
+}
+
+/// whether the secrets `a` and `b` are the same, comparing all their bytes even after a difference,
+/// so that the time of a refusal doesn't tell how much of a guess is right.
+fn same_secret(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// whether `account` may send `request`, by its role, then the keys the request touches.
+fn permits(account: &UserConfig, request: &Request) -> Result<bool> {
+    let pattern;
+    let (role, keys): (Role, Vec<&str>) = match request {
+        Request::Get { key }
+        | Request::GetTyped { key }
+        | Request::GetWithMeta { key }
+        | Request::GetStream { key } => (Role::Read, vec![key]),
+        Request::Keys { pattern: glob, .. } => {
+            pattern = KeyPattern::new(glob);
+            (Role::Read, vec![pattern.literal_prefix()])
+        }
+        Request::Stats => (Role::Read, vec![]),
+        Request::Set { key, .. }
+        | Request::SetIf { key, .. }
+        | Request::SetIfUnmodified { key, .. }
+        | Request::Remove { key }
+        | Request::Undelete { key }
+        | Request::Append { key, .. }
+        | Request::SetTyped { key, .. }
+        | Request::Incr { key, .. }
+        | Request::AcquireLease { key, .. }
+        | Request::ReleaseLease { key, .. }
+        | Request::SetStream { key } => (Role::Write, vec![key]),
+        Request::Rename { from, to } | Request::Copy { from, to } => (Role::Write, vec![from, to]),
+        Request::RemovePrefix { prefix } => (Role::Write, vec![prefix]),
+        Request::Eval { keys, .. } => (Role::Write, keys.iter().map(String::as_str).collect()),
+        Request::Transaction { writes } => (Role::Write, writes.iter().map(BatchWrite::key).collect()),
+        // they touch all the keys.
+        Request::Changes { .. } | Request::LogEnd => (Role::Read, vec![""]),
+        Request::Preload { .. } | Request::Restore { .. } => (Role::Admin, vec![""]),
+        // as if it runs for real.
+        Request::DryRun { request } => return permits(account, request),
+        Request::Batch { requests } => {
+            for request in requests {
+                if !permits(account, request)? {
+                    return Ok(false);
+                }
+            }
+            return Ok(true);
+        }
+        Request::Auth { .. } | Request::Deadline { .. } => return Err(ServerError::BadRequest),
+    };
+    let in_prefixes = |key: &str| {
+        account.prefixes.is_empty() || account.prefixes.iter().any(|prefix| key.starts_with(prefix.as_str()))
+    };
+    Ok(account.role >= role && keys.into_iter().all(in_prefixes))
+}
+
+/// shed the load once the server takes more memory than a limit, instead of getting killed for running out of it,
+/// see `KvServer::memory_limit`.
+///
+/// Beyond the limit, the writes, the scans and the preloads, which take more memory, are refused with `Overloaded`,
+/// while the cheap reads, the removes of single keys and the other admin requests still go on.
+pub struct LoadShed {
+    limit: u64,
+    usage: MemoryUsage,
+}
+
+impl LoadShed {
+    /// create an interceptor that sheds the load once `usage` goes beyond `limit` bytes.
+    pub fn new(limit: u64, usage: MemoryUsage) -> Self {
+        LoadShed { limit, usage }
+    }
+}
+
+/// whether `request` may take more memory, like a write, a scan, or a preload filling the caches.
+fn sheddable(request: &Request) -> bool {
+    match request {
+        Request::Set { .. }
+        | Request::SetIf { .. }
+        | Request::SetIfUnmodified { .. }
+        | Request::Append { .. }
+        | Request::SetTyped { .. }
+        | Request::Incr { .. }
+        | Request::Copy { .. }
+        | Request::Rename { .. }
+        | Request::RemovePrefix { .. }
+        | Request::Undelete { .. }
+        | Request::AcquireLease { .. }
+        | Request::SetStream { .. }
+        | Request::Keys { .. }
+        | Request::Changes { .. }
+        | Request::Eval { .. }
+        | Request::Transaction { .. }
+        | Request::Preload { .. }
+        | Request::DryRun { .. } => true,
+        Request::Batch { requests } => requests.iter().any(sheddable),
+        Request::Auth { request, .. } | Request::Deadline { request, .. } => sheddable(request),
+        _ => false,
+    }
+}
+
+impl Interceptor for LoadShed {
+    fn intercept(&self, request: Request, next: Next<'_>) -> Result<Response> {
+        if sheddable(&request) {
+            let used = self.usage.bytes();
+            if used > self.limit {
+                warn!("shedding the request, about {} bytes of memory in use, beyond {}.", used, self.limit);
+                return Err(ServerError::Overloaded { used, limit: self.limit });
+            }
+        }
+        next.run(request)
+    }
+}
+
+/// keep the admin requests, `stats`, `preload` and `restore`, on their own listener, see `KvServer::admin_listener`:
+/// the listener of the clients refuses them, and the admin listener refuses the others, with `Forbidden`.
+pub struct AdminSplit {
+    admin: bool,
+}
+
+impl AdminSplit {
+    /// create an interceptor for the listener of the clients, refusing the admin requests.
+    pub fn clients() -> Self {
+        AdminSplit { admin: false }
+    }
+
+    /// create an interceptor for the admin listener, refusing the requests of the clients.
+    pub fn admins() -> Self {
+        AdminSplit { admin: true }
+    }
+}
+
+impl Interceptor for AdminSplit {
+    fn intercept(&self, request: Request, next: Next<'_>) -> Result<Response> {
+        let admin_request = matches!(request, Request::Stats | Request::Preload { .. } | Request::Restore { .. });
+        if admin_request != self.admin {
+            return Err(ServerError::Forbidden);
+        }
+        next.run(request)
+    }
+}
+
+/// refuse the admin requests that carry a token, `preload` and `restore`, unless it's the admin token,
+/// the innermost interceptor of a `KvServer`, see `KvServer::admin_token`.
+///
+/// Without an admin token, they're all refused.
+/// The `stats` carries none, since it only reads, like the requests of the `read` role of `Acl`.
+pub struct AdminAuth {
+    token: Option<Arc<str>>,
+}
+
+impl AdminAuth {
+    /// create an interceptor that admits the admin requests carrying `token`.
+    pub fn new(token: Option<Arc<str>>) -> Self {
+        AdminAuth { token }
+    }
+}
+
+impl Interceptor for AdminAuth {
+    fn intercept(&self, request: Request, next: Next<'_>) -> Result<Response> {
+        if let Request::Preload { token, .. } | Request::Restore { token, .. } = &request {
+            match (self.token.as_deref(), token.as_deref()) {
+                (Some(expected), Some(token)) if same_secret(expected, token) => {}
+                _ => return Err(ServerError::Unauthorized),
+            }
+        }
+        next.run(request)
+    }
+}
@@ -4,7 +4,7 @@
 //! `sled` engine uses by LSM-tree index, and `kvs` engine uses hash index.
 //!
 //! ## quick start
-//! This project provides 2 CLIs: `kvs-server`, `kvs-client`, and its name reveals its usage.
+//! This project provides 3 CLIs: `kvs-server`, `kvs-client` and `kvs-admin`, and its name reveals its usage.
 //! ### server
 //! ```bash
 //! # to start server
@@ -21,20 +21,62 @@
 //! cargo run --bin kvs-client -- set $KEY_NAME $VALUE
 //! # to remove key $KEY_NAME.
 //! cargo run --bin kvs-client -- rm $KEY_NAME
+//! # to remove all the keys starting with $PREFIX, printing how many are removed.
+//! cargo run --bin kvs-client -- rm-prefix $PREFIX
+//! # to append $SUFFIX to the value of $KEY_NAME.
+//! cargo run --bin kvs-client -- append $KEY_NAME $SUFFIX
+//! # to add $N to the integer of $KEY_NAME, 1 when `--by` is absent.
+//! cargo run --bin kvs-client -- incr $KEY_NAME --by $N
+//! # to move the value of $KEY_NAME to $NEW_KEY_NAME, or copy it.
+//! cargo run --bin kvs-client -- rename $KEY_NAME $NEW_KEY_NAME
+//! cargo run --bin kvs-client -- copy $KEY_NAME $NEW_KEY_NAME
+//! # to lease $KEY_NAME for 30 seconds like a lock, printing its token, then release it by the token.
+//! cargo run --bin kvs-client -- lease $KEY_NAME --ttl-ms 30000
+//! cargo run --bin kvs-client -- release $KEY_NAME $TOKEN
+//! # to list the keys matching a glob pattern, like `user:*`.
+//! cargo run --bin kvs-client -- keys $PATTERN
+//! # to print the writes since a position as JSON lines, and wait for the new ones.
+//! cargo run --bin kvs-client -- changes --since $EPOCH:$OFFSET --follow
+//! # to replace all the data of the server by backups, with the admin token in the server config.
+//! cargo run --bin kvs-client -- restore --token $TOKEN $FULL_BACKUP $INCREMENTAL_BACKUP
 //! ```
 //! All operations will be performed on server at `localhost:4000`.
+//! When the server has `[users]` in its config, set the `KVS_USER` and `KVS_SECRET` env vars to send them as a user.
 //! Use `--help` to learn more.
+//!
+//! ### admin
+//! ```bash
+//! # with the server stopped, check, dump, compact, repair or count the data in the current directory.
+//! cargo run --bin kvs-admin -- verify
+//! cargo run --bin kvs-admin -- dump
+//! cargo run --bin kvs-admin -- compact
+//! cargo run --bin kvs-admin -- truncate
+//! cargo run --bin kvs-admin -- stats
+//! ```
+//! Use `--path` to operate on another data directory.
+//!
+//! ### fuzz
+//! ```bash
+//! # feed arbitrary bytes to the parsers of the contract, and to the reader of the data files.
+//! cargo +nightly fuzz run contract
+//! cargo +nightly fuzz run data_file
+//! ```
 
 
 #![deny(warnings)]
 #![deny(missing_docs)]
 
 pub use engines::engine::KvsEngine;
-pub use engines::errors::{KvError, Result};
+pub use engines::errors::{ErrorContext, KvError, Result};
 pub use engines::kvs::KvStore;
+pub use engines::metrics::{EngineMetrics, EngineMetricsSnapshot};
 
 /// Common part of benchmarking.
 pub mod benchmark_common;
+/// The linearizability checker of engines, for tests.
+pub mod checker;
+/// The client of the kvs contract.
+pub mod client;
 mod common;
 /// the default config of server.
 pub mod config;
@@ -42,7 +84,21 @@ pub mod config;
 pub mod contract;
 /// About the KvEngine abstract.
 pub mod engines;
+/// The entry points of the fuzz targets in `fuzz/`, taking arbitrary bytes.
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+/// The interceptors around the requests handled by the server.
+pub mod interceptor;
+/// The scripts run atomically on the server, see `ScriptRunner`.
+#[cfg(feature = "scripting")]
+pub mod script;
+/// The server of the kvs contract.
+pub mod server;
 /// Common part of server.
 pub mod server_common;
 /// The thread pools.
 pub mod thread_pool;
+/// The encodings of the values applied by the client, like an encryption or a compression.
+pub mod value_encoding;
+/// YCSB-style workloads for benchmarking.
+pub mod workload;
@@ -0,0 +1,118 @@
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
+
+use crate::{KvError, KvsEngine};
+use crate::server_common::{Result, ServerError};
+
+/// The keys a script may touch, declared by its `Request::Eval`, handed to `ScriptRunner::eval`.
+///
+/// Touching a key not declared fails, so that the `Acl` checks all the keys before the script runs.
+pub trait ScriptContext {
+    /// the value of `key`, or `None` if it doesn't exist.
+    fn get(&mut self, key: &str) -> crate::Result<Option<String>>;
+    /// set `key` to `value`.
+    fn set(&mut self, key: &str, value: String) -> crate::Result<()>;
+    /// remove `key`, returns whether it existed.
+    fn remove(&mut self, key: &str) -> crate::Result<bool>;
+}
+
+/// The interpreter of the scripts sent by `Request::Eval`, like an embedded Lua or WASM runtime,
+/// added to a `KvServer` by `KvServer::script_runner`.
+///
+/// A script runs alone: the other requests wait until it returns, so it reads and writes the keys atomically,
+/// like `EVAL` of Redis. So it should return quickly, and check `context::check_deadline` if it may loop.
+/// The writes done before it fails aren't rolled back.
+///
+/// # Example
+/// ```rust
+/// # use kvs::script::{ScriptContext, ScriptRunner};
+/// /// the script `move` renames the first key to the second one, if the second one doesn't exist.
+/// struct Move;
+///
+/// impl ScriptRunner for Move {
+///     fn eval(&self, script: &str, keys: &[String], _args: &[String], db: &mut dyn ScriptContext) -> kvs::Result<String> {
+///         match (script, keys) {
+///             ("move", [from, to]) if db.get(to)?.is_none() => match db.get(from)? {
+///                 Some(value) => {
+///                     db.set(to, value)?;
+///                     db.remove(from)?;
+///                     Ok("true".to_owned())
+///                 }
+///                 None => Ok("false".to_owned()),
+///             },
+///             ("move", [_, _]) => Ok("false".to_owned()),
+///             _ => Err(kvs::KvError::Other { reason: format!("unknown script {:?}", script) }),
+///         }
+///     }
+/// }
+/// ```
+pub trait ScriptRunner: Send + Sync {
+    /// run `script` with `args`, on the `keys` it declares, which are also in `db`,
+    /// returns the content of the response.
+    fn eval(&self, script: &str, keys: &[String], args: &[String], db: &mut dyn ScriptContext) -> crate::Result<String>;
+}
+
+/// the declared keys of an engine.
+struct Declared<'a, E> {
+    engine: &'a E,
+    keys: &'a [String],
+}
+
+impl<E: KvsEngine> Declared<'_, E> {
+    fn check(&self, key: &str) -> crate::Result<()> {
+        if self.keys.iter().any(|declared| declared == key) {
+            return Ok(());
+        }
+        Err(KvError::Other { reason: format!("the key {:?} isn't declared by the script", key) })
+    }
+}
+
+impl<E: KvsEngine> ScriptContext for Declared<'_, E> {
+    fn get(&mut self, key: &str) -> crate::Result<Option<String>> {
+        self.check(key)?;
+        self.engine.get(key.to_owned())
+    }
+
+    fn set(&mut self, key: &str, value: String) -> crate::Result<()> {
+        self.check(key)?;
+        self.engine.set(key.to_owned(), value)
+    }
+
+    fn remove(&mut self, key: &str) -> crate::Result<bool> {
+        self.check(key)?;
+        match self.engine.remove(key.to_owned()) {
+            Ok(()) => Ok(true),
+            Err(KvError::KeyNotFound) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// The scripting of a `KvServer`: the runner of the scripts, and the lock that makes them run alone.
+#[derive(Clone, Default)]
+pub(crate) struct Scripting {
+    runner: Option<Arc<dyn ScriptRunner>>,
+    /// taken exclusively by a script, and shared by the other requests.
+    alone: Arc<RwLock<()>>,
+}
+
+impl Scripting {
+    pub(crate) fn new(runner: Option<Arc<dyn ScriptRunner>>) -> Self {
+        Scripting { runner, alone: Default::default() }
+    }
+
+    /// hold off the scripts while a request other than them is handled.
+    pub(crate) fn shared(&self) -> RwLockReadGuard<'_, ()> {
+        self.alone.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// run `script` alone on `engine`.
+    ///
+    /// # Error
+    ///
+    /// `BadRequest` if the server runs no script.
+    pub(crate) fn eval<E: KvsEngine>(&self, engine: &E, script: &str, keys: &[String], args: &[String]) -> Result<String> {
+        let runner = self.runner.as_ref().ok_or(ServerError::BadRequest)?;
+        let _alone = self.alone.write().unwrap_or_else(PoisonError::into_inner);
+        Ok(runner.eval(script, keys, args, &mut Declared { engine, keys })?)
+    }
+}
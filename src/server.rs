@@ -0,0 +1,1038 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use fail::fail_point;
+use log::{error, info, warn};
+
+use crate::{KvError, KvsEngine, KvStore};
+#[cfg(feature = "failpoints")]
+use crate::common::failpoint_error;
+use crate::config::server::{EngineConfig, ServerConfig, UserConfig};
+use crate::contract::{KvContractMessage, Request, Response};
+use crate::engines::changes::Change;
+use crate::engines::context::{self, EnteredContext, RequestContext};
+use crate::engines::kvs::{CompactionEvent, SyncPolicy, WritePath};
+use crate::engines::offline;
+use crate::engines::pattern::{KeyPattern, ListOptions};
+use crate::engines::restorable::Restorable;
+use crate::engines::sled::{SledEngine, SledOptions};
+use crate::engines::tiered::Tiered;
+use crate::interceptor::{Acl, AdminAuth, AdminSplit, Audit, Interceptor, LoadShed, Next, RequestLog};
+#[cfg(feature = "scripting")]
+use crate::script::{ScriptRunner, Scripting};
+use crate::server_common::{
+    DryRunReport, Engine, MemoryUsage, MetaValue, Pool, Result, ServerError, ServerStats, VerifyMode,
+};
+use crate::server_common::ServerError::Timeout;
+use crate::thread_pool::*;
+
+/// how long the request of a connection may take to be read before it's closed,
+/// unless `KvServer::read_timeout` sets another one.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The server of the kvs contract, that serves requests by a `KvsEngine` on a `ThreadPool`.
+///
+/// The `kvs-server` binary is a thin wrapper of it, and it can also be embedded into other programs,
+/// like the benchmarks and the tests.
+///
+/// # Example
+/// ```no_run
+/// # use kvs::KvStore;
+/// # use kvs::server::KvServer;
+/// # use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let engine = KvStore::open(std::env::current_dir()?)?;
+/// let pool = SharedQueueThreadPool::new(4)?;
+/// let addr = KvServer::new(engine, pool).spawn("127.0.0.1:0".parse()?)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvServer<E, P> {
+    engine: E,
+    pool: P,
+    timeout: Option<Duration>,
+    read_timeout: Duration,
+    admin_token: Option<Arc<str>>,
+    /// the accounts checked by `Acl`.
+    users: BTreeMap<String, UserConfig>,
+    /// the interceptors added by `intercept`, between the built-in ones.
+    interceptors: Vec<Arc<dyn Interceptor>>,
+    /// the count of the connections closed by `read_timeout`, while their requests are read.
+    timed_out_reads: Arc<AtomicU64>,
+    /// the listener of the admin requests, see `admin_listener`.
+    admin_listener: Option<TcpListener>,
+    /// the memory in use, checked by `LoadShed` against `memory_limit`.
+    memory: MemoryUsage,
+    memory_limit: Option<u64>,
+    /// the runner of the scripts, see `script_runner`.
+    #[cfg(feature = "scripting")]
+    scripting: Scripting,
+    /// stops the serving from other threads, see `shutdown_handle`.
+    shutdown: ShutdownHandle,
+}
+
+/// The handle that stops a `KvServer` from another thread, see `KvServer::shutdown_handle`.
+///
+/// It's cheap to `Clone` it, and all clones stop the same server.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle(Arc<ShutdownState>);
+
+#[derive(Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    /// the addresses of the listeners serving, connected by `shutdown` to wake them from accepting.
+    listeners: Mutex<Vec<SocketAddr>>,
+    /// whether the server stops, and its engine is closed.
+    closed: (Mutex<bool>, Condvar),
+}
+
+impl ShutdownHandle {
+    /// stop the server from accepting connections, on both of its listeners,
+    /// then it closes its engine, see `KvsEngine::close`.
+    /// The requests accepted already are still served.
+    ///
+    /// It returns at once, `wait` for the engine to be closed.
+    pub fn shutdown(&self) {
+        self.0.requested.store(true, Ordering::SeqCst);
+        let listeners = self.0.listeners.lock().unwrap_or_else(|e| e.into_inner());
+        for addr in listeners.iter() {
+            // the listener checks the request once it accepts this connection.
+            if let Err(err) = TcpStream::connect(addr) {
+                warn!("failed to wake the listener on {}: {}", addr, err);
+            }
+        }
+    }
+
+    /// whether `shutdown` is called.
+    pub fn is_shutdown(&self) -> bool {
+        self.0.requested.load(Ordering::SeqCst)
+    }
+
+    /// block the current thread until the server stops serving, and its engine is closed, or fails to close.
+    pub fn wait(&self) {
+        let (closed, stopped) = &self.0.closed;
+        let mut closed = closed.lock().unwrap_or_else(|e| e.into_inner());
+        while !*closed {
+            closed = stopped.wait(closed).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// note that `listener` is serving, so that `shutdown` wakes it.
+    /// Returns whether the server is shut down already, then it needn't serve.
+    fn watch(&self, listener: &TcpListener) -> Result<bool> {
+        let mut addr = listener.local_addr()?;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        self.0.listeners.lock().unwrap_or_else(|e| e.into_inner()).push(addr);
+        Ok(self.is_shutdown())
+    }
+
+    fn mark_closed(&self) {
+        let (closed, stopped) = &self.0.closed;
+        *closed.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        stopped.notify_all();
+    }
+}
+
+/// A response, and the content streamed after it.
+struct Reply {
+    message: Response,
+    body: Option<Box<dyn Read>>,
+}
+
+impl From<Response> for Reply {
+    fn from(message: Response) -> Self {
+        Reply { message, body: None }
+    }
+}
+
+/// The changes streamed as JSON lines, see `Request::Changes`.
+///
+/// A failure while streaming ends the stream with an error, since the response is sent already.
+struct ChangeLines {
+    changes: Box<dyn Iterator<Item=crate::Result<Change>> + Send>,
+    /// the line being sent, and how much of it is read.
+    buf: Vec<u8>,
+    read: usize,
+}
+
+impl Read for ChangeLines {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read == self.buf.len() {
+            let change = match self.changes.next() {
+                Some(change) => change.map_err(|err| io::Error::other(err.to_string()))?,
+                None => return Ok(0),
+            };
+            self.buf = serde_json::to_vec(&change).expect("unable to serialize change into json.");
+            self.buf.push(b'\n');
+            self.read = 0;
+        }
+        let n = (&self.buf[self.read..]).read(buf)?;
+        self.read += n;
+        Ok(n)
+    }
+}
+
+/// A connection noting whether a read of it times out, i.e. the client sends its request slower than the read timeout.
+struct Watched<S> {
+    stream: S,
+    timed_out: bool,
+}
+
+impl<S: Read> Read for Watched<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stream.read(buf);
+        if let Err(err) = &read {
+            self.timed_out |= err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut;
+        }
+        read
+    }
+}
+
+/// A stream counting the bytes read from it, like the buffer of a request parsed from it.
+struct Counted<S> {
+    stream: S,
+    read: u64,
+}
+
+impl<S: Read> Read for Counted<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stream.read(buf)?;
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Watched<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// What the serving of a connection needs from the `KvServer`, cloned into the task serving it.
+struct ConnectionContext<E> {
+    engine: E,
+    metrics: PoolMetrics,
+    read_timeout: Duration,
+    timed_out_reads: Arc<AtomicU64>,
+    memory: MemoryUsage,
+    #[cfg(feature = "scripting")]
+    scripting: Scripting,
+    /// the interceptors of the listener accepting the connection.
+    chain: Arc<[Arc<dyn Interceptor>]>,
+}
+
+impl<E: Clone> Clone for ConnectionContext<E> {
+    fn clone(&self) -> Self {
+        ConnectionContext {
+            engine: self.engine.clone(),
+            metrics: self.metrics.clone(),
+            read_timeout: self.read_timeout,
+            timed_out_reads: self.timed_out_reads.clone(),
+            memory: self.memory.clone(),
+            #[cfg(feature = "scripting")]
+            scripting: self.scripting.clone(),
+            chain: self.chain.clone(),
+        }
+    }
+}
+
+/// The once-only right to reply a connection,
+/// shared by the task serving it and the watchdog of the task.
+#[derive(Clone, Default)]
+struct ReplyToken(Arc<AtomicBool>);
+
+impl ReplyToken {
+    fn claim(&self) -> bool {
+        !self.0.swap(true, Ordering::SeqCst)
+    }
+}
+
+impl<E, P> KvServer<E, P>
+    where
+        E: KvsEngine,
+        P: ThreadPool,
+{
+    /// create a server that serves by `engine` on `pool`, without a request timeout.
+    pub fn new(engine: E, pool: P) -> Self {
+        KvServer {
+            memory: MemoryUsage::new(engine.clone()),
+            memory_limit: None,
+            engine,
+            pool,
+            timeout: None,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            admin_token: None,
+            users: BTreeMap::new(),
+            interceptors: Vec::new(),
+            timed_out_reads: Arc::new(AtomicU64::new(0)),
+            admin_listener: None,
+            #[cfg(feature = "scripting")]
+            scripting: Scripting::default(),
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    /// the handle that stops this server from another thread, see `ShutdownHandle`.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// close the connections that send nothing for `timeout` while their requests are read,
+    /// so that the clients leaked, gone or too slow don't hold the workers of the pool.
+    /// They're counted in the `timed_out_reads` of the stats.
+    ///
+    /// A connection carries a single request, so it's the timeout of reading the request,
+    /// not of a connection kept idle between the requests.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// set the deadline of a request, since it's accepted.
+    /// A request exceeds it will be logged, and its client will get a timeout response.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// shed the load once the server takes more than `bytes` of memory, estimated by `MemoryUsage`,
+    /// by refusing the writes and the scans with `Overloaded`, see `LoadShed`.
+    /// Without it, the server takes as much memory as the requests need.
+    pub fn memory_limit(mut self, bytes: Option<u64>) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    /// run the scripts of `Request::Eval` by `runner`, like an embedded Lua or WASM interpreter, see `ScriptRunner`.
+    /// Without it, the scripts are refused with `BadRequest`.
+    #[cfg(feature = "scripting")]
+    pub fn script_runner(mut self, runner: Arc<dyn ScriptRunner>) -> Self {
+        self.scripting = Scripting::new(Some(runner));
+        self
+    }
+
+    /// set the secret that the admin requests, `preload` and `restore`, must carry.
+    /// Without it, they're refused.
+    pub fn admin_token(mut self, token: Option<String>) -> Self {
+        self.admin_token = token.map(Arc::from);
+        self
+    }
+
+    /// set the accounts that may send the requests, and what they may do, see `Acl`.
+    /// Without any, the requests needn't carry credentials.
+    pub fn users(mut self, users: BTreeMap<String, UserConfig>) -> Self {
+        self.users = users;
+        self
+    }
+
+    /// serve the admin requests, `stats`, `preload` and `restore`, on `listener` only, like one bound to localhost,
+    /// so that they aren't exposed to the clients, see `AdminSplit`.
+    ///
+    /// Its connections are served one by one on a thread of their own, not by the pool,
+    /// and through neither the `Acl` nor the interceptors added by `intercept`,
+    /// so that the operators still get the stats when the pool is busy, or the clients are throttled.
+    /// The admin token is still checked.
+    /// Without it, all the requests are served on the listener of `serve`.
+    pub fn admin_listener(mut self, listener: Option<TcpListener>) -> Self {
+        self.admin_listener = listener;
+        self
+    }
+
+    /// wrap the handling of each request by `interceptor`, see `Interceptor`.
+    ///
+    /// The interceptors run in the order they're added, after the logging of the request and the `Acl`,
+    /// and before the check of the admin token.
+    pub fn intercept(mut self, interceptor: impl Interceptor + 'static) -> Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// all the interceptors of a request, the outermost first.
+    fn chain(&self) -> Arc<[Arc<dyn Interceptor>]> {
+        let mut chain: Vec<Arc<dyn Interceptor>> = vec![
+            Arc::new(RequestLog),
+            Arc::new(Audit),
+            Arc::new(Acl::new(self.users.clone())),
+        ];
+        if self.admin_listener.is_some() {
+            chain.push(Arc::new(AdminSplit::clients()));
+        }
+        if let Some(limit) = self.memory_limit {
+            chain.push(Arc::new(LoadShed::new(limit, self.memory.clone())));
+        }
+        chain.extend(self.interceptors.iter().cloned());
+        chain.push(Arc::new(AdminAuth::new(self.admin_token.clone())));
+        chain.into()
+    }
+
+    /// all the interceptors of a request to the `admin_listener`, the outermost first.
+    fn admin_chain(&self) -> Arc<[Arc<dyn Interceptor>]> {
+        let chain: Vec<Arc<dyn Interceptor>> = vec![
+            Arc::new(RequestLog),
+            Arc::new(AdminSplit::admins()),
+            Arc::new(AdminAuth::new(self.admin_token.clone())),
+        ];
+        chain.into()
+    }
+
+    /// handle a request read from `stream` until EOF, and write the response back.
+    ///
+    /// It's what the server does to each connection, without the thread pool and the request timeout,
+    /// so that it can be tested with a `MockStream`.
+    pub fn handle(&self, stream: impl Read + Write) -> Result<()> {
+        let context = self.connection_context(self.chain());
+        Self::handle_request(stream, &context, ReplyToken::default(), Instant::now())
+    }
+
+    /// handle the request read from `stream` by `context`, whose connection is accepted at `accepted`.
+    fn handle_request(
+        mut stream: impl Read + Write,
+        context: &ConnectionContext<E>,
+        token: ReplyToken,
+        accepted: Instant,
+    ) -> Result<()> {
+        let ConnectionContext { memory, chain, .. } = context;
+        let mut streamed = None;
+        let mut counted = Counted { stream: &mut stream, read: 0 };
+        let parsed = Request::parse_head(&mut counted).and_then(|request| {
+            // the rest of the stream is the value of a streamed request, or nothing.
+            if !request.has_body() {
+                Request::parse_end(&mut counted)?;
+            }
+            Ok(request)
+        });
+        let _in_flight = memory.track(counted.read);
+        let message = parsed
+            .map_err(ServerError::from)
+            .and_then(|request| {
+                let (request, _entered) = Self::enter_deadline(request, accepted)?;
+                let mut handler = |request: Request| {
+                    // the interceptors may take a while, like waiting for a rate limit.
+                    context::check_deadline("request")?;
+                    let reply = Self::query(request, context, &mut stream)?;
+                    streamed = reply.body;
+                    Ok(reply.message)
+                };
+                Next::new(chain, &mut handler).run(request)
+            })
+            .unwrap_or_else(|err| {
+                error!(target: "app::error", "failed to handle a request: {} (code {}).", err, err.code());
+                err.to_response()
+            });
+        // an interceptor may replace the streamed response, then there's nothing to stream.
+        let body = streamed.filter(|_| message.has_body());
+        let bin = message.into_binary();
+        if !token.claim() {
+            return Err(Timeout);
+        }
+        fail_point!("server::before_reply", |_| Err(failpoint_error("server::before_reply").into()));
+        stream.write_all(bin.as_slice())?;
+        if let Some(mut body) = body {
+            io::copy(&mut body, &mut stream)?;
+        }
+        Ok(())
+    }
+
+    /// unwrap the `Request::Deadline` of the client, and tighten the deadline of the current request to it,
+    /// until the returned guard is dropped, so that the long scans of the engine stop at it.
+    ///
+    /// # Error
+    ///
+    /// `Timeout` if the deadline passes already, like when the request waits in the queue of the pool for too long,
+    /// then the request is skipped, since no one waits for its response.
+    fn enter_deadline(request: Request, accepted: Instant) -> Result<(Request, Option<EnteredContext>)> {
+        let (timeout_ms, request) = match request {
+            Request::Deadline { timeout_ms, request } => (timeout_ms, *request),
+            request => return Ok((request, None)),
+        };
+        let deadline = accepted + Duration::from_millis(timeout_ms);
+        if Instant::now() >= deadline {
+            let late = deadline.elapsed();
+            info!(target: "app::request", "skipping the request, since its deadline passes {:?} ago.", late);
+            return Err(Timeout);
+        }
+        let mut current = RequestContext::current().unwrap_or_else(|| RequestContext {
+            request_id: 0,
+            peer: "UNKNOWN".to_owned(),
+            deadline: None,
+        });
+        current.deadline = Some(current.deadline.map_or(deadline, |server_deadline| server_deadline.min(deadline)));
+        Ok((request, Some(current.enter())))
+    }
+
+    fn reply_timeout(mut stream: TcpStream, token: ReplyToken) {
+        if !token.claim() {
+            return;
+        }
+        let bin = Timeout.to_response().into_binary();
+        if let Err(err) = stream
+            .write_all(bin.as_slice())
+            .and_then(|_| stream.shutdown(Shutdown::Both))
+        {
+            error!(target: "app::error", "failed to reply timeout: {}", err);
+        }
+    }
+
+    fn query_db(
+        request: Request,
+        engine: &E,
+        metrics: &PoolMetrics,
+        timed_out_reads: &AtomicU64,
+        body: &mut dyn Read,
+    ) -> Result<Reply> {
+        let message = match request {
+            Request::Get { key } => {
+                // the bytes may be shared with the record cache, they're written into the response as they are.
+                match engine.get_bytes(key)? {
+                    Some(content) => Response::SharedContent { content },
+                    None => Response::NoContent,
+                }
+            }
+            Request::Set { key, value } => {
+                engine.set(key, value)?;
+                Response::NoContent
+            }
+            Request::GetWithMeta { key } => match engine.get_with_meta(key)? {
+                Some(found) => {
+                    let content = serde_json::to_string(&MetaValue::from(found))
+                        .expect("unable to serialize value into json.");
+                    Response::Content { content }
+                }
+                None => Response::NoContent,
+            },
+            Request::SetIfUnmodified { key, value, modified_ms } => {
+                let modified = UNIX_EPOCH + Duration::from_millis(modified_ms);
+                let written = engine.set_if_unmodified(key, value, modified)?;
+                Response::Content { content: written.to_string() }
+            }
+            Request::Remove { key } => {
+                engine.remove(key)?;
+                Response::NoContent
+            }
+            Request::Undelete { key } => {
+                engine.undelete(key)?;
+                Response::NoContent
+            }
+            Request::SetIf { key, value, condition } => {
+                let written = engine.set_if(key, value, condition)?;
+                Response::Content { content: written.to_string() }
+            }
+            Request::Append { key, suffix } => {
+                engine.append(key, suffix)?;
+                Response::NoContent
+            }
+            Request::GetTyped { key } => match engine.get_typed(key)? {
+                Some(value) => {
+                    let content = serde_json::to_string(&value).expect("unable to serialize value into json.");
+                    Response::Content { content }
+                }
+                None => Response::NoContent,
+            },
+            Request::SetTyped { key, value } => {
+                engine.set_typed(key, value)?;
+                Response::NoContent
+            }
+            Request::Incr { key, delta } => {
+                let sum = engine.incr(key, delta)?;
+                Response::Content { content: sum.to_string() }
+            }
+            Request::Rename { from, to } => {
+                engine.rename(from, to)?;
+                Response::NoContent
+            }
+            Request::Copy { from, to } => {
+                engine.copy(from, to)?;
+                Response::NoContent
+            }
+            Request::RemovePrefix { prefix } => {
+                let removed = engine.remove_prefix(prefix)?;
+                Response::Content { content: removed.to_string() }
+            }
+            Request::AcquireLease { key, ttl_ms } => {
+                match engine.acquire_lease(key, Duration::from_millis(ttl_ms))? {
+                    Some(lease) => Response::Content { content: lease.encode() },
+                    None => Response::NoContent,
+                }
+            }
+            Request::ReleaseLease { key, token } => {
+                let released = engine.release_lease(key, token)?;
+                Response::Content { content: released.to_string() }
+            }
+            Request::Stats => {
+                let stats = ServerStats {
+                    pool: metrics.snapshot(),
+                    engine: engine.metrics().snapshot(),
+                    timed_out_reads: timed_out_reads.load(Ordering::Relaxed),
+                };
+                let content = serde_json::to_string(&stats).expect("unable to serialize stats into json.");
+                Response::Content { content }
+            }
+            Request::Keys { pattern, options } => {
+                let keys = engine.list_keys(pattern, options)?;
+                let content = serde_json::to_string(&keys).expect("unable to serialize keys into json.");
+                Response::Content { content }
+            }
+            Request::SetStream { key } => {
+                engine.set_stream(key, body)?;
+                Response::NoContent
+            }
+            Request::GetStream { key } => {
+                return Ok(match engine.get_stream(key)? {
+                    Some(value) => Reply {
+                        message: Response::Stream,
+                        body: Some(value),
+                    },
+                    None => Response::NoContent.into(),
+                });
+            }
+            Request::Changes { since, follow } => {
+                let changes = engine.changes(since, follow)?;
+                return Ok(Reply {
+                    message: Response::Stream,
+                    body: Some(Box::new(ChangeLines { changes, buf: Vec::new(), read: 0 })),
+                });
+            }
+            Request::LogEnd => {
+                let end = engine.log_end()?;
+                let content = serde_json::to_string(&end).expect("unable to serialize log position into json.");
+                Response::Content { content }
+            }
+            // the admin token is checked by `AdminAuth`.
+            Request::Preload { target, .. } => {
+                let loaded = engine.preload(target)?;
+                Response::Content { content: loaded.to_string() }
+            }
+            // the admin token is checked by `AdminAuth`.
+            Request::Restore { archive, .. } => {
+                engine.restore(archive)?;
+                Response::NoContent
+            }
+            Request::Transaction { writes } => {
+                engine.write_batch(writes)?;
+                Response::NoContent
+            }
+            Request::Batch { requests } => {
+                let responses = requests
+                    .into_iter()
+                    .map(|request| Self::query_batched(request, engine, metrics, timed_out_reads))
+                    .collect();
+                Response::Batch { responses }
+            }
+            Request::DryRun { request } => {
+                let report = Self::dry_run(*request, engine)?;
+                let content = serde_json::to_string(&report).expect("unable to serialize report into json.");
+                Response::Content { content }
+            }
+            // unwrapped by `Acl` and `handle_request`, so they're nested in another one.
+            Request::Auth { .. } | Request::Deadline { .. } => return Err(ServerError::BadRequest),
+            // run by `query`, so it's nested, or the server runs no script.
+            Request::Eval { .. } => return Err(ServerError::BadRequest),
+        };
+        Ok(message.into())
+    }
+
+    /// handle `request` by the engine of `context`, the scripts alone, see `Scripting`.
+    #[cfg(feature = "scripting")]
+    fn query(request: Request, context: &ConnectionContext<E>, body: &mut dyn Read) -> Result<Reply> {
+        let ConnectionContext { engine, metrics, timed_out_reads, scripting, .. } = context;
+        if let Request::Eval { script, keys, args } = request {
+            let content = scripting.eval(engine, script.as_str(), keys.as_slice(), args.as_slice())?;
+            return Ok(Response::Content { content }.into());
+        }
+        let _shared = scripting.shared();
+        Self::query_db(request, engine, metrics, timed_out_reads, body)
+    }
+
+    /// handle `request` by the engine of `context`.
+    #[cfg(not(feature = "scripting"))]
+    fn query(request: Request, context: &ConnectionContext<E>, body: &mut dyn Read) -> Result<Reply> {
+        Self::query_db(request, &context.engine, &context.metrics, &context.timed_out_reads, body)
+    }
+
+    /// the response of a request in a batch, an error response if it fails, or it cannot be batched.
+    fn query_batched(request: Request, engine: &E, metrics: &PoolMetrics, timed_out_reads: &AtomicU64) -> Response {
+        let batchable = !matches!(
+            request,
+            Request::SetStream { .. }
+                | Request::GetStream { .. }
+                | Request::Changes { .. }
+                | Request::Stats
+                | Request::Preload { .. }
+                | Request::Restore { .. }
+                | Request::Batch { .. }
+                | Request::Eval { .. }
+                | Request::Auth { .. }
+                | Request::Deadline { .. }
+        );
+        if !batchable {
+            return ServerError::BadRequest.to_response();
+        }
+        // the rest of the batch fails once the request passes its deadline.
+        context::check_deadline("batch")
+            .map_err(ServerError::from)
+            .and_then(|_| Self::query_db(request, engine, metrics, timed_out_reads, &mut io::empty()))
+            .map(|reply| reply.message)
+            .unwrap_or_else(|err| err.to_response())
+    }
+
+    /// the keys `request` would touch, only the destructive requests of the clients can run dry.
+    fn dry_run(request: Request, engine: &E) -> Result<DryRunReport> {
+        let keys = match request {
+            // like the request itself, it fails if the key doesn't exist.
+            Request::Remove { key } => match engine.get(key.clone())? {
+                Some(_) => vec![key],
+                None => return Err(KvError::KeyNotFound.into()),
+            },
+            Request::RemovePrefix { prefix } => {
+                let pattern = format!("{}*", KeyPattern::escape(prefix.as_str()));
+                engine.list_keys(pattern, ListOptions::default())?
+            }
+            _ => return Err(ServerError::BadRequest),
+        };
+        Ok(DryRunReport::of(keys))
+    }
+
+    /// serve the connections accepted by `listener`, blocking the current thread until it's stopped by
+    /// `shutdown_handle`, or fails, and the ones accepted by the `admin_listener` on another thread.
+    /// Then close the engine, see `KvsEngine::close`, however it stops.
+    pub fn serve(mut self, listener: TcpListener) -> Result<()> {
+        let served = self.accept(listener);
+        info!("the listener stops, closing the engine.");
+        let closed = self.engine.close();
+        self.shutdown.mark_closed();
+        served.and(closed.map_err(ServerError::from))
+    }
+
+    /// serve the connections accepted by `listener` and the `admin_listener`, until the server is shut down.
+    fn accept(&mut self, listener: TcpListener) -> Result<()> {
+        info!("succeed to bind to {}, listening incoming requests.", listener.local_addr()?);
+        let request_ids = Arc::new(AtomicU64::new(0));
+        // before taking the admin listener, which the chain of the clients depends on.
+        let context = self.connection_context(self.chain());
+        if let Some(admin_listener) = self.admin_listener.take() {
+            info!("succeed to bind to {}, listening incoming admin requests.", admin_listener.local_addr()?);
+            let context = self.connection_context(self.admin_chain());
+            let request_ids = request_ids.clone();
+            let shutdown = self.shutdown.clone();
+            let stopped = shutdown.watch(&admin_listener)?;
+            thread::spawn(move || {
+                if stopped {
+                    return;
+                }
+                for stream in admin_listener.incoming() {
+                    if shutdown.is_shutdown() {
+                        info!("the admin listener stops.");
+                        break;
+                    }
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            error!(target: "app::error", "failed to accept an admin connection: {}", err);
+                            continue;
+                        }
+                    };
+                    let request_id = request_ids.fetch_add(1, Ordering::Relaxed);
+                    let accepted = Instant::now();
+                    let token = ReplyToken::default();
+                    Self::serve_connection(context.clone(), stream, request_id, accepted, None, token);
+                }
+            });
+        }
+        if self.shutdown.watch(&listener)? {
+            return Ok(());
+        }
+        for stream in listener.incoming() {
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!(target: "app::error", "failed to accept a connection: {}", err);
+                    continue;
+                }
+            };
+            let request_id = request_ids.fetch_add(1, Ordering::Relaxed);
+            let accepted = Instant::now();
+            let deadline = self.timeout.map(|timeout| accepted + timeout);
+            let token = ReplyToken::default();
+            // without the clone, the request is still served, but its client isn't told when it times out.
+            let timeout_stream = match self.timeout.map(|_| stream.try_clone()) {
+                Some(Ok(timeout_stream)) => Some(timeout_stream),
+                Some(Err(err)) => {
+                    error!(target: "app::error", "failed to clone a connection to reply its timeout: {}", err);
+                    None
+                }
+                None => None,
+            };
+            let task = {
+                let context = context.clone();
+                let token = token.clone();
+                move || Self::serve_connection(context, stream, request_id, accepted, deadline, token)
+            };
+            match (self.timeout, timeout_stream) {
+                (Some(timeout), Some(timeout_stream)) => self.pool.spawn_with_timeout(
+                    timeout,
+                    task,
+                    move || Self::reply_timeout(timeout_stream, token),
+                ),
+                _ => self.pool.spawn(task),
+            }
+        }
+        Ok(())
+    }
+
+    /// what the connections handled through `chain` need from the server.
+    fn connection_context(&self, chain: Arc<[Arc<dyn Interceptor>]>) -> ConnectionContext<E> {
+        ConnectionContext {
+            engine: self.engine.clone(),
+            metrics: self.pool.metrics(),
+            read_timeout: self.read_timeout,
+            timed_out_reads: self.timed_out_reads.clone(),
+            memory: self.memory.clone(),
+            #[cfg(feature = "scripting")]
+            scripting: self.scripting.clone(),
+            chain,
+        }
+    }
+
+    /// handle the request of a connection accepted at `accepted`,
+    /// with the request id and the peer in the context of the logs.
+    fn serve_connection(
+        context: ConnectionContext<E>,
+        stream: TcpStream,
+        request_id: u64,
+        accepted: Instant,
+        deadline: Option<Instant>,
+        token: ReplyToken,
+    ) {
+        let read_timeout = context.read_timeout;
+        let peer_addr = stream.peer_addr().map(|addr| format!("{}", addr))
+            .unwrap_or_else(|_| "UNKNOWN".to_owned());
+        log_mdc::insert("request_id", request_id.to_string());
+        log_mdc::insert("peer", peer_addr.as_str());
+        let entered = RequestContext { request_id, peer: peer_addr.clone(), deadline }.enter();
+        let start = Instant::now();
+        let mut watched = Watched { stream, timed_out: false };
+        let result = watched
+            .stream
+            .set_read_timeout(Some(read_timeout))
+            .map_err(ServerError::from)
+            .and_then(|_| Self::handle_request(&mut watched, &context, token, accepted));
+        log_mdc::insert("latency_us", start.elapsed().as_micros().to_string());
+        if watched.timed_out {
+            context.timed_out_reads.fetch_add(1, Ordering::Relaxed);
+            info!(target: "app::request", "closing the connection from {}, its request isn't read in {:?}.", peer_addr, read_timeout);
+        }
+        match result {
+            Ok(_) => info!(target: "app::request", "request {} from {} done.", request_id, peer_addr),
+            Err(err) => error!(target: "app::error", "An error: {} occurs during processing... with peer: {}", err, peer_addr)
+        };
+        // the worker is shared by requests, don't leak the context to the next one.
+        log_mdc::clear();
+        drop(entered);
+    }
+
+    /// bind to `addr` and serve on it, blocking the current thread.
+    /// Errors are logged instead of returned.
+    pub fn listen_on(self, addr: SocketAddr) {
+        info!("Our server will on: {}", addr);
+        match TcpListener::bind(addr).map_err(ServerError::from).and_then(|listener| self.serve(listener)) {
+            Err(err) => error!(target: "app::error", "err:{}; Our server on {} will stop...", err, addr),
+            Ok(_) => info!("goodbye!"),
+        }
+    }
+}
+
+impl<E, P> KvServer<E, P>
+    where
+        E: KvsEngine,
+        P: ThreadPool + Send + 'static,
+{
+    /// bind to `addr` and serve on a background thread.
+    ///
+    /// Returns the address actually bound, so `addr` can use the port `0`.
+    /// Once it returns, the server is ready to accept connections.
+    /// Take the `shutdown_handle` before, to stop it.
+    pub fn spawn(self, addr: SocketAddr) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        thread::spawn(move || {
+            if let Err(err) = self.serve(listener) {
+                error!(target: "app::error", "err:{}; Our server on {} will stop...", err, local_addr);
+            }
+        });
+        Ok(local_addr)
+    }
+}
+
+/// log the compactions of the `kvs` engine, their counts are in the stats too.
+fn log_compaction(event: &CompactionEvent) {
+    match event {
+        CompactionEvent::Started { epoch } => info!("compaction into epoch {} started.", epoch),
+        CompactionEvent::Progress { .. } => {}
+        CompactionEvent::Finished { epoch, bytes_written, records_dropped, elapsed } => info!(
+            "compaction into epoch {} finished in {:?}: {} bytes rewritten, {} records dropped.",
+            epoch, elapsed, bytes_written, records_dropped
+        ),
+        CompactionEvent::Failed { epoch, reason } => {
+            error!(target: "app::error", "compaction into epoch {} failed: {}.", epoch, reason)
+        }
+    }
+}
+
+/// verify the data of the `kvs` engine at `path` by `mode` before opening it, see `VerifyMode`.
+/// A new data directory has nothing to verify.
+///
+/// # Error
+///
+/// In the `refuse` mode, throws `CorruptedRecord` on corruption, after logging what's corrupted.
+fn verify_data(path: &Path, mode: VerifyMode) -> crate::Result<()> {
+    if mode == VerifyMode::Off || !offline::is_data_dir(path) {
+        return Ok(());
+    }
+    let report = offline::verify(path)?;
+    if report.is_intact() {
+        info!("verified {} records in {} data files of {}, intact.", report.records(), report.files.len(), path.display());
+        return Ok(());
+    }
+    error!(target: "app::error", "the data in {} is corrupted:\n{}", path.display(), report);
+    if mode == VerifyMode::Refuse {
+        return Err(KvError::CorruptedRecord {
+            reason: format!("the data in {} is corrupted, refusing to start.", path.display()),
+        });
+    }
+    warn!("serving the corrupted data in {} anyway.", path.display());
+    Ok(())
+}
+
+/// configure `server` by `config` besides its engine and pool, with the admin listener bound by `serve_with`.
+fn configure<E: KvsEngine, P: ThreadPool>(
+    server: KvServer<E, P>,
+    config: &ServerConfig,
+    timeout: Option<Duration>,
+    admin_listener: Option<TcpListener>,
+) -> KvServer<E, P> {
+    let read_timeout = config.connection.read_timeout_ms.map_or(DEFAULT_READ_TIMEOUT, Duration::from_millis);
+    server
+        .timeout(timeout)
+        .read_timeout(read_timeout)
+        .admin_token(config.admin.token.clone())
+        .users(config.users.clone())
+        .admin_listener(admin_listener)
+        .memory_limit(config.memory.limit)
+}
+
+/// open the engine at `path` with the `[engine]` section of `config` and the thread pool from `builder` by their kinds,
+/// then serve the connections accepted by `listener` with them, blocking the current thread.
+///
+/// The `kvs` engine can be restored from a backup remotely, with the admin token in `config`,
+/// on the admin address of it if any.
+/// Either engine keeps the values read recently in memory if `hot_tier` is set, see `Tiered`.
+pub fn serve_with(
+    engine: Engine,
+    pool: Pool,
+    path: PathBuf,
+    config: &ServerConfig,
+    builder: ThreadPoolBuilder,
+    timeout: Option<Duration>,
+    listener: TcpListener,
+) -> Result<()> {
+    let admin_listener = config.admin.addr.map(TcpListener::bind).transpose()?;
+    macro_rules! serve_on {
+        ($engine: expr, $pool: expr) => {
+            configure(KvServer::new($engine, $pool), config, timeout, admin_listener).serve(listener)
+        };
+    }
+    macro_rules! serve_on_pool {
+        ($engine: expr) => {
+            match pool {
+                Pool::Rayon => serve_on!($engine, RayonThreadPool::from_builder(builder)?),
+                Pool::SharedQueue => serve_on!($engine, SharedQueueThreadPool::from_builder(builder)?),
+                Pool::Naive => serve_on!($engine, NaiveThreadPool::from_builder(builder)?),
+                Pool::Cached => serve_on!($engine, CachedThreadPool::from_builder(builder)?),
+                Pool::Tokio => serve_on!($engine, TokioThreadPool::from_builder(builder)?),
+            }
+        };
+    }
+    let hot_tier = config.engine.hot_tier;
+    macro_rules! serve {
+        ($engine: expr) => {
+            match hot_tier {
+                Some(bytes) => serve_on_pool!(Tiered::new($engine, bytes)),
+                None => serve_on_pool!($engine),
+            }
+        };
+    }
+    let EngineConfig {
+        soft_delete, blob_threshold, index, codec, record_cache, sync, write_path, index_budget, index_warning,
+        slow_log_ms, verify_on_start, sled, ..
+    } = config.engine;
+    match engine {
+        Engine::Kvs if sled != SledOptions::default() => Err(KvError::Unsupported { operation: "sled" }.into()),
+        Engine::Kvs => serve!(Restorable::open(path, move |path| {
+            verify_data(path, verify_on_start)?;
+            let store = match codec {
+                Some(kind) => KvStore::open_with_codec(path, kind.codec())?,
+                None => KvStore::open(path)?,
+            };
+            let mut store = store.with_compaction_listener(log_compaction);
+            if soft_delete {
+                store = store.with_soft_delete();
+            }
+            if let Some(bytes) = blob_threshold {
+                store = store.with_blob_threshold(bytes);
+            }
+            if let Some(kind) = index {
+                store = store.with_index(kind)?;
+            }
+            if let Some(bytes) = record_cache {
+                store = store.with_record_cache(bytes);
+            }
+            store = store.with_sync(sync).with_write_path(write_path);
+            if let Some(bytes) = index_budget {
+                store = store.with_index_budget(bytes);
+            }
+            if let Some(bytes) = index_warning {
+                store = store.with_index_warning(bytes);
+            }
+            if let Some(millis) = slow_log_ms {
+                store = store.with_slow_log(Duration::from_millis(millis));
+            }
+            Ok(store)
+        })?),
+        Engine::Sled if soft_delete => Err(KvError::Unsupported { operation: "soft_delete" }.into()),
+        Engine::Sled if blob_threshold.is_some() => Err(KvError::Unsupported { operation: "blob_threshold" }.into()),
+        Engine::Sled if index.is_some() => Err(KvError::Unsupported { operation: "index" }.into()),
+        Engine::Sled if codec.is_some() => Err(KvError::Unsupported { operation: "codec" }.into()),
+        Engine::Sled if record_cache.is_some() => Err(KvError::Unsupported { operation: "record_cache" }.into()),
+        Engine::Sled if write_path != WritePath::default() => {
+            Err(KvError::Unsupported { operation: "write_path" }.into())
+        }
+        Engine::Sled if index_budget.is_some() => Err(KvError::Unsupported { operation: "index_budget" }.into()),
+        Engine::Sled if index_warning.is_some() => Err(KvError::Unsupported { operation: "index_warning" }.into()),
+        Engine::Sled if verify_on_start != VerifyMode::Off => {
+            Err(KvError::Unsupported { operation: "verify_on_start" }.into())
+        }
+        Engine::Sled if slow_log_ms.is_some() => Err(KvError::Unsupported { operation: "slow_log_ms" }.into()),
+        Engine::Sled if sync == SyncPolicy::Always && sled.flush_every_ms.is_some() => {
+            Err(KvError::Unsupported { operation: "sync" }.into())
+        }
+        Engine::Sled => serve!(SledEngine::open_with(path, sled)?),
+    }
+}
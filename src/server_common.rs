@@ -1,11 +1,22 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
 
-use failure::Fail;
+use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
+use thiserror::Error;
 
-use crate::KvError;
+use crate::config::log4rs::LogFilter;
+use crate::config::server::ConfigError;
+use crate::contract::Response;
+use crate::engines::engine::ValueWithMeta;
+use crate::{EngineMetrics, EngineMetricsSnapshot, KvError, KvsEngine};
 use crate::server_common::ServerError::{EngineError, UnsupportedContract};
+use crate::thread_pool::PoolMetricsSnapshot;
 
 #[derive(Debug, StructOpt, Clone)]
 #[structopt(name = "kvs",
@@ -35,25 +46,58 @@ pub struct ServerOpt {
     )]
     /// the thread pool to use.
     pub pool: Pool,
+    #[structopt(long = "--admin-addr", parse(try_from_str = str::parse))]
+    /// the address to serve the admin requests, `stats`, `preload` and `restore`, on, like a localhost one,
+    /// then the address of `--addr` refuses them.
+    /// It takes precedence over the `addr` of the `[admin]` section in the config file.
+    pub admin_addr: Option<SocketAddr>,
+    #[structopt(long = "--pin-workers")]
+    /// pin the workers of the thread pool to the cores of this machine, one by one.
+    pub pin_workers: bool,
+    #[structopt(long = "--soft-delete")]
+    /// make `rm` recoverable by `undelete` until the next compaction, only for the `kvs` engine.
+    pub soft_delete: bool,
+    #[structopt(long = "--request-timeout")]
+    /// the deadline of a request in milliseconds, since it's accepted.
+    /// A request exceeds it will be logged, and its client will get a timeout response.
+    pub request_timeout: Option<u64>,
+    #[structopt(long = "--verify-on-start", parse(try_from_str = str::parse))]
+    /// verify the data before serving, `warn` to log a summary, or `refuse` to refuse to start on corruption,
+    /// only for the `kvs` engine.
+    /// It takes precedence over the `verify_on_start` in the config file.
+    pub verify_on_start: Option<VerifyMode>,
+    #[structopt(long = "--read-timeout")]
+    /// close the connections whose requests aren't read in this many milliseconds, 10 seconds by default.
+    /// It takes precedence over the `read_timeout_ms` in the config file.
+    pub read_timeout: Option<u64>,
+    #[structopt(
+    default_value = "text",
+    parse(try_from_str = str::parse),
+    long = "--log-format"
+    )]
+    /// the format of logs, `text` for human, or `json` for log pipelines, one JSON object per line.
+    pub log_format: LogFormat,
+    #[structopt(long = "--log-level", parse(try_from_str = str::parse))]
+    /// the filter of logs, like `warn,app::request=info`.
+    /// When absent, the `RUST_LOG` env var, or the level in the config file is used, and `info` by default.
+    pub log_level: Option<LogFilter>,
+    #[structopt(long = "--config", parse(from_os_str))]
+    /// the config file in TOML, see `ServerConfig` for its content.
+    pub config: Option<PathBuf>,
 }
 
 /// the engine of user select.
-#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
 pub enum Engine {
     /// the `KvStore` engine.
+    #[default]
     Kvs,
     /// the `SledEngine` engine.
     Sled,
 }
 
-impl Default for Engine {
-    fn default() -> Self {
-        Engine::Kvs
-    }
-}
-
-#[derive(Debug, Eq, PartialEq, Clone, Copy, Fail)]
-#[fail(display = "No such engine")]
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Error)]
+#[error("No such engine")]
 /// Throws when we cannot parse the command line input into an engine.
 pub struct NoSuchEngine;
 
@@ -78,25 +122,24 @@ impl AsRef<str> for Engine {
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
 /// The thread pool type of the server.
 pub enum Pool {
     /// the `NaiveThreadPool`, it just spawn new threads.
     Naive,
+    /// the `CachedThreadPool`, it spawns threads on demand, and reuses the idle ones.
+    Cached,
     /// the `RayonThreadPool`, from the `rayon` creat.
     Rayon,
     /// the `SharedQueueThreadPool`, a fixed thread pool that uses a shared, boundless queue to work.
+    #[default]
     SharedQueue,
+    /// the `TokioThreadPool`, the blocking pool of a `tokio` runtime.
+    Tokio,
 }
 
-impl Default for Pool {
-    fn default() -> Self {
-        Pool::SharedQueue
-    }
-}
-
-#[derive(Debug, Eq, PartialEq, Clone, Fail)]
-#[fail(display = "No such pool: {}", 0)]
+#[derive(Debug, Eq, PartialEq, Clone, Error)]
+#[error("No such pool: {0}")]
 /// Throws when we cannot parse the command line to an thread pool name.
 pub struct NoSuchPool(String);
 
@@ -106,8 +149,10 @@ impl FromStr for Pool {
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         match s.to_lowercase().as_str() {
             "naive" => Ok(Pool::Naive),
+            "cached" => Ok(Pool::Cached),
             "shared_queue" => Ok(Pool::SharedQueue),
             "rayon" => Ok(Pool::Rayon),
+            "tokio" => Ok(Pool::Tokio),
             _ => Err(NoSuchPool(s.to_owned())),
         }
     }
@@ -117,31 +162,270 @@ impl AsRef<str> for Pool {
     fn as_ref(&self) -> &str {
         match *self {
             Pool::Naive => "naive",
+            Pool::Cached => "cached",
             Pool::Rayon => "rayon",
             Pool::SharedQueue => "shared_queue",
+            Pool::Tokio => "tokio",
+        }
+    }
+}
+
+/// The format of the server logs.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub enum LogFormat {
+    /// the plain text, for human.
+    #[default]
+    Text,
+    /// one JSON object per line, with the `request_id`, `peer` and `latency_us` of requests in its `mdc` field.
+    Json,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Error)]
+#[error("No such log format: {0}")]
+/// Throws when we cannot parse the command line to a log format.
+pub struct NoSuchLogFormat(String);
+
+impl FromStr for LogFormat {
+    type Err = NoSuchLogFormat;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(NoSuchLogFormat(s.to_owned())),
+        }
+    }
+}
+
+impl AsRef<str> for LogFormat {
+    fn as_ref(&self) -> &str {
+        match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+/// Whether the server verifies the data of the `kvs` engine before serving, see `offline::verify`,
+/// for the paranoid deployments after unclean shutdowns.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyMode {
+    /// serve without verifying.
+    #[default]
+    Off,
+    /// verify, and log a summary, then serve whatever it finds.
+    Warn,
+    /// verify, and refuse to start on corruption.
+    Refuse,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Error)]
+#[error("No such verify mode: {0}")]
+/// Throws when we cannot parse the command line or the config file to a verify mode.
+pub struct NoSuchVerifyMode(String);
+
+impl FromStr for VerifyMode {
+    type Err = NoSuchVerifyMode;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(VerifyMode::Off),
+            "warn" => Ok(VerifyMode::Warn),
+            "refuse" => Ok(VerifyMode::Refuse),
+            _ => Err(NoSuchVerifyMode(s.to_owned())),
+        }
+    }
+}
+
+impl AsRef<str> for VerifyMode {
+    fn as_ref(&self) -> &str {
+        match self {
+            VerifyMode::Off => "off",
+            VerifyMode::Warn => "warn",
+            VerifyMode::Refuse => "refuse",
+        }
+    }
+}
+
+/// The statistics of a running server, which is the content of the response of a `stats` request.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ServerStats {
+    /// the metrics of the thread pool that serves requests.
+    pub pool: PoolMetricsSnapshot,
+    /// the metrics of the engine.
+    #[serde(default)]
+    pub engine: EngineMetricsSnapshot,
+    /// the count of the connections closed since their requests aren't read within the read timeout,
+    /// see `KvServer::read_timeout`.
+    #[serde(default)]
+    pub timed_out_reads: u64,
+}
+
+/// A value with when it's last modified and its metadata,
+/// which is the content of the response of a `Request::GetWithMeta`.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MetaValue {
+    /// the value.
+    pub value: String,
+    /// the milliseconds since the unix epoch when the value is set, `None` if the engine doesn't track it.
+    #[serde(default)]
+    pub modified_ms: Option<u64>,
+    /// the user metadata set with the value.
+    #[serde(default)]
+    pub meta: Option<String>,
+}
+
+impl From<ValueWithMeta> for MetaValue {
+    fn from(found: ValueWithMeta) -> Self {
+        let modified_ms = found
+            .modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_millis() as u64);
+        MetaValue { value: found.value, modified_ms, meta: found.meta }
+    }
+}
+
+impl From<MetaValue> for ValueWithMeta {
+    fn from(found: MetaValue) -> Self {
+        ValueWithMeta {
+            value: found.value,
+            modified: found.modified_ms.map(|millis| UNIX_EPOCH + Duration::from_millis(millis)),
+            meta: found.meta,
+        }
+    }
+}
+
+/// The approximate memory a server takes: the index and the caches of its engine,
+/// and the requests being handled, see `KvServer::memory_limit`.
+///
+/// It's cheap to `Clone` it, and all clones share the same counter of the requests.
+#[derive(Clone)]
+pub struct MemoryUsage {
+    /// the metrics of the engine serving now, read at each check.
+    engine: Arc<dyn Fn() -> EngineMetrics + Send + Sync>,
+    in_flight: Arc<AtomicU64>,
+}
+
+impl Debug for MemoryUsage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryUsage").field("in_flight", &self.in_flight).finish()
+    }
+}
+
+impl Default for MemoryUsage {
+    fn default() -> Self {
+        MemoryUsage { engine: Arc::new(EngineMetrics::default), in_flight: Arc::default() }
+    }
+}
+
+impl MemoryUsage {
+    /// the memory taken by `engine`, and the requests tracked by `track`.
+    ///
+    /// The metrics of the engine are read at each check instead of once,
+    /// since the engine behind it may be replaced with its own counters, like by `Restorable::restore`.
+    pub fn new<E: KvsEngine>(engine: E) -> Self {
+        // the engines may not be `Sync`, like `KvStore`.
+        let engine = Mutex::new(engine);
+        MemoryUsage {
+            engine: Arc::new(move || engine.lock().map(|engine| engine.metrics()).unwrap_or_default()),
+            in_flight: Arc::default(),
         }
     }
+
+    /// the bytes of memory in use.
+    pub fn bytes(&self) -> u64 {
+        let engine = (self.engine)().snapshot();
+        engine.index_bytes + engine.cache_bytes + self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// count a request of `bytes` in use, until the returned guard is dropped.
+    pub fn track(&self, bytes: u64) -> InFlight {
+        self.in_flight.fetch_add(bytes, Ordering::Relaxed);
+        InFlight { in_flight: self.in_flight.clone(), bytes }
+    }
 }
 
-#[derive(Debug, Fail)]
+/// A request counted by `MemoryUsage::track`, until it's dropped.
+pub struct InFlight {
+    in_flight: Arc<AtomicU64>,
+    bytes: u64,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// The keys a request would touch, which is the content of the response of a `Request::DryRun`.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DryRunReport {
+    /// how many keys the request would touch.
+    pub count: usize,
+    /// the first few of them in ascending order, at most `DryRunReport::SAMPLE_SIZE`.
+    pub sample: Vec<String>,
+}
+
+impl DryRunReport {
+    /// the most keys in the `sample`.
+    pub const SAMPLE_SIZE: usize = 10;
+
+    /// the report of a request touching `keys`.
+    pub fn of(keys: Vec<String>) -> Self {
+        DryRunReport {
+            count: keys.len(),
+            sample: keys.into_iter().take(Self::SAMPLE_SIZE).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
 /// the error type of `KvServer` context.
 /// It simply extends the `KvError` with two new conditions:
 /// `BadRequest` and `UnSupportedContract`.
 pub enum ServerError {
-    #[fail(display = "Engine exception: {}", eng_error)]
+    #[error("Engine exception: {eng_error}")]
     /// Throws when the underlying engine meet an exception.
     EngineError {
-        #[cause]
+        #[source]
         /// the inner exception thrown by `KvsEngine`.
         eng_error: crate::KvError,
     },
-    #[fail(display = "Bad request.")]
+    #[error("Bad request.")]
     /// Throws when the request has right binary format, but bad semantic of a request.
     BadRequest,
-    #[fail(display = "Unsupported contract.")]
+    #[error("Request timeout.")]
+    /// Throws when the request isn't handled before its deadline.
+    Timeout,
+    #[error("Bad config: {config_error}")]
+    /// Throws when the config file cannot be loaded or applied.
+    BadConfig {
+        #[source]
+        /// the error occurs on loading the config.
+        config_error: ConfigError,
+    },
+    #[error("Unauthorized.")]
+    /// Throws when an admin request doesn't carry the admin token of the server,
+    /// or the server has no admin token,
+    /// or the request doesn't carry the right credentials of a user, see `Acl`.
+    Unauthorized,
+    #[error("Forbidden.")]
+    /// Throws when the user isn't allowed to send the request, by its role or its prefixes, see `Acl`.
+    Forbidden,
+    #[error("Overloaded: about {used} bytes of memory in use, beyond the limit of {limit} bytes.")]
+    /// Throws when the server takes more memory than its limit, and the request would take more,
+    /// like a write or a scan, see `LoadShed`.
+    Overloaded {
+        /// the bytes of memory in use.
+        used: u64,
+        /// the limit of the server.
+        limit: u64,
+    },
+    #[error("Unsupported contract.")]
     /// Throws when the request has malformed binary format.
     UnsupportedContract {
-        #[cause]
+        #[source]
         /// the error occurs on contract.
         contract_error: crate::contract::Error,
     },
@@ -150,15 +434,64 @@ pub enum ServerError {
 /// The `Result` type of `Server` context.
 pub type Result<T> = std::result::Result<T, ServerError>;
 
+impl ServerError {
+    /// the stable numeric code of this error.
+    ///
+    /// The errors of the engine keep their `KvError::code`, and `3xx` are the errors of the server itself.
+    pub fn code(&self) -> u16 {
+        match self {
+            EngineError { eng_error } => eng_error.code(),
+            ServerError::BadRequest => 301,
+            UnsupportedContract { .. } => 302,
+            ServerError::Timeout => 303,
+            ServerError::BadConfig { .. } => 304,
+            ServerError::Unauthorized => 305,
+            ServerError::Forbidden => 306,
+            ServerError::Overloaded { .. } => 307,
+        }
+    }
+
+    /// whether the same request may succeed when retried later.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            EngineError { eng_error } => eng_error.is_retryable(),
+            ServerError::Timeout | ServerError::Overloaded { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// the error response of this error, with its code and retryability.
+    pub fn to_response(&self) -> Response {
+        Response::Error {
+            code: self.code(),
+            retryable: self.is_retryable(),
+            reason: self.to_string(),
+        }
+    }
+}
+
 impl From<crate::KvError> for ServerError {
     fn from(err: KvError) -> Self {
-        EngineError { eng_error: err }
+        match err {
+            // the same as the deadline watched by the pool, see `KvServer::timeout`.
+            KvError::DeadlineExceeded { .. } => ServerError::Timeout,
+            err => EngineError { eng_error: err },
+        }
+    }
+}
+
+impl From<ConfigError> for ServerError {
+    fn from(config_error: ConfigError) -> Self {
+        ServerError::BadConfig { config_error }
     }
 }
 
 impl From<crate::contract::Error> for ServerError {
     fn from(contract_error: crate::contract::Error) -> Self {
-        UnsupportedContract { contract_error }
+        match contract_error {
+            crate::contract::Error::BadMessage { .. } => ServerError::BadRequest,
+            contract_error => UnsupportedContract { contract_error },
+        }
     }
 }
 
@@ -0,0 +1,125 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use core_affinity::CoreId;
+
+use crate::Result;
+use crate::thread_pool::hooks::TaskHooks;
+use crate::thread_pool::ThreadPool;
+
+/// The options of thread pools beyond the pool size.
+///
+/// # Example
+/// ```no_run
+/// # use kvs::thread_pool::*;
+/// # use log::debug;
+/// # fn main() -> kvs::Result<()> {
+/// let pool: SharedQueueThreadPool = ThreadPoolBuilder::new(4)
+///     .pin_to_cores(vec![0, 1])
+///     .before_task(|| debug!("task started"))
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ThreadPoolBuilder {
+    size: usize,
+    cores: Option<Arc<Vec<usize>>>,
+    hooks: TaskHooks,
+    keep_alive: Duration,
+}
+
+impl ThreadPoolBuilder {
+    /// the default of `keep_alive`.
+    pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+    /// create a builder of pool with specified size.
+    pub fn new(size: usize) -> Self {
+        ThreadPoolBuilder {
+            size,
+            cores: None,
+            hooks: TaskHooks::default(),
+            keep_alive: Self::DEFAULT_KEEP_ALIVE,
+        }
+    }
+
+    /// pin the workers to the cores, the worker with index `i` will run on `cores[i % cores.len()]`.
+    ///
+    /// On platforms that don't support thread affinity, this is a no-op.
+    pub fn pin_to_cores(mut self, cores: Vec<usize>) -> Self {
+        if !cores.is_empty() {
+            self.cores = Some(Arc::new(cores));
+        }
+        self
+    }
+
+    /// pin the workers to all the cores of this machine, one by one.
+    ///
+    /// On platforms that don't support thread affinity, this is a no-op.
+    pub fn pin_to_all_cores(self) -> Self {
+        let cores = core_affinity::get_core_ids()
+            .map(|ids| ids.into_iter().map(|core| core.id).collect())
+            .unwrap_or_default();
+        self.pin_to_cores(cores)
+    }
+
+    /// call `hook` on the worker right before every task runs,
+    /// e.g. to enter a tracing span or to set up some thread-local state.
+    ///
+    /// Calling it again replaces the previous hook.
+    pub fn before_task<F>(mut self, hook: F) -> Self
+        where
+            F: 'static + Send + Sync + Fn(),
+    {
+        self.hooks.before = Some(Arc::new(hook));
+        self
+    }
+
+    /// call `hook` on the worker right after every task runs, even if the task panics.
+    ///
+    /// Calling it again replaces the previous hook.
+    pub fn after_task<F>(mut self, hook: F) -> Self
+        where
+            F: 'static + Send + Sync + Fn(),
+    {
+        self.hooks.after = Some(Arc::new(hook));
+        self
+    }
+
+    /// how long an idle worker waits for a new task before exiting.
+    ///
+    /// Only the pools reaping idle workers, i.e. `CachedThreadPool`, respect this.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// the size of pool.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// build the thread pool.
+    pub fn build<P: ThreadPool>(self) -> Result<P> {
+        P::from_builder(self)
+    }
+
+    /// the keep-alive time of idle workers.
+    pub(crate) fn keep_alive_time(&self) -> Duration {
+        self.keep_alive
+    }
+
+    /// the hooks around every task.
+    pub(crate) fn hooks(&self) -> &TaskHooks {
+        &self.hooks
+    }
+
+    /// apply the options about the worker thread to the current thread.
+    pub(crate) fn setup_worker(&self, index: usize) {
+        if let Some(cores) = &self.cores {
+            core_affinity::set_for_current(CoreId {
+                id: cores[index % cores.len()],
+            });
+        }
+    }
+}
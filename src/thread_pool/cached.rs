@@ -0,0 +1,156 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+use crate::Result;
+
+use super::builder::ThreadPoolBuilder;
+use super::metrics::PoolMetrics;
+use super::pool::ThreadPool;
+use super::worker::{set_current_worker_index, worker_name};
+
+type Task = Box<dyn FnOnce() + 'static + Send>;
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Task>,
+    /// the workers waiting for a task.
+    idle: usize,
+    /// the workers alive, idle or not.
+    workers: usize,
+    /// the indexes released by reaped workers, reused before growing.
+    free_indexes: Vec<usize>,
+    next_index: usize,
+    shutdown: bool,
+}
+
+impl State {
+    fn acquire_index(&mut self) -> usize {
+        self.free_indexes.pop().unwrap_or_else(|| {
+            self.next_index += 1;
+            self.next_index - 1
+        })
+    }
+
+    fn retire(&mut self, index: usize) {
+        self.workers -= 1;
+        self.free_indexes.push(index);
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    task_arrived: Condvar,
+    metrics: PoolMetrics,
+    builder: ThreadPoolBuilder,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The cached thread pool, a `NaiveThreadPool` that reuses its threads.
+///
+/// A new worker is spawned only when there is no idle worker for the task,
+/// and an idle worker exits after waiting `ThreadPoolBuilder::keep_alive` for a new task.
+/// The size of the pool is the upper bound of the workers,
+/// the tasks spawned when all the workers are busy will wait in a queue.
+///
+/// A panicking task doesn't kill its worker.
+pub struct CachedThreadPool {
+    shared: Arc<Shared>,
+}
+
+impl CachedThreadPool {
+    /// the count of the workers alive, idle or not.
+    pub fn live_workers(&self) -> usize {
+        self.shared.lock().workers
+    }
+
+    fn recruit(&self, state: &mut State) {
+        let index = state.acquire_index();
+        state.workers += 1;
+        let shared = self.shared.clone();
+        thread::Builder::new()
+            .name(worker_name(index))
+            .spawn(move || {
+                set_current_worker_index(index);
+                shared.builder.setup_worker(index);
+                work(&shared, index);
+            })
+            .unwrap();
+    }
+}
+
+fn work(shared: &Shared, index: usize) {
+    let keep_alive = shared.builder.keep_alive_time();
+    let mut state = shared.lock();
+    loop {
+        if let Some(task) = state.queue.pop_front() {
+            drop(state);
+            // the panic has been counted by the metrics, and reported by the panic hook.
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                shared.builder.hooks().run(&shared.metrics, task)
+            }));
+            state = shared.lock();
+            continue;
+        }
+        if state.shutdown {
+            break;
+        }
+        state.idle += 1;
+        let (guard, wait) = shared
+            .task_arrived
+            .wait_timeout(state, keep_alive)
+            .unwrap_or_else(|e| e.into_inner());
+        state = guard;
+        state.idle -= 1;
+        if wait.timed_out() && state.queue.is_empty() {
+            break;
+        }
+    }
+    state.retire(index);
+}
+
+impl ThreadPool for CachedThreadPool {
+    fn spawn<R>(&self, runnable: R)
+        where
+            R: 'static + Send + FnOnce(),
+    {
+        self.shared.metrics.task_queued();
+        let mut state = self.shared.lock();
+        state.queue.push_back(Box::new(runnable));
+        if state.queue.len() > state.idle && state.workers < self.shared.builder.size() {
+            self.recruit(&mut state);
+        } else {
+            self.shared.task_arrived.notify_one();
+        }
+    }
+
+    fn from_builder(builder: ThreadPoolBuilder) -> Result<Self> {
+        Ok(CachedThreadPool {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State::default()),
+                task_arrived: Condvar::new(),
+                metrics: PoolMetrics::default(),
+                builder,
+            }),
+        })
+    }
+
+    fn metrics(&self) -> PoolMetrics {
+        self.shared.metrics.clone()
+    }
+}
+
+/// Dropping the pool lets the idle workers exit at once,
+/// the tasks queued or running will still be finished.
+impl Drop for CachedThreadPool {
+    fn drop(&mut self) {
+        self.shared.lock().shutdown = true;
+        self.shared.task_arrived.notify_all();
+    }
+}
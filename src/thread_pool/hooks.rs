@@ -0,0 +1,47 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::thread_pool::metrics::PoolMetrics;
+
+type Hook = Arc<dyn Fn() + Send + Sync>;
+
+/// The callbacks around every task executed by a pool.
+#[derive(Clone, Default)]
+pub(crate) struct TaskHooks {
+    pub(crate) before: Option<Hook>,
+    pub(crate) after: Option<Hook>,
+}
+
+struct AfterGuard<'a>(&'a Option<Hook>);
+
+impl Drop for AfterGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(after) = self.0 {
+            after()
+        }
+    }
+}
+
+impl TaskHooks {
+    /// run a queued task with the hooks on the current worker, and record it into the metrics.
+    ///
+    /// The `after` hook runs even if the task panics.
+    pub(crate) fn run<R: FnOnce()>(&self, metrics: &PoolMetrics, task: R) {
+        metrics.run(|| {
+            if let Some(before) = &self.before {
+                before()
+            }
+            let _after = AfterGuard(&self.after);
+            task()
+        })
+    }
+}
+
+impl fmt::Debug for TaskHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskHooks")
+            .field("before", &self.before.is_some())
+            .field("after", &self.after.is_some())
+            .finish()
+    }
+}
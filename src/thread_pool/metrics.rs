@@ -0,0 +1,122 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Default, Debug)]
+struct Counters {
+    queued: AtomicUsize,
+    busy_workers: AtomicUsize,
+    completed_tasks: AtomicU64,
+    panicked_tasks: AtomicU64,
+    idle: (Mutex<()>, Condvar),
+}
+
+/// The live counters of a thread pool.
+///
+/// It's cheap to `Clone` it, and all clones share the same counters,
+/// so that it can be sent to anywhere that needs to observe the pool, like the stats command of server.
+#[derive(Clone, Default, Debug)]
+pub struct PoolMetrics(Arc<Counters>);
+
+/// A point-in-time view of `PoolMetrics`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PoolMetricsSnapshot {
+    /// the tasks submitted but not yet started.
+    pub queued: usize,
+    /// the workers that are running some task.
+    pub busy_workers: usize,
+    /// the tasks finished normally.
+    pub completed_tasks: u64,
+    /// the tasks finished by panicking.
+    pub panicked_tasks: u64,
+}
+
+struct TaskGuard<'a>(&'a PoolMetrics);
+
+impl Drop for TaskGuard<'_> {
+    fn drop(&mut self) {
+        let counters = &(self.0).0;
+        counters.busy_workers.fetch_sub(1, Ordering::SeqCst);
+        if thread::panicking() {
+            counters.panicked_tasks.fetch_add(1, Ordering::SeqCst);
+        } else {
+            counters.completed_tasks.fetch_add(1, Ordering::SeqCst);
+        }
+        if self.0.is_idle() {
+            let _lock = counters.idle.0.lock().unwrap_or_else(|e| e.into_inner());
+            counters.idle.1.notify_all();
+        }
+    }
+}
+
+impl PoolMetrics {
+    /// the count of tasks submitted but not yet started.
+    pub fn queued(&self) -> usize {
+        self.0.queued.load(Ordering::SeqCst)
+    }
+
+    /// the count of workers that are running some task.
+    pub fn busy_workers(&self) -> usize {
+        self.0.busy_workers.load(Ordering::SeqCst)
+    }
+
+    /// the count of tasks finished normally.
+    pub fn completed_tasks(&self) -> u64 {
+        self.0.completed_tasks.load(Ordering::SeqCst)
+    }
+
+    /// the count of tasks finished by panicking.
+    pub fn panicked_tasks(&self) -> u64 {
+        self.0.panicked_tasks.load(Ordering::SeqCst)
+    }
+
+    /// whether there is no task queued or running.
+    pub fn is_idle(&self) -> bool {
+        // `run` increases `busy_workers` before decreasing `queued`,
+        // so loading in this order never sees a running task as neither queued nor busy.
+        self.queued() == 0 && self.busy_workers() == 0
+    }
+
+    /// block the current thread until there is no task queued or running.
+    pub fn wait_idle(&self) {
+        let idle = &self.0.idle;
+        let mut lock = idle.0.lock().unwrap_or_else(|e| e.into_inner());
+        while !self.is_idle() {
+            lock = idle.1.wait(lock).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// take a snapshot of all counters.
+    pub fn snapshot(&self) -> PoolMetricsSnapshot {
+        PoolMetricsSnapshot {
+            queued: self.queued(),
+            busy_workers: self.busy_workers(),
+            completed_tasks: self.completed_tasks(),
+            panicked_tasks: self.panicked_tasks(),
+        }
+    }
+
+    /// record that a task is submitted to the pool.
+    pub(crate) fn task_queued(&self) {
+        self.0.queued.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// record that a task submitted is dropped without running, like one spawned to a terminated pool.
+    pub(crate) fn task_rejected(&self) {
+        self.0.queued.fetch_sub(1, Ordering::SeqCst);
+        if self.is_idle() {
+            let _lock = self.0.idle.0.lock().unwrap_or_else(|e| e.into_inner());
+            self.0.idle.1.notify_all();
+        }
+    }
+
+    /// run a queued task on the current worker, and record its outcome, even it panics.
+    pub(crate) fn run<R: FnOnce()>(&self, task: R) {
+        self.0.busy_workers.fetch_add(1, Ordering::SeqCst);
+        self.0.queued.fetch_sub(1, Ordering::SeqCst);
+        let _guard = TaskGuard(self);
+        task();
+    }
+}
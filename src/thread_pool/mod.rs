@@ -1,10 +1,22 @@
+pub use builder::ThreadPoolBuilder;
+pub use cached::CachedThreadPool;
+pub use metrics::{PoolMetrics, PoolMetricsSnapshot};
 pub use pool::ThreadPool;
 pub use shared_queue::SharedQueueThreadPool;
 pub use trivial::NaiveThreadPool;
+pub use worker::current_worker_index;
 
 pub use self::rayon::RayonThreadPool;
+pub use self::tokio::TokioThreadPool;
 
+mod builder;
+mod cached;
+mod hooks;
+mod metrics;
 mod pool;
 mod rayon;
 mod shared_queue;
+mod tokio;
 mod trivial;
+mod watchdog;
+mod worker;
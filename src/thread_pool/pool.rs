@@ -1,4 +1,12 @@
-use crate::Result;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+
+use crate::{KvError, Result};
+use crate::benchmark_common::Promise;
+use crate::thread_pool::builder::ThreadPoolBuilder;
+use crate::thread_pool::metrics::PoolMetrics;
+use crate::thread_pool::watchdog;
 
 /// the common abstraction of a thread pool.
 pub trait ThreadPool: Sized {
@@ -7,5 +15,83 @@ pub trait ThreadPool: Sized {
         where
         R: 'static + Send + FnOnce();
     /// create an new thread pool with specified size.
-    fn new(size: usize) -> Result<Self>;
+    fn new(size: usize) -> Result<Self> {
+        Self::from_builder(ThreadPoolBuilder::new(size))
+    }
+    /// create an new thread pool with the options in the builder.
+    fn from_builder(builder: ThreadPoolBuilder) -> Result<Self>;
+    /// the live metrics of this pool, like queue length and busy workers.
+    fn metrics(&self) -> PoolMetrics;
+
+    /// block the current thread until the queue is empty and all workers are idle.
+    ///
+    /// Tasks spawned by other threads during waiting will also be waited.
+    fn wait_idle(&self) {
+        self.metrics().wait_idle()
+    }
+
+    /// like `spawn`, but returns a `Promise` of the task's result,
+    /// so that the caller can wait for the task and retrieve its value.
+    ///
+    /// # Error
+    ///
+    /// When the task panics, the promise will be fulfilled with `TaskPanicked`,
+    /// and the worker running it won't be poisoned.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use kvs::thread_pool::*;
+    /// # fn main() -> kvs::Result<()> {
+    /// let pool = SharedQueueThreadPool::new(4)?;
+    /// let promise = pool.spawn_with_result(|| 21 * 2);
+    /// assert_eq!(42, promise.get()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn spawn_with_result<F, T>(&self, f: F) -> Promise<Result<T>>
+        where
+            F: 'static + Send + FnOnce() -> T,
+            T: 'static + Send,
+    {
+        let promise = Promise::new();
+        self.spawn({
+            let promise = promise.clone();
+            move || {
+                let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+                    KvError::TaskPanicked {
+                        reason: panic_reason(payload.as_ref()),
+                    }
+                });
+                promise.fulfill(result);
+            }
+        });
+        promise
+    }
+
+    /// like `spawn`, but the task is watched by a watchdog:
+    /// if it isn't finished in `timeout` since it's spawned, the watchdog will log it,
+    /// and call `on_timeout` (on the watchdog thread), so the one waiting for it can be told.
+    ///
+    /// The task itself won't be interrupted, because there is no safe way to kill a thread.
+    fn spawn_with_timeout<R, T>(&self, timeout: Duration, runnable: R, on_timeout: T)
+        where
+            R: 'static + Send + FnOnce(),
+            T: 'static + Send + FnOnce(),
+    {
+        let handle = watchdog::watch(Instant::now() + timeout, Box::new(on_timeout));
+        self.spawn(move || {
+            let _handle = handle;
+            runnable();
+        })
+    }
+}
+
+fn panic_reason(payload: &(dyn Any + Send)) -> String {
+    if let Some(reason) = payload.downcast_ref::<&str>() {
+        (*reason).to_owned()
+    } else if let Some(reason) = payload.downcast_ref::<String>() {
+        reason.clone()
+    } else {
+        "unknown panic payload".to_owned()
+    }
 }
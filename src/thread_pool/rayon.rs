@@ -1,20 +1,82 @@
+use std::sync::Arc;
+
+use lazy_static::lazy_static;
 use rayon::ThreadPool;
 
 use crate::Result;
+use crate::thread_pool::builder::ThreadPoolBuilder;
+use crate::thread_pool::hooks::TaskHooks;
+use crate::thread_pool::metrics::PoolMetrics;
+use crate::thread_pool::worker::{set_current_worker_index, worker_name};
+
+lazy_static! {
+    static ref GLOBAL: Arc<ThreadPool> = Arc::new(
+        build_inner(&ThreadPoolBuilder::new(num_cpus::get()))
+            .expect("failed to build the global rayon thread pool")
+    );
+}
+
+fn build_inner(builder: &ThreadPoolBuilder) -> Result<ThreadPool> {
+    let builder = builder.clone();
+    Ok(rayon::ThreadPoolBuilder::new()
+        .num_threads(builder.size())
+        .thread_name(worker_name)
+        .start_handler(move |index| {
+            set_current_worker_index(index);
+            builder.setup_worker(index);
+        })
+        .build()?)
+}
 
 /// the `ThreadPool` implementation using the `rayon` thread pool.
-pub struct RayonThreadPool(ThreadPool);
+///
+/// `rayon` doesn't expose its queue, so the metrics are counted by wrapping every spawned task.
+/// When many `RayonThreadPool`s share one `rayon` pool, each of them only counts the tasks spawned by itself.
+pub struct RayonThreadPool {
+    inner: Arc<ThreadPool>,
+    metrics: PoolMetrics,
+    hooks: TaskHooks,
+}
+
+impl RayonThreadPool {
+    /// wrap an already-built `rayon` thread pool, so it can be shared with other users of it.
+    pub fn from_existing(inner: Arc<ThreadPool>) -> Self {
+        RayonThreadPool {
+            inner,
+            metrics: PoolMetrics::default(),
+            hooks: TaskHooks::default(),
+        }
+    }
+
+    /// a handle to the process-wide `rayon` thread pool, with one worker per CPU.
+    ///
+    /// The pool is built at the first call, and all handles share it,
+    /// so it's cheap to call this over and over, like in the benchmarks.
+    pub fn global() -> Self {
+        Self::from_existing(GLOBAL.clone())
+    }
+}
 
 impl crate::thread_pool::ThreadPool for RayonThreadPool {
     fn spawn<R>(&self, runnable: R)
         where
             R: 'static + Send + FnOnce(),
     {
-        self.0.spawn(runnable)
+        let metrics = self.metrics.clone();
+        let hooks = self.hooks.clone();
+        metrics.task_queued();
+        self.inner.spawn(move || hooks.run(&metrics, runnable))
+    }
+
+    fn from_builder(builder: ThreadPoolBuilder) -> Result<Self> {
+        let inner = Arc::new(build_inner(&builder)?);
+        Ok(RayonThreadPool {
+            hooks: builder.hooks().clone(),
+            ..Self::from_existing(inner)
+        })
     }
 
-    fn new(size: usize) -> Result<Self> {
-        let inner = rayon::ThreadPoolBuilder::new().num_threads(size).build()?;
-        Ok(RayonThreadPool(inner))
+    fn metrics(&self) -> PoolMetrics {
+        self.metrics.clone()
     }
 }
@@ -5,8 +5,11 @@ use crossbeam_channel::{Receiver, Sender, unbounded};
 use log::error;
 
 use crate::Result;
+use crate::thread_pool::builder::ThreadPoolBuilder;
+use crate::thread_pool::metrics::PoolMetrics;
 use crate::thread_pool::pool::ThreadPool;
 use crate::thread_pool::shared_queue::MasterMessage::TaskDone;
+use crate::thread_pool::worker::{set_current_worker_index, worker_name};
 
 struct Delayed(Option<Box<dyn FnOnce()>>);
 
@@ -37,7 +40,7 @@ enum MasterMessage {
     Terminate(Sender<()>),
     TaskDone(WorkerBroker),
     GracefulShutdown(Sender<()>),
-    Panicked,
+    Panicked(usize),
 }
 
 enum WorkerMessage {
@@ -73,10 +76,7 @@ impl PoolState {
     }
 
     fn is_terminating(&self) -> bool {
-        match self {
-            PoolState::Terminating { .. } | PoolState::GracefulShutdown => true,
-            _ => false,
-        }
+        matches!(self, PoolState::Terminating { .. } | PoolState::GracefulShutdown)
     }
 }
 
@@ -86,6 +86,8 @@ struct ThreadMaster {
     pool_size: usize,
     state: PoolState,
     terminate_hook: Option<Sender<()>>,
+    metrics: PoolMetrics,
+    builder: ThreadPoolBuilder,
 }
 
 #[derive(Clone)]
@@ -94,7 +96,10 @@ struct ThreadMaster {
 /// This thread pool uses two shared queues: (task)waiting queue and idle worker queue,
 /// every worker will send back a message when they finish their work, so that we can schedule a new work to it,
 /// or push it into idle worker queue.
-pub struct SharedQueueThreadPool(Sender<MasterMessage>);
+pub struct SharedQueueThreadPool {
+    master: Sender<MasterMessage>,
+    metrics: PoolMetrics,
+}
 
 impl SharedQueueThreadPool {
     /// Shutdown the pool asynchronously, all pending task won't be executed.
@@ -102,7 +107,7 @@ impl SharedQueueThreadPool {
     /// we will send the `Terminate` message to its worker.
     pub fn shutdown(&self) -> Receiver<()> {
         let (s, r) = unbounded();
-        self.0.send(MasterMessage::Terminate(s)).unwrap();
+        self.master.send(MasterMessage::Terminate(s)).unwrap();
         r
     }
 
@@ -111,7 +116,7 @@ impl SharedQueueThreadPool {
     /// When the waiting task queue becoming empty, our state will transform to `Terminating`.
     pub fn graceful_shutdown(&self) -> Receiver<()> {
         let (s, r) = unbounded();
-        self.0.send(MasterMessage::GracefulShutdown(s)).unwrap();
+        self.master.send(MasterMessage::GracefulShutdown(s)).unwrap();
         r
     }
 }
@@ -127,27 +132,38 @@ impl ThreadPool for SharedQueueThreadPool {
         where
             R: 'static + Send + FnOnce(),
     {
-        self.0
+        self.metrics.task_queued();
+        self.master
             .send(MasterMessage::NewTask(Box::new(runnable)))
             .unwrap();
     }
 
-    fn new(size: usize) -> Result<Self> {
-        Ok(ThreadMaster::new(size).start_work())
+    fn from_builder(builder: ThreadPoolBuilder) -> Result<Self> {
+        Ok(ThreadMaster::new(builder).start_work())
+    }
+
+    fn metrics(&self) -> PoolMetrics {
+        self.metrics.clone()
     }
 }
 
 impl ThreadMaster {
-    fn new(pool_size: usize) -> Self {
+    fn new(builder: ThreadPoolBuilder) -> Self {
         ThreadMaster {
             waiting: VecDeque::new(),
             idle_workers: VecDeque::new(),
             state: PoolState::Running,
             terminate_hook: None,
-            pool_size,
+            metrics: PoolMetrics::default(),
+            pool_size: builder.size(),
+            builder,
         }
     }
 
+    fn recruit(&self, this: Sender<MasterMessage>, index: usize) -> WorkerBroker {
+        WorkerBroker::new(this, self.metrics.clone(), self.builder.clone(), index)
+    }
+
     fn send_terminate(&mut self) {
         if let Some(hook) = self.terminate_hook.take() {
             hook.send(()).unwrap();
@@ -156,9 +172,12 @@ impl ThreadMaster {
 
     fn start_work(mut self) -> SharedQueueThreadPool {
         let (this, mail_box) = unbounded();
-        (0..self.pool_size)
-            .for_each(|_| self.idle_workers.push_back(WorkerBroker::new(this.clone())));
+        (0..self.pool_size).for_each(|index| {
+            let broker = self.recruit(this.clone(), index);
+            self.idle_workers.push_back(broker)
+        });
         let this2 = this.clone();
+        let metrics = self.metrics.clone();
         thread::Builder::new()
             .name("shared-queue-thread-pool-master".to_owned())
             .spawn(move || {
@@ -169,7 +188,10 @@ impl ThreadMaster {
                 }
             })
             .unwrap();
-        SharedQueueThreadPool(this)
+        SharedQueueThreadPool {
+            master: this,
+            metrics,
+        }
     }
 
     fn handle_message(&mut self, message: MasterMessage, this: Sender<MasterMessage>) -> bool {
@@ -178,6 +200,7 @@ impl ThreadMaster {
             NewTask(task) => {
                 if self.state.is_terminating() {
                     error!(target: "app::error", "Trying to spawn a work to a terminated executor.");
+                    self.metrics.task_rejected();
                     // 比起导致可能的线程泄漏，我们在此处选择更加稳妥的解决方案——继续等待直到它自己结束。
                     return false;
                 }
@@ -210,6 +233,10 @@ impl ThreadMaster {
                 }
 
                 self.state = PoolState::Terminating { ended_workers: 0 };
+                // the pending tasks won't be executed.
+                for _ in self.waiting.drain(..) {
+                    self.metrics.task_rejected();
+                }
                 while let Some(worker) = self.idle_workers.pop_front() {
                     worker.unsafe_terminate();
                     self.state.incr_ended_workers();
@@ -220,16 +247,16 @@ impl ThreadMaster {
                     return true;
                 }
             }
-            Panicked => {
+            Panicked(index) => {
                 if !self.state.is_terminating() {
-                    error!("One worker panicked, we are recruiting a new now!");
-                    let broker = WorkerBroker::new(this.clone());
+                    error!("The worker {} panicked, we are recruiting a new now!", worker_name(index));
+                    let broker = self.recruit(this.clone(), index);
                     self.new_broker(broker);
                 } else {
-                    error!("One worker panicked in the dying executor, what to do...?");
+                    error!("The worker {} panicked in the dying executor, what to do...?", worker_name(index));
                     match self.state {
                         PoolState::GracefulShutdown => {
-                            let broker = WorkerBroker::new(this.clone());
+                            let broker = self.recruit(this.clone(), index);
                             self.new_broker(broker);
                             if self.waiting.is_empty() {
                                 this.send(MasterMessage::Terminate(
@@ -288,17 +315,24 @@ impl ThreadMaster {
 }
 
 impl WorkerBroker {
-    fn new(master: Sender<MasterMessage>) -> Self {
+    fn new(
+        master: Sender<MasterMessage>,
+        metrics: PoolMetrics,
+        builder: ThreadPoolBuilder,
+        index: usize,
+    ) -> Self {
         let (s, r) = unbounded::<WorkerMessage>();
         let worker = WorkerBroker(s);
         let abroad_worker = worker.clone();
         thread::Builder::new()
-            .name("shared-queue-thread-pool-worker".to_owned())
+            .name(worker_name(index))
             .spawn(move || {
+                set_current_worker_index(index);
+                builder.setup_worker(index);
                 let master2 = master.clone();
                 delay! {
                     if thread::panicking() {
-                        master2.send(MasterMessage::Panicked).unwrap();
+                        master2.send(MasterMessage::Panicked(index)).unwrap();
                     }
                 }
                 // 这儿我们可以放心地让这个线程 Panic。
@@ -306,7 +340,7 @@ impl WorkerBroker {
                     let message = r.recv().unwrap();
                     match message {
                         WorkerMessage::RunTask(task) => {
-                            task();
+                            builder.hooks().run(&metrics, task);
                             master.send(TaskDone(abroad_worker.clone())).unwrap();
                         }
                         WorkerMessage::Terminate => break,
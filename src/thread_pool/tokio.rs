@@ -0,0 +1,80 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::runtime::{Builder, Runtime};
+
+use crate::Result;
+use crate::thread_pool::builder::ThreadPoolBuilder;
+use crate::thread_pool::hooks::TaskHooks;
+use crate::thread_pool::metrics::PoolMetrics;
+use crate::thread_pool::worker::set_current_worker_index;
+
+/// the `ThreadPool` implementation on the blocking pool of a `tokio` runtime.
+///
+/// The runtime can be shared with async code by `runtime` or `from_runtime`,
+/// so that the sync and async parts of the server can run on one configured executor.
+///
+/// `tokio` names all its threads the same, so the workers are named `kvs-tokio-worker`,
+/// but `current_worker_index` still tells them apart.
+pub struct TokioThreadPool {
+    runtime: Arc<Runtime>,
+    metrics: PoolMetrics,
+    hooks: TaskHooks,
+}
+
+impl TokioThreadPool {
+    /// wrap an already built runtime, its blocking pool will run the spawned tasks.
+    pub fn from_runtime(runtime: Arc<Runtime>) -> Self {
+        TokioThreadPool {
+            runtime,
+            metrics: PoolMetrics::default(),
+            hooks: TaskHooks::default(),
+        }
+    }
+
+    /// the runtime under this pool.
+    pub fn runtime(&self) -> &Arc<Runtime> {
+        &self.runtime
+    }
+}
+
+impl crate::thread_pool::ThreadPool for TokioThreadPool {
+    fn spawn<R>(&self, runnable: R)
+        where
+            R: 'static + Send + FnOnce(),
+    {
+        let metrics = self.metrics.clone();
+        let hooks = self.hooks.clone();
+        metrics.task_queued();
+        self.runtime
+            .handle()
+            .spawn_blocking(move || hooks.run(&metrics, runnable));
+    }
+
+    /// create a runtime with `num_cpus` core threads for async tasks,
+    /// and at most `size` threads in its blocking pool for the spawned tasks.
+    fn from_builder(builder: ThreadPoolBuilder) -> Result<Self> {
+        let core_threads = num_cpus::get();
+        let next_index = Arc::new(AtomicUsize::new(0));
+        let hooks = builder.hooks().clone();
+        let runtime = Builder::new()
+            .threaded_scheduler()
+            .core_threads(core_threads)
+            .max_threads(core_threads + builder.size())
+            .thread_name("kvs-tokio-worker")
+            .on_thread_start(move || {
+                let index = next_index.fetch_add(1, Ordering::SeqCst);
+                set_current_worker_index(index);
+                builder.setup_worker(index);
+            })
+            .build()?;
+        Ok(TokioThreadPool {
+            hooks,
+            ..Self::from_runtime(Arc::new(runtime))
+        })
+    }
+
+    fn metrics(&self) -> PoolMetrics {
+        self.metrics.clone()
+    }
+}
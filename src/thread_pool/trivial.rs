@@ -1,23 +1,53 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
 use crate::Result;
 
+use super::builder::ThreadPoolBuilder;
+use super::metrics::PoolMetrics;
 use super::pool::ThreadPool;
+use super::worker::{set_current_worker_index, worker_name};
 
 /// The naïve thread pool implementation.
 /// when `ThreadPool::spawn` called, it just simply spawn a new thread.
 /// and never reuse them.
 ///
 /// It's just a thread factory!
-pub struct NaiveThreadPool;
+/// Every thread gets a new worker index, so the index grows without bound.
+pub struct NaiveThreadPool {
+    metrics: PoolMetrics,
+    next_index: AtomicUsize,
+    builder: ThreadPoolBuilder,
+}
 
 impl ThreadPool for NaiveThreadPool {
     fn spawn<R>(&self, runnable: R)
         where
             R: 'static + Send + FnOnce(),
     {
-        std::thread::spawn(runnable);
+        let metrics = self.metrics.clone();
+        metrics.task_queued();
+        let index = self.next_index.fetch_add(1, Ordering::SeqCst);
+        let builder = self.builder.clone();
+        thread::Builder::new()
+            .name(worker_name(index))
+            .spawn(move || {
+                set_current_worker_index(index);
+                builder.setup_worker(index);
+                builder.hooks().run(&metrics, runnable)
+            })
+            .unwrap();
+    }
+
+    fn from_builder(builder: ThreadPoolBuilder) -> Result<Self> {
+        Ok(NaiveThreadPool {
+            metrics: PoolMetrics::default(),
+            next_index: AtomicUsize::new(0),
+            builder,
+        })
     }
 
-    fn new(_n: usize) -> Result<Self> {
-        Ok(NaiveThreadPool)
+    fn metrics(&self) -> PoolMetrics {
+        self.metrics.clone()
     }
 }
@@ -0,0 +1,101 @@
+use std::cmp::{Ordering as CmpOrdering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, unbounded};
+use lazy_static::lazy_static;
+use log::error;
+
+type Callback = Box<dyn FnOnce() + Send + 'static>;
+
+/// A task watched by the watchdog.
+struct Watch {
+    deadline: Instant,
+    done: Arc<AtomicBool>,
+    on_timeout: Callback,
+}
+
+impl PartialEq for Watch {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Watch {}
+
+impl PartialOrd for Watch {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Watch {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.deadline.cmp(&other.deadline)
+    }
+}
+
+lazy_static! {
+    static ref WATCHDOG: Mutex<Sender<Watch>> = Mutex::new(start_watchdog());
+}
+
+fn start_watchdog() -> Sender<Watch> {
+    let (s, r) = unbounded();
+    thread::Builder::new()
+        .name("kvs-watchdog".to_owned())
+        .spawn(move || watch_loop(r))
+        .unwrap();
+    s
+}
+
+fn watch_loop(mail_box: Receiver<Watch>) {
+    let mut watching = BinaryHeap::<Reverse<Watch>>::new();
+    loop {
+        let next = match watching.peek() {
+            Some(Reverse(watch)) => mail_box
+                .recv_timeout(watch.deadline.saturating_duration_since(Instant::now())),
+            None => mail_box.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match next {
+            Ok(watch) => watching.push(Reverse(watch)),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        let now = Instant::now();
+        while watching.peek().map(|Reverse(watch)| watch.deadline <= now).unwrap_or(false) {
+            let Reverse(watch) = watching.pop().unwrap();
+            if !watch.done.load(Ordering::SeqCst) {
+                error!(target: "app::error", "A task exceeded its deadline, it's still occupying a worker.");
+                (watch.on_timeout)();
+            }
+        }
+    }
+}
+
+/// The handle of a watched task, the task is considered done when it's dropped, even by panicking.
+pub(crate) struct WatchHandle(Arc<AtomicBool>);
+
+impl Drop for WatchHandle {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// watch a task, when it isn't done before the deadline, `on_timeout` will be called on the watchdog thread.
+pub(crate) fn watch(deadline: Instant, on_timeout: Callback) -> WatchHandle {
+    let done = Arc::new(AtomicBool::new(false));
+    let watch = Watch {
+        deadline,
+        done: done.clone(),
+        on_timeout,
+    };
+    WATCHDOG
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .send(watch)
+        .expect("the watchdog thread is dead.");
+    WatchHandle(done)
+}
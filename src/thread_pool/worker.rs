@@ -0,0 +1,33 @@
+use std::cell::Cell;
+
+thread_local! {
+    static WORKER_INDEX: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// the index of the pool worker running the current thread.
+///
+/// Returns `None` when the current thread isn't a worker of our thread pools.
+/// The index of a worker is also a part of its thread name, like `kvs-worker-3`.
+///
+/// # Example
+/// ```no_run
+/// # use kvs::thread_pool::*;
+/// # fn main() -> kvs::Result<()> {
+/// let pool = SharedQueueThreadPool::new(4)?;
+/// pool.spawn(|| println!("running at worker {:?}", current_worker_index()));
+/// # Ok(())
+/// # }
+/// ```
+pub fn current_worker_index() -> Option<usize> {
+    WORKER_INDEX.with(|index| index.get())
+}
+
+/// mark the current thread as the worker with the index.
+pub(crate) fn set_current_worker_index(index: usize) {
+    WORKER_INDEX.with(|cell| cell.set(Some(index)));
+}
+
+/// the thread name of the worker with the index.
+pub(crate) fn worker_name(index: usize) -> String {
+    format!("kvs-worker-{}", index)
+}
@@ -0,0 +1,103 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::{KvError, Result};
+
+/// the header of the enveloped values, followed by the name of the encoding, `:`, and the encoded bytes in base64.
+/// It starts with a control character, which the plain text values hardly start with.
+pub const ENVELOPE_HEADER: &str = "\u{1}kvs-enc:";
+
+/// A transform of the values applied by `KvsClient` before sending them, and reverted after reading them,
+/// like an encryption or a compression, added by `KvsClient::with_encoding`.
+///
+/// The encoded values are sent in envelopes naming their encodings, see `ENVELOPE_HEADER`,
+/// so the server and the other clients see them as opaque strings.
+///
+/// # Example
+/// ```rust
+/// # use kvs::value_encoding::ValueEncoding;
+/// /// flips the bits of the values, a stand-in for an encryption.
+/// #[derive(Debug)]
+/// struct Flip;
+///
+/// impl ValueEncoding for Flip {
+///     fn name(&self) -> &str {
+///         "flip"
+///     }
+///
+///     fn encode(&self, value: &[u8]) -> kvs::Result<Vec<u8>> {
+///         Ok(value.iter().map(|byte| !byte).collect())
+///     }
+///
+///     fn decode(&self, encoded: &[u8]) -> kvs::Result<Vec<u8>> {
+///         self.encode(encoded)
+///     }
+/// }
+/// ```
+pub trait ValueEncoding: Debug + Send + Sync {
+    /// the name in the envelopes of the values it encodes, which picks it to decode them, without `:`.
+    fn name(&self) -> &str;
+    /// encode the bytes of a value.
+    fn encode(&self, value: &[u8]) -> Result<Vec<u8>>;
+    /// decode the bytes encoded by `encode`.
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// The encodings of a `KvsClient`: the one encoding the values written, and the ones decoding the values read.
+///
+/// The values without envelopes are plain ones, like the ones written before adding any encoding,
+/// and they're read as they are, so that the plain and the encoded values coexist during a migration.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Encodings {
+    writing: Option<Arc<dyn ValueEncoding>>,
+    reading: Vec<Arc<dyn ValueEncoding>>,
+}
+
+impl Encodings {
+    /// encode the values written by `encoding`, and decode the ones of it read.
+    pub(crate) fn write_with(&mut self, encoding: Arc<dyn ValueEncoding>) {
+        self.writing = Some(encoding.clone());
+        self.read_with(encoding);
+    }
+
+    /// decode the values of `encoding` read, replacing the one of the same name.
+    pub(crate) fn read_with(&mut self, encoding: Arc<dyn ValueEncoding>) {
+        self.reading.retain(|known| known.name() != encoding.name());
+        self.reading.push(encoding);
+    }
+
+    /// whether the values written are sent as they are.
+    pub(crate) fn is_plain(&self) -> bool {
+        self.writing.is_none()
+    }
+
+    /// the value to send for `value`, in the envelope of the encoding of the writes if any.
+    pub(crate) fn encode(&self, value: String) -> Result<String> {
+        match &self.writing {
+            Some(encoding) => {
+                let encoded = encoding.encode(value.as_bytes())?;
+                Ok(format!("{}{}:{}", ENVELOPE_HEADER, encoding.name(), base64::encode(encoded.as_slice())))
+            }
+            None => Ok(value),
+        }
+    }
+
+    /// the value read as `value`, decoded by the encoding named in its envelope if it has one.
+    ///
+    /// # Error
+    ///
+    /// when the encoding isn't known, or the envelope is malformed, it throws.
+    pub(crate) fn decode(&self, value: String) -> Result<String> {
+        let enveloped = match value.strip_prefix(ENVELOPE_HEADER) {
+            Some(enveloped) => enveloped,
+            None => return Ok(value),
+        };
+        let malformed = |reason: String| KvError::Other { reason: format!("malformed encoded value: {}", reason) };
+        let (name, payload) = enveloped.split_once(':').ok_or_else(|| malformed("no encoding named".to_owned()))?;
+        let encoding = self.reading.iter().find(|encoding| encoding.name() == name).ok_or_else(|| KvError::Other {
+            reason: format!("no encoding {:?} to decode the value.", name),
+        })?;
+        let encoded = base64::decode(payload).map_err(|err| malformed(err.to_string()))?;
+        String::from_utf8(encoding.decode(encoded.as_slice())?).map_err(|err| malformed(err.to_string()))
+    }
+}
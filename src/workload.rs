@@ -0,0 +1,297 @@
+use std::str::FromStr;
+
+use rand::{Rng, SeedableRng};
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use structopt::StructOpt;
+use thiserror::Error;
+
+use crate::{KvsEngine, Result};
+
+/// How the keys of reads and updates are chosen among the loaded records.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub enum KeyDistribution {
+    /// every record is equally likely to be chosen.
+    Uniform,
+    /// a few records are far more likely to be chosen than the others, like YCSB's default.
+    #[default]
+    Zipfian,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Error)]
+#[error("No such key distribution: {0}")]
+/// Throws when we cannot parse the command line to a key distribution.
+pub struct NoSuchKeyDistribution(String);
+
+impl FromStr for KeyDistribution {
+    type Err = NoSuchKeyDistribution;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "uniform" => Ok(KeyDistribution::Uniform),
+            "zipfian" => Ok(KeyDistribution::Zipfian),
+            _ => Err(NoSuchKeyDistribution(s.to_owned())),
+        }
+    }
+}
+
+impl AsRef<str> for KeyDistribution {
+    fn as_ref(&self) -> &str {
+        match self {
+            KeyDistribution::Uniform => "uniform",
+            KeyDistribution::Zipfian => "zipfian",
+        }
+    }
+}
+
+/// A YCSB-style workload: load some records, then run a mix of operations on them.
+///
+/// The operations are generated from `seed`, so the same workload gives the same operations,
+/// and the results of different engines or pools are comparable.
+///
+/// # Example
+/// ```no_run
+/// # use kvs::KvStore;
+/// # use kvs::workload::Workload;
+/// # fn main() -> kvs::Result<()> {
+/// let store = KvStore::open(std::env::current_dir()?)?;
+/// let workload = Workload::default();
+/// for operation in workload.load().chain(workload.operations()) {
+///     operation.apply(&store)?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, StructOpt)]
+pub struct Workload {
+    /// the number of records to load before running the operations.
+    #[structopt(long = "--records", default_value = "1000")]
+    pub record_count: usize,
+    /// the number of operations to run.
+    #[structopt(long = "--operations", default_value = "1000")]
+    pub operation_count: usize,
+    /// the proportion of reads in the operations.
+    #[structopt(long = "--read-proportion", default_value = "0.5")]
+    pub read_proportion: f64,
+    /// the proportion of updates in the operations.
+    #[structopt(long = "--update-proportion", default_value = "0.5")]
+    pub update_proportion: f64,
+    /// the proportion of inserts of new records in the operations.
+    /// The proportions are normalized, so they don't need to sum to 1.
+    #[structopt(long = "--insert-proportion", default_value = "0")]
+    pub insert_proportion: f64,
+    /// how the keys of reads and updates are chosen, `uniform` or `zipfian`.
+    #[structopt(long = "--distribution", default_value = "zipfian", parse(try_from_str = str::parse))]
+    pub distribution: KeyDistribution,
+    /// the length of the values written.
+    #[structopt(long = "--value-size", default_value = "100")]
+    pub value_size: usize,
+    /// the seed of the random operations.
+    #[structopt(long = "--seed", default_value = "0")]
+    pub seed: u64,
+}
+
+impl Default for Workload {
+    /// like the YCSB workload A: half reads and half updates, in zipfian distribution.
+    fn default() -> Self {
+        Workload {
+            record_count: 1000,
+            operation_count: 1000,
+            read_proportion: 0.5,
+            update_proportion: 0.5,
+            insert_proportion: 0.0,
+            distribution: KeyDistribution::Zipfian,
+            value_size: 100,
+            seed: 0,
+        }
+    }
+}
+
+/// An operation of a workload.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Operation {
+    /// get a loaded record.
+    Read {
+        /// the key to get.
+        key: String,
+    },
+    /// overwrite a loaded record.
+    Update {
+        /// the key to set.
+        key: String,
+        /// the new value.
+        value: String,
+    },
+    /// set a new record.
+    Insert {
+        /// the key to set.
+        key: String,
+        /// the value of the record.
+        value: String,
+    },
+}
+
+impl Operation {
+    /// the bytes of the keys and values this operation writes, `0` for reads.
+    pub fn logical_bytes(&self) -> u64 {
+        match self {
+            Operation::Read { .. } => 0,
+            Operation::Update { key, value } | Operation::Insert { key, value } => (key.len() + value.len()) as u64,
+        }
+    }
+
+    /// perform this operation on `engine`.
+    /// A read of an absent key isn't an error, since the records may be loaded partially.
+    pub fn apply(&self, engine: &impl KvsEngine) -> Result<()> {
+        match self {
+            Operation::Read { key } => engine.get(key.to_owned()).map(|_| ()),
+            Operation::Update { key, value } | Operation::Insert { key, value } => {
+                engine.set(key.to_owned(), value.to_owned())
+            }
+        }
+    }
+}
+
+/// the key of the `n`th record.
+pub fn key_of(n: usize) -> String {
+    format!("Key{}", n)
+}
+
+impl Workload {
+    /// the inserts of the records to load.
+    pub fn load(&self) -> impl Iterator<Item=Operation> {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let value_size = self.value_size;
+        (0..self.record_count).map(move |n| Operation::Insert {
+            key: key_of(n),
+            value: random_value(&mut rng, value_size),
+        })
+    }
+
+    /// the operations to run after loading.
+    ///
+    /// Reads and updates choose among the loaded records,
+    /// and inserts add records after them.
+    pub fn operations(&self) -> Operations {
+        Operations {
+            rng: StdRng::seed_from_u64(self.seed.wrapping_add(1)),
+            keys: match self.distribution {
+                KeyDistribution::Uniform => KeyChooser::Uniform(self.record_count),
+                KeyDistribution::Zipfian => KeyChooser::Zipfian(Zipfian::new(self.record_count)),
+            },
+            workload: self.clone(),
+            generated: 0,
+            inserted: 0,
+        }
+    }
+}
+
+fn random_value(rng: &mut impl Rng, size: usize) -> String {
+    rng.sample_iter(&Alphanumeric).take(size).collect()
+}
+
+enum KeyChooser {
+    Uniform(usize),
+    Zipfian(Zipfian),
+}
+
+impl KeyChooser {
+    fn next(&self, rng: &mut impl Rng) -> usize {
+        match self {
+            KeyChooser::Uniform(0) => 0,
+            KeyChooser::Uniform(n) => rng.gen_range(0, *n),
+            KeyChooser::Zipfian(zipfian) => zipfian.next(rng),
+        }
+    }
+}
+
+/// The zipfian generator of YCSB, by "Quickly Generating Billion-Record Synthetic Databases", Gray et al.
+struct Zipfian {
+    items: usize,
+    theta: f64,
+    zeta_n: f64,
+    alpha: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    const THETA: f64 = 0.99;
+
+    fn zeta(n: usize, theta: f64) -> f64 {
+        (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum()
+    }
+
+    fn new(items: usize) -> Self {
+        let theta = Self::THETA;
+        let zeta_n = Self::zeta(items, theta);
+        let zeta_2 = Self::zeta(2, theta);
+        Zipfian {
+            items,
+            theta,
+            zeta_n,
+            alpha: 1.0 / (1.0 - theta),
+            eta: (1.0 - (2.0 / items as f64).powf(1.0 - theta)) / (1.0 - zeta_2 / zeta_n),
+        }
+    }
+
+    fn next(&self, rng: &mut impl Rng) -> usize {
+        if self.items < 2 {
+            return 0;
+        }
+        let u: f64 = rng.gen();
+        let uz = u * self.zeta_n;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1;
+        }
+        let n = (self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as usize;
+        n.min(self.items - 1)
+    }
+}
+
+/// The operations of a workload, see `Workload::operations`.
+pub struct Operations {
+    rng: StdRng,
+    keys: KeyChooser,
+    workload: Workload,
+    generated: usize,
+    inserted: usize,
+}
+
+impl Iterator for Operations {
+    type Item = Operation;
+
+    fn next(&mut self) -> Option<Operation> {
+        if self.generated >= self.workload.operation_count {
+            return None;
+        }
+        self.generated += 1;
+        let w = &self.workload;
+        let total = w.read_proportion + w.update_proportion + w.insert_proportion;
+        let dice = self.rng.gen::<f64>() * total;
+        let operation = if dice < w.read_proportion {
+            Operation::Read {
+                key: key_of(self.keys.next(&mut self.rng)),
+            }
+        } else if dice < w.read_proportion + w.update_proportion {
+            Operation::Update {
+                key: key_of(self.keys.next(&mut self.rng)),
+                value: random_value(&mut self.rng, w.value_size),
+            }
+        } else {
+            self.inserted += 1;
+            Operation::Insert {
+                key: key_of(w.record_count + self.inserted - 1),
+                value: random_value(&mut self.rng, w.value_size),
+            }
+        };
+        Some(operation)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.workload.operation_count - self.generated;
+        (remaining, Some(remaining))
+    }
+}
@@ -0,0 +1,116 @@
+use std::thread;
+use std::time::Duration;
+
+use tempfile::TempDir;
+
+use kvs::{KvsEngine, KvStore, Result};
+use kvs::checker::{Call, Event, History, Outcome, Recorder};
+use kvs::engines::sled::SledEngine;
+
+fn event(call: Call, outcome: Outcome, start: u64, end: u64) -> Event {
+    Event {
+        call,
+        outcome,
+        start: Duration::from_millis(start),
+        end: Duration::from_millis(end),
+    }
+}
+
+fn set(value: &str) -> Call {
+    Call::Set {
+        key: "key".to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+fn get() -> Call {
+    Call::Get { key: "key".to_owned() }
+}
+
+fn read(value: &str) -> Outcome {
+    Outcome::Value(Some(value.to_owned()))
+}
+
+#[test]
+fn check_handmade_histories() {
+    // the read overlaps both writes, so it may see either of them.
+    let concurrent = History {
+        events: vec![
+            event(set("1"), Outcome::Done, 0, 10),
+            event(set("2"), Outcome::Done, 5, 20),
+            event(get(), read("1"), 8, 30),
+            event(get(), read("2"), 31, 40),
+        ],
+    };
+    assert!(concurrent.check().is_ok());
+
+    // the second write finished before the read started, so the read is stale.
+    let stale = History {
+        events: vec![
+            event(set("1"), Outcome::Done, 0, 10),
+            event(set("2"), Outcome::Done, 11, 20),
+            event(get(), read("1"), 21, 30),
+        ],
+    };
+    let violation = stale.check().unwrap_err();
+    assert_eq!(violation.key, "key");
+    assert_eq!(violation.events.len(), 3);
+
+    // a failed write may take effect or not.
+    let unknown = History {
+        events: vec![
+            event(set("1"), Outcome::Done, 0, 10),
+            event(set("2"), Outcome::Unknown, 11, 20),
+            event(get(), read("2"), 21, 30),
+            event(Call::Remove { key: "key".to_owned() }, Outcome::Done, 31, 40),
+            event(get(), Outcome::Value(None), 41, 50),
+            event(Call::Remove { key: "key".to_owned() }, Outcome::NotFound, 51, 60),
+        ],
+    };
+    assert!(unknown.check().is_ok());
+
+    let removed_twice = History {
+        events: vec![
+            event(set("1"), Outcome::Done, 0, 10),
+            event(Call::Remove { key: "key".to_owned() }, Outcome::Done, 11, 20),
+            event(Call::Remove { key: "key".to_owned() }, Outcome::Done, 21, 30),
+        ],
+    };
+    assert!(removed_twice.check().is_err());
+}
+
+fn concurrent_set_get(engine: impl KvsEngine) -> Result<()> {
+    let recorder = Recorder::new(engine);
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let recorder = recorder.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..10 {
+                    let key = format!("key{}", i % 3);
+                    recorder.set(key.clone(), format!("value{}-{}", t, i))?;
+                    recorder.get(key)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let history = recorder.history();
+    assert_eq!(history.events.len(), 80);
+    history.check().unwrap();
+    Ok(())
+}
+
+#[test]
+fn kvstore_is_linearizable() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_set_get(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_is_linearizable() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_set_get(SledEngine::open(temp_dir.path())?)
+}
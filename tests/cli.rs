@@ -1,10 +1,13 @@
 use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::TcpListener;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 
 use assert_cmd::prelude::*;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use tempfile::TempDir;
 
@@ -21,28 +24,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -53,35 +56,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -92,28 +95,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -124,18 +127,70 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 }
 
+// `kvs-client` should exit with code 3 when the server isn't reachable.
+#[test]
+fn client_cli_connection_error() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr", "127.0.0.1:4019"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(3);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key", "value", "--quiet", "--addr", "127.0.0.1:4019"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(3)
+        .stderr(is_empty());
+}
+
+// `kvs-client` should exit with code 3 when the response is cut short.
+#[test]
+fn client_cli_truncated_response() {
+    let temp_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let _ = stream.read_to_end(&mut Vec::new());
+            let _ = stream.write_all(b"{\"status\":\"cont");
+        }
+    });
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr", addr.as_str()])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(3)
+        .stderr(contains("malformed response"));
+
+    // the requests sent by `KvsClient` too.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--base64", "--addr", addr.as_str()])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(3)
+        .stderr(contains("malformed response"));
+}
+
 // `kvs-client -V` should print the version
 #[test]
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -146,7 +201,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -158,13 +213,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -172,6 +228,275 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+#[test]
+fn cli_json_log_format() {
+    let temp_dir = TempDir::new().unwrap();
+    let stdout_path = temp_dir.path().join("stdout");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--addr", "127.0.0.1:4020", "--log-format", "json"])
+        .current_dir(&temp_dir)
+        .stdout(File::create(&stdout_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4020"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(200));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let content = fs::read_to_string(&stdout_path).expect("unable to read from stdout file");
+    let lines: Vec<serde_json::Value> = content
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("every line should be a JSON object"))
+        .collect();
+    let done = lines
+        .iter()
+        .find(|line| line["target"] == "app::request" && line["mdc"]["latency_us"].is_string())
+        .expect("the finished request should be logged");
+    assert_eq!(done["mdc"]["request_id"], "0");
+    assert!(done["mdc"]["peer"].as_str().unwrap().starts_with("127.0.0.1:"));
+}
+
+#[test]
+fn cli_log_level() {
+    let temp_dir = TempDir::new().unwrap();
+    let stdout_path = temp_dir.path().join("stdout");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--addr", "127.0.0.1:4021", "--log-level", "warn"])
+        .current_dir(&temp_dir)
+        .stdout(File::create(&stdout_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4021", "--log-level", "debug"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty())
+        .stderr(contains("sending"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4021"])
+        .env("RUST_LOG", "nonsense")
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1);
+    thread::sleep(Duration::from_millis(200));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let content = fs::read_to_string(&stdout_path).expect("unable to read from stdout file");
+    assert!(!content.contains("handling request"));
+}
+
+#[test]
+fn cli_bench() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--addr", "127.0.0.1:4022"])
+        .env("KV_DISABLE_LOG", "1")
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "bench", "--addr", "127.0.0.1:4022", "--records", "20", "--operations", "50",
+            "--distribution", "uniform", "--insert-proportion", "0.1",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("ops_per_sec").and(contains("\"errors\":0")));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--addr", "127.0.0.1:4023"])
+        .env("KV_DISABLE_LOG", "1")
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    for key in ["user:1", "user:2", "admin:1"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, "value", "--addr", "127.0.0.1:4023"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["keys", "user:*", "--addr", "127.0.0.1:4023"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:1\nuser:2\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["keys", "*", "--reverse", "--limit", "2", "--addr", "127.0.0.1:4023"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:2\nuser:1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["keys", "*", "--reverse", "--after", "user:1", "--addr", "127.0.0.1:4023"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("admin:1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-client set --value-file` and `get --output-file` should keep binary and multi-line values as they're
+#[test]
+fn cli_binary_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--addr", "127.0.0.1:4027"])
+        .env("KV_DISABLE_LOG", "1")
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let binary = [0u8, 255, 10, 13, 0xc3];
+    fs::write(temp_dir.path().join("binary"), binary).unwrap();
+    fs::write(temp_dir.path().join("lines"), "line 1\n  line 2\n").unwrap();
+    for (key, file) in [("binary", "binary"), ("lines", "lines")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, "--value-file", file, "--addr", "127.0.0.1:4027"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", key, "--output-file", "out", "--addr", "127.0.0.1:4027"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+        assert_eq!(fs::read(temp_dir.path().join("out")).unwrap(), fs::read(temp_dir.path().join(file)).unwrap());
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "binary", "--base64", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("AP8KDcM=\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "copied", "AP8KDcM=", "--base64", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "copied", "--output-file", "out", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert_eq!(fs::read(temp_dir.path().join("out")).unwrap(), binary);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "bad", "not base64!", "--base64", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "absent", "--value-file", "absent", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "absent", "--output-file", "out", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2);
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_undelete() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--addr", "127.0.0.1:4024", "--soft-delete"])
+        .env("KV_DISABLE_LOG", "1")
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", "127.0.0.1:4024"])
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    client(&["set", "key1", "value1"]).success();
+    client(&["rm", "key1"]).success();
+    client(&["undelete", "key1"]).success();
+    client(&["get", "key1"]).success().stdout("value1\n");
+    client(&["undelete", "key2"]).code(2);
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_conditional_set() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--addr", "127.0.0.1:4025"])
+        .env("KV_DISABLE_LOG", "1")
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", "127.0.0.1:4025"])
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    client(&["set", "key1", "value1", "--xx"]).code(5).stdout("Condition not met\n");
+    client(&["set", "key1", "value1", "--nx"]).success().stdout(is_empty());
+    client(&["set", "key1", "value2", "--nx", "-q"]).code(5).stdout(is_empty());
+    client(&["set", "key1", "value3", "--xx"]).success();
+    client(&["get", "key1"]).success().stdout("value3\n");
+    client(&["set", "key1", "value4", "--nx", "--xx"]).code(1);
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -179,15 +504,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -198,15 +524,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -218,19 +545,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -238,7 +566,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -246,7 +574,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -254,7 +582,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -262,23 +590,47 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key1", "--quiet", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
         .stdout(contains("Key not found"));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["get", "key2", "--quiet", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
+        .code(2)
         .stderr(contains("Key not found"));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["rm", "key2", "-q", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stderr(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -286,12 +638,65 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "tmp:1", "value", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm-prefix", "tmp:", "--dry-run", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"count\":1,\"sample\":[\"tmp:1\"]}\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "tmp:2", "--dry-run", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm-prefix", "tmp:", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "visits", "--by", "-3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("completed_tasks").and(contains("bytes_written")));
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -299,29 +704,30 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .success()
+        .code(2)
         .stdout(contains("Key not found"));
     sender.send(()).unwrap();
     handle.join().unwrap();
@@ -336,3 +742,64 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `kvs-admin` works on the data directory of a stopped server, and reports corruption by the exit code.
+#[test]
+fn cli_admin() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = kvs::KvStore::open(temp_dir.path()).unwrap();
+        kvs::KvsEngine::set(&store, "key1".to_owned(), "value1".to_owned()).unwrap();
+        kvs::KvsEngine::set(&store, "key1".to_owned(), "value2".to_owned()).unwrap();
+        kvs::KvsEngine::remove(&store, "key1".to_owned()).unwrap();
+        kvs::KvsEngine::set(&store, "key2".to_owned(), "value3".to_owned()).unwrap();
+    }
+    let admin = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-admin").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+
+    admin(&["verify"]).assert().success().stdout(contains("4 records, ok"));
+    admin(&["dump"])
+        .assert()
+        .success()
+        .stdout(contains("set \"key1\" = \"value2\"").and(contains("rm \"key1\"")));
+    admin(&["stats"])
+        .assert()
+        .success()
+        .stdout(contains("records: 4 (2 garbage)").and(contains("keys: 1 live, 1 removed")));
+
+    // a torn record by a crash.
+    let data = fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.file_name().unwrap().to_string_lossy().starts_with("kvs-data-"))
+        .unwrap();
+    fs::OpenOptions::new().append(true).open(&data).unwrap().write_all(b"{\"Put\":").unwrap();
+    admin(&["verify"]).assert().code(2).stdout(contains("unreadable"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--verify-on-start", "refuse", "--addr", "127.0.0.1:4026"])
+        .env("KV_DISABLE_LOG", "1")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    admin(&["truncate"]).assert().success().stdout(contains("cut 7 bytes"));
+    admin(&["verify"]).assert().success();
+
+    // the compacted file, and the empty one for the later writes.
+    admin(&["compact"]).assert().success();
+    admin(&["stats"])
+        .assert()
+        .success()
+        .stdout(contains("records: 2 (0 garbage)").and(contains("data files: 2 ")));
+
+    let other_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify", "--path"])
+        .arg(other_dir.path())
+        .assert()
+        .code(3);
+}
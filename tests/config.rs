@@ -0,0 +1,141 @@
+use std::path::PathBuf;
+
+use log::{Level, LevelFilter, Record};
+use tempfile::TempDir;
+
+use kvs::config::log4rs::{file_appender, LogFilter};
+use kvs::config::server::{ConfigError, LogFileConfig, Role, ServerConfig, UserConfig};
+use kvs::engines::codec::CodecKind;
+use kvs::engines::kvs::{IndexKind, SyncPolicy, WritePath};
+use kvs::engines::sled::{SledMode, SledOptions};
+use kvs::server_common::{LogFormat, VerifyMode};
+
+#[test]
+fn parse_server_config() {
+    let config = ServerConfig::from_toml(
+        r#"
+        [log.file]
+        path = "logs/kvs.log"
+        max_size = 1024
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        config.log.file,
+        Some(LogFileConfig {
+            path: PathBuf::from("logs/kvs.log"),
+            max_size: Some(1024),
+            rotate_every_secs: None,
+            keep: 7,
+        })
+    );
+
+    assert!(!config.engine.soft_delete);
+    let config = ServerConfig::from_toml("[engine]\nsoft_delete = true").unwrap();
+    assert!(config.engine.soft_delete);
+    assert_eq!(config.engine.blob_threshold, None);
+    let config = ServerConfig::from_toml("[engine]\nblob_threshold = 4096").unwrap();
+    assert_eq!(config.engine.blob_threshold, Some(4096));
+    assert_eq!(config.engine.index, None);
+    let config = ServerConfig::from_toml("[engine]\nindex = \"ordered\"").unwrap();
+    assert_eq!(config.engine.index, Some(IndexKind::Ordered));
+    assert!(ServerConfig::from_toml("[engine]\nindex = \"skiplist\"").is_err());
+    assert_eq!(config.engine.codec, None);
+    let config = ServerConfig::from_toml("[engine]\ncodec = \"bincode\"").unwrap();
+    assert_eq!(config.engine.codec, Some(CodecKind::Bincode));
+    let config = ServerConfig::from_toml("[engine]\nrecord_cache = 4096").unwrap();
+    assert_eq!(config.engine.record_cache, Some(4096));
+    assert_eq!(ServerConfig::default().engine.sync, SyncPolicy::Never);
+    let config = ServerConfig::from_toml("[engine]\nsync = \"always\"").unwrap();
+    assert_eq!(config.engine.sync, SyncPolicy::Always);
+    assert_eq!(ServerConfig::default().engine.write_path, WritePath::Buffered);
+    let config = ServerConfig::from_toml("[engine]\nwrite_path = \"vectored\"").unwrap();
+    assert_eq!(config.engine.write_path, WritePath::Vectored);
+    let config = ServerConfig::from_toml("[engine]\nindex_budget = 1048576").unwrap();
+    assert_eq!(config.engine.index_budget, Some(1048576));
+    assert_eq!(ServerConfig::default().engine.verify_on_start, VerifyMode::Off);
+    let config = ServerConfig::from_toml("[engine]\nverify_on_start = \"refuse\"").unwrap();
+    assert_eq!(config.engine.verify_on_start, VerifyMode::Refuse);
+    let config = ServerConfig::from_toml("[admin]\naddr = \"127.0.0.1:4001\"").unwrap();
+    assert_eq!(config.admin.addr, Some("127.0.0.1:4001".parse().unwrap()));
+    let config = ServerConfig::from_toml("[engine]\nindex_warning = 524288").unwrap();
+    assert_eq!(config.engine.index_warning, Some(524288));
+    assert!(config.users.is_empty());
+    let config = ServerConfig::from_toml("[users.alice]\nsecret = \"s3cret\"\nprefixes = [\"tenant:a:\"]").unwrap();
+    assert_eq!(
+        config.users.get("alice"),
+        Some(&UserConfig { secret: "s3cret".to_owned(), role: Role::Read, prefixes: vec!["tenant:a:".to_owned()] })
+    );
+    assert!(ServerConfig::from_toml("[users.alice]\nsecret = \"s3cret\"\nrole = \"root\"").is_err());
+    let config = ServerConfig::from_toml("[engine]\nslow_log_ms = 50").unwrap();
+    assert_eq!(config.engine.slow_log_ms, Some(50));
+    let config = ServerConfig::from_toml("[engine]\nhot_tier = 65536").unwrap();
+    assert_eq!(config.engine.hot_tier, Some(65536));
+    assert_eq!(config.engine.sled, SledOptions::default());
+    let config = ServerConfig::from_toml("[engine.sled]\ncache_capacity = 4096\nflush_every_ms = 100\nmode = \"fast\"").unwrap();
+    assert_eq!(
+        config.engine.sled,
+        SledOptions { cache_capacity: Some(4096), flush_every_ms: Some(100), mode: SledMode::Fast }
+    );
+    assert!(ServerConfig::from_toml("[engine.sled]\nmode = \"tiny\"").is_err());
+    assert_eq!(config.admin.token, None);
+    let config = ServerConfig::from_toml("[admin]\ntoken = \"secret\"").unwrap();
+    assert_eq!(config.admin.token.as_deref(), Some("secret"));
+    assert_eq!(config.connection.read_timeout_ms, None);
+    let config = ServerConfig::from_toml("[connection]\nread_timeout_ms = 30000").unwrap();
+    assert_eq!(config.connection.read_timeout_ms, Some(30000));
+
+    assert_eq!(ServerConfig::from_toml("").unwrap(), ServerConfig::default());
+    match ServerConfig::from_toml("[log]\nfiles = 1") {
+        Err(ConfigError::Parse(_)) => (),
+        other => panic!("unknown fields should be rejected, but got {:?}", other),
+    }
+}
+
+#[test]
+fn rotate_log_file_by_size() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.log");
+    let appender = file_appender(
+        &LogFileConfig {
+            path: path.clone(),
+            max_size: Some(200),
+            rotate_every_secs: None,
+            keep: 2,
+        },
+        LogFormat::Text,
+    )
+    .unwrap();
+    for i in 0..100 {
+        appender
+            .append(
+                &Record::builder()
+                    .level(Level::Info)
+                    .target("app::request")
+                    .args(format_args!("the record {}", i))
+                    .build(),
+            )
+            .unwrap();
+    }
+    appender.flush();
+
+    let rotated = |n: u32| PathBuf::from(format!("{}.{}", path.display(), n));
+    assert!(path.exists());
+    assert!(rotated(0).exists());
+    assert!(rotated(1).exists());
+    assert!(!rotated(2).exists());
+    assert!(std::fs::metadata(&path).unwrap().len() <= 200);
+}
+
+#[test]
+fn parse_log_filter() {
+    let filter: LogFilter = "debug, kvs::engines=trace ,app::request=off".parse().unwrap();
+    assert_eq!(filter.default_level(), LevelFilter::Debug);
+    assert_eq!(filter.level_of("kvs::engines"), Some(LevelFilter::Trace));
+    assert_eq!(filter.level_of("app::request"), Some(LevelFilter::Off));
+    assert_eq!(filter.level_of("app::error"), None);
+
+    assert_eq!("".parse::<LogFilter>().unwrap(), LogFilter::default());
+    assert!("loud".parse::<LogFilter>().is_err());
+    assert!("kvs=loud".parse::<LogFilter>().is_err());
+}
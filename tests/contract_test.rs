@@ -1,12 +1,215 @@
-use std::io;
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Duration;
 
-use kvs::contract::KvContractMessage;
+use bytes::Bytes;
+
+use kvs::contract::{Error, KvContractMessage, Request, Response};
+use kvs::contract::mock::duplex;
+use kvs::engines::changes::LogPosition;
+use kvs::engines::engine::{BatchWrite, Preload, SetCondition};
+use kvs::engines::pattern::ListOptions;
+use kvs::engines::typed::TypedValue;
+use kvs::KvError;
+use kvs::server_common::ServerError;
+
+fn parse_request(input: &str) -> kvs::contract::Result<Request> {
+    Request::parse(io::Cursor::new(input.as_bytes()))
+}
 
 #[test]
 fn make_and_parse() {
-    let c = KvContractMessage::remove("hello".to_owned());
-    let bc = c.clone().into_binary();
-    let reader = io::Cursor::new(bc.as_slice());
-    let cr = KvContractMessage::parse(reader).expect("Failed to parse.");
-    assert_eq!(c, cr);
+    let requests = vec![
+        Request::Remove { key: "hello".to_owned() },
+        Request::SetIf { key: "key".to_owned(), value: "value".to_owned(), condition: SetCondition::IfAbsent },
+        Request::Keys {
+            pattern: "user:*".to_owned(),
+            options: ListOptions { reverse: true, offset: 3, limit: Some(10), after: Some("user:9".to_owned()) },
+        },
+        Request::Changes { since: LogPosition { epoch: 3, offset: 1024 }, follow: true },
+        Request::LogEnd,
+        Request::RemovePrefix { prefix: "session:1:".to_owned() },
+        Request::SetTyped { key: "avatar".to_owned(), value: TypedValue::Bytes(vec![0, 255, 10]) },
+        Request::Incr { key: "visits".to_owned(), delta: -1 },
+        Request::AcquireLease { key: "lock".to_owned(), ttl_ms: 30000 },
+        Request::Restore { token: None, archive: vec![("0.log".to_owned(), b"{}".to_vec())].into_iter().collect() },
+        Request::Stats,
+        Request::GetWithMeta { key: "key".to_owned() },
+        Request::SetIfUnmodified { key: "key".to_owned(), value: "value".to_owned(), modified_ms: 1_600_000_000_000 },
+        Request::Preload { token: None, target: Preload::Prefix("user:".to_owned()) },
+        Request::Eval { script: "return 1".to_owned(), keys: vec!["key".to_owned()], args: vec!["1".to_owned()] },
+        Request::Auth { user: "alice".to_owned(), secret: "s3cret".to_owned(), request: Box::new(Request::Stats) },
+        Request::DryRun { request: Box::new(Request::Remove { key: "key".to_owned() }) },
+        Request::Deadline { timeout_ms: 500, request: Box::new(Request::Get { key: "key".to_owned() }) },
+        Request::Batch { requests: vec![Request::Get { key: "key".to_owned() }, Request::Remove { key: "key".to_owned() }] },
+        Request::Transaction {
+            writes: vec![
+                BatchWrite::Set { key: "key".to_owned(), value: "value".to_owned() },
+                BatchWrite::Remove { key: "other".to_owned() },
+            ],
+        },
+    ];
+    for request in requests {
+        let bin = request.clone().into_binary();
+        let parsed = Request::parse(io::Cursor::new(bin.as_slice())).expect("Failed to parse.");
+        assert_eq!(parsed, request);
+    }
+}
+
+#[test]
+fn requests_on_the_wire() {
+    assert_eq!(
+        parse_request(r#"{"op":"append","key":"events","suffix":"login;"}"#).unwrap(),
+        Request::Append { key: "events".to_owned(), suffix: "login;".to_owned() }
+    );
+    assert_eq!(
+        parse_request(r#"{"op":"set_if","key":"key","value":"value","condition":"xx"}"#).unwrap(),
+        Request::SetIf { key: "key".to_owned(), value: "value".to_owned(), condition: SetCondition::IfPresent }
+    );
+    // the absent options are the defaults.
+    assert_eq!(
+        parse_request(r#"{"op":"keys","pattern":"*"}"#).unwrap(),
+        Request::Keys { pattern: "*".to_owned(), options: ListOptions::default() }
+    );
+    assert_eq!(
+        parse_request(r#"{"op":"keys","pattern":"*","options":{"limit":10}}"#).unwrap(),
+        Request::Keys { pattern: "*".to_owned(), options: ListOptions { limit: Some(10), ..ListOptions::default() } }
+    );
+    assert_eq!(
+        parse_request(r#"{"op":"set_typed","key":"visits","value":{"integer":42}}"#).unwrap(),
+        Request::SetTyped { key: "visits".to_owned(), value: TypedValue::Integer(42) }
+    );
+    // the bytes are in base64, or in the arrays of the earlier versions.
+    let avatar = Request::SetTyped { key: "avatar".to_owned(), value: TypedValue::Bytes(vec![0, 255, 10]) };
+    assert!(String::from_utf8(avatar.clone().into_binary()).unwrap().contains(r#"{"bytes":"AP8K"}"#));
+    assert_eq!(parse_request(r#"{"op":"set_typed","key":"avatar","value":{"bytes":"AP8K"}}"#).unwrap(), avatar);
+    assert_eq!(parse_request(r#"{"op":"set_typed","key":"avatar","value":{"bytes":[0,255,10]}}"#).unwrap(), avatar);
+    assert!(parse_request(r#"{"op":"set_typed","key":"avatar","value":{"bytes":"AP8K!"}}"#).is_err());
+    assert_eq!(
+        parse_request(r#"{"op":"changes"}"#).unwrap(),
+        Request::Changes { since: LogPosition::default(), follow: false }
+    );
+    assert_eq!(
+        parse_request(r#"{"op":"transaction","writes":[{"op":"set","key":"key","value":"value"},{"op":"remove","key":"other"}]}"#)
+            .unwrap(),
+        Request::Transaction {
+            writes: vec![
+                BatchWrite::Set { key: "key".to_owned(), value: "value".to_owned() },
+                BatchWrite::Remove { key: "other".to_owned() },
+            ],
+        }
+    );
+}
+
+#[test]
+fn bad_messages() {
+    let inputs = vec![
+        // unknown operation.
+        r#"{"op":"launch","key":"key"}"#,
+        // missing field.
+        r#"{"op":"append","key":"events"}"#,
+        r#"{"op":"copy","from":"from"}"#,
+        // malformed field.
+        r#"{"op":"set_if","key":"key","value":"value","condition":"maybe"}"#,
+        r#"{"op":"acquire_lease","key":"lock","ttl_ms":"forever"}"#,
+        r#"{"op":"keys","pattern":"*","options":{"limit":"ten"}}"#,
+        r#"{"op":"changes","since":3}"#,
+        // a response isn't a request.
+        r#"{"status":"no_content"}"#,
+    ];
+    for input in inputs {
+        match parse_request(input) {
+            Err(Error::BadMessage { .. }) => {}
+            other => panic!("parsed {:?} into {:?}", input, other),
+        }
+    }
+}
+
+#[test]
+fn error_response_carries_code() {
+    let err = ServerError::from(KvError::KeyNotFound);
+    let bin = err.to_response().into_binary();
+    let parsed = Response::parse(io::Cursor::new(bin.as_slice())).expect("Failed to parse.");
+    match parsed {
+        Response::Error { code, retryable, .. } => {
+            assert_eq!(code, KvError::KeyNotFound.code());
+            assert!(!retryable);
+        }
+        other => panic!("unexpected response: {:?}", other),
+    }
+
+    // the scans stopped past the deadline answer like the requests the pool times out.
+    let err = ServerError::from(KvError::DeadlineExceeded { operation: "list_keys" });
+    assert_eq!(err.code(), ServerError::Timeout.code());
+    match ServerError::Timeout.to_response() {
+        Response::Error { code, retryable, .. } => {
+            assert_eq!(code, ServerError::Timeout.code());
+            assert!(retryable);
+        }
+        other => panic!("unexpected response: {:?}", other),
+    }
+}
+
+#[test]
+fn shared_content_is_sent_as_content() {
+    let shared = Response::SharedContent { content: Bytes::from_static("value \"1\"".as_bytes()) };
+    let bin = shared.into_binary();
+    let parsed = Response::parse(io::Cursor::new(bin.as_slice())).expect("Failed to parse.");
+    assert_eq!(parsed, Response::Content { content: "value \"1\"".to_owned() });
+    let batch = Response::Batch { responses: vec![Response::SharedContent { content: Bytes::from_static(b"v") }] };
+    let parsed = Response::parse(io::Cursor::new(batch.into_binary().as_slice())).expect("Failed to parse.");
+    assert_eq!(parsed, Response::Batch { responses: vec![Response::Content { content: "v".to_owned() }] });
+}
+
+#[test]
+fn parse_chunked_and_delayed_stream() {
+    let message = Request::Set { key: "key".to_owned(), value: "value with \"quotes\"".to_owned() };
+    let (mut client, server) = duplex();
+    let mut server = server.chunked(3).delayed(Duration::from_millis(1));
+    let writer = thread::spawn({
+        let message = message.clone();
+        move || {
+            for byte in message.into_binary() {
+                client.write_all(&[byte]).unwrap();
+            }
+            client.shutdown_write();
+            client
+        }
+    });
+    let parsed = Request::parse(&mut server).expect("Failed to parse.");
+    assert_eq!(parsed, message);
+    writer.join().unwrap();
+}
+
+#[test]
+fn parse_malformed_stream() {
+    let inputs: Vec<&[u8]> = vec![
+        b"",
+        b"{\"op\":\"get\",",
+        b"{\"op\":\"get\",\"key\":\"key\"}garbage",
+        b"not json at all",
+    ];
+    for input in inputs {
+        let (mut client, server) = duplex();
+        client.write_all(input).unwrap();
+        client.shutdown_write();
+        assert!(Request::parse(server.chunked(1)).is_err(), "parsed {:?}", input);
+    }
+}
+
+#[test]
+fn parse_head_leaves_body() {
+    let message = Request::SetStream { key: "key".to_owned() };
+    assert!(message.has_body());
+    let mut bin = message.clone().into_binary();
+    bin.extend_from_slice(b"{\"not\": \"a message\"} but the value");
+    let (mut client, server) = duplex();
+    client.write_all(bin.as_slice()).unwrap();
+    client.shutdown_write();
+    let mut server = server.chunked(1);
+    let parsed = Request::parse_head(&mut server).expect("Failed to parse.");
+    assert_eq!(parsed, message);
+    let mut body = String::new();
+    server.read_to_string(&mut body).unwrap();
+    assert_eq!(body, "{\"not\": \"a message\"} but the value");
 }
@@ -0,0 +1,149 @@
+//! crash tests driven by the fail points, run them by `cargo test --features failpoints`.
+#![cfg(feature = "failpoints")]
+
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use fail::FailScenario;
+use tempfile::TempDir;
+
+use kvs::{KvsEngine, KvStore, Result};
+use kvs::client::KvsClient;
+use kvs::engines::kvs::WritePath;
+use kvs::server::KvServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+
+// a failed append leaves nothing behind: the key is absent, before and after reopening.
+#[test]
+fn crash_before_append() -> Result<()> {
+    let scenario = FailScenario::setup();
+    for write_path in [WritePath::Buffered, WritePath::Vectored] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?.with_write_path(write_path);
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        fail::cfg("kvs::before_append", "return").unwrap();
+        assert!(store.set("key1".to_owned(), "value2".to_owned()).is_err());
+        assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+        fail::remove("kvs::before_append");
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+    }
+    scenario.teardown();
+    Ok(())
+}
+
+// once a record is appended, it's durable even if the index isn't updated:
+// reopening the store rebuilds the index from the log.
+#[test]
+fn crash_before_index_update() -> Result<()> {
+    let scenario = FailScenario::setup();
+    for write_path in [WritePath::Buffered, WritePath::Vectored] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?.with_write_path(write_path);
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        fail::cfg("kvs::before_index_update", "return").unwrap();
+        assert!(store.set("key1".to_owned(), "value2".to_owned()).is_err());
+        fail::remove("kvs::before_index_update");
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    }
+    scenario.teardown();
+    Ok(())
+}
+
+// the records failing right after they're appended are durable too, and the later writes go on after them.
+#[test]
+fn crash_after_append() -> Result<()> {
+    let scenario = FailScenario::setup();
+    for write_path in [WritePath::Buffered, WritePath::Vectored] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?.with_write_path(write_path);
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        fail::cfg("kvs::after_append", "return").unwrap();
+        assert!(store.set("key1".to_owned(), "value2".to_owned()).is_err());
+        fail::remove("kvs::after_append");
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+    scenario.teardown();
+    Ok(())
+}
+
+#[test]
+fn crash_before_reply() -> Result<()> {
+    let scenario = FailScenario::setup();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = KvServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let client = KvsClient::new(addr);
+
+    fail::cfg("server::before_reply", "return").unwrap();
+    assert!(client.set("key1".to_owned(), "value1".to_owned()).is_err());
+    fail::remove("server::before_reply");
+    // the request is done, only the reply is lost.
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    scenario.teardown();
+    Ok(())
+}
+
+// a compaction crashed before publishing its file leaves the elder files intact,
+// and reopening the store drops the unfinished file.
+#[test]
+fn crash_before_compaction_rename() -> Result<()> {
+    let scenario = FailScenario::setup();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compacting = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().ends_with(".compacting"))
+            .count()
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    let value = |iter: usize| format!("{}{}", iter, "v".repeat(1024 * 1024));
+
+    fail::cfg("kvs::before_compaction_rename", "return").unwrap();
+    let mut iter = 0;
+    while compacting() == 0 {
+        assert!(iter < 100, "No compaction detected");
+        store.set("key1".to_owned(), value(iter))?;
+        iter += 1;
+    }
+    // let the compaction reach the fail point.
+    thread::sleep(Duration::from_millis(500));
+    fail::remove("kvs::before_compaction_rename");
+    assert_eq!(store.metrics().snapshot().compactions, 0);
+    assert_eq!(store.metrics().snapshot().compaction_failures, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some(value(iter - 1)));
+
+    // the failed compaction doesn't keep the later ones from running.
+    while store.metrics().snapshot().compactions == 0 {
+        assert!(iter < 200, "No compaction detected after the failed one");
+        store.set("key1".to_owned(), value(iter))?;
+        iter += 1;
+        thread::sleep(Duration::from_millis(10));
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(compacting(), 0);
+    assert_eq!(store.get("key1".to_owned())?, Some(value(iter - 1)));
+    scenario.teardown();
+    Ok(())
+}
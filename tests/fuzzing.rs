@@ -0,0 +1,50 @@
+//! the fuzz entry points on the inputs cut or corrupted at each byte, run them by `cargo test --features fuzzing`.
+#![cfg(feature = "fuzzing")]
+
+use std::fs;
+
+use tempfile::TempDir;
+
+use kvs::{KvsEngine, KvStore, Result};
+use kvs::contract::{KvContractMessage, Request};
+use kvs::engines::codec::CodecKind;
+use kvs::fuzz;
+
+#[test]
+fn parse_cut_and_corrupted_requests() {
+    let requests = vec![
+        Request::Set { key: "key".to_owned(), value: "value".to_owned() },
+        Request::Batch { requests: vec![Request::Get { key: "key".to_owned() }, Request::Stats] },
+    ];
+    for request in requests {
+        let binary = request.into_binary();
+        for at in 0..binary.len() {
+            fuzz::parse_contract(&binary[..at]);
+            let mut corrupted = binary.clone();
+            corrupted[at] ^= 0x5a;
+            fuzz::parse_contract(corrupted.as_slice());
+        }
+    }
+    fuzz::parse_contract(b"{\"op\":\"get\",\"key\":\"key\"} {\"op\":\"stats\"}");
+    fuzz::parse_contract(b"\xff\xfe");
+}
+
+#[test]
+fn read_cut_and_corrupted_data_files() -> Result<()> {
+    for (pick, kind) in [(0u8, CodecKind::Json), (1, CodecKind::Bincode), (2, CodecKind::JsonCrc)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_codec(temp_dir.path(), kind.codec())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set_with_meta("key2".to_owned(), "value2".to_owned(), Some("text/plain".to_owned()))?;
+        store.remove("key1".to_owned())?;
+        drop(store);
+        let content = fs::read(temp_dir.path().join("kvs-data-1"))?;
+        for at in 0..content.len() {
+            fuzz::read_data_file([&[pick], &content[..at]].concat().as_slice());
+            let mut corrupted = [&[pick], content.as_slice()].concat();
+            corrupted[at + 1] ^= 0x5a;
+            fuzz::read_data_file(corrupted.as_slice());
+        }
+    }
+    Ok(())
+}
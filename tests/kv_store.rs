@@ -1,10 +1,27 @@
-use std::sync::{Arc, Barrier};
+use std::fs::{self, OpenOptions};
+use std::error::Error;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
-use kvs::{KvsEngine, KvStore, Result};
+use kvs::{KvError, KvsEngine, KvStore, Result};
+use kvs::engines::archive::{ArchiveStorage, DirObjectStore};
+use kvs::engines::changes::{Change, LogPosition};
+use kvs::engines::context::RequestContext;
+use kvs::engines::codec::{CodecKind, JsonLines, RecordCodec};
+use kvs::engines::engine::{BatchWrite, Preload, ValueWithMeta};
+use kvs::engines::kvs::{CompactionEvent, IndexKind, SyncPolicy, WritePath};
+use kvs::engines::lease::Lease;
+use kvs::engines::pattern::{KeyPattern, ListOptions};
+use kvs::engines::sled::{SledEngine, SledMode, SledOptions};
+use kvs::engines::storage::{Fault, FaultyStorage, FsStorage, MemStorage, Storage};
+use kvs::engines::tiered::Tiered;
+use kvs::engines::typed::TypedValue;
 
 // Should get previously stored value
 #[test]
@@ -211,3 +228,1817 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should report where the data file is corrupted
+#[test]
+fn corrupted_record_context() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let data_file = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .find(|path| path.file_name().unwrap().to_str().unwrap().starts_with("kvs-data-"))
+        .expect("the data file should exist");
+    let valid_len = fs::metadata(&data_file)?.len() as usize;
+    OpenOptions::new()
+        .append(true)
+        .open(&data_file)?
+        .write_all(b"not a record\n")?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(err @ KvError::WithContext { .. }) => {
+            // the parse error is the source of the context.
+            let source = err.source().expect("the context should have a source");
+            assert!(source.to_string().starts_with("Failed to parse file"));
+            let context = match err {
+                KvError::WithContext { context, .. } => context,
+                _ => unreachable!(),
+            };
+            assert_eq!(context.operation, "build_index");
+            assert_eq!(
+                Some(context.file_name.as_str()),
+                data_file.file_name().unwrap().to_str()
+            );
+            assert_eq!(context.offset, valid_len);
+        }
+        Err(err) => panic!("the error should carry context: {}", err),
+        Ok(_) => panic!("opening a corrupted store should fail"),
+    }
+    Ok(())
+}
+
+// Should count the operations
+#[test]
+fn engine_metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let metrics = store.metrics();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.clone().set("key2".to_owned(), "value2".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("key3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.remove("key3".to_owned()).is_err());
+
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.sets, 2);
+    assert_eq!(snapshot.gets, 2);
+    assert_eq!(snapshot.removes, 2);
+    assert_eq!(snapshot.misses, 2);
+    assert!(snapshot.bytes_written > 0);
+    Ok(())
+}
+
+// Should report the stale bytes and the last compaction, to tell how soon the disk fills
+#[test]
+fn compaction_health() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let metrics = store.metrics();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let fresh = metrics.snapshot();
+    assert_eq!((fresh.stale_bytes, fresh.last_compaction), (0, 0));
+    assert_eq!(fresh.data_bytes, fresh.bytes_written);
+
+    for _ in 0..9 {
+        store.set("key1".to_owned(), "value1".to_owned())?;
+    }
+    let stale = metrics.snapshot();
+    assert_eq!(stale.data_bytes, stale.bytes_written);
+    assert_eq!(stale.stale_bytes, stale.data_bytes / 10 * 9);
+    assert!((stale.stale_ratio() - 0.9).abs() < 0.01);
+
+    store.compact()?;
+    let compacted = metrics.snapshot();
+    assert!(compacted.last_compaction > 0);
+    assert_eq!(compacted.stale_bytes, 0);
+    assert_eq!(compacted.data_bytes, fresh.data_bytes);
+    assert_eq!(compacted.stale_ratio(), 0.0);
+
+    drop(store);
+    let reopened = KvStore::open(temp_dir.path())?.metrics().snapshot();
+    assert_eq!((reopened.stale_bytes, reopened.data_bytes), (0, fresh.data_bytes));
+    Ok(())
+}
+
+// Should serve the hot keys from the record cache, evicting the least recently used records
+#[test]
+fn record_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_record_cache(256);
+    let metrics = store.metrics();
+    let value = |n: usize| format!("{}{}", n, "v".repeat(50));
+
+    store.set("key1".to_owned(), value(1))?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value(1)));
+    assert_eq!(store.clone().get("key1".to_owned())?, Some(value(1)));
+    assert_eq!((metrics.snapshot().cache_hits, metrics.snapshot().cache_misses), (1, 1));
+
+    // the overwritten value is in another record, so it's never served from the cache.
+    store.set("key1".to_owned(), value(2))?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value(2)));
+    assert_eq!((metrics.snapshot().cache_hits, metrics.snapshot().cache_misses), (1, 2));
+
+    // only about 2 records fit in, so the ones not used recently are evicted.
+    for key_id in 2..=4 {
+        store.set(format!("key{}", key_id), value(key_id))?;
+        store.get(format!("key{}", key_id))?;
+    }
+    assert_eq!(store.get("key4".to_owned())?, Some(value(4)));
+    assert_eq!(store.get("key1".to_owned())?, Some(value(2)));
+    assert_eq!((metrics.snapshot().cache_hits, metrics.snapshot().cache_misses), (2, 6));
+    let cached = metrics.snapshot().cache_bytes;
+    assert!(cached > 0 && cached <= 256, "{} bytes cached", cached);
+    Ok(())
+}
+
+// Should warm the record cache up by preloading, so that the first reads hit it
+#[test]
+fn preload_into_record_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["user:1", "user:2", "user:3", "session:1"] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    store.remove("user:3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?.with_record_cache(1024);
+    let metrics = store.metrics();
+    assert_eq!(store.preload(Preload::Prefix("user:".to_owned()))?, 2);
+    assert_eq!(store.preload(Preload::Keys(vec!["session:1".to_owned(), "absent".to_owned()]))?, 1);
+    // none of them reads the data files again.
+    let misses = metrics.snapshot().cache_misses;
+    for key in &["user:1", "user:2", "session:1"] {
+        assert_eq!(store.get(key.to_string())?, Some("value".to_owned()));
+    }
+    assert_eq!((metrics.snapshot().cache_hits, metrics.snapshot().cache_misses), (3, misses));
+    assert_eq!(store.preload(Preload::Prefix(String::new()))?, 3);
+    Ok(())
+}
+
+// Should warm the hot tier up by preloading, so that the first reads are served from memory
+#[test]
+fn preload_into_hot_tier() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let tiered = Tiered::new(KvStore::open(temp_dir.path())?, 1024);
+    for key in &["user:1", "user:2", "session:1"] {
+        tiered.set(key.to_string(), "value".to_owned())?;
+    }
+    assert_eq!(tiered.hot_bytes(), 0);
+    assert_eq!(tiered.preload(Preload::Prefix("user:".to_owned()))?, 2);
+    assert_eq!(tiered.hot_bytes(), 2 * ("user:N".len() + "value".len()));
+    assert_eq!(tiered.preload(Preload::Keys(vec!["absent".to_owned()]))?, 0);
+    let hits = tiered.metrics().snapshot().cache_hits;
+    for key in &["user:1", "user:2"] {
+        assert_eq!(tiered.get(key.to_string())?, Some("value".to_owned()));
+    }
+    assert_eq!(tiered.metrics().snapshot().cache_hits, hits + 2);
+    Ok(())
+}
+
+// Should share the values in the record cache with the readers of bytes, instead of copying them for each read
+#[test]
+fn get_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_record_cache(1024).with_blob_threshold(16);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "a value longer than the blob threshold".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+
+    let first = store.get_bytes("key1".to_owned())?.expect("key1 should be present");
+    let second = store.clone().get_bytes("key1".to_owned())?.expect("key1 should be present");
+    assert_eq!(&first[..], b"value1");
+    assert_eq!(first.as_ptr(), second.as_ptr());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        store.get_bytes("key2".to_owned())?.as_deref(),
+        Some(&b"a value longer than the blob threshold"[..])
+    );
+    assert_eq!(store.get_bytes("key3".to_owned())?, None);
+    assert_eq!(store.get_bytes("key4".to_owned())?, None);
+
+    // without the cache, the value is taken from the record read.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?.as_deref(), Some(&b"value1"[..]));
+    Ok(())
+}
+
+// A failed write on a full disk leaves no trace, and the store keeps working once there is space again.
+#[test]
+fn write_on_full_disk() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let storage = FaultyStorage::new();
+    let store = KvStore::open_with_storage(temp_dir.path(), Arc::new(storage.clone()))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    storage.fail_after(8, Fault::Fail(io::ErrorKind::Other));
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    storage.heal();
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A record torn by a crash is dropped on reopening, without losing the acknowledged ones.
+#[test]
+fn recover_from_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let storage = FaultyStorage::new();
+    let store = KvStore::open_with_storage(temp_dir.path(), Arc::new(storage.clone()))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    storage.fail_after(8, Fault::Torn);
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    // the crashed file can't be written, even to roll back the torn record.
+    assert!(store.set("key3".to_owned(), "value3".to_owned()).is_err());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should keep the whole data directory in the memory storage, across compactions and reopening
+// the salvaging open cuts the data file at the corrupted record, which fails the plain open.
+#[test]
+fn open_salvaging_corrupted_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let data_file = temp_dir.path().join("kvs-data-1");
+    let content = fs::read_to_string(&data_file)?;
+    let second = content.find('\n').expect("the first record is written") + 1;
+    fs::write(&data_file, format!("{}#{}", &content[..second], &content[second + 1..]))?;
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    let store = KvStore::open_salvaging(temp_dir.path(), Arc::new(FsStorage))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.set("key2".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn open_in_memory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let storage = MemStorage::new();
+    let store = KvStore::open_with_storage(temp_dir.path(), Arc::new(storage.clone()))?.with_blob_threshold(16);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("large".to_owned(), "l".repeat(64))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.compact()?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.close()?;
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 0, "nothing should be written to the disk");
+
+    let store = KvStore::open_with_storage(temp_dir.path(), Arc::new(storage.clone()))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("large".to_owned())?, Some("l".repeat(64)));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // another storage has none of the files.
+    let store = KvStore::open_with_storage(temp_dir.path(), Arc::new(MemStorage::new()))?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Should move the sealed data files to the object store, and fetch them back once they're read
+#[test]
+fn archive_cold_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let bucket_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_files = |dir: &Path| -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("kvs-data-"))
+            .collect();
+        names.sort();
+        names
+    };
+    let open = || -> Result<(Arc<ArchiveStorage>, KvStore)> {
+        let bucket = Arc::new(DirObjectStore::open(bucket_dir.path())?);
+        let storage = Arc::new(ArchiveStorage::new(temp_dir.path(), Arc::new(FsStorage), bucket));
+        let store = KvStore::open_with_storage(temp_dir.path(), storage.clone())?;
+        Ok((storage, store))
+    };
+    let (storage, store) = open()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    // the compacted data file is just written, so it isn't cold yet.
+    assert!(storage.archive_cold(Duration::from_secs(3600))?.is_empty());
+    assert!(storage.archive_cold(Duration::from_secs(0))?.contains(&"kvs-data-2".to_owned()));
+    assert_eq!(data_files(temp_dir.path()), vec!["kvs-data-3"], "only the data file being written should stay");
+    assert!(data_files(bucket_dir.path()).contains(&"kvs-data-2".to_owned()));
+    store.close()?;
+
+    // the clean open reads none of the data files.
+    let (storage, store) = open()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(!data_files(temp_dir.path()).contains(&"kvs-data-2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(data_files(temp_dir.path()).contains(&"kvs-data-2".to_owned()), "the data file read should be cached");
+
+    // the data files dropped after a compaction are deleted from the archive too.
+    storage.archive_cold(Duration::from_secs(0))?;
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!data_files(bucket_dir.path()).contains(&"kvs-data-2".to_owned()));
+    Ok(())
+}
+
+// Should remove the data files whichever copy exists, and not hide the failures of the local removals
+#[test]
+fn remove_archived_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let bucket_dir = TempDir::new().expect("unable to create temporary working directory");
+    let bucket = Arc::new(DirObjectStore::open(bucket_dir.path())?);
+    let storage = ArchiveStorage::new(temp_dir.path(), Arc::new(FsStorage), bucket);
+
+    fs::write(bucket_dir.path().join("kvs-data-1"), b"archived")?;
+    storage.remove(&temp_dir.path().join("kvs-data-1"))?;
+    assert!(!bucket_dir.path().join("kvs-data-1").exists());
+    let missing = storage.remove(&temp_dir.path().join("kvs-data-1")).unwrap_err();
+    assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+
+    // the local file can't be removed, though its archived copy is deleted.
+    fs::create_dir(temp_dir.path().join("kvs-data-2"))?;
+    fs::write(bucket_dir.path().join("kvs-data-2"), b"archived")?;
+    let failed = storage.remove(&temp_dir.path().join("kvs-data-2")).unwrap_err();
+    assert_ne!(failed.kind(), io::ErrorKind::NotFound);
+    Ok(())
+}
+
+#[test]
+fn key_pattern_matching() {
+    let pattern = KeyPattern::new("user:*:na?e");
+    assert_eq!(pattern.literal_prefix(), "user:");
+    assert!(pattern.matches("user:42:name"));
+    assert!(pattern.matches("user::nave"));
+    assert!(pattern.matches("user:a:b:name"));
+    assert!(!pattern.matches("user:42:names"));
+    assert!(!pattern.matches("admin:42:name"));
+
+    assert!(KeyPattern::new("*").matches(""));
+    assert!(KeyPattern::new("a**b").matches("ab"));
+    assert!(!KeyPattern::new("?").matches(""));
+    let escaped = KeyPattern::new("what\\?");
+    assert_eq!(escaped.literal_prefix(), "what?");
+    assert!(escaped.matches("what?"));
+    assert!(!escaped.matches("whats"));
+    assert!(KeyPattern::new("\\").matches("\\"));
+    let prefix = KeyPattern::new(format!("{}*", KeyPattern::escape("a*?\\")).as_str());
+    assert_eq!(prefix.literal_prefix(), "a*?\\");
+    assert!(prefix.matches("a*?\\b"));
+    assert!(!prefix.matches("ab?\\"));
+}
+
+fn list_keys(engine: impl KvsEngine) -> Result<()> {
+    for key in ["user:2", "user:10", "user:1", "admin:1", "user"] {
+        engine.set(key.to_owned(), "value".to_owned())?;
+    }
+    engine.remove("user:10".to_owned())?;
+    assert_eq!(engine.keys("user:*".to_owned())?, vec!["user:1", "user:2"]);
+    assert_eq!(engine.keys("*:1".to_owned())?, vec!["admin:1", "user:1"]);
+    assert_eq!(engine.keys("user?".to_owned())?, Vec::<String>::new());
+    assert_eq!(engine.keys("user".to_owned())?, vec!["user"]);
+    assert_eq!(engine.keys("*".to_owned())?.len(), 4);
+
+    let last = |offset, limit| ListOptions { reverse: true, offset, limit, after: None };
+    assert_eq!(engine.list_keys("user:*".to_owned(), last(0, Some(1)))?, vec!["user:2"]);
+    assert_eq!(engine.list_keys("user*".to_owned(), last(1, None))?, vec!["user:1", "user"]);
+    assert_eq!(engine.list_keys("*".to_owned(), last(1, Some(2)))?, vec!["user:1", "user"]);
+    let first = ListOptions { offset: 1, limit: Some(2), ..ListOptions::default() };
+    assert_eq!(engine.list_keys("*".to_owned(), first)?, vec!["user", "user:1"]);
+    assert!(engine.list_keys("*".to_owned(), last(4, None))?.is_empty());
+    assert!(engine.list_keys("*".to_owned(), last(0, Some(0)))?.is_empty());
+
+    // the cursor is exclusive, and needn't be a present key.
+    let after = |key: &str, reverse| ListOptions { reverse, after: Some(key.to_owned()), ..ListOptions::default() };
+    assert_eq!(engine.list_keys("*".to_owned(), after("user", false))?, vec!["user:1", "user:2"]);
+    assert_eq!(engine.list_keys("*".to_owned(), after("user:10", true))?, vec!["user:1", "user", "admin:1"]);
+    assert_eq!(engine.list_keys("user:*".to_owned(), after("a", false))?, vec!["user:1", "user:2"]);
+    let page = ListOptions { limit: Some(2), ..ListOptions::default() };
+    let keys = engine.list_keys("*".to_owned(), page.clone())?;
+    assert_eq!(keys, vec!["admin:1", "user"]);
+    let page = page.next_page(&keys).expect("a full page should have a next one");
+    let keys = engine.list_keys("*".to_owned(), page.clone())?;
+    assert_eq!(keys, vec!["user:1", "user:2"]);
+    let page = page.next_page(&keys).expect("a full page should have a next one");
+    let keys = engine.list_keys("*".to_owned(), page.clone())?;
+    assert!(keys.is_empty());
+    assert_eq!(page.next_page(&keys), None);
+    Ok(())
+}
+
+#[test]
+fn keys_by_pattern() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    list_keys(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    list_keys(KvStore::open(temp_dir.path())?.with_index(IndexKind::Ordered)?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    list_keys(SledEngine::open(temp_dir.path())?)
+}
+
+// Should list each key present during a paginated listing exactly once, though the store is compacted between the pages
+#[test]
+fn paginate_across_compactions() -> Result<()> {
+    for kind in [IndexKind::Hash, IndexKind::Ordered] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?.with_index(kind)?;
+        for key_id in 0..100 {
+            store.set(format!("key{:03}", key_id), "value".to_owned())?;
+        }
+        for reverse in [false, true] {
+            let mut listed: Vec<String> = Vec::new();
+            let mut page = Some(ListOptions { reverse, limit: Some(7), ..ListOptions::default() });
+            let mut round = 0;
+            while let Some(options) = page {
+                let keys = store.list_keys("key*".to_owned(), options.clone())?;
+                listed.extend(keys.iter().cloned());
+                page = options.next_page(&keys);
+                // move all the records, and churn the keys out of the stable range, before the next page.
+                round += 1;
+                for key_id in 0..100 {
+                    store.set(format!("key{:03}", key_id), format!("value{}", round))?;
+                }
+                store.set(format!("key{:03}x", round), "new".to_owned())?;
+                let _ = store.remove(format!("key{:03}x", round - 1));
+                store.compact()?;
+            }
+            let stable: Vec<String> = listed.iter().filter(|key| key.len() == 6).cloned().collect();
+            let mut expected: Vec<String> = (0..100).map(|key_id| format!("key{:03}", key_id)).collect();
+            if reverse {
+                expected.reverse();
+            }
+            assert_eq!(stable, expected, "with the {:?} index, reversed: {}", kind, reverse);
+            let mut deduplicated = listed.clone();
+            deduplicated.sort();
+            deduplicated.dedup();
+            assert_eq!(deduplicated.len(), listed.len());
+        }
+    }
+    Ok(())
+}
+
+// Should keep the index kind chosen in the data directory, and the records when switching it
+#[test]
+fn index_kind_persists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.index_kind(), IndexKind::Hash);
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    let store = store.with_index(IndexKind::Ordered)?;
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    store.set("c".to_owned(), "3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.index_kind(), IndexKind::Ordered);
+    assert_eq!(store.keys("*".to_owned())?, vec!["a", "b", "c"]);
+    let store = store.with_index(IndexKind::Hash)?;
+    assert_eq!(store.get("c".to_owned())?, Some("3".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.index_kind(), IndexKind::Hash);
+    assert_eq!(store.keys("*".to_owned())?, vec!["a", "b", "c"]);
+    assert_eq!("Ordered".parse::<IndexKind>().unwrap(), IndexKind::Ordered);
+    assert!("skiplist".parse::<IndexKind>().is_err());
+    Ok(())
+}
+
+/// `JsonLines` under another name, like a codec of another crate.
+struct Renamed;
+
+impl RecordCodec for Renamed {
+    fn name(&self) -> &str {
+        "renamed"
+    }
+
+    fn encode(&self, command: &kvs::engines::kvs::KvCommand) -> Vec<u8> {
+        JsonLines.encode(command)
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<kvs::engines::kvs::KvCommand> {
+        JsonLines.decode(record)
+    }
+
+    fn read_record(&self, reader: &mut dyn BufRead, record: &mut Vec<u8>) -> io::Result<bool> {
+        JsonLines.read_record(reader, record)
+    }
+}
+
+// Should keep the codec the data directory is created with, and tell the corrupted and the torn records by it
+#[test]
+fn record_codecs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_codec(temp_dir.path(), CodecKind::Bincode.codec())?.with_history(2);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("key0".to_owned(), "again".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "after".to_owned())?;
+    let history: Vec<Option<String>> =
+        store.history("key0".to_owned())?.into_iter().map(|version| version.map(|found| found.value)).collect();
+    assert_eq!(history, vec![Some("again".to_owned()), Some("value0".to_owned())]);
+    let changes = store.changes(LogPosition::default(), false)?.collect::<Result<Vec<Change>>>()?;
+    assert_eq!(changes.last().map(|change| change.value.clone()), Some(Some("after".to_owned())));
+    drop(store);
+
+    // json lines would read the records wrong, and the data files are never converted.
+    assert!(KvStore::open_with_codec(temp_dir.path(), CodecKind::Json.codec()).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("again".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("after".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    drop(store);
+    assert!(kvs::engines::offline::verify(temp_dir.path())?.is_intact());
+
+    // a torn record at the end is dropped, while a corrupted one before it is refused.
+    let mut data_files: Vec<(u64, PathBuf)> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter_map(|path| {
+            let epoch = path.file_name()?.to_str()?.strip_prefix("kvs-data-")?.parse().ok()?;
+            Some((epoch, path))
+        })
+        .collect();
+    data_files.sort();
+    let (_, newest) = data_files.last().unwrap();
+    let len = fs::metadata(newest)?.len();
+    OpenOptions::new().write(true).open(newest)?.set_len(len - 1)?;
+    assert_eq!(KvStore::open(temp_dir.path())?.get("key2".to_owned())?, Some("value2".to_owned()));
+    let (_, eldest) = data_files.first().unwrap();
+    let mut content = fs::read(eldest)?;
+    // in the first record, after its length and checksum.
+    content[9] ^= 0xff;
+    fs::write(eldest, content)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::WithContext { source, .. }) => assert!(matches!(*source, KvError::CorruptedRecord { .. })),
+        other => panic!("the corrupted record should be refused, but got {:?}", other.map(|_| ())),
+    }
+    assert!(!kvs::engines::offline::verify(temp_dir.path())?.is_intact());
+
+    // the codecs of other crates are recorded by their names, and only they read their data files.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_codec(temp_dir.path(), Arc::new(Renamed))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    let store = KvStore::open_with_codec(temp_dir.path(), Arc::new(Renamed))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!("yaml".parse::<CodecKind>().is_err());
+    Ok(())
+}
+
+// Should refuse a corrupted record in checksummed json lines, even when it still parses, unlike the legacy ones
+#[test]
+fn checksummed_json_lines() -> Result<()> {
+    let data_file = |path: &Path| -> PathBuf {
+        fs::read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.file_name().unwrap().to_string_lossy().starts_with("kvs-data-"))
+            .unwrap()
+    };
+    for (kind, refused) in [(CodecKind::JsonCrc, true), (CodecKind::Json, false)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_codec(temp_dir.path(), kind.codec())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        drop(store);
+
+        // a torn record at the end is dropped.
+        let path = temp_dir.path();
+        let len = fs::metadata(data_file(path))?.len();
+        OpenOptions::new().write(true).open(data_file(path))?.set_len(len - 3)?;
+        let store = KvStore::open(path)?;
+        assert_eq!(store.get("key2".to_owned())?, None);
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+        // a corrupted value is still in json, so only the checksum tells it.
+        let content = fs::read_to_string(data_file(path))?;
+        fs::write(data_file(path), content.replacen("value1", "value2", 1))?;
+        match KvStore::open(path) {
+            Err(KvError::WithContext { source, .. }) if refused => {
+                assert!(matches!(*source, KvError::CorruptedRecord { .. }))
+            }
+            Ok(store) if !refused => assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned())),
+            other => panic!("unexpected result of opening the corrupted {:?}: {:?}", kind, other.map(|_| ())),
+        }
+    }
+    assert_eq!("json-crc".parse::<CodecKind>().unwrap(), CodecKind::JsonCrc);
+    Ok(())
+}
+
+// Should recover the engine marker torn by a crash, from the data in the directory
+#[test]
+fn recover_engine_marker() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join(".engine"), "")?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert_eq!(fs::read_to_string(temp_dir.path().join(".engine"))?, "kvs");
+    assert!(!temp_dir.path().join(".engine.tmp").exists());
+
+    fs::write(temp_dir.path().join(".engine"), "\0\0")?;
+    assert!(matches!(SledEngine::open(temp_dir.path()), Err(KvError::IllegalWorkingDirectory)));
+    assert_eq!(fs::read_to_string(temp_dir.path().join(".engine"))?, "\0\0");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(fs::read_to_string(temp_dir.path().join(".engine"))?, "kvs");
+    Ok(())
+}
+
+// Should keep when a value is written and its metadata, across reopening
+#[test]
+fn value_metadata() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // a record written before the timestamps were introduced.
+    fs::write(temp_dir.path().join(".engine"), "kvs")?;
+    fs::write(temp_dir.path().join("kvs-data-1"), "{\"Put\":{\"key\":\"old\",\"value\":\"value0\"}}\n")?;
+    let store = KvStore::open(temp_dir.path())?;
+    let before = SystemTime::now() - Duration::from_millis(1);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_meta("key2".to_owned(), "value2".to_owned(), Some("rev-7".to_owned()))?;
+    let after = SystemTime::now();
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let found = store.get_with_meta("key1".to_owned())?.expect("key1 should exist");
+    assert_eq!(found.value, "value1");
+    let modified = found.modified.expect("the modified time should be recorded");
+    assert!(before <= modified && modified <= after);
+    assert_eq!(found.meta, None);
+    let found = store.get_with_meta("key2".to_owned())?.expect("key2 should exist");
+    assert_eq!(found.meta.as_deref(), Some("rev-7"));
+    let found = store.get_with_meta("old".to_owned())?.expect("old should exist");
+    assert_eq!((found.value.as_str(), found.modified), ("value0", None));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get_with_meta("key1".to_owned())?, None);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledEngine::open(temp_dir.path())?;
+    sled.set_with_meta("key1".to_owned(), "value1".to_owned(), None)?;
+    assert_eq!(sled.get_with_meta("key1".to_owned())?.and_then(|found| found.modified), None);
+    match sled.set_with_meta("key1".to_owned(), "value1".to_owned(), Some("rev-7".to_owned())) {
+        Err(KvError::Unsupported { operation }) => assert_eq!(operation, "set_with_meta"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    Ok(())
+}
+
+// Should retain the last versions of keys, across reopening and compaction
+#[test]
+fn versioned_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_history(3);
+    for version in 1..=4 {
+        store.set("audited".to_owned(), format!("value{}", version))?;
+    }
+    store.remove("audited".to_owned())?;
+    let values = |history: Vec<Option<ValueWithMeta>>| -> Vec<Option<String>> {
+        history.into_iter().map(|version| version.map(|found| found.value)).collect()
+    };
+    let expected = vec![None, Some("value4".to_owned()), Some("value3".to_owned())];
+    assert_eq!(values(store.history("audited".to_owned())?), expected);
+    assert_eq!(store.get_version("audited".to_owned(), 2)?.map(|found| found.value), Some("value3".to_owned()));
+    assert_eq!(store.get_version("audited".to_owned(), 3)?, None);
+    assert!(store.history("absent".to_owned())?.is_empty());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(values(store.history("audited".to_owned())?), vec![None]);
+    let store = store.with_history(3);
+    assert_eq!(values(store.history("audited".to_owned())?), expected);
+
+    // overwrite until a compaction is done, then once more after it, since the writes during it lose their elder versions.
+    let value = |iter: usize| format!("{}{}", iter, "v".repeat(1000));
+    let mut iter = 0;
+    while store.metrics().snapshot().compactions == 0 {
+        assert!(iter < 100, "No compaction detected");
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), value(iter))?;
+        }
+        iter += 1;
+    }
+    // wait for the compactions in the background by one more, which also keeps the writes below from starting another.
+    while store.compact().is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), value(iter))?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?.with_history(3);
+    assert_eq!(values(store.history("audited".to_owned())?), expected);
+    assert_eq!(values(store.history("key1".to_owned())?)[..2], [Some(value(iter)), Some(value(iter - 1))]);
+    assert_eq!(values(store.history("key999".to_owned())?)[..2], [Some(value(iter)), Some(value(iter - 1))]);
+    Ok(())
+}
+
+// Should restore the removed values in the soft-delete mode, until compaction
+#[test]
+fn soft_delete_and_undelete() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_soft_delete();
+    store.set_with_meta("key1".to_owned(), "value1".to_owned(), Some("rev-1".to_owned()))?;
+    store.remove("key1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.undelete("key1".to_owned())?;
+    let found = store.get_with_meta("key1".to_owned())?.expect("key1 should be restored");
+    assert_eq!((found.value.as_str(), found.meta.as_deref()), ("value1", Some("rev-1")));
+    // undeleting a present key does nothing.
+    store.undelete("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    match store.undelete("key2".to_owned()) {
+        Err(KvError::KeyNotFound) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    // the removals in the mode are recoverable after reopening, but the ones without it aren't.
+    store.remove("key1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.undelete("key2".to_owned()).is_err());
+    let store = store.with_soft_delete();
+    store.undelete("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    match SledEngine::open(temp_dir.path())?.undelete("key1".to_owned()) {
+        Err(KvError::Unsupported { operation }) => assert_eq!(operation, "undelete"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    Ok(())
+}
+
+// Should open from the checkpoint, replaying only the data files since it
+#[test]
+fn checkpoint_and_obsolete_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let checkpoint = store.checkpoint()?;
+    assert!(store.obsolete_segments(&checkpoint)?.is_empty());
+    store.set("key1".to_owned(), "value1-new".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let obsolete = store.obsolete_segments(&checkpoint)?;
+    assert_eq!(obsolete.len(), 1);
+    drop(store);
+
+    // the data file before the checkpoint is never read again, so the garbage in it goes unnoticed.
+    OpenOptions::new()
+        .append(true)
+        .open(&obsolete[0])?
+        .write_all(b"not a record\n")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1-new".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    fs::remove_file(&obsolete[0])?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1-new".to_owned()));
+
+    // the removed values are still referred in the soft-delete mode.
+    let store = store.with_soft_delete();
+    let checkpoint = store.checkpoint()?;
+    store.remove("key3".to_owned())?;
+    assert!(store.obsolete_segments(&checkpoint)?.is_empty());
+    drop(store);
+
+    // a stale checkpoint is ignored.
+    fs::write(temp_dir.path().join("kvs-checkpoint"), "{\"checkpoint\":{\"epoch\":9},\"steal\":0,\"index\":[[\"key1\",{\"offset\":0,\"length\":4096,\"epoch\":2}]]}")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1-new".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// Should refuse the new keys beyond the index budget, but still overwrite and remove the present ones
+#[test]
+fn index_budget() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let entry = |key: &str| key.len() as u64 + KvStore::INDEX_ENTRY_OVERHEAD;
+    let store = KvStore::open(temp_dir.path())?.with_index_budget(entry("key1") + entry("key2"));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.index_bytes(), entry("key1") + entry("key2"));
+    assert_eq!(store.index_keys(), 2);
+    let metrics = store.metrics().snapshot();
+    assert_eq!((metrics.index_keys, metrics.index_bytes), (2, entry("key1") + entry("key2")));
+    match store.set("key3".to_owned(), "value3".to_owned()) {
+        Err(KvError::IndexFull { budget }) => assert_eq!(budget, entry("key1") + entry("key2")),
+        other => panic!("the new key should be refused, but got {:?}", other),
+    }
+    assert!(store.ingest(vec![("key0".to_owned(), "value0".to_owned())]).is_err());
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.set("key1".to_owned(), "value1-new".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1-new".to_owned()));
+    drop(store);
+
+    // the memory is estimated again by the opens.
+    let store = KvStore::open(temp_dir.path())?.with_index_warning(entry("key1"));
+    assert_eq!(store.index_bytes(), entry("key1") + entry("key2"));
+    assert_eq!(store.metrics().snapshot().index_keys, 2);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.index_keys(), 3);
+    assert_eq!(store.metrics().snapshot().index_bytes, entry("key1") + entry("key2") + entry("key3"));
+    Ok(())
+}
+
+// Should load the index saved by a graceful close, unless the saved one is damaged
+#[test]
+fn close_and_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.close()?;
+
+    // the data files are never replayed, so the garbage in them goes unnoticed.
+    let data_file = temp_dir.path().join("kvs-data-1");
+    OpenOptions::new().append(true).open(&data_file)?.write_all(b"not a record\n")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.close()?;
+
+    // a damaged index is ignored, then the data files are replayed.
+    let checkpoint_file = temp_dir.path().join("kvs-checkpoint");
+    let damaged = fs::read_to_string(&checkpoint_file)?.replace("key1", "key0");
+    fs::write(&checkpoint_file, damaged)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+// Should mark a graceful close as a clean shutdown for the next open only, never losing the writes after it
+#[test]
+fn close_marks_clean_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let marker = temp_dir.path().join("kvs-clean");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let clone = store.clone();
+    KvsEngine::close(&store)?;
+    assert!(marker.exists());
+
+    // the writes of a clone after the close are replayed anyway.
+    clone.set("key2".to_owned(), "value2".to_owned())?;
+    drop((store, clone));
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!marker.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.close()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!marker.exists());
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should find the saved index that doesn't match the records, which the opens would trust
+#[test]
+fn verify_saved_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1-new".to_owned())?;
+    store.close()?;
+    let report = kvs::engines::offline::verify(temp_dir.path())?;
+    assert!(report.is_intact(), "{}", report);
+    assert_eq!(report.records(), 3);
+
+    // an index saved before the checksum, pointing to the overwritten value.
+    let checkpoint_file = temp_dir.path().join("kvs-checkpoint");
+    let mut checkpoint: serde_json::Value = serde_json::from_slice(&fs::read(&checkpoint_file)?)?;
+    checkpoint.as_object_mut().unwrap().remove("checksum");
+    for entry in checkpoint["index"].as_array_mut().unwrap() {
+        if entry[0] == "key1" {
+            entry[1]["offset"] = serde_json::json!(0);
+        }
+    }
+    fs::write(&checkpoint_file, serde_json::to_vec(&checkpoint)?)?;
+    let report = kvs::engines::offline::verify(temp_dir.path())?;
+    assert!(!report.is_intact());
+    assert_eq!(report.index_errors.len(), 1);
+    assert!(report.index_errors[0].contains("\"key1\""), "{}", report);
+
+    // a damaged index is ignored by the opens, but it's still reported.
+    let damaged = fs::read_to_string(&checkpoint_file)?.replace("index", "indices");
+    fs::write(&checkpoint_file, damaged)?;
+    assert!(!kvs::engines::offline::verify(temp_dir.path())?.index_errors.is_empty());
+    fs::remove_file(&checkpoint_file)?;
+    assert!(kvs::engines::offline::verify(temp_dir.path())?.is_intact());
+    Ok(())
+}
+
+// Should restore from a full backup and the incremental ones after it
+#[test]
+fn incremental_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let full_dir = backup_dir.path().join("full");
+    let incremental_dir = backup_dir.path().join("incremental");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let full = store.backup(&full_dir)?;
+    store.set("key1".to_owned(), "value1-new".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let incremental = store.backup_incremental(&full, &incremental_dir)?;
+    assert!(incremental.epoch > full.epoch);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+
+    let data_files = |dir: &Path| -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("kvs-data-"))
+            .collect();
+        names.sort();
+        names
+    };
+    let full_files = data_files(&full_dir);
+    assert!(!full_files.is_empty());
+    assert!(data_files(&incremental_dir).iter().all(|name| !full_files.contains(name)));
+
+    let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+    for dir in [&full_dir, &incremental_dir] {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            fs::copy(entry.path(), restore_dir.path().join(entry.file_name()))?;
+        }
+    }
+    let restored = KvStore::open(restore_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1-new".to_owned()));
+    assert_eq!(restored.get("key2".to_owned())?, None);
+    assert_eq!(restored.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(restored.get("key4".to_owned())?, None);
+    Ok(())
+}
+
+// Should back up a single point of the data, while the writes and the compactions go on
+#[test]
+fn backup_while_writing() -> Result<()> {
+    const KEYS: usize = 2000;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let store = KvStore::open(temp_dir.path())?.with_blob_threshold(64);
+    let writer = store.clone();
+    let writes = thread::spawn(move || -> Result<()> {
+        for i in 0..KEYS {
+            writer.set(format!("key{:05}", i), format!("{:0>100}", i))?;
+        }
+        Ok(())
+    });
+    let compactor = store.clone();
+    let compactions = thread::spawn(move || {
+        for _ in 0..20 {
+            // fails when a compaction or the backup is running.
+            let _ = compactor.compact();
+            thread::sleep(Duration::from_millis(1));
+        }
+    });
+    thread::sleep(Duration::from_millis(20));
+    store.backup(backup_dir.path())?;
+    writes.join().unwrap()?;
+    compactions.join().unwrap();
+
+    // the keys are written in order, so the backup has a prefix of them.
+    let restored = KvStore::open(backup_dir.path())?;
+    let keys = restored.keys("key*".to_owned())?;
+    for (i, key) in keys.iter().enumerate() {
+        assert_eq!(key, &format!("key{:05}", i));
+        assert_eq!(restored.get(key.clone())?, Some(format!("{:0>100}", i)));
+    }
+
+    // the writes after a backup start from its position.
+    let checkpoint = store.backup(backup_dir.path().join("again"))?;
+    store.set("after".to_owned(), "backup".to_owned())?;
+    let changes = store.changes(checkpoint.position(), false)?.collect::<Result<Vec<Change>>>()?;
+    assert_eq!(keys_of(changes), vec![("after".to_owned(), Some("backup".to_owned()))]);
+    Ok(())
+}
+
+// Should keep the large values in blob files, and drop the overwritten ones by compaction
+#[test]
+fn blob_spillover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let files = |prefix: &str| -> Vec<String> {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with(prefix))
+            .collect()
+    };
+    let large = |iter: usize| format!("{}{}", iter, "b".repeat(1024 * 1024));
+    let store = KvStore::open(temp_dir.path())?.with_blob_threshold(1024);
+    store.set_with_meta("large".to_owned(), large(0), Some("meta".to_owned()))?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(files("kvs-blob-").len(), 1);
+    let data_size: u64 = files("kvs-data-")
+        .iter()
+        .map(|name| fs::metadata(temp_dir.path().join(name)).unwrap().len())
+        .sum();
+    assert!(data_size < 1024, "the large value shouldn't be in the data files");
+    drop(store);
+
+    // the pointers are followed without the threshold set.
+    let store = KvStore::open(temp_dir.path())?;
+    let found = store.get_with_meta("large".to_owned())?.unwrap();
+    assert_eq!((found.value, found.meta), (large(0), Some("meta".to_owned())));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    let store = store.with_blob_threshold(1024);
+    let mut iter = 1;
+    while store.metrics().snapshot().compactions == 0 {
+        assert!(iter < 100, "No compaction detected");
+        store.set("large".to_owned(), large(iter))?;
+        iter += 1;
+    }
+    assert!(files("kvs-blob-").len() < iter, "the overwritten blobs should be dropped");
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large(iter - 1)));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Should sync the small records and the spilled values, and read them back after reopening
+#[test]
+fn synced_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let large = "l".repeat(64 * 1024);
+    let store = KvStore::open(temp_dir.path())?.with_blob_threshold(1024).with_sync(SyncPolicy::Always);
+    store.set("large".to_owned(), large.clone())?;
+    store.set_stream("streamed".to_owned(), &mut io::Cursor::new(large.as_bytes()))?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.remove("small".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(store.get("streamed".to_owned())?, Some(large));
+    assert_eq!(store.get("small".to_owned())?, None);
+    Ok(())
+}
+
+// the concurrent writes appended together keep the last value of each key, the spilled ones too.
+#[test]
+fn vectored_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?
+        .with_blob_threshold(1024)
+        .with_sync(SyncPolicy::Always)
+        .with_write_path(WritePath::Vectored);
+    let value = |thread: usize, key: usize, round: usize| match key % 4 {
+        0 => format!("{}-{}-{}", thread, round, "l".repeat(2048)),
+        _ => format!("{}-{}", thread, round),
+    };
+    let workers: Vec<_> = (0..8)
+        .map(|thread| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for round in 0..5 {
+                    for key in 0..20 {
+                        store.set(format!("key{}-{}", thread, key), value(thread, key, round))?;
+                        // all the threads write the shared keys, which are never in the same group.
+                        store.set(format!("shared{}", key), value(thread, key, round))?;
+                    }
+                }
+                store.remove(format!("key{}-0", thread))
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap()?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread in 0..8 {
+            assert_eq!(store.get(format!("key{}-0", thread))?, None);
+            for key in 1..20 {
+                assert_eq!(store.get(format!("key{}-{}", thread, key))?, Some(value(thread, key, 4)));
+            }
+        }
+        for key in 0..20 {
+            let shared = store.get(format!("shared{}", key))?.unwrap();
+            assert!((0..8).any(|thread| shared == value(thread, key, 4)));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+fn stream_values(engine: impl KvsEngine) -> Result<()> {
+    let large = "s".repeat(256 * 1024);
+    engine.set_stream("large".to_owned(), &mut io::Cursor::new(large.as_bytes()))?;
+    engine.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(engine.get("large".to_owned())?, Some(large.clone()));
+    let mut streamed = String::new();
+    engine.get_stream("large".to_owned())?.unwrap().read_to_string(&mut streamed)?;
+    assert_eq!(streamed, large);
+    streamed.clear();
+    engine.get_stream("small".to_owned())?.unwrap().read_to_string(&mut streamed)?;
+    assert_eq!(streamed, "value");
+    assert!(engine.get_stream("absent".to_owned())?.is_none());
+    Ok(())
+}
+
+// Should set and get values by streams, without the whole values in memory for `KvStore`
+#[test]
+fn streamed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    stream_values(KvStore::open(temp_dir.path())?)?;
+    let blobs = fs::read_dir(temp_dir.path())?
+        .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with("kvs-blob-"))
+        .count();
+    assert_eq!(blobs, 1, "a streamed value should be spilled whatever the threshold is");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?.map(|value| value.len()), Some(256 * 1024));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    stream_values(SledEngine::open(temp_dir.path())?)
+}
+
+fn get_or_insert_concurrently(engine: impl KvsEngine) -> Result<()> {
+    engine.set("present".to_owned(), "value".to_owned())?;
+    assert_eq!(engine.get_or_insert_with("present".to_owned(), || "default".to_owned())?, "value");
+    engine.set("removed".to_owned(), "value".to_owned())?;
+    engine.remove("removed".to_owned())?;
+    assert_eq!(engine.get_or_insert_with("removed".to_owned(), || "default".to_owned())?, "default");
+
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                engine.get_or_insert_with("session".to_owned(), || format!("created by {}", thread_id))
+            })
+        })
+        .collect();
+    let mut values = Vec::new();
+    for handle in handles {
+        values.push(handle.join().unwrap()?);
+    }
+    values.dedup();
+    assert_eq!(values.len(), 1, "every caller should get the same value");
+    assert_eq!(engine.get("session".to_owned())?, values.pop());
+    Ok(())
+}
+
+fn append_concurrently(engine: impl KvsEngine) -> Result<()> {
+    engine.append("events".to_owned(), "created;".to_owned())?;
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || engine.append("events".to_owned(), format!("{};", thread_id)))
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let events = engine.get("events".to_owned())?.unwrap();
+    assert!(events.starts_with("created;"));
+    assert_eq!(events.split(';').filter(|event| !event.is_empty()).count(), 9, "no append should be lost: {}", events);
+    Ok(())
+}
+
+// Should append to the values as single writes, without losing the concurrent ones
+#[test]
+fn append_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    append_concurrently(store.clone())?;
+    store.set_with_meta("tagged".to_owned(), "a".to_owned(), Some("meta".to_owned()))?;
+    store.append("tagged".to_owned(), "b".to_owned())?;
+    let found = store.get_with_meta("tagged".to_owned())?.unwrap();
+    assert_eq!((found.value, found.meta), ("ab".to_owned(), Some("meta".to_owned())));
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    append_concurrently(SledEngine::open(temp_dir.path())?)
+}
+
+fn set_conditionally(engine: impl KvsEngine) -> Result<()> {
+    assert!(!engine.set_xx("key".to_owned(), "value1".to_owned())?);
+    assert_eq!(engine.get("key".to_owned())?, None);
+    assert!(engine.set_nx("key".to_owned(), "value1".to_owned())?);
+    assert!(!engine.set_nx("key".to_owned(), "value2".to_owned())?);
+    assert_eq!(engine.get("key".to_owned())?, Some("value1".to_owned()));
+    assert!(engine.set_xx("key".to_owned(), "value3".to_owned())?);
+    assert_eq!(engine.get("key".to_owned())?, Some("value3".to_owned()));
+    engine.remove("key".to_owned())?;
+    assert!(!engine.set_xx("key".to_owned(), "value4".to_owned())?);
+    assert!(engine.set_nx("key".to_owned(), "value5".to_owned())?);
+
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                engine.set_nx("lock".to_owned(), format!("held by {}", thread_id))
+            })
+        })
+        .collect();
+    let mut written = 0;
+    for handle in handles {
+        if handle.join().unwrap()? {
+            written += 1;
+        }
+    }
+    assert_eq!(written, 1, "only one caller should write an absent key");
+    Ok(())
+}
+
+// Should only write when the key is absent for `set_nx`, or present for `set_xx`
+#[test]
+fn set_if() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    set_conditionally(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    set_conditionally(SledEngine::open(temp_dir.path())?)
+}
+
+// Should only write when the value is unmodified since it's read, even within the same millisecond
+#[test]
+fn set_if_unmodified() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!store.set_if_unmodified("key".to_owned(), "value".to_owned(), SystemTime::now())?);
+    store.set("key".to_owned(), "value1".to_owned())?;
+    let read = store.get_with_meta("key".to_owned())?.unwrap().modified.unwrap();
+
+    assert!(store.set_if_unmodified("key".to_owned(), "value2".to_owned(), read)?);
+    // the second write based on the same read is stale.
+    assert!(!store.set_if_unmodified("key".to_owned(), "value3".to_owned(), read)?);
+    let found = store.get_with_meta("key".to_owned())?.unwrap();
+    assert_eq!(found.value, "value2");
+    assert!(found.modified.unwrap() > read);
+    assert!(store.set_if_unmodified("key".to_owned(), "value3".to_owned(), found.modified.unwrap())?);
+    assert_eq!(store.get("key".to_owned())?, Some("value3".to_owned()));
+
+    store.remove("key".to_owned())?;
+    assert!(!store.set_if_unmodified("key".to_owned(), "value4".to_owned(), found.modified.unwrap())?);
+    Ok(())
+}
+
+fn lease_keys(engine: impl KvsEngine) -> Result<()> {
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let engine = engine.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                engine.acquire_lease("lock".to_owned(), Duration::from_secs(60))
+            })
+        })
+        .collect();
+    let mut leases = Vec::new();
+    for handle in handles {
+        leases.extend(handle.join().unwrap()?);
+    }
+    assert_eq!(leases.len(), 1, "only one should hold the lease");
+    let lease = leases.pop().unwrap();
+    assert_eq!(engine.get("lock".to_owned())?.as_deref().and_then(Lease::decode), Some(lease.clone()));
+    assert!(!engine.release_lease("lock".to_owned(), "forged".to_owned())?);
+    assert!(engine.release_lease("lock".to_owned(), lease.token)?);
+    assert_eq!(engine.get("lock".to_owned())?, None);
+
+    // an expired lease is taken by the next one, and cannot be released by its holder any more.
+    let expired = engine.acquire_lease("lock".to_owned(), Duration::from_millis(10))?.unwrap();
+    assert!(engine.acquire_lease("lock".to_owned(), Duration::from_secs(60))?.is_none());
+    thread::sleep(Duration::from_millis(20));
+    let next = engine.acquire_lease("lock".to_owned(), Duration::from_secs(60))?.unwrap();
+    assert!(!engine.release_lease("lock".to_owned(), expired.token)?);
+    assert!(engine.release_lease("lock".to_owned(), next.token)?);
+
+    engine.set("plain".to_owned(), "value".to_owned())?;
+    assert!(engine.acquire_lease("plain".to_owned(), Duration::from_secs(60)).is_err());
+    Ok(())
+}
+
+// Should lease a key to one holder at a time, until it's released or expires
+#[test]
+fn leases() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    lease_keys(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    lease_keys(SledEngine::open(temp_dir.path())?)
+}
+
+fn rename_and_copy_keys(engine: impl KvsEngine) -> Result<()> {
+    engine.set("src".to_owned(), "value1".to_owned())?;
+    engine.set("dst".to_owned(), "old".to_owned())?;
+    engine.rename("src".to_owned(), "dst".to_owned())?;
+    assert_eq!(engine.get("src".to_owned())?, None);
+    assert_eq!(engine.get("dst".to_owned())?, Some("value1".to_owned()));
+    engine.rename("dst".to_owned(), "dst".to_owned())?;
+    assert_eq!(engine.get("dst".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(engine.rename("src".to_owned(), "dst".to_owned()), Err(KvError::KeyNotFound)));
+
+    engine.copy("dst".to_owned(), "copied".to_owned())?;
+    assert_eq!(engine.get("dst".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("copied".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(engine.copy("src".to_owned(), "copied".to_owned()), Err(KvError::KeyNotFound)));
+    assert_eq!(engine.get("copied".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should move or copy the values atomically, failing on the absent keys
+#[test]
+fn rename_and_copy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    rename_and_copy_keys(store.clone())?;
+    store.set_with_meta("tagged".to_owned(), "value".to_owned(), Some("meta".to_owned()))?;
+    store.rename("tagged".to_owned(), "renamed".to_owned())?;
+    assert_eq!(store.get_with_meta("renamed".to_owned())?.and_then(|found| found.meta), Some("meta".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("src".to_owned())?, None);
+    assert_eq!(store.get("dst".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("renamed".to_owned())?, Some("value".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    rename_and_copy_keys(SledEngine::open(temp_dir.path())?)
+}
+
+fn remove_keys_by_prefix(engine: impl KvsEngine) -> Result<()> {
+    for key in ["session:1:user", "session:1:cart", "session:10:user", "session:2:user", "sessions"] {
+        engine.set(key.to_owned(), "value".to_owned())?;
+    }
+    assert_eq!(engine.get("session:1:user".to_owned())?, Some("value".to_owned()));
+    assert_eq!(engine.remove_prefix("session:1".to_owned())?, 3);
+    assert_eq!(engine.keys("*".to_owned())?, vec!["session:2:user".to_owned(), "sessions".to_owned()]);
+    assert_eq!(engine.get("session:1:user".to_owned())?, None);
+    assert_eq!(engine.remove_prefix("session:1".to_owned())?, 0);
+    assert_eq!(engine.remove_prefix(String::new())?, 2);
+    assert!(engine.keys("*".to_owned())?.is_empty());
+    Ok(())
+}
+
+// Should remove all the keys under a prefix by one write
+#[test]
+fn remove_by_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    remove_keys_by_prefix(store.clone())?;
+    assert_eq!(store.metrics().snapshot().removes, 5);
+    store.set("tenant:a:1".to_owned(), "value".to_owned())?;
+    store.set("tenant:b:1".to_owned(), "value".to_owned())?;
+    assert_eq!(store.remove_prefix("tenant:a:".to_owned())?, 1);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys("*".to_owned())?, vec!["tenant:b:1".to_owned()]);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    remove_keys_by_prefix(KvStore::open(temp_dir.path())?.with_index(IndexKind::Ordered)?)?;
+    // the removed keys kept in the soft-delete mode aren't removed again.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_soft_delete();
+    store.set("tenant:a:1".to_owned(), "value".to_owned())?;
+    store.set("tenant:a:2".to_owned(), "value".to_owned())?;
+    store.remove("tenant:a:1".to_owned())?;
+    assert_eq!(store.remove_prefix("tenant:a:".to_owned())?, 1);
+    store.undelete("tenant:a:2".to_owned())?;
+    assert_eq!(store.get("tenant:a:2".to_owned())?, Some("value".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledEngine::open(temp_dir.path())?;
+    remove_keys_by_prefix(sled.clone())?;
+    assert_eq!(sled.metrics().snapshot().removes, 5);
+    // the values read into memory are forgotten too.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    remove_keys_by_prefix(Tiered::new(KvStore::open(temp_dir.path())?, 1024))
+}
+
+fn write_in_batches(engine: impl KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.write_batch(vec![
+        BatchWrite::Set { key: "key1".to_owned(), value: "value3".to_owned() },
+        BatchWrite::Remove { key: "key2".to_owned() },
+        // the absent keys are skipped, and the last write of a key wins.
+        BatchWrite::Remove { key: "key3".to_owned() },
+        BatchWrite::Set { key: "key4".to_owned(), value: "value4".to_owned() },
+        BatchWrite::Set { key: "key4".to_owned(), value: "value5".to_owned() },
+    ])?;
+    engine.write_batch(Vec::new())?;
+    assert_eq!(engine.keys("*".to_owned())?, vec!["key1".to_owned(), "key4".to_owned()]);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key4".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// Should apply the writes of a batch by one write
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    write_in_batches(store.clone())?;
+    let snapshot = store.metrics().snapshot();
+    assert_eq!((snapshot.sets, snapshot.removes), (4, 1));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_in_batches(SledEngine::open(temp_dir.path())?)?;
+    // the values read into memory are forgotten too.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_in_batches(Tiered::new(KvStore::open(temp_dir.path())?, 1024))
+}
+
+// Should keep the type of each value, and increase the integers only
+#[test]
+fn typed_values() -> Result<()> {
+    for codec in [CodecKind::Json, CodecKind::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_codec(temp_dir.path(), codec.codec())?;
+        store.set_typed("bytes".to_owned(), TypedValue::Bytes(vec![0, 159, 255]))?;
+        store.set_typed("count".to_owned(), TypedValue::Integer(-2))?;
+        store.set("text".to_owned(), "41".to_owned())?;
+        assert_eq!(store.get("bytes".to_owned())?, Some("009fff".to_owned()));
+        assert_eq!(store.get_typed("text".to_owned())?, Some(TypedValue::String("41".to_owned())));
+        assert_eq!(store.incr("count".to_owned(), 5)?, 3);
+        assert_eq!(store.incr("text".to_owned(), 1)?, 42);
+        assert_eq!(store.incr("absent".to_owned(), -1)?, -1);
+        assert!(matches!(store.incr("bytes".to_owned(), 1), Err(KvError::WrongType { .. })));
+        assert!(store.incr("count".to_owned(), i64::MAX).is_err());
+        store.copy("bytes".to_owned(), "bytes2".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open_with_codec(temp_dir.path(), codec.codec())?;
+        assert_eq!(store.get_typed("bytes2".to_owned())?, Some(TypedValue::Bytes(vec![0, 159, 255])));
+        assert_eq!(store.get_typed("count".to_owned())?, Some(TypedValue::Integer(3)));
+        assert_eq!(store.get_typed("text".to_owned())?, Some(TypedValue::Integer(42)));
+        assert_eq!(store.get_typed("missing".to_owned())?, None);
+    }
+
+    // sled keeps no type, but still increases the integers in strings.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledEngine::open(temp_dir.path())?;
+    assert_eq!(sled.incr("count".to_owned(), 2)?, 2);
+    assert_eq!(sled.get_typed("count".to_owned())?, Some(TypedValue::String("2".to_owned())));
+    sled.set("text".to_owned(), "value".to_owned())?;
+    assert!(matches!(sled.incr("text".to_owned(), 1), Err(KvError::WrongType { .. })));
+    assert!(matches!(
+        sled.set_typed("bytes".to_owned(), TypedValue::Bytes(vec![1])),
+        Err(KvError::Unsupported { .. })
+    ));
+    Ok(())
+}
+
+// Should stop the long scans of a request once it passes its deadline, writing nothing
+#[test]
+fn stop_scans_past_deadline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_slow_log(Duration::from_secs(0));
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledEngine::open(sled_dir.path())?;
+    for key in ["session:1", "session:2"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+        sled.set(key.to_owned(), "value".to_owned())?;
+    }
+    let expired = RequestContext { request_id: 1, peer: "127.0.0.1:50000".to_owned(), deadline: Some(Instant::now()) };
+    {
+        let _entered = expired.clone().enter();
+        assert_eq!(RequestContext::current(), Some(expired));
+        assert!(matches!(store.list_keys("*".to_owned(), ListOptions::default()), Err(KvError::DeadlineExceeded { .. })));
+        assert!(matches!(store.remove_prefix("session:".to_owned()), Err(KvError::DeadlineExceeded { .. })));
+        assert!(matches!(sled.remove_prefix("session:".to_owned()), Err(KvError::DeadlineExceeded { .. })));
+        // the point operations needn't stop.
+        assert_eq!(store.get("session:1".to_owned())?, Some("value".to_owned()));
+    }
+    assert_eq!(RequestContext::current(), None);
+    assert_eq!(store.keys("*".to_owned())?.len(), 2);
+    assert_eq!(sled.keys("*".to_owned())?.len(), 2);
+
+    let pending = RequestContext { request_id: 2, peer: "127.0.0.1:50000".to_owned(), deadline: None };
+    let _entered = pending.enter();
+    assert_eq!(store.remove_prefix("session:".to_owned())?, 2);
+    Ok(())
+}
+
+// A rename torn by a crash is dropped as a whole on reopening, even if its first record is intact.
+#[test]
+fn recover_from_torn_rename() -> Result<()> {
+    // the removal of `src` is the last record, cut it partly, then wholly.
+    for cut in [3, r#"{"Rm":{"key":"src"}}"#.len() as u64 + 1] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("src".to_owned(), "value1".to_owned())?;
+        store.rename("src".to_owned(), "dst".to_owned())?;
+        drop(store);
+
+        let data_file = temp_dir.path().join("kvs-data-1");
+        let file = OpenOptions::new().write(true).open(&data_file)?;
+        file.set_len(fs::metadata(&data_file)?.len() - cut)?;
+        drop(file);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("src".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("dst".to_owned())?, None);
+        store.rename("src".to_owned(), "dst".to_owned())?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("src".to_owned())?, None);
+        assert_eq!(store.get("dst".to_owned())?, Some("value1".to_owned()));
+    }
+    Ok(())
+}
+
+fn serve_from_hot_tier(engine: impl KvsEngine) -> Result<()> {
+    // room for two of the entries below.
+    let tiered = Tiered::new(engine, 2 * "keyN".len() + 2 * "valueN".len());
+    for n in 1..=3 {
+        tiered.set(format!("key{}", n), format!("value{}", n))?;
+    }
+    assert_eq!(tiered.hot_bytes(), 0, "the writes shouldn't fill the hot tier");
+    assert_eq!(tiered.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(tiered.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(tiered.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(tiered.metrics().snapshot().cache_hits, 1);
+    // key2 is the coldest one, so it's evicted for key3.
+    assert_eq!(tiered.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(tiered.hot_bytes(), 2 * "keyN".len() + 2 * "valueN".len());
+    assert_eq!(tiered.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(tiered.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(tiered.metrics().snapshot().cache_hits, 2);
+
+    tiered.set("key2".to_owned(), "new2".to_owned())?;
+    assert_eq!(tiered.get("key2".to_owned())?, Some("new2".to_owned()));
+    tiered.append("key2".to_owned(), "!".to_owned())?;
+    assert_eq!(tiered.get("key2".to_owned())?, Some("new2!".to_owned()));
+    tiered.rename("key2".to_owned(), "key4".to_owned())?;
+    assert_eq!(tiered.get("key2".to_owned())?, None);
+    assert_eq!(tiered.get("key4".to_owned())?, Some("new2!".to_owned()));
+    tiered.remove("key4".to_owned())?;
+    assert_eq!(tiered.get("key4".to_owned())?, None);
+    assert_eq!(tiered.keys("key*".to_owned())?, vec!["key1".to_owned(), "key3".to_owned()]);
+    Ok(())
+}
+
+// Should serve the hot values from memory, evicting the least recently read ones, and never serve stale values
+#[test]
+fn tiered_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    serve_from_hot_tier(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    serve_from_hot_tier(SledEngine::open(temp_dir.path())?)
+}
+
+// Should get the present value or insert the default one atomically
+#[test]
+fn get_or_insert_with() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    get_or_insert_concurrently(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    get_or_insert_concurrently(SledEngine::open(temp_dir.path())?)
+}
+
+// Should report the start, the progress and the result of compactions to the listeners
+#[test]
+fn compaction_events() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let received = events.clone();
+    let store = KvStore::open(temp_dir.path())?
+        .with_compaction_listener(move |event| received.lock().unwrap().push(event.clone()));
+    let value = |iter: usize| format!("{}{}", iter, "v".repeat(1000));
+    let keys = KvStore::COMPACTION_PROGRESS_KEYS * 2;
+    let mut iter = 0;
+    while store.metrics().snapshot().compactions == 0 {
+        assert!(iter < 100, "No compaction detected");
+        for key_id in 0..keys {
+            store.set(format!("key{}", key_id), value(iter))?;
+        }
+        iter += 1;
+    }
+    // the metrics are recorded right before the finished event.
+    let finished = || events.lock().unwrap().iter().any(|event| matches!(event, CompactionEvent::Finished { .. }));
+    for _ in 0..100 {
+        if finished() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let events = events.lock().unwrap().clone();
+    let epoch = match events.first() {
+        Some(CompactionEvent::Started { epoch }) => *epoch,
+        first => panic!("the first event should be the start, but it's {:?}", first),
+    };
+    assert!(events.iter().any(|event| matches!(event, CompactionEvent::Progress { epoch: e, .. } if *e == epoch)));
+    match events.iter().find(|event| matches!(event, CompactionEvent::Finished { .. })) {
+        Some(CompactionEvent::Finished { epoch: e, bytes_written, records_dropped, .. }) => {
+            assert_eq!(*e, epoch);
+            assert_eq!(*bytes_written, store.metrics().snapshot().compaction_bytes_written);
+            assert!(*records_dropped > 0, "the overwritten records should be dropped");
+        }
+        _ => panic!("no finished event in {:?}", events),
+    }
+    Ok(())
+}
+
+// Should load the sorted pairs at once, over the elder values, and keep them after reopening
+#[test]
+fn ingest_sorted_pairs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key00001".to_owned(), "old".to_owned())?;
+    store.set("other".to_owned(), "kept".to_owned())?;
+    let pairs = (0..10000).map(|i| (format!("key{:05}", i), format!("value{}", i)));
+    assert!(store.ingest(pairs)? > 0);
+    assert_eq!(store.get("key00001".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key09999".to_owned())?, Some("value9999".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, Some("kept".to_owned()));
+
+    let unsorted = vec![("b".to_owned(), "1".to_owned()), ("a".to_owned(), "2".to_owned())];
+    assert!(store.ingest(unsorted).is_err());
+    assert_eq!(store.get("b".to_owned())?, None);
+    store.set("key00002".to_owned(), "new".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys("key*".to_owned())?.len(), 10000);
+    assert_eq!(store.get("key00001".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key00002".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    Ok(())
+}
+
+// Should keep the ingested pairs after reopening, with the checkpoints made during the ingestion
+#[test]
+fn checkpoint_while_ingesting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let ingesting = {
+        let store = store.clone();
+        thread::spawn(move || store.ingest((0..50000).map(|i| (format!("key{:05}", i), format!("value{}", i)))))
+    };
+    while !ingesting.is_finished() {
+        store.checkpoint()?;
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(ingesting.join().unwrap()? > 0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys("key*".to_owned())?.len(), 50000);
+    assert_eq!(store.get("key49999".to_owned())?, Some("value49999".to_owned()));
+    Ok(())
+}
+
+fn keys_of(changes: Vec<Change>) -> Vec<(String, Option<String>)> {
+    changes.into_iter().map(|change| (change.key, change.value)).collect()
+}
+
+// Should read the writes from the log in order, resume from a change, and skip the records rewritten by compactions
+#[test]
+fn log_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_blob_threshold(16);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_meta("key2".to_owned(), "value2".to_owned(), Some("meta".to_owned()))?;
+    store.remove("key1".to_owned())?;
+    store.set("large".to_owned(), "l".repeat(64))?;
+
+    let changes = store.changes(LogPosition::default(), false)?.collect::<Result<Vec<Change>>>()?;
+    assert_eq!(changes[1].meta, Some("meta".to_owned()));
+    assert!(changes[0].modified.is_some());
+    let resume_from = changes[1].next;
+    assert_eq!(changes[3].next, store.log_position()?);
+    assert_eq!(keys_of(changes), vec![
+        ("key1".to_owned(), Some("value1".to_owned())),
+        ("key2".to_owned(), Some("value2".to_owned())),
+        ("key1".to_owned(), None),
+        ("large".to_owned(), Some("l".repeat(64))),
+    ]);
+    let resumed = store.changes(resume_from, false)?.collect::<Result<Vec<Change>>>()?;
+    assert_eq!(resumed.len(), 2);
+    assert_eq!(resumed[0].key, "key1");
+
+    // a follower reads on across the compaction, to the writes after it only.
+    let mut follower = store.changes(resume_from, true)?;
+    assert_eq!(follower.by_ref().take(2).count(), 2);
+    store.compact()?;
+    let writer = store.clone();
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        writer.set("key3".to_owned(), "value3".to_owned())
+    });
+    assert_eq!(follower.next().transpose()?.map(|change| change.key), Some("key3".to_owned()));
+    handle.join().unwrap()?;
+
+    // the changes from the beginning make up the data then, the compacted ones in no order.
+    let changes = store.changes(LogPosition::default(), false)?.collect::<Result<Vec<Change>>>()?;
+    let mut keys = keys_of(changes);
+    assert_eq!(keys.pop(), Some(("key3".to_owned(), Some("value3".to_owned()))));
+    keys.sort();
+    assert_eq!(keys, vec![
+        ("key1".to_owned(), None),
+        ("key2".to_owned(), Some("value2".to_owned())),
+        ("large".to_owned(), Some("l".repeat(64))),
+    ]);
+    assert!(store.changes(resume_from, false).is_err());
+    assert!("3:1024".parse::<LogPosition>().is_ok());
+    assert!("3".parse::<LogPosition>().is_err());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledEngine::open(temp_dir.path())?;
+    assert!(matches!(sled.changes(LogPosition::default(), false), Err(KvError::Unsupported { .. })));
+    Ok(())
+}
+
+// Should open sled with the tuning, and parse its modes
+#[test]
+fn sled_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = SledOptions { cache_capacity: Some(1024 * 1024), flush_every_ms: Some(100), mode: SledMode::Fast };
+    let sled = SledEngine::open_with(temp_dir.path(), options)?;
+    sled.set("key1".to_owned(), "value1".to_owned())?;
+    sled.append("key1".to_owned(), "!".to_owned())?;
+    assert_eq!(sled.get("key1".to_owned())?, Some("value1!".to_owned()));
+
+    assert_eq!("FAST".parse::<SledMode>(), Ok(SledMode::Fast));
+    assert_eq!(SledMode::default().as_ref(), "small");
+    assert!("tiny".parse::<SledMode>().is_err());
+    Ok(())
+}
+
+// Should load the pairs into sled by one batch, unsorted ones too
+#[test]
+fn sled_ingest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledEngine::open(temp_dir.path())?;
+    sled.set("key00001".to_owned(), "old".to_owned())?;
+    let pairs = (0..1000).rev().map(|i| (format!("key{:05}", i), format!("value{}", i)));
+    assert!(sled.ingest(pairs)? > 0);
+    assert_eq!(sled.get("key00001".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(sled.metrics().snapshot().sets, 1001);
+    assert_eq!(sled.keys("key*".to_owned())?.len(), 1000);
+    assert_eq!(sled.get("key00999".to_owned())?, Some("value999".to_owned()));
+    Ok(())
+}
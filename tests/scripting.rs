@@ -0,0 +1,83 @@
+//! tests of the server-side scripts, run them by `cargo test --features scripting`.
+#![cfg(feature = "scripting")]
+
+use std::sync::Arc;
+use std::thread;
+
+use tempfile::TempDir;
+
+use kvs::{KvError, KvStore, Result};
+use kvs::client::KvsClient;
+use kvs::script::{ScriptContext, ScriptRunner};
+use kvs::server::KvServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+
+/// runs `incr`, which adds the first argument to each key, and returns the last sum.
+struct Incr;
+
+impl ScriptRunner for Incr {
+    fn eval(&self, script: &str, keys: &[String], args: &[String], db: &mut dyn ScriptContext) -> Result<String> {
+        if script != "incr" {
+            return Err(KvError::Other { reason: format!("unknown script {:?}", script) });
+        }
+        let by: i64 = args.first().and_then(|arg| arg.parse().ok()).unwrap_or(1);
+        let mut sum = 0;
+        for key in keys {
+            sum = db.get(key)?.and_then(|value| value.parse().ok()).unwrap_or(0i64) + by;
+            db.set(key, sum.to_string())?;
+        }
+        Ok(sum.to_string())
+    }
+}
+
+/// runs `peek`, which reads the key `other` whatever the keys declared.
+struct Peek;
+
+impl ScriptRunner for Peek {
+    fn eval(&self, _script: &str, _keys: &[String], _args: &[String], db: &mut dyn ScriptContext) -> Result<String> {
+        Ok(db.get("other")?.unwrap_or_default())
+    }
+}
+
+fn spawn(temp_dir: &TempDir, runner: Arc<dyn ScriptRunner>) -> KvsClient {
+    let addr = KvServer::new(KvStore::open(temp_dir.path()).unwrap(), SharedQueueThreadPool::new(4).unwrap())
+        .script_runner(runner)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    KvsClient::new(addr)
+}
+
+// the read-modify-writes of the concurrent scripts never interleave.
+#[test]
+fn scripts_run_atomically() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn(&temp_dir, Arc::new(Incr));
+    let keys = vec!["counter".to_owned(), "total".to_owned()];
+    assert_eq!(client.eval("incr".to_owned(), keys.clone(), vec!["2".to_owned()]).unwrap(), "2");
+
+    let workers: Vec<_> = (0..4)
+        .map(|_| {
+            let (client, keys) = (client.clone(), keys.clone());
+            thread::spawn(move || {
+                for _ in 0..25 {
+                    client.eval("incr".to_owned(), keys.clone(), vec!["1".to_owned()]).unwrap();
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    assert_eq!(client.get("counter".to_owned()).unwrap(), Some("102".to_owned()));
+    assert_eq!(client.get("total".to_owned()).unwrap(), Some("102".to_owned()));
+    assert!(client.eval("launch".to_owned(), keys, vec![]).is_err());
+}
+
+#[test]
+fn scripts_touch_declared_keys_only() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let client = spawn(&temp_dir, Arc::new(Peek));
+    client.set("other".to_owned(), "secret".to_owned()).unwrap();
+    assert_eq!(client.eval("peek".to_owned(), vec!["other".to_owned()], vec![]).unwrap(), "secret");
+    assert!(client.eval("peek".to_owned(), vec!["mine".to_owned()], vec![]).is_err());
+}
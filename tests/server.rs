@@ -0,0 +1,792 @@
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tempfile::TempDir;
+
+use kvs::{KvError, KvsEngine, KvStore};
+use kvs::client::{CachingClient, KvsClient};
+use kvs::config::server::{Role, UserConfig};
+use kvs::contract::{KvContractMessage, Request, Response};
+use kvs::contract::mock::duplex;
+use kvs::engines::changes::LogPosition;
+use kvs::engines::codec::CodecKind;
+use kvs::engines::engine::{BatchWrite, Preload};
+use kvs::engines::restorable::{data_dir, read_archive, Restorable};
+use kvs::engines::typed::TypedValue;
+use kvs::interceptor::Next;
+use kvs::server::KvServer;
+use kvs::server_common::{DryRunReport, ServerError};
+use kvs::value_encoding::{ENVELOPE_HEADER, ValueEncoding};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+
+#[test]
+fn embedded_server_and_client() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let addr = KvServer::new(engine, pool)
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let client = KvsClient::new(addr);
+
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned()).unwrap(), None);
+    client.remove("key1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    match client.remove("key2".to_owned()) {
+        Err(KvError::KeyNotFound) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    client.set("user:1".to_owned(), "alice".to_owned()).unwrap();
+    client.set("user:2".to_owned(), "bob".to_owned()).unwrap();
+    assert_eq!(client.keys("user:*".to_owned()).unwrap(), vec!["user:1", "user:2"]);
+    assert!(client.keys("nobody*".to_owned()).unwrap().is_empty());
+
+    let large = "s".repeat(1024 * 1024);
+    client.set_stream("large".to_owned(), &mut large.as_bytes()).unwrap();
+    let mut streamed = String::new();
+    client.get_stream("large".to_owned()).unwrap().unwrap().read_to_string(&mut streamed).unwrap();
+    assert_eq!(streamed, large);
+    assert!(client.get_stream("absent".to_owned()).unwrap().is_none());
+
+    let stats = client.stats().unwrap();
+    assert_eq!(stats.engine.sets, 4);
+    assert!(stats.engine.data_bytes >= stats.engine.bytes_written);
+
+    client.append("events".to_owned(), "login;".to_owned()).unwrap();
+    client.append("events".to_owned(), "logout;".to_owned()).unwrap();
+    assert_eq!(client.get("events".to_owned()).unwrap(), Some("login;logout;".to_owned()));
+
+    client.rename("events".to_owned(), "archived".to_owned()).unwrap();
+    client.copy("archived".to_owned(), "copied".to_owned()).unwrap();
+    assert_eq!(client.get("events".to_owned()).unwrap(), None);
+    assert_eq!(client.get("copied".to_owned()).unwrap(), Some("login;logout;".to_owned()));
+    assert!(matches!(client.rename("events".to_owned(), "copied".to_owned()), Err(KvError::KeyNotFound)));
+
+    let changes: Vec<_> = client.changes(LogPosition::default(), false).unwrap().map(Result::unwrap).collect();
+    assert_eq!(changes.first().map(|change| change.key.as_str()), Some("key1"));
+    let last = changes.last().unwrap();
+    assert_eq!((last.key.as_str(), last.value.as_deref()), ("copied", Some("login;logout;")));
+    assert_eq!(client.changes(last.next, false).unwrap().count(), 0);
+
+    client.set("session:1:user".to_owned(), "alice".to_owned()).unwrap();
+    client.set("session:1:cart".to_owned(), "[]".to_owned()).unwrap();
+    assert_eq!(client.remove_prefix("session:1:".to_owned()).unwrap(), 2);
+    assert!(client.keys("session:*".to_owned()).unwrap().is_empty());
+
+    client.set_typed("avatar".to_owned(), TypedValue::Bytes(vec![0, 255])).unwrap();
+    assert_eq!(client.get_typed("avatar".to_owned()).unwrap(), Some(TypedValue::Bytes(vec![0, 255])));
+    assert_eq!(client.incr("visits".to_owned(), 2).unwrap(), 2);
+    assert_eq!(client.get_typed("visits".to_owned()).unwrap(), Some(TypedValue::Integer(2)));
+    assert!(client.incr("avatar".to_owned(), 1).is_err());
+
+    let lease = client.acquire_lease("lock".to_owned(), Duration::from_secs(60)).unwrap().unwrap();
+    assert!(client.acquire_lease("lock".to_owned(), Duration::from_secs(60)).unwrap().is_none());
+    assert!(client.release_lease("lock".to_owned(), lease.token).unwrap());
+}
+
+fn request(server: &KvServer<KvStore, SharedQueueThreadPool>, input: &[u8]) -> Response {
+    let (mut client, server_end) = duplex();
+    client.write_all(input).unwrap();
+    client.shutdown_write();
+    server.handle(server_end.chunked(2)).unwrap();
+    Response::parse(client).expect("malformed response")
+}
+
+#[test]
+fn handle_requests_on_mock_stream() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvServer::new(
+        KvStore::open(temp_dir.path()).unwrap(),
+        SharedQueueThreadPool::new(1).unwrap(),
+    );
+
+    let set = Request::Set { key: "key1".to_owned(), value: "value1".to_owned() }.into_binary();
+    assert_eq!(request(&server, &set), Response::NoContent);
+    let get = Request::Get { key: "key1".to_owned() }.into_binary();
+    assert_eq!(request(&server, &get), Response::Content { content: "value1".to_owned() });
+
+    let error_code = |input: &[u8]| match request(&server, input) {
+        Response::Error { code, .. } => code,
+        other => panic!("unexpected response: {:?}", other),
+    };
+    let remove = Request::Remove { key: "key2".to_owned() }.into_binary();
+    assert_eq!(error_code(&remove), KvError::KeyNotFound.code());
+    assert_eq!(error_code(b"{\"op\":\"set\","), 302);
+    assert_eq!(error_code(b"{\"op\":\"launch\"}"), ServerError::BadRequest.code());
+    assert_eq!(error_code(b"{\"op\":\"get\"}"), ServerError::BadRequest.code());
+    // without an admin token, the admin requests are refused.
+    let restore = Request::Restore { token: None, archive: Default::default() }.into_binary();
+    assert_eq!(error_code(&restore), ServerError::Unauthorized.code());
+    // without a script runner, the scripts are refused.
+    let eval = Request::Eval { script: "return 1".to_owned(), keys: vec![], args: vec![] }.into_binary();
+    assert_eq!(error_code(&eval), ServerError::BadRequest.code());
+}
+
+#[test]
+fn check_users() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let user = |secret: &str, role: Role, prefixes: &[&str]| UserConfig {
+        secret: secret.to_owned(),
+        role,
+        prefixes: prefixes.iter().map(|prefix| (*prefix).to_owned()).collect(),
+    };
+    let users = vec![
+        ("reader".to_owned(), user("secret1", Role::Read, &[])),
+        ("tenant".to_owned(), user("secret2", Role::Write, &["tenant:a:"])),
+    ];
+    let server = KvServer::new(
+        KvStore::open(temp_dir.path()).unwrap(),
+        SharedQueueThreadPool::new(1).unwrap(),
+    )
+        .users(users.into_iter().collect());
+    let as_user = |user: &str, secret: &str, request: Request| {
+        Request::Auth { user: user.to_owned(), secret: secret.to_owned(), request: Box::new(request) }.into_binary()
+    };
+    let code_of = |response: Response| match response {
+        Response::Error { code, .. } => code,
+        response => panic!("unexpected response: {:?}", response),
+    };
+    let set = |key: &str| Request::Set { key: key.to_owned(), value: "value".to_owned() };
+
+    // the anonymous requests and the wrong secrets are refused.
+    let get = Request::Get { key: "tenant:a:1".to_owned() };
+    assert_eq!(code_of(request(&server, &get.clone().into_binary())), ServerError::Unauthorized.code());
+    assert_eq!(code_of(request(&server, &as_user("reader", "secret2", get.clone()))), ServerError::Unauthorized.code());
+    assert_eq!(code_of(request(&server, &as_user("reader", "secret", get.clone()))), ServerError::Unauthorized.code());
+    assert_eq!(request(&server, &as_user("reader", "secret1", get.clone())), Response::NoContent);
+    assert_eq!(code_of(request(&server, &as_user("reader", "secret1", set("key")))), ServerError::Forbidden.code());
+
+    // a user restricted to some prefixes touches the keys under them only.
+    assert_eq!(request(&server, &as_user("tenant", "secret2", set("tenant:a:1"))), Response::NoContent);
+    assert_eq!(code_of(request(&server, &as_user("tenant", "secret2", set("tenant:b:1")))), ServerError::Forbidden.code());
+    let rename = Request::Rename { from: "tenant:a:1".to_owned(), to: "tenant:b:1".to_owned() };
+    assert_eq!(code_of(request(&server, &as_user("tenant", "secret2", rename))), ServerError::Forbidden.code());
+    let transaction = Request::Transaction {
+        writes: vec![
+            BatchWrite::Set { key: "tenant:a:2".to_owned(), value: "value".to_owned() },
+            BatchWrite::Remove { key: "tenant:b:1".to_owned() },
+        ],
+    };
+    assert_eq!(code_of(request(&server, &as_user("tenant", "secret2", transaction))), ServerError::Forbidden.code());
+    let keys = |pattern: &str| Request::Keys { pattern: pattern.to_owned(), options: Default::default() };
+    assert_eq!(
+        request(&server, &as_user("tenant", "secret2", keys("tenant:a:*"))),
+        Response::Content { content: r#"["tenant:a:1"]"#.to_owned() }
+    );
+    assert_eq!(code_of(request(&server, &as_user("tenant", "secret2", keys("*")))), ServerError::Forbidden.code());
+    let changes = Request::Changes { since: LogPosition::default(), follow: false };
+    assert_eq!(code_of(request(&server, &as_user("tenant", "secret2", changes))), ServerError::Forbidden.code());
+    assert_eq!(request(&server, &as_user("reader", "secret1", get)), Response::Content { content: "value".to_owned() });
+
+    // the credentials are never nested.
+    let nested = Request::Auth { user: "reader".to_owned(), secret: "secret1".to_owned(), request: Box::new(Request::Stats) };
+    assert_eq!(code_of(request(&server, &as_user("reader", "secret1", nested))), ServerError::BadRequest.code());
+}
+
+#[test]
+fn intercept_requests() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let errors = Arc::new(AtomicU64::new(0));
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let server = KvServer::new(
+        KvStore::open(temp_dir.path()).unwrap(),
+        SharedQueueThreadPool::new(1).unwrap(),
+    )
+        // counts the failed requests, including the refused ones.
+        .intercept({
+            let errors = errors.clone();
+            let order = order.clone();
+            move |request: Request, next: Next<'_>| {
+                order.lock().unwrap().push("metrics");
+                let response = next.run(request);
+                if response.is_err() {
+                    errors.fetch_add(1, Ordering::Relaxed);
+                }
+                response
+            }
+        })
+        // refuses the writes of the keys under `readonly:`.
+        .intercept({
+            let order = order.clone();
+            move |request: Request, next: Next<'_>| {
+                order.lock().unwrap().push("guard");
+                match &request {
+                    Request::Set { key, .. } if key.starts_with("readonly:") => Err(ServerError::Unauthorized),
+                    _ => next.run(request),
+                }
+            }
+        });
+
+    let set = Request::Set { key: "key1".to_owned(), value: "value1".to_owned() }.into_binary();
+    assert_eq!(request(&server, &set), Response::NoContent);
+    assert_eq!(*order.lock().unwrap(), vec!["metrics", "guard"]);
+    let set = Request::Set { key: "readonly:key1".to_owned(), value: "value1".to_owned() }.into_binary();
+    assert!(matches!(request(&server, &set), Response::Error { code, .. } if code == ServerError::Unauthorized.code()));
+    let get = Request::Get { key: "readonly:key1".to_owned() }.into_binary();
+    assert_eq!(request(&server, &get), Response::NoContent);
+    let remove = Request::Remove { key: "key2".to_owned() }.into_binary();
+    assert!(matches!(request(&server, &remove), Response::Error { .. }));
+    assert_eq!(errors.load(Ordering::Relaxed), 2);
+
+    // the malformed requests never reach the interceptors.
+    order.lock().unwrap().clear();
+    assert!(matches!(request(&server, b"{\"op\":\"launch\"}"), Response::Error { .. }));
+    assert!(order.lock().unwrap().is_empty());
+}
+
+#[test]
+fn remote_restore() {
+    let source_dir = TempDir::new().expect("unable to create temporary source directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let source = KvStore::open(source_dir.path()).unwrap();
+    source.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    source.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    source.backup(backup_dir.path()).unwrap();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = Restorable::open(temp_dir.path(), |path| KvStore::open(path)).unwrap();
+    let addr = KvServer::new(engine, SharedQueueThreadPool::new(2).unwrap())
+        .admin_token(Some("secret".to_owned()))
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let client = KvsClient::new(addr);
+    client.set("key3".to_owned(), "value3".to_owned()).unwrap();
+
+    let archive = read_archive(&[backup_dir.path()]).unwrap();
+    assert!(client.restore("guess".to_owned(), archive.clone()).is_err());
+    assert_eq!(client.get("key3".to_owned()).unwrap(), Some("value3".to_owned()));
+    let mut illegal = archive.clone();
+    illegal.insert("../kvs-data-0".to_owned(), Vec::new());
+    assert!(client.restore("secret".to_owned(), illegal).is_err());
+    assert_eq!(data_dir(temp_dir.path()).unwrap(), temp_dir.path());
+
+    client.restore("secret".to_owned(), archive).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert_eq!(client.get("key3".to_owned()).unwrap(), None);
+    client.set("key4".to_owned(), "value4".to_owned()).unwrap();
+    assert_eq!(client.keys("*".to_owned()).unwrap(), vec!["key1", "key2", "key4"]);
+    let restored = data_dir(temp_dir.path()).unwrap();
+    assert_ne!(restored, temp_dir.path());
+    assert!(restored.join("kvs-checkpoint").exists());
+}
+
+#[test]
+fn remote_restore_binary_backup() {
+    let source_dir = TempDir::new().expect("unable to create temporary source directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let source = KvStore::open_with_codec(source_dir.path(), CodecKind::Bincode.codec()).unwrap();
+    source.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    source.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    source.backup(backup_dir.path()).unwrap();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = Restorable::open(temp_dir.path(), |path| {
+        KvStore::open_with_codec(path, CodecKind::Bincode.codec())
+    }).unwrap();
+    let addr = KvServer::new(engine, SharedQueueThreadPool::new(2).unwrap())
+        .admin_token(Some("secret".to_owned()))
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let client = KvsClient::new(addr);
+
+    let archive = read_archive(&[backup_dir.path()]).unwrap();
+    assert!(archive.values().any(|content| std::str::from_utf8(content).is_err()));
+    client.restore("secret".to_owned(), archive).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned()).unwrap(), Some("value2".to_owned()));
+}
+
+#[test]
+fn serve_admin_requests_apart() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let admin_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let admin_addr = admin_listener.local_addr().unwrap();
+    let addr = KvServer::new(engine, SharedQueueThreadPool::new(1).unwrap())
+        .admin_token(Some("secret".to_owned()))
+        .admin_listener(Some(admin_listener))
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let code_of = |response: Option<Response>| match response {
+        Some(Response::Error { code, .. }) => code,
+        response => panic!("unexpected response: {:?}", response),
+    };
+
+    let client = KvsClient::new(addr);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(code_of(client.send(Request::Stats).unwrap()), ServerError::Forbidden.code());
+    let admin = KvsClient::new(admin_addr);
+    assert_eq!(admin.stats().unwrap().engine.sets, 1);
+    assert_eq!(admin.preload("secret".to_owned(), Preload::Keys(vec!["key1".to_owned()])).unwrap(), 1);
+    let preload = Request::Preload { token: Some("secret".to_owned()), target: Preload::Prefix(String::new()) };
+    assert_eq!(code_of(client.send(preload).unwrap()), ServerError::Forbidden.code());
+    assert_eq!(code_of(admin.send(Request::Get { key: "key1".to_owned() }).unwrap()), ServerError::Forbidden.code());
+    // the admin token is still required.
+    let restore = Request::Restore { token: None, archive: Default::default() };
+    assert_eq!(code_of(admin.send(restore).unwrap()), ServerError::Unauthorized.code());
+    let preload = Request::Preload { token: Some("guess".to_owned()), target: Preload::Prefix(String::new()) };
+    assert_eq!(code_of(admin.send(preload).unwrap()), ServerError::Unauthorized.code());
+}
+
+#[test]
+fn refuse_preload_without_admin_token() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let server = KvServer::new(engine, SharedQueueThreadPool::new(1).unwrap());
+    let preload = Request::Preload { token: None, target: Preload::Prefix(String::new()) };
+    match request(&server, preload.into_binary().as_slice()) {
+        Response::Error { code, .. } => assert_eq!(code, ServerError::Unauthorized.code()),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    // the stats only reads, so it needs no token.
+    assert!(matches!(request(&server, Request::Stats.into_binary().as_slice()), Response::Content { .. }));
+}
+
+#[test]
+fn refuse_preload_with_wrong_admin_token() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let server = KvServer::new(engine, SharedQueueThreadPool::new(1).unwrap()).admin_token(Some("secret".to_owned()));
+    let preload = |token: &str| Request::Preload { token: Some(token.to_owned()), target: Preload::Prefix(String::new()) };
+    for token in ["", "secre", "secres", "secret!"] {
+        match request(&server, preload(token).into_binary().as_slice()) {
+            Response::Error { code, .. } => assert_eq!(code, ServerError::Unauthorized.code()),
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+    assert_eq!(request(&server, preload("secret").into_binary().as_slice()), Response::Content { content: "1".to_owned() });
+}
+
+#[test]
+fn shed_load_beyond_memory_limit() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    engine.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    let limit = engine.index_bytes() + 4096;
+    let addr = KvServer::new(engine.clone(), SharedQueueThreadPool::new(1).unwrap())
+        .memory_limit(Some(limit))
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let client = KvsClient::new(addr);
+    client.set("key3".to_owned(), "value3".to_owned()).unwrap();
+
+    // the request itself takes memory too.
+    let large = "v".repeat(8192);
+    match client.send(Request::Set { key: "key4".to_owned(), value: large }).unwrap() {
+        Some(Response::Error { code, retryable, .. }) => {
+            assert_eq!((code, retryable), (ServerError::Overloaded { used: 0, limit }.code(), true))
+        }
+        response => panic!("unexpected response: {:?}", response),
+    }
+    assert_eq!(engine.get("key4".to_owned()).unwrap(), None);
+    // the client tells a permanent error from a rejection to retry.
+    match client.incr("key1".to_owned(), 1) {
+        Err(KvError::Remote { code, retryable, .. }) => assert_eq!((code, retryable), (203, false)),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    // the index grows beyond the limit, then only the cheap reads and the removes of single keys go on.
+    for n in 0..100 {
+        engine.set(format!("padding:{:03}", n), "value".to_owned()).unwrap();
+    }
+    let overloaded = ServerError::Overloaded { used: 0, limit }.code();
+    let shed = |request: Request| match client.send(request).unwrap() {
+        Some(Response::Error { code, .. }) => assert_eq!(code, overloaded),
+        response => panic!("unexpected response: {:?}", response),
+    };
+    shed(Request::Set { key: "key5".to_owned(), value: "value5".to_owned() });
+    shed(Request::Keys { pattern: "key*".to_owned(), options: Default::default() });
+    shed(Request::Rename { from: "key1".to_owned(), to: "key6".to_owned() });
+    shed(Request::RemovePrefix { prefix: "padding:".to_owned() });
+    shed(Request::Preload { token: None, target: Preload::Prefix(String::new()) });
+    match client.set("key5".to_owned(), "value5".to_owned()) {
+        Err(err @ KvError::Remote { .. }) => assert_eq!((err.code(), err.is_retryable()), (overloaded, true)),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert_eq!(engine.get("key6".to_owned()).unwrap(), None);
+    client.remove("key2".to_owned()).unwrap();
+    assert_eq!(engine.keys("padding:*".to_owned()).unwrap().len(), 100);
+}
+
+#[test]
+fn shed_load_by_the_restored_engine() {
+    let source_dir = TempDir::new().expect("unable to create temporary source directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let source = KvStore::open(source_dir.path()).unwrap();
+    source.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    source.backup(backup_dir.path()).unwrap();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = Restorable::open(temp_dir.path(), |path| KvStore::open(path)).unwrap();
+    for n in 0..200 {
+        engine.set(format!("padding:{:03}", n), "value".to_owned()).unwrap();
+    }
+    let limit = engine.metrics().snapshot().index_bytes / 2;
+    let addr = KvServer::new(engine, SharedQueueThreadPool::new(2).unwrap())
+        .admin_token(Some("secret".to_owned()))
+        .memory_limit(Some(limit))
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let client = KvsClient::new(addr);
+    assert!(client.set("key2".to_owned(), "value2".to_owned()).is_err());
+
+    // the memory of the engine restored is checked, instead of the one replaced.
+    client.restore("secret".to_owned(), read_archive(&[backup_dir.path()]).unwrap()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+}
+
+/// wait for `done` for at most 10 seconds.
+fn wait_until(done: &dyn Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn cache_on_client_until_written() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    // one worker is held by following the changes.
+    let addr = KvServer::new(engine.clone(), SharedQueueThreadPool::new(2).unwrap())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let client = CachingClient::new(KvsClient::new(addr), 16, Duration::from_secs(60)).unwrap();
+
+    engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    // the values read before the earlier changes arrive aren't cached.
+    wait_until(&|| {
+        assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+        client.is_cached("key1")
+    });
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+
+    // a write by another one is forgotten once its change arrives.
+    engine.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    wait_until(&|| !client.is_cached("key1"));
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value2".to_owned()));
+
+    // its own writes are forgotten at once.
+    wait_until(&|| client.get("key1".to_owned()).is_ok() && client.is_cached("key1"));
+    client.set("key1".to_owned(), "value3".to_owned()).unwrap();
+    assert!(!client.is_cached("key1"));
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value3".to_owned()));
+    client.remove("key1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+}
+
+/// forwards the connections to a server, and cuts them while it's down, like a flaky network.
+struct Proxy {
+    addr: SocketAddr,
+    down: Arc<AtomicBool>,
+    connections: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Proxy {
+    fn spawn(server: SocketAddr) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let down = Arc::new(AtomicBool::new(false));
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        let (is_down, accepted) = (down.clone(), connections.clone());
+        std::thread::spawn(move || {
+            for inbound in listener.incoming().flatten() {
+                // the connections accepted while it's down are dropped at once.
+                if is_down.load(Ordering::SeqCst) {
+                    continue;
+                }
+                let outbound = TcpStream::connect(server).unwrap();
+                accepted.lock().unwrap().push(inbound.try_clone().unwrap());
+                pipe(inbound.try_clone().unwrap(), outbound.try_clone().unwrap());
+                pipe(outbound, inbound);
+            }
+        });
+        Proxy { addr, down, connections }
+    }
+
+    /// cut the connections and refuse the new ones if `down`, or else forward them again.
+    fn set_down(&self, down: bool) {
+        self.down.store(down, Ordering::SeqCst);
+        if down {
+            for connection in self.connections.lock().unwrap().drain(..) {
+                let _ = connection.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+/// copy from `from` to `to` on another thread, then end the writes to `to`.
+fn pipe(mut from: TcpStream, mut to: TcpStream) {
+    std::thread::spawn(move || {
+        let _ = std::io::copy(&mut from, &mut to);
+        let _ = to.shutdown(Shutdown::Write);
+    });
+}
+
+#[test]
+fn cache_on_client_after_compaction() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    // the worker following the changes cut is held until it sends the next change.
+    let addr = KvServer::new(engine.clone(), SharedQueueThreadPool::new(4).unwrap())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let proxy = Proxy::spawn(addr);
+    let client = CachingClient::new(KvsClient::new(proxy.addr), 16, Duration::from_secs(60)).unwrap();
+    engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    wait_until(&|| client.get("key1".to_owned()).is_ok() && client.is_cached("key1"));
+
+    // the changes missed while the connections are cut are dropped by a compaction.
+    proxy.set_down(true);
+    wait_until(&|| !client.is_cached("key1"));
+    engine.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    engine.compact().unwrap();
+    proxy.set_down(false);
+
+    // it follows the changes from the end of the log instead, and caches the values again.
+    wait_until(&|| {
+        assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value2".to_owned()));
+        client.is_cached("key1")
+    });
+}
+
+#[test]
+fn dry_run_destructive_requests() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let addr = KvServer::new(engine, SharedQueueThreadPool::new(2).unwrap())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let client = KvsClient::new(addr);
+    for n in 0..12 {
+        client.set(format!("tmp:{:02}", n), "value".to_owned()).unwrap();
+    }
+    client.set("tmp*".to_owned(), "value".to_owned()).unwrap();
+
+    let report = client.dry_run(Request::RemovePrefix { prefix: "tmp:".to_owned() }).unwrap();
+    assert_eq!(report.count, 12);
+    assert_eq!(report.sample.len(), DryRunReport::SAMPLE_SIZE);
+    assert_eq!(report.sample[0], "tmp:00");
+    // the prefix is literal, not a glob.
+    let report = client.dry_run(Request::RemovePrefix { prefix: "tmp*".to_owned() }).unwrap();
+    assert_eq!(report.sample, vec!["tmp*"]);
+    let report = client.dry_run(Request::Remove { key: "tmp:00".to_owned() }).unwrap();
+    assert_eq!(report, DryRunReport { count: 1, sample: vec!["tmp:00".to_owned()] });
+    assert!(matches!(client.dry_run(Request::Remove { key: "absent".to_owned() }), Err(KvError::KeyNotFound)));
+    assert!(client.dry_run(Request::Get { key: "tmp:00".to_owned() }).is_err());
+    assert_eq!(client.keys("tmp*".to_owned()).unwrap().len(), 13);
+
+    assert_eq!(client.remove_prefix("tmp:".to_owned()).unwrap(), 12);
+    assert_eq!(client.dry_run(Request::RemovePrefix { prefix: "tmp:".to_owned() }).unwrap().count, 0);
+}
+
+#[test]
+fn batch_requests_fail_one_by_one() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let addr = KvServer::new(engine, SharedQueueThreadPool::new(2).unwrap())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let client = KvsClient::new(addr);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let responses = client
+        .batch(vec![
+            Request::Get { key: "key1".to_owned() },
+            Request::Remove { key: "absent".to_owned() },
+            Request::Stats,
+            Request::Set { key: "key2".to_owned(), value: "value2".to_owned() },
+            Request::Get { key: "key2".to_owned() },
+        ])
+        .unwrap();
+    assert_eq!(responses.len(), 5);
+    assert_eq!(responses[0], Response::Content { content: "value1".to_owned() });
+    assert!(matches!(responses[1], Response::Error { code, .. } if code == KvError::KeyNotFound.code()));
+    assert!(matches!(responses[2], Response::Error { code, .. } if code == ServerError::BadRequest.code()));
+    assert_eq!(responses[3], Response::NoContent);
+    assert_eq!(responses[4], Response::Content { content: "value2".to_owned() });
+
+    let values = client.get_many(vec!["key2".to_owned(), "absent".to_owned(), "key1".to_owned()]).unwrap();
+    let values: Vec<_> = values.into_iter().map(Result::unwrap).collect();
+    assert_eq!(values, vec![Some("value2".to_owned()), None, Some("value1".to_owned())]);
+    let results = client.set_many(vec![("key3".to_owned(), "value3".to_owned())]).unwrap();
+    assert!(results[0].is_ok());
+    assert_eq!(client.get("key3".to_owned()).unwrap(), Some("value3".to_owned()));
+}
+
+#[test]
+fn transaction_over_the_wire() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let addr = KvServer::new(engine, SharedQueueThreadPool::new(2).unwrap())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let client = KvsClient::new(addr);
+    client.set("from".to_owned(), "1".to_owned()).unwrap();
+
+    let mut transaction = client.transaction();
+    transaction.remove("from".to_owned()).set("to".to_owned(), "1".to_owned());
+    transaction.commit().unwrap();
+    assert_eq!(client.get("from".to_owned()).unwrap(), None);
+    assert_eq!(client.get("to".to_owned()).unwrap(), Some("1".to_owned()));
+
+    // nothing is sent before the commit.
+    let mut transaction = client.transaction();
+    transaction.remove("to".to_owned());
+    drop(transaction);
+    assert_eq!(client.get("to".to_owned()).unwrap(), Some("1".to_owned()));
+}
+
+#[test]
+fn set_if_unmodified_over_the_wire() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    engine.set_with_meta("key".to_owned(), "value1".to_owned(), Some("text/plain".to_owned())).unwrap();
+    let addr = KvServer::new(engine, SharedQueueThreadPool::new(1).unwrap())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let client = KvsClient::new(addr);
+
+    let found = client.get_with_meta("key".to_owned()).unwrap().unwrap();
+    assert_eq!((found.value.as_str(), found.meta.as_deref()), ("value1", Some("text/plain")));
+    let modified = found.modified.unwrap();
+    assert!(client.set_if_unmodified("key".to_owned(), "value2".to_owned(), modified).unwrap());
+    assert!(!client.set_if_unmodified("key".to_owned(), "value3".to_owned(), modified).unwrap());
+    assert_eq!(client.get("key".to_owned()).unwrap(), Some("value2".to_owned()));
+    assert_eq!(client.get_with_meta("absent".to_owned()).unwrap(), None);
+}
+
+#[test]
+fn skip_requests_past_deadline() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let server = KvServer::new(engine.clone(), SharedQueueThreadPool::new(1).unwrap());
+    let expired = Request::Deadline { timeout_ms: 0, request: Box::new(Request::Stats) };
+    match request(&server, expired.into_binary().as_slice()) {
+        Response::Error { code, retryable, .. } => assert_eq!((code, retryable), (ServerError::Timeout.code(), true)),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    let in_time = Request::Deadline { timeout_ms: 60_000, request: Box::new(Request::Get { key: "key".to_owned() }) };
+    assert_eq!(request(&server, in_time.into_binary().as_slice()), Response::NoContent);
+
+    let addr = server
+        .read_timeout(Duration::from_millis(500))
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    // a client sending nothing holds the only worker until its read times out, while the next request waits in the queue.
+    let _idle = TcpStream::connect(addr).unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    let client = KvsClient::new(addr).with_timeout(Duration::from_millis(100));
+    match client.set("late".to_owned(), "value".to_owned()) {
+        Err(KvError::DeadlineExceeded { .. }) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert_eq!(engine.get("late".to_owned()).unwrap(), None);
+    let client = KvsClient::new(addr).with_timeout(Duration::from_secs(60));
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(engine.get("key".to_owned()).unwrap(), Some("value".to_owned()));
+}
+
+#[test]
+fn time_out_slow_requests() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let addr = KvServer::new(engine, SharedQueueThreadPool::new(1).unwrap())
+        .read_timeout(Duration::from_millis(200))
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+
+    // a client sending nothing, which would hold the only worker forever.
+    let mut quiet = TcpStream::connect(addr).unwrap();
+    let mut reply = Vec::new();
+    quiet.read_to_end(&mut reply).unwrap();
+
+    let client = KvsClient::new(addr);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(client.stats().unwrap().timed_out_reads, 1);
+}
+
+#[test]
+fn shutdown_closes_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let admin_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = KvServer::new(engine, SharedQueueThreadPool::new(2).unwrap()).admin_listener(Some(admin_listener));
+    let shutdown = server.shutdown_handle();
+    let addr = server.spawn("127.0.0.1:0".parse().unwrap()).unwrap();
+    let client = KvsClient::new(addr);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    shutdown.shutdown();
+    shutdown.wait();
+    assert!(shutdown.is_shutdown());
+    assert!(TcpStream::connect(addr).is_err(), "the listener should be dropped");
+    assert!(temp_dir.path().join("kvs-clean").exists());
+
+    // the clean open takes the marker away.
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert!(!temp_dir.path().join("kvs-clean").exists());
+}
+
+/// shifts the bytes of the values by its offset, a stand-in for an encryption.
+#[derive(Debug)]
+struct Shift(&'static str, u8);
+
+impl ValueEncoding for Shift {
+    fn name(&self) -> &str {
+        self.0
+    }
+
+    fn encode(&self, value: &[u8]) -> kvs::Result<Vec<u8>> {
+        Ok(value.iter().map(|byte| byte.wrapping_add(self.1)).collect())
+    }
+
+    fn decode(&self, encoded: &[u8]) -> kvs::Result<Vec<u8>> {
+        Ok(encoded.iter().map(|byte| byte.wrapping_sub(self.1)).collect())
+    }
+}
+
+#[test]
+fn encode_values_on_client() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = KvServer::new(KvStore::open(temp_dir.path()).unwrap(), SharedQueueThreadPool::new(1).unwrap())
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let plain = KvsClient::new(addr);
+    let old = KvsClient::new(addr).with_encoding(Arc::new(Shift("shift1", 1)));
+    let new = KvsClient::new(addr)
+        .with_encoding(Arc::new(Shift("shift2", 2)))
+        .with_decoding(Arc::new(Shift("shift1", 1)));
+
+    plain.set("plain".to_owned(), "value0".to_owned()).unwrap();
+    old.set("old".to_owned(), "value1".to_owned()).unwrap();
+    new.set_many(vec![("new".to_owned(), "value2".to_owned())]).unwrap().remove(0).unwrap();
+    // the server keeps the envelopes, and the plain values and the ones of both encodings read during the migration.
+    match plain.send(Request::Get { key: "new".to_owned() }).unwrap() {
+        Some(Response::Content { content }) => assert!(content.starts_with(ENVELOPE_HEADER)),
+        response => panic!("unexpected response: {:?}", response),
+    }
+    assert!(plain.get("new".to_owned()).is_err());
+    let keys = vec!["plain".to_owned(), "old".to_owned(), "new".to_owned(), "absent".to_owned()];
+    let values: Vec<_> = new.get_many(keys).unwrap().into_iter().map(Result::unwrap).collect();
+    assert_eq!(values, vec![Some("value0".to_owned()), Some("value1".to_owned()), Some("value2".to_owned()), None]);
+    assert_eq!(new.get_with_meta("old".to_owned()).unwrap().unwrap().value, "value1");
+    assert!(old.get("new".to_owned()).is_err());
+
+    assert!(matches!(new.append("new".to_owned(), "suffix".to_owned()), Err(KvError::Unsupported { .. })));
+    plain.append("plain".to_owned(), "-suffix".to_owned()).unwrap();
+    assert_eq!(new.get("plain".to_owned()).unwrap(), Some("value0-suffix".to_owned()));
+}
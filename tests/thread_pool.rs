@@ -1,9 +1,14 @@
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use crossbeam_utils::sync::WaitGroup;
+use tempfile::TempDir;
 
-use kvs::Result;
+use kvs::{KvError, KvStore, Result};
+use kvs::benchmark_common::Promise;
+use kvs::engines::pattern::ListOptions;
+use kvs::engines::pooled::PooledEngine;
 use kvs::thread_pool::*;
 
 fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
@@ -46,6 +51,50 @@ fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
     spawn_counter(pool)
 }
 
+fn spawn_with_result<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let promises: Vec<_> = (0..20)
+        .map(|i| pool.spawn_with_result(move || i * 2))
+        .collect();
+    for (i, promise) in promises.into_iter().enumerate() {
+        assert_eq!(promise.get()?, i * 2);
+    }
+
+    let promise = pool.spawn_with_result(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!("boom");
+    });
+    match promise.get() {
+        Err(KvError::TaskPanicked { reason }) => assert_eq!(reason, "boom"),
+        _ => panic!("the panic should be reported by the promise"),
+    }
+
+    // the pool should keep working after a task panicked.
+    assert_eq!(pool.spawn_with_result(|| 42).get()?, 42);
+    Ok(())
+}
+
+fn metrics_count_tasks<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let metrics = pool.metrics();
+    for _ in 0..10 {
+        pool.spawn_with_result(|| ()).get()?;
+    }
+    let _ = pool
+        .spawn_with_result(|| {
+            panic_control::disable_hook_in_current_thread();
+            panic!();
+        })
+        .get();
+
+    let snapshot = metrics.snapshot();
+    // the result is sent just before the task returns, so it may haven't been counted yet.
+    assert!(snapshot.completed_tasks >= 10);
+    assert_eq!(snapshot.panicked_tasks, 0);
+    assert!(snapshot.busy_workers <= 1);
+    Ok(())
+}
+
 #[test]
 fn naive_thread_pool_spawn_counter() -> Result<()> {
     let pool = NaiveThreadPool::new(4)?;
@@ -68,3 +117,376 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn naive_thread_pool_spawn_with_result() -> Result<()> {
+    spawn_with_result::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_with_result() -> Result<()> {
+    spawn_with_result::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_spawn_with_result() -> Result<()> {
+    spawn_with_result::<RayonThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_metrics() -> Result<()> {
+    metrics_count_tasks::<SharedQueueThreadPool>()?;
+
+    let pool = SharedQueueThreadPool::new(2)?;
+    for _ in 0..10 {
+        pool.spawn(|| {
+            panic_control::disable_hook_in_current_thread();
+            panic!();
+        })
+    }
+    spawn_counter(SharedQueueThreadPool::new(2)?)?;
+    while pool.metrics().panicked_tasks() < 10 {
+        std::thread::yield_now();
+    }
+    assert_eq!(pool.metrics().queued(), 0);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_metrics_drop_rejected_tasks() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(1)?;
+    let (release, released) = crossbeam_channel::bounded::<()>(0);
+    pool.spawn(move || {
+        let _ = released.recv();
+    });
+    for _ in 0..3 {
+        pool.spawn(|| {});
+    }
+    while pool.metrics().busy_workers() < 1 || pool.metrics().queued() < 3 {
+        std::thread::yield_now();
+    }
+    // the tasks pending are dropped by the shutdown, they mustn't be counted as queued forever.
+    let terminated = pool.shutdown();
+    drop(release);
+    terminated.recv_timeout(Duration::from_secs(5)).expect("the pool should terminate");
+    pool.wait_idle();
+    assert_eq!(pool.metrics().queued(), 0);
+    assert_eq!(pool.metrics().completed_tasks(), 1);
+    Ok(())
+}
+
+#[test]
+fn rayon_thread_pool_metrics() -> Result<()> {
+    metrics_count_tasks::<RayonThreadPool>()
+}
+
+fn workers_are_named<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    for _ in 0..20 {
+        let (name, index) = pool
+            .spawn_with_result(|| {
+                let name = std::thread::current().name().map(str::to_owned);
+                (name, current_worker_index())
+            })
+            .get()?;
+        let index = index.expect("the task should run on a worker");
+        assert_eq!(name, Some(format!("kvs-worker-{}", index)));
+    }
+    assert_eq!(current_worker_index(), None);
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_worker_name() -> Result<()> {
+    workers_are_named::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_worker_name() -> Result<()> {
+    workers_are_named::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_worker_name() -> Result<()> {
+    workers_are_named::<RayonThreadPool>()
+}
+
+fn wait_idle<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..100 {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+    pool.wait_idle();
+    assert_eq!(counter.load(Ordering::SeqCst), 100);
+    assert!(pool.metrics().is_idle());
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_wait_idle() -> Result<()> {
+    wait_idle::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_wait_idle() -> Result<()> {
+    wait_idle::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_wait_idle() -> Result<()> {
+    wait_idle::<RayonThreadPool>()
+}
+
+#[test]
+fn tokio_thread_pool_spawn_counter() -> Result<()> {
+    let pool = TokioThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn tokio_thread_pool_spawn_with_result() -> Result<()> {
+    spawn_with_result::<TokioThreadPool>()
+}
+
+#[test]
+fn tokio_thread_pool_metrics() -> Result<()> {
+    metrics_count_tasks::<TokioThreadPool>()
+}
+
+#[test]
+fn tokio_thread_pool_wait_idle() -> Result<()> {
+    wait_idle::<TokioThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_pinned() -> Result<()> {
+    let pool: SharedQueueThreadPool = ThreadPoolBuilder::new(4).pin_to_cores(vec![0]).build()?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn rayon_thread_pool_pinned() -> Result<()> {
+    let pool: RayonThreadPool = ThreadPoolBuilder::new(4).pin_to_all_cores().build()?;
+    spawn_counter(pool)
+}
+
+fn task_hooks<P: ThreadPool>() -> Result<()> {
+    let (pool, before, after) = counted_hooks_pool::<P>()?;
+    for _ in 0..20 {
+        pool.spawn(|| ());
+    }
+    pool.wait_idle();
+    assert_eq!(before.load(Ordering::SeqCst), 20);
+    assert_eq!(after.load(Ordering::SeqCst), 20);
+    Ok(())
+}
+
+fn counted_hooks_pool<P: ThreadPool>() -> Result<(P, Arc<AtomicUsize>, Arc<AtomicUsize>)> {
+    let before = Arc::new(AtomicUsize::new(0));
+    let after = Arc::new(AtomicUsize::new(0));
+    let pool: P = ThreadPoolBuilder::new(4)
+        .before_task({
+            let before = before.clone();
+            move || {
+                assert!(current_worker_index().is_some());
+                before.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .after_task({
+            let after = after.clone();
+            move || {
+                after.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .build()?;
+    Ok((pool, before, after))
+}
+
+#[test]
+fn naive_thread_pool_task_hooks() -> Result<()> {
+    task_hooks::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_task_hooks() -> Result<()> {
+    task_hooks::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_task_hooks_with_panic() -> Result<()> {
+    let (pool, before, after) = counted_hooks_pool::<SharedQueueThreadPool>()?;
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    });
+    pool.wait_idle();
+    assert_eq!(before.load(Ordering::SeqCst), 1);
+    assert_eq!(after.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[test]
+fn rayon_thread_pool_task_hooks() -> Result<()> {
+    task_hooks::<RayonThreadPool>()
+}
+
+#[test]
+fn tokio_thread_pool_task_hooks() -> Result<()> {
+    task_hooks::<TokioThreadPool>()
+}
+
+#[test]
+fn spawn_with_timeout() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let (s, r) = std::sync::mpsc::channel();
+
+    let slow = s.clone();
+    pool.spawn_with_timeout(
+        Duration::from_millis(50),
+        || std::thread::sleep(Duration::from_millis(500)),
+        move || slow.send("slow").unwrap(),
+    );
+    pool.spawn_with_timeout(Duration::from_millis(50), || (), move || s.send("fast").unwrap());
+
+    assert_eq!(r.recv_timeout(Duration::from_secs(5)).unwrap(), "slow");
+    pool.wait_idle();
+    // the fast task has finished, its watch will never fire.
+    assert!(r.recv_timeout(Duration::from_millis(200)).is_err());
+    Ok(())
+}
+
+#[test]
+fn rayon_thread_pool_from_existing() -> Result<()> {
+    let inner = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(2).build()?);
+    let first = RayonThreadPool::from_existing(inner.clone());
+    let second = RayonThreadPool::from_existing(inner);
+
+    first.spawn(|| ());
+    first.spawn(|| ());
+    second.spawn(|| ());
+    first.wait_idle();
+    second.wait_idle();
+
+    // the workers are shared, but each handle only counts its own tasks.
+    assert_eq!(first.metrics().completed_tasks(), 2);
+    assert_eq!(second.metrics().completed_tasks(), 1);
+    Ok(())
+}
+
+#[test]
+fn rayon_thread_pool_global() -> Result<()> {
+    spawn_counter(RayonThreadPool::global())?;
+    spawn_counter(RayonThreadPool::global())
+}
+
+#[test]
+fn cached_thread_pool_spawn_counter() -> Result<()> {
+    let pool = CachedThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn cached_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<CachedThreadPool>()
+}
+
+#[test]
+fn cached_thread_pool_spawn_with_result() -> Result<()> {
+    spawn_with_result::<CachedThreadPool>()
+}
+
+#[test]
+fn cached_thread_pool_worker_name() -> Result<()> {
+    workers_are_named::<CachedThreadPool>()
+}
+
+#[test]
+fn cached_thread_pool_wait_idle() -> Result<()> {
+    wait_idle::<CachedThreadPool>()
+}
+
+#[test]
+fn cached_thread_pool_task_hooks() -> Result<()> {
+    task_hooks::<CachedThreadPool>()
+}
+
+#[test]
+fn cached_thread_pool_reaps_idle_workers() -> Result<()> {
+    let pool: CachedThreadPool = ThreadPoolBuilder::new(4)
+        .keep_alive(Duration::from_millis(100))
+        .build()?;
+    for _ in 0..20 {
+        pool.spawn(|| std::thread::sleep(Duration::from_millis(10)));
+    }
+    pool.wait_idle();
+    assert!(pool.live_workers() <= 4);
+
+    // a worker is reused when it is idle.
+    for _ in 0..20 {
+        pool.spawn_with_result(|| ()).get()?;
+    }
+    assert!(pool.live_workers() <= 4);
+
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(pool.live_workers(), 0);
+    spawn_counter(pool)
+}
+
+#[test]
+fn promise_get_timeout_and_combinators() -> Result<()> {
+    let promise = Promise::new();
+    assert_eq!(promise.get_timeout(Duration::from_millis(10)), None);
+    // fulfilled before anyone waits.
+    promise.fulfill(1);
+    assert!(promise.is_fulfill());
+    assert_eq!(promise.get_timeout(Duration::from_millis(10)), Some(1));
+    assert!(!promise.is_fulfill());
+
+    let pool = SharedQueueThreadPool::new(2)?;
+    let chained = pool
+        .spawn_with_result(|| 20)
+        .map(|result| result.map(|n| n + 1))
+        .then({
+            let pool = pool.clone();
+            move |result| pool.spawn_with_result(move || result.map(|n| n * 2))
+        });
+    assert_eq!(chained.get_timeout(Duration::from_secs(5)).expect("timeout")??, 42);
+
+    // the value is mapped at once if it's already there.
+    let ready = Promise::new();
+    ready.fulfill("ready");
+    assert_eq!(ready.map(str::len).get(), 5);
+    Ok(())
+}
+
+// The async code awaits the operations of a blocking engine run on the pool sharing its runtime.
+#[test]
+fn pooled_engine_on_tokio_runtime() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = Arc::new(TokioThreadPool::new(2)?);
+    let engine = PooledEngine::new(KvStore::open(temp_dir.path())?, pool.clone());
+    let (s, r) = std::sync::mpsc::channel();
+    pool.runtime().handle().spawn(async move {
+        let result = async move {
+            let set = engine.set("key".to_owned(), "value".to_owned());
+            set.await?;
+            let get = engine.get("key".to_owned());
+            let value = get.await?;
+            let list = engine.list_keys("*".to_owned(), ListOptions::default());
+            let keys = list.await?;
+            let panicked = engine.run(|_| -> Result<()> { panic!("oops") });
+            Ok((value, keys, panicked.await)) as Result<_>
+        };
+        s.send(result.await).unwrap();
+    });
+    let (value, keys, panicked) = r.recv_timeout(Duration::from_secs(5)).expect("timeout")?;
+    assert_eq!(value, Some("value".to_owned()));
+    assert_eq!(keys, vec!["key".to_owned()]);
+    assert!(matches!(panicked, Err(KvError::TaskPanicked { .. })));
+    Ok(())
+}
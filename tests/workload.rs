@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tempfile::TempDir;
+
+use kvs::{KvsEngine, KvStore, Result};
+use kvs::benchmark_common::{dir_size, LatencyRecorder, LatencyReport, SpaceProbe};
+use kvs::workload::{key_of, KeyDistribution, Operation, Workload};
+
+#[test]
+fn workload_is_reproducible() {
+    let workload = Workload {
+        insert_proportion: 0.2,
+        ..Workload::default()
+    };
+    let first: Vec<_> = workload.load().chain(workload.operations()).collect();
+    let second: Vec<_> = workload.load().chain(workload.operations()).collect();
+    assert_eq!(first, second);
+    assert_eq!(first.len(), workload.record_count + workload.operation_count);
+
+    let reseeded = Workload { seed: 1, ..workload };
+    assert_ne!(first, reseeded.load().chain(reseeded.operations()).collect::<Vec<_>>());
+}
+
+#[test]
+fn workload_operation_mix() {
+    let workload = Workload {
+        record_count: 100,
+        operation_count: 10000,
+        read_proportion: 0.9,
+        update_proportion: 0.0,
+        insert_proportion: 0.1,
+        distribution: KeyDistribution::Uniform,
+        value_size: 8,
+        seed: 42,
+    };
+    let mut reads = 0;
+    let mut inserts = 0;
+    for operation in workload.operations() {
+        match operation {
+            Operation::Read { .. } => reads += 1,
+            Operation::Insert { key, value } => {
+                assert_eq!(key, key_of(workload.record_count + inserts));
+                assert_eq!(value.len(), 8);
+                inserts += 1;
+            }
+            Operation::Update { .. } => panic!("no update is expected"),
+        }
+    }
+    assert!(reads > 8500 && reads < 9500, "unexpected reads: {}", reads);
+    assert_eq!(reads + inserts, 10000);
+}
+
+#[test]
+fn zipfian_prefers_hot_keys() {
+    let workload = Workload {
+        record_count: 1000,
+        operation_count: 10000,
+        read_proportion: 1.0,
+        update_proportion: 0.0,
+        ..Workload::default()
+    };
+    let mut hits = HashMap::new();
+    for operation in workload.operations() {
+        if let Operation::Read { key } = operation {
+            *hits.entry(key).or_insert(0usize) += 1;
+        }
+    }
+    let hottest = hits[&key_of(0)];
+    // the first record is the hottest, it's far more than the 10 hits of a uniform distribution.
+    assert!(hottest > 500, "the hottest key is read {} times", hottest);
+    assert!(hits.values().all(|&n| n <= hottest));
+}
+
+#[test]
+fn apply_workload() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let workload = Workload {
+        record_count: 50,
+        operation_count: 200,
+        ..Workload::default()
+    };
+    for operation in workload.load().chain(workload.operations()) {
+        operation.apply(&store)?;
+    }
+    for n in 0..workload.record_count {
+        assert!(store.get(key_of(n))?.is_some());
+    }
+    Ok(())
+}
+
+#[test]
+fn latency_percentiles() {
+    let latencies = LatencyRecorder::new();
+    assert_eq!(latencies.report(), LatencyReport::default());
+    for ms in (1..=100).rev() {
+        latencies.record(Duration::from_millis(ms));
+    }
+    let report = latencies.report();
+    assert_eq!(report.count, 100);
+    assert_eq!(report.p50, Duration::from_millis(50));
+    assert_eq!(report.p95, Duration::from_millis(95));
+    assert_eq!(report.p99, Duration::from_millis(99));
+    assert_eq!(report.max, Duration::from_millis(100));
+
+    assert_eq!(latencies.time(|| 42), 42);
+    assert_eq!(latencies.report().count, 101);
+    latencies.clear();
+    assert_eq!(latencies.report().count, 0);
+}
+
+#[test]
+fn space_report() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let probe = SpaceProbe::start(temp_dir.path(), store.metrics().snapshot());
+    let workload = Workload {
+        record_count: 100,
+        operation_count: 100,
+        read_proportion: 0.0,
+        ..Workload::default()
+    };
+    let mut logical_bytes = 0;
+    for operation in workload.load().chain(workload.operations()) {
+        operation.apply(&store)?;
+        logical_bytes += operation.logical_bytes();
+    }
+    let report = probe.report(logical_bytes, store.metrics().snapshot())?;
+    assert_eq!(report.logical_bytes, logical_bytes);
+    assert!(report.bytes_written > report.logical_bytes);
+    assert_eq!(report.compaction_bytes_written, 0);
+    assert!(report.disk_bytes >= report.bytes_written);
+    assert_eq!(report.disk_bytes, dir_size(temp_dir.path())?);
+    assert!(report.write_amplification() > 1.0);
+    assert!(report.space_amplification() > 1.0);
+    Ok(())
+}